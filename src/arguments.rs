@@ -0,0 +1,288 @@
+//! # Argument mounts
+//!
+//! `arguments` finds the host paths in the arguments of the program for `--auto-mount-args`:
+//! existing files and directories are mounted read-only, and the parent directory of files the
+//! program is expected to create is mounted writable. The paths in the arguments are replaced by
+//! the absolute paths which are mounted.
+
+use anyhow::anyhow;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::landlock::Access;
+use crate::SYSTEM_MOUNTS;
+
+/// Options followed by a file the program creates.
+const OUTPUT_OPTIONS: [&str; 3] = ["-o", "--out", "--output"];
+
+/// Directories provided by the sandbox itself.
+const SANDBOX_DIRS: [&str; 3] = ["/dev", "/proc", "/sys"];
+
+/// A tmpfs of its own in the sandbox, files created directly in it stay in the sandbox instead of
+/// exposing the one of the host.
+const SANDBOX_TMP: &str = "/tmp";
+
+/// Credentials which are never mounted for arguments, relative to the home directory.
+const SENSITIVE_HOME_PATHS: [&str; 12] = [
+    ".aws",
+    ".azure",
+    ".config/gcloud",
+    ".config/gh",
+    ".docker",
+    ".git-credentials",
+    ".gnupg",
+    ".kube",
+    ".local/share/keyrings",
+    ".netrc",
+    ".password-store",
+    ".ssh",
+];
+
+/// Credentials of the system which are never mounted for arguments.
+const SENSITIVE_PATHS: [&str; 3] = ["/etc/gshadow", "/etc/shadow", "/etc/sudoers"];
+
+/// The arguments of the program with paths made absolute, and the mounts for them.
+#[derive(Debug, Default, PartialEq)]
+pub struct ArgumentMounts {
+    pub arguments: Vec<String>,
+    pub readonly: Vec<PathBuf>,
+    pub writable: Vec<PathBuf>,
+}
+
+/// What the sandbox shows of the host without mounts for the arguments.
+pub struct Visible<'a> {
+    pub home: &'a Path,
+    pub current_dir: &'a Path,
+    /// Access to the current directory, if it is mounted
+    pub current_dir_access: Option<Access>,
+    /// Whether the system directories are mounted as a whole
+    pub system: bool,
+    /// Other mounts, like `--mount` and `--mount-writable`
+    pub mounts: &'a [(PathBuf, Access)],
+}
+
+/// Finds the paths in the arguments, either an argument as a whole, the value of a `--flag=path`
+/// argument, or a file to create after an output option like `-o`. Arguments which are no
+/// existing path, and aren't a path in an existing directory, are left alone.
+pub fn argument_mounts(
+    arguments: &[String],
+    visible: &Visible,
+) -> Result<ArgumentMounts, anyhow::Error> {
+    let mut mounts = ArgumentMounts::default();
+    let mut output = false;
+    for argument in arguments {
+        let (prefix, value, is_output) = match argument.split_once('=') {
+            Some((option, value)) if option.starts_with('-') => (
+                &argument[..=option.len()],
+                value,
+                OUTPUT_OPTIONS.contains(&option),
+            ),
+            _ if argument.starts_with('-') => {
+                output = OUTPUT_OPTIONS.contains(&argument.as_str());
+                mounts.arguments.push(argument.clone());
+                continue;
+            }
+            _ => ("", argument.as_str(), output),
+        };
+        output = false;
+        let argument = match mounts.mount(value, is_output, visible)? {
+            Some(path) => format!("{prefix}{}", path.display()),
+            None => argument.clone(),
+        };
+        mounts.arguments.push(argument);
+    }
+    Ok(mounts)
+}
+
+impl ArgumentMounts {
+    /// Mounts the path in the argument if it is one, returns the absolute path.
+    fn mount(
+        &mut self,
+        value: &str,
+        output: bool,
+        visible: &Visible,
+    ) -> Result<Option<PathBuf>, anyhow::Error> {
+        if value.is_empty() {
+            return Ok(None);
+        }
+        let path = visible.current_dir.join(value);
+        if let Ok(path) = fs::canonicalize(&path) {
+            if visible.contains(&path, Access::ReadOnly) {
+                return Ok(None);
+            }
+            check_sensitive(&path, value, visible.home)?;
+            if !self.readonly.contains(&path) {
+                self.readonly.push(path.clone());
+            }
+            return Ok(Some(path));
+        }
+
+        // A file to create, in an existing directory
+        if !output && !value.contains('/') {
+            return Ok(None);
+        }
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return Ok(None);
+        };
+        let Ok(dir) = fs::canonicalize(dir) else {
+            return Ok(None);
+        };
+        if !dir.is_dir() {
+            return Ok(None);
+        }
+        let path = dir.join(name);
+        if visible.contains(&dir, Access::ReadWrite) || dir == Path::new(SANDBOX_TMP) {
+            return Ok(Some(path));
+        }
+        if visible.current_dir_access.is_some() && dir == visible.current_dir {
+            return Err(anyhow!(
+                "Argument '{value}' is created in the read-only current directory, use \
+                 --current-dir-writable"
+            ));
+        }
+        check_sensitive(&dir, value, visible.home)?;
+        if !self.writable.contains(&dir) {
+            self.writable.push(dir);
+        }
+        Ok(Some(path))
+    }
+}
+
+impl Visible<'_> {
+    /// Whether the path is visible in the sandbox with the access already.
+    fn contains(&self, path: &Path, access: Access) -> bool {
+        let current_dir = match self.current_dir_access {
+            Some(Access::ReadWrite) => true,
+            Some(Access::ReadOnly) => access == Access::ReadOnly,
+            None => false,
+        };
+        (current_dir && path.starts_with(self.current_dir))
+            || SANDBOX_DIRS.iter().any(|dir| path.starts_with(dir))
+            || (self.system
+                && access == Access::ReadOnly
+                && SYSTEM_MOUNTS.iter().any(|dir| path.starts_with(dir)))
+            || self.mounts.iter().any(|(dir, dir_access)| {
+                path.starts_with(dir)
+                    && (*dir_access == Access::ReadWrite || access == Access::ReadOnly)
+            })
+    }
+}
+
+/// Whether a path is or contains the home directory or sensitive files, or is in a sensitive
+/// directory.
+pub(crate) fn is_sensitive(path: &Path, home: &Path) -> bool {
+    home.starts_with(path)
+        || SENSITIVE_HOME_PATHS
+            .iter()
+            .map(|sensitive| home.join(sensitive))
+            .chain(SENSITIVE_PATHS.iter().map(PathBuf::from))
+            .any(|sensitive| path.starts_with(&sensitive) || sensitive.starts_with(path))
+}
+
+fn check_sensitive(path: &Path, argument: &str, home: &Path) -> Result<(), anyhow::Error> {
+    if is_sensitive(path, home) {
+        return Err(anyhow!(
+            "Refusing to mount {path:?} for argument '{argument}', it is or contains sensitive \
+             files, use --mount to mount it explicitly"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime_dir::RuntimeDir;
+
+    fn strings(arguments: &[&str]) -> Vec<String> {
+        arguments
+            .iter()
+            .map(|argument| argument.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_argument_mounts() -> Result<(), anyhow::Error> {
+        let runtime_dir = RuntimeDir::new("arguments")?;
+        let dir = runtime_dir.path().canonicalize()?;
+        let home = dir.join("home");
+        let project = home.join("project");
+        let other = home.join("other");
+        fs::create_dir_all(&project)?;
+        fs::create_dir_all(&other)?;
+        fs::create_dir_all(home.join(".ssh"))?;
+        fs::write(project.join("notes.txt"), "")?;
+        fs::write(other.join("input.txt"), "")?;
+        fs::write(home.join(".ssh/id_ed25519"), "")?;
+        let mut visible = Visible {
+            home: &home,
+            current_dir: &project,
+            current_dir_access: None,
+            system: true,
+            mounts: &[],
+        };
+
+        let mounts = argument_mounts(
+            &strings(&[
+                "-v",
+                "notes.txt",
+                "--input=../other/input.txt",
+                "/usr/bin/ls",
+                "-o",
+                "out.bin",
+                "hello",
+                "../other/new/file",
+            ]),
+            &visible,
+        )?;
+        let path = |path: &Path| path.display().to_string();
+        assert_eq!(
+            mounts.arguments,
+            [
+                "-v".to_string(),
+                path(&project.join("notes.txt")),
+                format!("--input={}", path(&other.join("input.txt"))),
+                "/usr/bin/ls".to_string(),
+                "-o".to_string(),
+                path(&project.join("out.bin")),
+                "hello".to_string(),
+                "../other/new/file".to_string(),
+            ]
+        );
+        assert_eq!(
+            mounts.readonly,
+            [project.join("notes.txt"), other.join("input.txt")]
+        );
+        assert_eq!(mounts.writable, std::slice::from_ref(&project));
+
+        // Already visible in the current directory
+        visible.current_dir_access = Some(Access::ReadOnly);
+        let mounts = argument_mounts(&strings(&["notes.txt"]), &visible)?;
+        assert_eq!(
+            mounts,
+            ArgumentMounts {
+                arguments: strings(&["notes.txt"]),
+                ..Default::default()
+            }
+        );
+        assert!(argument_mounts(&strings(&["-o", "out.bin"]), &visible).is_err());
+        visible.current_dir_access = Some(Access::ReadWrite);
+        assert!(argument_mounts(&strings(&["-o", "out.bin"]), &visible)?
+            .writable
+            .is_empty());
+
+        // Created in the tmpfs of the sandbox, without exposing the one of the host
+        let mounts = argument_mounts(&strings(&["-o", "/tmp/out.bin"]), &visible)?;
+        assert_eq!(mounts.arguments, strings(&["-o", "/tmp/out.bin"]));
+        assert!(mounts.writable.is_empty());
+
+        // Sensitive paths
+        for argument in ["../.ssh/id_ed25519", "..", "../.ssh/new", "/"] {
+            assert!(
+                argument_mounts(&strings(&[argument]), &visible).is_err(),
+                "{argument}"
+            );
+        }
+        Ok(())
+    }
+}
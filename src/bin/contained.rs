@@ -5,14 +5,19 @@
 
 use std::path::PathBuf;
 use std::process::ExitCode;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
-#[command(version)]
+#[command(version, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// The program to run
-    program: PathBuf,
+    #[arg(required = true)]
+    program: Option<PathBuf>,
 
     /// Arguments to the programs
     arguments: Vec<String>,
@@ -21,9 +26,41 @@ struct Cli {
     #[arg(long, default_value = "empty")]
     image: String,
 
-    /// Network mode
-    #[arg(long, default_value = "none")]
-    network: String,
+    /// Network mode, `none` by default
+    #[arg(long)]
+    network: Option<String>,
+
+    /// Publish a port, `[IP:]HOST:CONTAINER[/PROTOCOL]`, binding to 127.0.0.1 unless IP is given
+    #[arg(short, long)]
+    publish: Vec<contained::PortMapping>,
+
+    /// Add an entry to /etc/hosts, `NAME:IP`
+    #[arg(long)]
+    add_host: Vec<contained::HostAlias>,
+
+    /// Allow access to these hosts via an HTTP(S) proxy (e.g. `example.org,*.crates.io`)
+    #[arg(long, value_delimiter = ',', conflicts_with = "network")]
+    allow_host: Vec<String>,
+
+    /// Memory limit, like `512m` or `2g`
+    #[arg(long, value_parser = contained::parse_memory)]
+    memory: Option<u64>,
+
+    /// Number of CPUs, like `1.5`
+    #[arg(long, value_parser = contained::parse_cpus)]
+    cpus: Option<f64>,
+
+    /// Maximum number of processes
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pids_limit: Option<u32>,
+
+    /// Kill the sandbox after this time, like `90s` or `10m`, and exit with status 124
+    #[arg(long, value_parser = contained::parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Seccomp profile in the Docker/OCI JSON format, or `unconfined` to disable seccomp
+    #[arg(long)]
+    seccomp_profile: Option<contained::SeccompProfile>,
 
     /// Mount the current directory
     #[arg(long, conflicts_with = "current_dir_writable")]
@@ -41,10 +78,97 @@ struct Cli {
     #[arg(long)]
     mount_writable: Vec<PathBuf>,
 
-    /// Pass environment variable
+    /// Mount the current directory with a copy-on-write overlay, review the changes after the run
+    #[arg(long, conflicts_with = "current_dir_writable")]
+    overlay: bool,
+
+    /// Mount additional directory with a copy-on-write overlay
+    #[arg(long)]
+    overlay_mount: Vec<PathBuf>,
+
+    /// What to do with changes in overlays: `ask`, `apply`, `discard` or `patch=FILE`
+    #[arg(long, default_value = "ask")]
+    overlay_action: contained::OverlayAction,
+
+    /// Write a JSON journal of the paths changed in writable mounts to this file
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// Compare the content of files for the journal, not only size and modification time
+    #[arg(long)]
+    journal_hash: bool,
+
+    /// Exit with an error if paths in writable mounts matching this pattern are changed,
+    /// like `package-lock.json` or `.github/`
+    #[arg(long, value_parser = contained::parse_write_pattern)]
+    fail_on_write: Vec<String>,
+
+    /// Make the root file system writable with a throwaway overlay, limited to SIZE
+    #[arg(long, value_name = "SIZE", num_args = 0..=1, require_equals = true,
+          default_missing_value = "1g", value_parser = contained::parse_memory)]
+    ephemeral_root: Option<u64>,
+
+    /// Private home directory: `ephemeral`, or `persistent[:NAME]` kept between runs
+    /// (named after the program by default)
+    #[arg(long)]
+    home: Option<contained::HomeMode>,
+
+    /// Mount a shared cache writable, `NAME[:PATH]`, like `cargo`, `npm` or `npm:/home/u/.npm`
+    #[arg(long)]
+    cache: Vec<contained::CacheSpec>,
+
+    /// Toolchain preset: `rust`, `node`, `python`, `go`, `java`, or one defined in
+    /// `~/.config/contained/presets.json`
+    #[arg(long)]
+    preset: Vec<contained::Preset>,
+
+    /// Profile written by `contained learn`, a name or a path to a JSON file: mounts its paths,
+    /// and the current directory if the profile needs it. The network must be enabled explicitly
+    #[arg(long)]
+    profile: Option<contained::Profile>,
+
+    /// Print the directories mounted for the program, its interpreters and libraries, and why,
+    /// and a table of what the sandbox sees: paths, environment, network, capabilities and user
+    #[arg(long)]
+    explain: bool,
+
+    /// Print the `podman run` command line instead of running anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Mount only the files of the system directories which the program needs: its libraries,
+    /// interpreters, locales and time zones, and name service and network config
+    #[arg(long, conflicts_with = "ephemeral_root")]
+    minimal: bool,
+
+    /// Mount the existing files and directories in the arguments read-only, and the directory of
+    /// files to create (after `-o` or with a `/`) writable, replacing them by absolute paths
+    #[arg(long)]
+    auto_mount_args: bool,
+
+    /// Start an interactive shell instead of the program, `$SHELL` if it is visible in the sandbox
+    /// or `/bin/sh`, with the same mounts, environment, network and working directory
+    #[arg(long)]
+    shell: bool,
+
+    /// Pass environment variable, `NAME`, `NAME=VALUE` or a pattern like `LC_*`
     #[arg(short, long)]
     env: Vec<String>,
 
+    /// Pass the environment variables of a file in the dotenv format
+    #[arg(long)]
+    env_file: Vec<PathBuf>,
+
+    /// Don't pass environment variables matching this pattern, in addition to ones which may
+    /// contain secrets like `*_TOKEN`, `*SECRET*` or `AWS_*`, unless given by name with `--env`
+    #[arg(long)]
+    env_deny: Vec<String>,
+
+    /// Mount a secret read-only at `/run/secrets/NAME`, `NAME=file:PATH`, `NAME=env:VAR` or
+    /// `NAME=cmd:COMMAND`, kept in memory and removed after the run
+    #[arg(long)]
+    secret: Vec<contained::SecretSpec>,
+
     /// Working directory
     #[arg(short, long)]
     workdir: Option<PathBuf>,
@@ -54,20 +178,164 @@ struct Cli {
     x11: bool,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the persistent home directories of `--home`
+    #[command(subcommand)]
+    Home(HomeCommand),
+    /// Manage the shared caches of `--cache`
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Run a program traced, recording the files it uses and whether it needs the network, and
+    /// write a profile for `--profile`. Writes outside the current directory and /tmp are denied
+    Learn {
+        /// Write the profile to this file instead of `~/.config/contained/profiles/PROGRAM.json`
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// The program to run
+        program: PathBuf,
+        /// Arguments to the program
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        arguments: Vec<String>,
+    },
+    /// Check that the sandboxes still isolate programs: a probe tries to read the home directory,
+    /// `~/.ssh` and `/etc/shadow`, write outside writable mounts, connect to the network, see host
+    /// processes, ptrace the host process and gain capabilities. Exits with 1 if any of it succeeds
+    Verify {
+        /// Backend to verify, `podman`, `docker`, `bwrap` or `native`, all available ones by
+        /// default
+        #[arg(long)]
+        backend: Vec<contained::Backend>,
+        /// Image for the podman and docker backends
+        #[arg(long, default_value = "empty")]
+        image: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum HomeCommand {
+    /// List the home directories
+    Ls,
+    /// Remove home directories, with their Docker volumes
+    Rm {
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+    /// Print the path of a home directory
+    Path { name: String },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// List the caches and their sizes
+    Ls,
+    /// Remove caches not in use, all of them unless names are given
+    Prune { names: Vec<String> },
+}
+
+fn run_command(command: Command) -> Result<ExitCode, anyhow::Error> {
+    match command {
+        Command::Home(HomeCommand::Ls) => {
+            for name in contained::list_homes()? {
+                println!("{name}");
+            }
+        }
+        Command::Home(HomeCommand::Rm { names }) => {
+            for name in names {
+                contained::remove_home(&name)?;
+            }
+        }
+        Command::Home(HomeCommand::Path { name }) => {
+            println!("{}", contained::home_path(&name)?.display());
+        }
+        Command::Cache(CacheCommand::Ls) => {
+            for (name, size) in contained::list_caches()? {
+                println!("{name}\t{size}");
+            }
+        }
+        Command::Cache(CacheCommand::Prune { names }) => {
+            for name in contained::prune_caches(&names)? {
+                println!("{name}");
+            }
+        }
+        Command::Learn {
+            output,
+            program,
+            arguments,
+        } => contained::learn(&program, &arguments, output.as_deref())?,
+        Command::Verify { backend, image } => {
+            return Ok(ExitCode::from(contained::verify(&backend, &image)?));
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
 fn main() -> Result<ExitCode, anyhow::Error> {
+    if let Some(exit_code) = contained::sandbox_helper()? {
+        return Ok(ExitCode::from(exit_code));
+    }
+
     let cli = Cli::parse();
-    contained::contained_via_command(
+    if let Some(command) = cli.command {
+        return run_command(command);
+    }
+    let program = cli.program.expect("required argument");
+    let profile = cli.profile.as_ref();
+    if profile.is_some_and(|p| p.network()) && cli.network.is_none() && cli.allow_host.is_empty() {
+        eprintln!("contained: the profile needs the network, enable it with --network pasta");
+    }
+    let network = cli.network.clone().unwrap_or("none".to_string());
+    let mount_current_dir = cli.current_dir
+        || cli.current_dir_writable
+        || cli.overlay
+        || profile.is_some_and(|p| p.mount_current_dir());
+    let mount_current_dir_writable = cli.current_dir_writable
+        || (profile.is_some_and(|p| p.current_dir_writable()) && !cli.current_dir && !cli.overlay);
+    let options = contained::SandboxOptions {
+        allow_hosts: cli.allow_host,
+        publish: cli.publish,
+        add_hosts: cli.add_host,
+        limits: contained::ResourceLimits {
+            memory: cli.memory,
+            cpus: cli.cpus,
+            pids_limit: cli.pids_limit,
+            timeout: cli.timeout,
+        },
+        seccomp: cli.seccomp_profile.unwrap_or_default(),
+        overlay_current_dir: cli.overlay,
+        overlay_mounts: cli.overlay_mount,
+        overlay_action: cli.overlay_action,
+        journal: cli.journal,
+        journal_hash: cli.journal_hash,
+        fail_on_write: cli.fail_on_write,
+        ephemeral_root: cli.ephemeral_root,
+        home: cli.home,
+        caches: cli.cache,
+        presets: cli.preset,
+        explain: cli.explain,
+        dry_run: cli.dry_run,
+        minimal: cli.minimal,
+        profile: cli.profile,
+        auto_mount_args: cli.auto_mount_args,
+        shell: cli.shell,
+        env_files: cli.env_file,
+        env_deny: cli.env_deny,
+        secrets: cli.secret,
+        ..Default::default()
+    };
+    let exit_code = contained::contained_via_command(
         &cli.image,
-        &cli.program,
+        &program,
         &cli.arguments,
-        &cli.network,
-        cli.current_dir || cli.current_dir_writable,
-        cli.current_dir_writable,
+        &network,
+        mount_current_dir,
+        mount_current_dir_writable,
         &cli.mount,
         &cli.mount_writable,
         &cli.env,
         cli.workdir,
         cli.x11,
+        &options,
     )?;
-    Ok(ExitCode::SUCCESS)
+    Ok(ExitCode::from(exit_code))
 }
@@ -0,0 +1,64 @@
+//! # landlocked
+//!
+//! Run a program restricted with Landlock, without any external binaries.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// The program to run
+    program: PathBuf,
+
+    /// Arguments to the programs
+    arguments: Vec<String>,
+
+    /// Enable network access
+    #[arg(long)]
+    network: bool,
+
+    /// Seccomp profile in the Docker/OCI JSON format, or `unconfined` to disable seccomp
+    #[arg(long)]
+    seccomp_profile: Option<contained::SeccompProfile>,
+
+    /// Allow only read access to the current directory, instead of writable access
+    #[arg(long, conflicts_with = "no_current_dir")]
+    current_dir_readonly: bool,
+
+    /// Don't allow access to the current directory
+    #[arg(long)]
+    no_current_dir: bool,
+
+    /// Allow access to additional directory read-only
+    #[arg(long)]
+    mount: Vec<PathBuf>,
+
+    /// Allow access to additional directory writable
+    #[arg(long)]
+    mount_writable: Vec<PathBuf>,
+
+    /// Pass environment variable, `NAME`, `NAME=VALUE` or a pattern like `LC_*`
+    #[arg(short, long)]
+    env: Vec<String>,
+
+    /// Working directory
+    #[arg(short, long)]
+    workdir: Option<PathBuf>,
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let cli = Cli::parse();
+    let options = contained::LandlockOptions {
+        network: cli.network,
+        current_dir: !cli.no_current_dir,
+        current_dir_writable: !cli.current_dir_readonly,
+        mount_readonly: cli.mount,
+        mount_writable: cli.mount_writable,
+        extra_env: cli.env,
+        workdir: cli.workdir,
+        seccomp: cli.seccomp_profile.unwrap_or_default(),
+    };
+    contained::landlocked(&cli.program, &cli.arguments, &options)
+}
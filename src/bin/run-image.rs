@@ -5,6 +5,7 @@
 
 use std::path::PathBuf;
 use std::process::ExitCode;
+use std::time::Duration;
 
 use clap::Parser;
 
@@ -25,6 +26,34 @@ struct Cli {
     #[arg(long, default_value = "none")]
     network: String,
 
+    /// Publish a port, `[IP:]HOST:CONTAINER[/PROTOCOL]`, binding to 127.0.0.1 unless IP is given
+    #[arg(short, long)]
+    publish: Vec<contained::PortMapping>,
+
+    /// Add an entry to /etc/hosts, `NAME:IP`
+    #[arg(long)]
+    add_host: Vec<contained::HostAlias>,
+
+    /// Memory limit, like `512m` or `2g`
+    #[arg(long, value_parser = contained::parse_memory)]
+    memory: Option<u64>,
+
+    /// Number of CPUs, like `1.5`
+    #[arg(long, value_parser = contained::parse_cpus)]
+    cpus: Option<f64>,
+
+    /// Maximum number of processes
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pids_limit: Option<u32>,
+
+    /// Kill the sandbox after this time, like `90s` or `10m`, and exit with status 124
+    #[arg(long, value_parser = contained::parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Seccomp profile in the Docker/OCI JSON format, or `unconfined` to disable seccomp
+    #[arg(long)]
+    seccomp_profile: Option<contained::SeccompProfile>,
+
     /// Mount the current directory
     #[arg(long, conflicts_with = "current_dir_writable")]
     current_dir: bool,
@@ -41,10 +70,44 @@ struct Cli {
     #[arg(long)]
     mount_writable: Vec<PathBuf>,
 
-    /// Pass environment variable
+    /// Mount the current directory with a copy-on-write overlay, review the changes after the run
+    #[arg(long, conflicts_with = "current_dir_writable")]
+    overlay: bool,
+
+    /// Mount additional directory with a copy-on-write overlay
+    #[arg(long)]
+    overlay_mount: Vec<PathBuf>,
+
+    /// What to do with changes in overlays: `ask`, `apply`, `discard` or `patch=FILE`
+    #[arg(long, default_value = "ask")]
+    overlay_action: contained::OverlayAction,
+
+    /// Write a JSON journal of the paths changed in writable mounts to this file
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// Compare the content of files for the journal, not only size and modification time
+    #[arg(long)]
+    journal_hash: bool,
+
+    /// Exit with an error if paths in writable mounts matching this pattern are changed,
+    /// like `package-lock.json` or `.github/`
+    #[arg(long, value_parser = contained::parse_write_pattern)]
+    fail_on_write: Vec<String>,
+
+    /// Pass environment variable, `NAME`, `NAME=VALUE` or a pattern like `LC_*`
     #[arg(short, long)]
     env: Vec<String>,
 
+    /// Pass the environment variables of a file in the dotenv format
+    #[arg(long)]
+    env_file: Vec<PathBuf>,
+
+    /// Don't pass environment variables matching this pattern, in addition to ones which may
+    /// contain secrets like `*_TOKEN`, `*SECRET*` or `AWS_*`, unless given by name with `--env`
+    #[arg(long)]
+    env_deny: Vec<String>,
+
     /// Working directory
     #[arg(short, long)]
     workdir: Option<PathBuf>,
@@ -56,18 +119,39 @@ struct Cli {
 
 fn main() -> Result<ExitCode, anyhow::Error> {
     let cli = Cli::parse();
-    contained::run_image_via_command(
+    let options = contained::SandboxOptions {
+        publish: cli.publish,
+        add_hosts: cli.add_host,
+        limits: contained::ResourceLimits {
+            memory: cli.memory,
+            cpus: cli.cpus,
+            pids_limit: cli.pids_limit,
+            timeout: cli.timeout,
+        },
+        seccomp: cli.seccomp_profile.unwrap_or_default(),
+        overlay_current_dir: cli.overlay,
+        overlay_mounts: cli.overlay_mount,
+        overlay_action: cli.overlay_action,
+        journal: cli.journal,
+        journal_hash: cli.journal_hash,
+        fail_on_write: cli.fail_on_write,
+        env_files: cli.env_file,
+        env_deny: cli.env_deny,
+        ..Default::default()
+    };
+    let exit_code = contained::run_image_via_command(
         &cli.image,
         &cli.arguments,
         cli.entrypoint,
         &cli.network,
-        cli.current_dir || cli.current_dir_writable,
+        cli.current_dir || cli.current_dir_writable || cli.overlay,
         cli.current_dir_writable,
         &cli.mount,
         &cli.mount_writable,
         &cli.env,
         cli.workdir,
         cli.x11,
+        &options,
     )?;
-    Ok(ExitCode::SUCCESS)
+    Ok(ExitCode::from(exit_code))
 }
@@ -4,6 +4,7 @@
 
 use std::path::PathBuf;
 use std::process::ExitCode;
+use std::time::Duration;
 
 use clap::Parser;
 
@@ -16,9 +17,43 @@ struct Cli {
     /// Arguments to the programs
     arguments: Vec<String>,
 
-    /// Enable network access
+    /// Enable network access, sharing the host network, or `--network=pasta` for user-mode
+    /// networking
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "host",
+          value_parser = ["host", "pasta"])]
+    network: Option<String>,
+
+    /// Use the built-in namespace sandbox instead of bubblewrap
+    #[arg(long)]
+    native: bool,
+
+    /// Allow access to these hosts via an HTTP(S) proxy (e.g. `example.org,*.crates.io`)
+    #[arg(long, value_delimiter = ',', conflicts_with = "network")]
+    allow_host: Vec<String>,
+
+    /// Publish a port with `--network=pasta`, `[IP:]HOST:CONTAINER[/PROTOCOL]`
+    #[arg(long)]
+    publish: Vec<contained::PortMapping>,
+
+    /// Memory limit, like `512m` or `2g`
+    #[arg(long, value_parser = contained::parse_memory)]
+    memory: Option<u64>,
+
+    /// Number of CPUs, like `1.5`
+    #[arg(long, value_parser = contained::parse_cpus)]
+    cpus: Option<f64>,
+
+    /// Maximum number of processes
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pids_limit: Option<u32>,
+
+    /// Kill the sandbox after this time, like `90s` or `10m`, and exit with status 124
+    #[arg(long, value_parser = contained::parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Seccomp profile in the Docker/OCI JSON format, or `unconfined` to disable seccomp
     #[arg(long)]
-    network: bool,
+    seccomp_profile: Option<contained::SeccompProfile>,
 
     /// Mount the current directory
     #[arg(long, conflicts_with = "current_dir_writable")]
@@ -36,27 +71,163 @@ struct Cli {
     #[arg(long)]
     mount_writable: Vec<PathBuf>,
 
-    /// Pass environment variable
+    /// Mount the current directory with a copy-on-write overlay, review the changes after the run
+    #[arg(long, conflicts_with = "current_dir_writable")]
+    overlay: bool,
+
+    /// Mount additional directory with a copy-on-write overlay
+    #[arg(long)]
+    overlay_mount: Vec<PathBuf>,
+
+    /// What to do with changes in overlays: `ask`, `apply`, `discard` or `patch=FILE`
+    #[arg(long, default_value = "ask")]
+    overlay_action: contained::OverlayAction,
+
+    /// Write a JSON journal of the paths changed in writable mounts to this file
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// Compare the content of files for the journal, not only size and modification time
+    #[arg(long)]
+    journal_hash: bool,
+
+    /// Exit with an error if paths in writable mounts matching this pattern are changed,
+    /// like `package-lock.json` or `.github/`
+    #[arg(long, value_parser = contained::parse_write_pattern)]
+    fail_on_write: Vec<String>,
+
+    /// Make the root file system writable with a throwaway overlay, limited to SIZE
+    #[arg(long, value_name = "SIZE", num_args = 0..=1, require_equals = true,
+          default_missing_value = "1g", value_parser = contained::parse_memory)]
+    ephemeral_root: Option<u64>,
+
+    /// Private home directory: `ephemeral`, or `persistent[:NAME]` kept between runs
+    /// (named after the program by default)
+    #[arg(long)]
+    home: Option<contained::HomeMode>,
+
+    /// Mount a shared cache writable, `NAME[:PATH]`, like `cargo`, `npm` or `npm:/home/u/.npm`
+    #[arg(long)]
+    cache: Vec<contained::CacheSpec>,
+
+    /// Toolchain preset: `rust`, `node`, `python`, `go`, `java`, or one defined in
+    /// `~/.config/contained/presets.json`
+    #[arg(long)]
+    preset: Vec<contained::Preset>,
+
+    /// Profile written by `contained learn`, a name or a path to a JSON file: mounts its paths,
+    /// and the current directory if the profile needs it. The network must be enabled explicitly
+    #[arg(long)]
+    profile: Option<contained::Profile>,
+
+    /// Print the directories mounted for the program, its interpreters and libraries, and why,
+    /// and a table of what the sandbox sees: paths, environment, network, capabilities and user
+    #[arg(long)]
+    explain: bool,
+
+    /// Print the `bwrap` command line instead of running anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Mount only the files of the system directories which the program needs: its libraries,
+    /// interpreters, locales and time zones, and name service and network config
+    #[arg(long, conflicts_with = "ephemeral_root")]
+    minimal: bool,
+
+    /// Mount the existing files and directories in the arguments read-only, and the directory of
+    /// files to create (after `-o` or with a `/`) writable, replacing them by absolute paths
+    #[arg(long)]
+    auto_mount_args: bool,
+
+    /// Start an interactive shell instead of the program, `$SHELL` if it is visible in the sandbox
+    /// or `/bin/sh`, with the same mounts, environment, network and working directory
+    #[arg(long)]
+    shell: bool,
+
+    /// Pass environment variable, `NAME`, `NAME=VALUE` or a pattern like `LC_*`
     #[arg(short, long)]
     env: Vec<String>,
 
+    /// Pass the environment variables of a file in the dotenv format
+    #[arg(long)]
+    env_file: Vec<PathBuf>,
+
+    /// Don't pass environment variables matching this pattern, in addition to ones which may
+    /// contain secrets like `*_TOKEN`, `*SECRET*` or `AWS_*`, unless given by name with `--env`
+    #[arg(long)]
+    env_deny: Vec<String>,
+
+    /// Mount a secret read-only at `/run/secrets/NAME`, `NAME=file:PATH`, `NAME=env:VAR` or
+    /// `NAME=cmd:COMMAND`, kept in memory and removed after the run
+    #[arg(long)]
+    secret: Vec<contained::SecretSpec>,
+
     /// Working directory
     #[arg(short, long)]
     workdir: Option<PathBuf>,
 }
 
 fn main() -> Result<ExitCode, anyhow::Error> {
+    if let Some(exit_code) = contained::sandbox_helper()? {
+        return Ok(ExitCode::from(exit_code));
+    }
+
     let cli = Cli::parse();
-    contained::wrapped(
+    let profile = cli.profile.as_ref();
+    if profile.is_some_and(|p| p.network()) && cli.network.is_none() && cli.allow_host.is_empty() {
+        eprintln!("wrapped: the profile needs the network, enable it with --network");
+    }
+    let network = cli.network.clone();
+    let mount_current_dir = cli.current_dir
+        || cli.current_dir_writable
+        || cli.overlay
+        || profile.is_some_and(|p| p.mount_current_dir());
+    let mount_current_dir_writable = cli.current_dir_writable
+        || (profile.is_some_and(|p| p.current_dir_writable()) && !cli.current_dir && !cli.overlay);
+    let options = contained::SandboxOptions {
+        allow_hosts: cli.allow_host,
+        pasta_network: network.as_deref() == Some("pasta"),
+        native: cli.native,
+        publish: cli.publish,
+        limits: contained::ResourceLimits {
+            memory: cli.memory,
+            cpus: cli.cpus,
+            pids_limit: cli.pids_limit,
+            timeout: cli.timeout,
+        },
+        seccomp: cli.seccomp_profile.unwrap_or_default(),
+        overlay_current_dir: cli.overlay,
+        overlay_mounts: cli.overlay_mount,
+        overlay_action: cli.overlay_action,
+        journal: cli.journal,
+        journal_hash: cli.journal_hash,
+        fail_on_write: cli.fail_on_write,
+        ephemeral_root: cli.ephemeral_root,
+        home: cli.home,
+        caches: cli.cache,
+        presets: cli.preset,
+        explain: cli.explain,
+        dry_run: cli.dry_run,
+        minimal: cli.minimal,
+        profile: cli.profile,
+        auto_mount_args: cli.auto_mount_args,
+        shell: cli.shell,
+        env_files: cli.env_file,
+        env_deny: cli.env_deny,
+        secrets: cli.secret,
+        ..Default::default()
+    };
+    let exit_code = contained::wrapped(
         &cli.program,
         &cli.arguments,
-        cli.network,
-        cli.current_dir || cli.current_dir_writable,
-        cli.current_dir_writable,
+        network.as_deref() == Some("host"),
+        mount_current_dir,
+        mount_current_dir_writable,
         &cli.mount,
         &cli.mount_writable,
         &cli.env,
         cli.workdir,
+        &options,
     )?;
-    Ok(ExitCode::SUCCESS)
+    Ok(ExitCode::from(exit_code))
 }
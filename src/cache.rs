@@ -0,0 +1,313 @@
+//! # Caches
+//!
+//! `cache` contains named cache directories for toolchains, kept in
+//! `~/.local/share/contained/caches` and shared between runs. A sandbox locks its caches while it
+//! runs, so that parallel sandboxes don't use the same cache at the same time.
+
+use anyhow::{anyhow, Context};
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fs, io};
+
+use crate::ephemeral::disk_usage;
+use crate::home::{data_dir, list_dirs, SANDBOX_HOME};
+
+/// Where the caches of known tools are mounted, relative to the home directory of the sandbox.
+const KNOWN_CACHES: [(&str, &str); 7] = [
+    ("cargo", ".cargo/registry"),
+    ("go", "go/pkg/mod"),
+    ("gradle", ".gradle/caches"),
+    ("maven", ".m2/repository"),
+    ("npm", ".npm"),
+    ("pip", ".cache/pip"),
+    ("yarn", ".cache/yarn"),
+];
+
+/// A cache requested with `--cache NAME[:PATH]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheSpec {
+    name: String,
+    /// Where the cache is mounted in the sandbox
+    target: PathBuf,
+}
+
+impl FromStr for CacheSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, target) = match s.split_once(':') {
+            Some((name, target)) => {
+                if !target.starts_with('/') {
+                    return Err(anyhow!("Cache path '{target}' is not absolute"));
+                }
+                (name, PathBuf::from(target))
+            }
+            None => {
+                let (_, dir) =
+                    KNOWN_CACHES
+                        .iter()
+                        .find(|(known, _)| *known == s)
+                        .ok_or(anyhow!(
+                            "Unknown cache '{s}', use NAME:PATH or one of {}",
+                            KNOWN_CACHES.map(|(known, _)| known).join(", ")
+                        ))?;
+                (s, Path::new(SANDBOX_HOME).join(dir))
+            }
+        };
+        check_name(name)?;
+        Ok(Self {
+            name: name.to_string(),
+            target,
+        })
+    }
+}
+
+impl CacheSpec {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the cache is mounted in the home directory of the sandbox, like the known ones.
+    pub(crate) fn in_sandbox_home(&self) -> bool {
+        self.target.starts_with(SANDBOX_HOME)
+    }
+}
+
+/// A cache directory on the host, locked until dropped.
+pub struct Cache {
+    name: String,
+    path: PathBuf,
+    target: PathBuf,
+    _lock: File,
+}
+
+impl Cache {
+    /// Creates and locks the caches, waiting while other sandboxes use them.
+    pub fn open_all(specs: &[CacheSpec]) -> Result<Vec<Self>, anyhow::Error> {
+        if specs.is_empty() {
+            return Ok(Vec::new());
+        }
+        Self::open_all_in(&caches_dir()?, specs)
+    }
+
+    pub(crate) fn open_all_in(dir: &Path, specs: &[CacheSpec]) -> Result<Vec<Self>, anyhow::Error> {
+        let mut specs = specs.iter().collect::<Vec<_>>();
+        // Always locked in the same order, so that sandboxes using overlapping caches wait for
+        // each other instead of deadlocking
+        specs.sort_by(|a, b| a.name.cmp(&b.name));
+        if let Some(pair) = specs.windows(2).find(|pair| pair[0].name == pair[1].name) {
+            return Err(anyhow!("Cache '{}' is used more than once", pair[0].name));
+        }
+        specs
+            .into_iter()
+            .map(|spec| Self::open(dir, spec))
+            .collect()
+    }
+
+    fn open(dir: &Path, spec: &CacheSpec) -> Result<Self, anyhow::Error> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Unable to create caches directory {dir:?}"))?;
+        let lock = lock_file(dir, &spec.name)?;
+        if !try_lock(&lock, libc::LOCK_EX)? {
+            eprintln!(
+                "contained: waiting for cache '{}', used by another sandbox",
+                spec.name
+            );
+            lock_wait(&lock)?;
+        }
+        // Only created once locked, the cache may have been pruned while waiting
+        let path = dir.join(&spec.name);
+        fs::create_dir_all(&path)
+            .with_context(|| format!("Unable to create cache directory {path:?}"))?;
+        Ok(Self {
+            name: spec.name.clone(),
+            path,
+            target: spec.target.clone(),
+            _lock: lock,
+        })
+    }
+
+    /// The directory on the host.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Where the cache is mounted in the sandbox.
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    /// Name of the Docker volume of the cache.
+    pub fn volume(&self) -> String {
+        format!("contained-cache-{}", self.name)
+    }
+}
+
+fn check_name(name: &str) -> Result<(), anyhow::Error> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        return Err(anyhow!(
+            "Invalid cache name '{name}', expected letters, digits, '.', '_' or '-'"
+        ));
+    }
+    Ok(())
+}
+
+/// Directory of the caches, with a lock file next to each cache.
+fn caches_dir() -> Result<PathBuf, anyhow::Error> {
+    Ok(data_dir()?.join("caches"))
+}
+
+fn lock_file(dir: &Path, name: &str) -> Result<File, anyhow::Error> {
+    let path = dir.join(format!("{name}.lock"));
+    File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Unable to open lock file {path:?}"))
+}
+
+/// Locks without waiting, returns whether the lock was taken.
+fn try_lock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+    // SAFETY: plain system call without pointers
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    if error.kind() == io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(error)
+    }
+}
+
+fn lock_wait(file: &File) -> io::Result<()> {
+    loop {
+        // SAFETY: plain system call without pointers
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// Names and sizes in bytes of the caches.
+pub fn list_caches() -> Result<Vec<(String, u64)>, anyhow::Error> {
+    list_caches_in(&caches_dir()?)
+}
+
+fn list_caches_in(dir: &Path) -> Result<Vec<(String, u64)>, anyhow::Error> {
+    Ok(list_dirs(dir)?
+        .into_iter()
+        .map(|name| {
+            let size = disk_usage(&dir.join(&name));
+            (name, size)
+        })
+        .collect())
+}
+
+/// Removes the caches with the names, or all caches, except those in use by a sandbox.
+/// Returns the names of the removed caches.
+pub fn prune_caches(names: &[String]) -> Result<Vec<String>, anyhow::Error> {
+    prune_caches_in(&caches_dir()?, names)
+}
+
+fn prune_caches_in(dir: &Path, names: &[String]) -> Result<Vec<String>, anyhow::Error> {
+    let names = if names.is_empty() {
+        list_dirs(dir)?
+    } else {
+        for name in names {
+            check_name(name)?;
+            if !dir.join(name).is_dir() {
+                return Err(anyhow!("Cache '{name}' not found"));
+            }
+        }
+        names.to_vec()
+    };
+
+    let mut removed = Vec::new();
+    for name in names {
+        let lock = lock_file(dir, &name)?;
+        if !try_lock(&lock, libc::LOCK_EX)? {
+            eprintln!("contained: cache '{name}' is in use, not removed");
+            continue;
+        }
+        let path = dir.join(&name);
+        // The lock file is kept, sandboxes waiting for it create the directory again once they
+        // have the lock
+        fs::remove_dir_all(&path).with_context(|| format!("Unable to remove {path:?}"))?;
+        removed.push(name);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime_dir::RuntimeDir;
+
+    #[test]
+    fn test_parse_cache() {
+        let spec = "cargo".parse::<CacheSpec>().unwrap();
+        assert_eq!(spec.name, "cargo");
+        assert_eq!(spec.target, Path::new("/home/contained/.cargo/registry"));
+        let spec = "npm:/home/u/.npm".parse::<CacheSpec>().unwrap();
+        assert_eq!(spec.name, "npm");
+        assert_eq!(spec.target, Path::new("/home/u/.npm"));
+
+        assert!("unknown".parse::<CacheSpec>().is_err());
+        assert!("npm:relative".parse::<CacheSpec>().is_err());
+        assert!("../x:/x".parse::<CacheSpec>().is_err());
+    }
+
+    #[test]
+    fn test_caches() -> Result<(), anyhow::Error> {
+        let data = RuntimeDir::new("cache-test")?;
+        let dir = data.path();
+        let specs = ["npm".parse()?, "build:/build".parse()?];
+
+        let caches = Cache::open_all_in(dir, &specs)?;
+        assert_eq!(
+            caches.iter().map(Cache::volume).collect::<Vec<_>>(),
+            ["contained-cache-build", "contained-cache-npm"]
+        );
+        assert_eq!(caches[0].target(), Path::new("/build"));
+        fs::write(caches[1].path().join("package"), [0; 8192])?;
+        let listed = list_caches_in(dir)?;
+        assert_eq!(listed[0], ("build".to_string(), 0));
+        assert_eq!(listed[1].0, "npm");
+        assert!(listed[1].1 >= 8192);
+        assert!(Cache::open_all_in(dir, &[specs[0].clone(), specs[0].clone()]).is_err());
+
+        // Locked by this sandbox
+        let lock = lock_file(dir, "npm")?;
+        assert!(!try_lock(&lock, libc::LOCK_EX)?);
+        assert!(prune_caches_in(dir, &[])?.is_empty());
+        drop(caches);
+        assert!(try_lock(&lock, libc::LOCK_EX)?);
+        drop(lock);
+
+        assert_eq!(prune_caches_in(dir, &["npm".to_string()])?, ["npm"]);
+        assert!(prune_caches_in(dir, &["npm".to_string()]).is_err());
+        assert_eq!(prune_caches_in(dir, &[])?, ["build"]);
+        assert!(list_caches_in(dir)?.is_empty());
+
+        // Still locked with the same lock file after pruning
+        assert!(dir.join("npm.lock").exists());
+        let caches = Cache::open_all_in(dir, &specs[..1])?;
+        assert!(caches[0].path().is_dir());
+        let lock = lock_file(dir, "npm")?;
+        assert!(!try_lock(&lock, libc::LOCK_EX)?);
+        Ok(())
+    }
+}
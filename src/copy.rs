@@ -0,0 +1,138 @@
+//! # Copies
+//!
+//! `copy` copies files and directories into a container before it starts, and out of it after it
+//! exits, as tar archives via the Docker archive API instead of mounts.
+
+use anyhow::{anyhow, Context};
+use std::path::{Component, Path, PathBuf};
+use users::{get_effective_gid, get_effective_uid};
+
+use crate::docker_client::DockerClient;
+use crate::tar::{unpack, TarWriter};
+
+/// A file or directory copied between the host and a container.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CopySpec {
+    host: PathBuf,
+    /// Absolute path in the container
+    container: String,
+}
+
+/// Parses a copy into the container, `HOST:CONTAINER`.
+pub fn parse_copy_in(s: &str) -> Result<CopySpec, anyhow::Error> {
+    let (host, container) = s
+        .rsplit_once(':')
+        .ok_or(anyhow!("Invalid copy '{s}', expected HOST:CONTAINER"))?;
+    CopySpec::new(host, container)
+}
+
+/// Parses a copy out of the container, `CONTAINER:HOST`.
+pub fn parse_copy_out(s: &str) -> Result<CopySpec, anyhow::Error> {
+    let (container, host) = s
+        .split_once(':')
+        .ok_or(anyhow!("Invalid copy '{s}', expected CONTAINER:HOST"))?;
+    CopySpec::new(host, container)
+}
+
+impl CopySpec {
+    fn new(host: &str, container: &str) -> Result<Self, anyhow::Error> {
+        if host.is_empty() {
+            return Err(anyhow!("Empty host path"));
+        }
+        if !container.starts_with('/') {
+            return Err(anyhow!("Container path '{container}' is not absolute"));
+        }
+        let mut normalized = String::new();
+        for component in Path::new(container).components() {
+            match component {
+                Component::Normal(component) => {
+                    normalized.push('/');
+                    normalized.push_str(component.to_str().expect("UTF-8"));
+                }
+                Component::ParentDir => {
+                    return Err(anyhow!("Container path '{container}' contains '..'"));
+                }
+                _ => {}
+            }
+        }
+        if normalized.is_empty() {
+            return Err(anyhow!("Cannot copy the root directory of the container"));
+        }
+        Ok(Self {
+            host: PathBuf::from(host),
+            container: normalized,
+        })
+    }
+
+    /// The path in the container, relative to its root directory.
+    fn relative_container(&self) -> &str {
+        &self.container[1..]
+    }
+}
+
+/// Copies the `copy_in` paths into a created container, and creates the directories of the
+/// `copy_out` paths for the user if they don't exist, so that the program can write there.
+pub fn copy_in(
+    client: &DockerClient,
+    id: &str,
+    copy_in: &[CopySpec],
+    copy_out: &[CopySpec],
+) -> Result<(), anyhow::Error> {
+    if copy_in.is_empty() && copy_out.is_empty() {
+        return Ok(());
+    }
+    let mut archive = TarWriter::new(get_effective_uid(), get_effective_gid());
+    for copy in copy_in {
+        archive
+            .append_path(&copy.host, copy.relative_container())
+            .with_context(|| format!("Unable to copy {:?} into the container", copy.host))?;
+    }
+    for copy in copy_out {
+        let (dir, _) = copy.container.rsplit_once('/').expect("absolute path");
+        if !dir.is_empty() && !client.path_exists(id, dir)? {
+            archive.append_dir(&dir[1..], 0o755);
+        }
+    }
+    client
+        .put_archive(id, "/", archive.finish())
+        .context("Unable to copy files into the container")
+}
+
+/// Copies the `copy_out` paths out of an exited container.
+pub fn copy_out(
+    client: &DockerClient,
+    id: &str,
+    copy_out: &[CopySpec],
+) -> Result<(), anyhow::Error> {
+    for copy in copy_out {
+        let archive = client
+            .get_archive(id, &copy.container)
+            .with_context(|| format!("Unable to copy {} out of the container", copy.container))?;
+        unpack(&archive, &copy.host)
+            .with_context(|| format!("Unable to copy {} to {:?}", copy.container, copy.host))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_copy() {
+        let copy = parse_copy_in("data/in:put.csv:/work//./input.csv").unwrap();
+        assert_eq!(copy.host, Path::new("data/in:put.csv"));
+        assert_eq!(copy.container, "/work/input.csv");
+        assert_eq!(copy.relative_container(), "work/input.csv");
+
+        let copy = parse_copy_out("/out:results/run:1").unwrap();
+        assert_eq!(copy.host, Path::new("results/run:1"));
+        assert_eq!(copy.container, "/out");
+
+        assert!(parse_copy_in("input.csv").is_err());
+        assert!(parse_copy_in("input.csv:work").is_err());
+        assert!(parse_copy_in("input.csv:/work/../etc/passwd").is_err());
+        assert!(parse_copy_out("/:out").is_err());
+        assert!(parse_copy_out("/out:").is_err());
+    }
+}
@@ -0,0 +1,723 @@
+//! # Program dependencies
+//!
+//! `dependencies` finds the directories outside the system mounts which a program needs to run:
+//! its own directory, the interpreters of scripts, following `#!/usr/bin/env NAME` through `PATH`,
+//! and the dynamic loader and libraries of ELF binaries, searched like the loader does with
+//! `RPATH`, `RUNPATH` and `/etc/ld.so.conf`.
+
+use anyhow::anyhow;
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use crate::SYSTEM_MOUNTS;
+
+const LD_SO_CONF: &str = "/etc/ld.so.conf";
+
+/// Files of the system directories needed by most programs, mounted with `--minimal` if they
+/// exist: the loader cache, user and name service config, locales and time zones.
+const RUNTIME_FILES: [&str; 8] = [
+    "/etc/ld.so.cache",
+    "/etc/nsswitch.conf",
+    "/etc/passwd",
+    "/etc/group",
+    "/etc/localtime",
+    "/usr/lib/locale",
+    "/usr/share/locale",
+    "/usr/share/zoneinfo",
+];
+
+/// Files needed for network access, except `/etc/resolv.conf` set up by the backends.
+const NETWORK_FILES: [&str; 6] = [
+    "/etc/hosts",
+    "/etc/host.conf",
+    "/etc/gai.conf",
+    "/etc/ssl",
+    "/etc/pki",
+    "/usr/share/ca-certificates",
+];
+
+/// Libraries loaded by the C library at runtime, for the name service.
+const NSS_LIBRARIES: [&str; 1] = ["libnss_files.so.2"];
+const NETWORK_LIBRARIES: [&str; 2] = ["libnss_dns.so.2", "libresolv.so.2"];
+
+/// Library directories searched after those given by the binaries and `/etc/ld.so.conf`.
+const DEFAULT_LIBRARY_DIRS: [&str; 4] = ["/lib", "/usr/lib", "/lib64", "/usr/lib64"];
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+/// A directory mounted read-only for the program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dependency {
+    pub dir: PathBuf,
+    /// Why the directory is needed, like `interpreter of /home/u/bin/tool`
+    pub reason: String,
+}
+
+/// Directories outside the system mounts needed to run the program, which must be resolved
+/// already. Files which can't be read or parsed are skipped, the program reports them when run.
+pub fn program_dependencies(program: &Path) -> Vec<Dependency> {
+    let mut resolver = Resolver::default();
+    resolver.visit(program, format!("program {}", program.display()), &[]);
+    resolver.dependencies
+}
+
+/// Files of the system directories needed to run the programs, which must be resolved already:
+/// their interpreters, loaders and libraries, both as named and with symlinks resolved, and the
+/// runtime files of the C library. Fails if a dependency is missing.
+pub fn system_closure(programs: &[&Path], network: bool) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut resolver = Resolver {
+        closure: Some(BTreeSet::new()),
+        ..Default::default()
+    };
+    for program in programs {
+        resolver.visit(program, format!("program {}", program.display()), &[]);
+    }
+    if !resolver.missing.is_empty() {
+        return Err(anyhow!(
+            "Missing dependencies for --minimal: {}",
+            resolver.missing.join(", ")
+        ));
+    }
+    let libraries = match network {
+        true => [&NSS_LIBRARIES[..], &NETWORK_LIBRARIES].concat(),
+        false => NSS_LIBRARIES.to_vec(),
+    };
+    for library in libraries {
+        // Optional, the C library might not use them
+        if let Some(path) = resolver.find_library(library, &[], &[]) {
+            resolver.visit(&path, format!("library {library}"), &[]);
+        }
+    }
+    let mut files = resolver.closure.unwrap_or_default();
+    let data = match network {
+        true => [&RUNTIME_FILES[..], &NETWORK_FILES].concat(),
+        false => RUNTIME_FILES.to_vec(),
+    };
+    files.extend(data.iter().map(PathBuf::from).filter(|path| path.exists()));
+    Ok(files.into_iter().filter(|path| is_system(path)).collect())
+}
+
+#[derive(Default)]
+struct Resolver {
+    dependencies: Vec<Dependency>,
+    visited: HashSet<PathBuf>,
+    ld_so_conf_dirs: Option<Vec<PathBuf>>,
+    /// All files the programs need, when collecting them
+    closure: Option<BTreeSet<PathBuf>>,
+    /// Dependencies which were not found, when collecting all files
+    missing: Vec<String>,
+}
+
+impl Resolver {
+    /// Adds the directory, unless it is covered by the system mounts or another dependency.
+    fn add(&mut self, dir: &Path, reason: String) {
+        if is_system(dir)
+            || self
+                .dependencies
+                .iter()
+                .any(|dependency| dir.starts_with(&dependency.dir))
+        {
+            return;
+        }
+        self.dependencies
+            .retain(|dependency| !dependency.dir.starts_with(dir));
+        self.dependencies.push(Dependency {
+            dir: dir.to_path_buf(),
+            reason,
+        });
+    }
+
+    /// Adds the directory of the file and follows its interpreter or libraries.
+    fn visit(&mut self, file: &Path, reason: String, rpath: &[PathBuf]) {
+        let canonical = fs::canonicalize(file);
+        if let Some(closure) = &mut self.closure {
+            match &canonical {
+                Ok(canonical) => {
+                    closure.insert(file.to_path_buf());
+                    closure.insert(canonical.clone());
+                }
+                Err(_) => self.missing.push(format!("{} ({reason})", file.display())),
+            }
+        }
+        if let Some(dir) = file.parent() {
+            self.add(dir, reason);
+        }
+        let Ok(canonical) = canonical else {
+            return;
+        };
+        if canonical != file {
+            if let Some(dir) = canonical.parent() {
+                self.add(dir, format!("target of {}", file.display()));
+            }
+        }
+        if !self.visited.insert(canonical.clone()) {
+            return;
+        }
+        let Ok(file) = File::open(&canonical) else {
+            return;
+        };
+        let mut head = [0; 256];
+        let Ok(len) = file.read_at(&mut head, 0) else {
+            return;
+        };
+        let head = &head[..len];
+        if head.starts_with(b"#!") {
+            self.visit_script(&canonical, head);
+            return;
+        }
+        match Elf::parse(&file) {
+            Ok(Some(elf)) => self.visit_elf(&canonical, elf, rpath),
+            Ok(None) => {}
+            Err(error) => {
+                if self.closure.is_some() {
+                    self.missing
+                        .push(format!("{} ({error})", canonical.display()));
+                }
+            }
+        }
+    }
+
+    fn visit_script(&mut self, script: &Path, head: &[u8]) {
+        let Some(interpreter) = shebang_interpreter(head) else {
+            return;
+        };
+        let reason = format!("interpreter of {}", script.display());
+        if let Some(command) = shebang_command(head).filter(|command| *command != interpreter) {
+            // Like `/usr/bin/env`
+            self.visit(&command, reason.clone(), &[]);
+        }
+        if let Ok(canonical) = fs::canonicalize(&interpreter) {
+            // Interpreters like Python load their libraries relative to the prefix
+            if let Some(prefix) = canonical
+                .parent()
+                .filter(|dir| dir.file_name().is_some_and(|name| name == "bin"))
+                .and_then(Path::parent)
+                // Not a directory like `/opt` or the home directory
+                .filter(|prefix| {
+                    prefix.components().count() > 2
+                        && env::var_os("HOME")
+                            .is_none_or(|home| !Path::new(&home).starts_with(prefix))
+                })
+            {
+                self.add(prefix, format!("prefix of the {reason}"));
+            }
+        }
+        self.visit(&interpreter, reason, &[]);
+    }
+
+    fn visit_elf(&mut self, binary: &Path, elf: Elf, rpath: &[PathBuf]) {
+        if let Some(interpreter) = &elf.interpreter {
+            self.visit(
+                interpreter,
+                format!("dynamic loader of {}", binary.display()),
+                &[],
+            );
+        }
+        let origin = binary.parent().unwrap_or(Path::new("/"));
+        let expand = |paths: &[String]| {
+            paths
+                .iter()
+                .flat_map(|paths| paths.split(':'))
+                .filter_map(|path| expand_origin(path, origin))
+                .collect::<Vec<_>>()
+        };
+        // RPATH applies to the libraries loaded by the binary too, RUNPATH replaces it
+        let rpath = if elf.runpath.is_empty() {
+            let mut own = expand(&elf.rpath);
+            own.extend_from_slice(rpath);
+            own
+        } else {
+            Vec::new()
+        };
+        let runpath = expand(&elf.runpath);
+
+        for library in &elf.needed {
+            let Some(path) = self.find_library(library, &rpath, &runpath) else {
+                if self.closure.is_some() {
+                    self.missing
+                        .push(format!("{library} (library of {})", binary.display()));
+                }
+                continue;
+            };
+            if !is_system(&path) || self.closure.is_some() {
+                let reason = format!("library {library} of {}", binary.display());
+                self.visit(&path, reason, &rpath);
+            }
+        }
+    }
+
+    fn find_library(
+        &mut self,
+        name: &str,
+        rpath: &[PathBuf],
+        runpath: &[PathBuf],
+    ) -> Option<PathBuf> {
+        if name.contains('/') {
+            return Some(PathBuf::from(name));
+        }
+        let ld_so_conf_dirs = self.ld_so_conf_dirs.get_or_insert_with(|| {
+            let mut dirs = Vec::new();
+            read_ld_so_conf(Path::new(LD_SO_CONF), &mut dirs, 0);
+            dirs
+        });
+        rpath
+            .iter()
+            .chain(runpath)
+            .chain(ld_so_conf_dirs.iter())
+            .map(PathBuf::as_path)
+            .chain(DEFAULT_LIBRARY_DIRS.iter().map(Path::new))
+            .find(|dir| dir.join(name).is_file())
+            // Without `..` from `$ORIGIN/../lib`, to mount the directory
+            .map(|dir| {
+                fs::canonicalize(dir)
+                    .unwrap_or(dir.to_path_buf())
+                    .join(name)
+            })
+    }
+}
+
+fn is_system(path: &Path) -> bool {
+    SYSTEM_MOUNTS.iter().any(|dir| path.starts_with(dir))
+}
+
+/// The command of the `#!` line.
+fn shebang_command(head: &[u8]) -> Option<PathBuf> {
+    let line = head[2..].split(|&b| b == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    line.split_whitespace().next().map(PathBuf::from)
+}
+
+/// The interpreter of a script, or the program run by `/usr/bin/env`, found in `PATH`.
+fn shebang_interpreter(head: &[u8]) -> Option<PathBuf> {
+    let line = head[2..].split(|&b| b == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    let mut words = line.split_whitespace();
+    let interpreter = PathBuf::from(words.next()?);
+    if interpreter.file_name().is_some_and(|name| name == "env") {
+        let program = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
+        let paths = env::var_os("PATH")?;
+        return env::split_paths(&paths)
+            .map(|dir| dir.join(program))
+            .find(|path| path.is_file());
+    }
+    Some(interpreter)
+}
+
+/// Replaces `$ORIGIN`, paths with other variables like `$LIB` are skipped.
+fn expand_origin(path: &str, origin: &Path) -> Option<PathBuf> {
+    let origin = origin.to_str()?;
+    let path = path.replace("${ORIGIN}", origin).replace("$ORIGIN", origin);
+    (!path.is_empty() && !path.contains('$')).then(|| PathBuf::from(path))
+}
+
+/// Adds the directories of the config, following `include` patterns with one `*`.
+fn read_ld_so_conf(path: &Path, dirs: &mut Vec<PathBuf>, depth: u32) {
+    let Ok(content) = fs::read_to_string(path) else {
+        return;
+    };
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some(pattern) = line.strip_prefix("include") {
+            if depth > 4 {
+                continue;
+            }
+            for pattern in pattern.split_whitespace() {
+                let pattern = Path::new("/etc").join(pattern);
+                for file in expand_pattern(&pattern) {
+                    read_ld_so_conf(&file, dirs, depth + 1);
+                }
+            }
+        } else if line.starts_with('/') {
+            dirs.push(PathBuf::from(line));
+        }
+    }
+}
+
+fn expand_pattern(pattern: &Path) -> Vec<PathBuf> {
+    let (Some(dir), Some(name)) = (
+        pattern.parent(),
+        pattern.file_name().and_then(|n| n.to_str()),
+    ) else {
+        return Vec::new();
+    };
+    let Some((prefix, suffix)) = name.split_once('*') else {
+        return vec![pattern.to_path_buf()];
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| {
+            name.len() >= prefix.len() + suffix.len()
+                && name.starts_with(prefix)
+                && name.ends_with(suffix)
+        })
+        .map(|name| dir.join(name))
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+/// What the dynamic loader needs from an ELF binary.
+#[derive(Debug, Default, PartialEq)]
+struct Elf {
+    interpreter: Option<PathBuf>,
+    needed: Vec<String>,
+    rpath: Vec<String>,
+    runpath: Vec<String>,
+}
+
+/// Reads ELF headers in either byte order and class.
+struct ElfReader<'a> {
+    file: &'a File,
+    is_64: bool,
+    little_endian: bool,
+}
+
+impl ElfReader<'_> {
+    fn bytes<const N: usize>(&self, offset: u64) -> Option<[u8; N]> {
+        let mut buf = [0; N];
+        self.file.read_exact_at(&mut buf, offset).ok()?;
+        Some(buf)
+    }
+
+    fn u16(&self, offset: u64) -> Option<u16> {
+        let bytes = self.bytes(offset)?;
+        Some(match self.little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    }
+
+    fn u32(&self, offset: u64) -> Option<u32> {
+        let bytes = self.bytes(offset)?;
+        Some(match self.little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    }
+
+    fn u64(&self, offset: u64) -> Option<u64> {
+        let bytes = self.bytes(offset)?;
+        Some(match self.little_endian {
+            true => u64::from_le_bytes(bytes),
+            false => u64::from_be_bytes(bytes),
+        })
+    }
+
+    /// An address or offset, 32 or 64 bits wide.
+    fn word(&self, offset: u64) -> Option<u64> {
+        match self.is_64 {
+            true => self.u64(offset),
+            false => self.u32(offset).map(u64::from),
+        }
+    }
+
+    fn string(&self, offset: u64) -> Option<String> {
+        let mut bytes = Vec::new();
+        let mut chunk = [0; 256];
+        loop {
+            let len = self
+                .file
+                .read_at(&mut chunk, offset.checked_add(bytes.len() as u64)?)
+                .ok()?;
+            if len == 0 {
+                return None;
+            }
+            if let Some(end) = chunk[..len].iter().position(|&b| b == 0) {
+                bytes.extend_from_slice(&chunk[..end]);
+                return String::from_utf8(bytes).ok();
+            }
+            bytes.extend_from_slice(&chunk[..len]);
+            if bytes.len() > 4096 {
+                return None;
+            }
+        }
+    }
+}
+
+impl Elf {
+    /// Parses the file, `None` if it is no ELF file. Fails if it is truncated or its offsets are
+    /// out of range.
+    fn parse(file: &File) -> Result<Option<Self>, anyhow::Error> {
+        let mut ident = [0; 6];
+        if file.read_exact_at(&mut ident, 0).is_err() || ident[..4] != *b"\x7fELF" {
+            return Ok(None);
+        }
+        let reader = ElfReader {
+            file,
+            is_64: ident[4] == 2,
+            little_endian: ident[5] == 1,
+        };
+        Self::parse_headers(&reader)
+            .map(Some)
+            .ok_or(anyhow!("malformed ELF file"))
+    }
+
+    fn parse_headers(reader: &ElfReader) -> Option<Self> {
+        let (phoff, phentsize, phnum) = match reader.is_64 {
+            true => (reader.u64(0x20)?, reader.u16(0x36)?, reader.u16(0x38)?),
+            false => (
+                u64::from(reader.u32(0x1c)?),
+                reader.u16(0x2a)?,
+                reader.u16(0x2c)?,
+            ),
+        };
+
+        let mut elf = Elf::default();
+        // (virtual address, file offset, size) of the loaded segments
+        let mut loads = Vec::new();
+        let mut dynamic = None;
+        for i in 0..u64::from(phnum) {
+            let header = phoff.checked_add(i * u64::from(phentsize))?;
+            let field = |offset: u64| header.checked_add(offset);
+            let (offset, vaddr, filesz) = match reader.is_64 {
+                true => (
+                    reader.u64(field(8)?)?,
+                    reader.u64(field(16)?)?,
+                    reader.u64(field(32)?)?,
+                ),
+                false => (
+                    u64::from(reader.u32(field(4)?)?),
+                    u64::from(reader.u32(field(8)?)?),
+                    u64::from(reader.u32(field(16)?)?),
+                ),
+            };
+            match reader.u32(header)? {
+                PT_LOAD => loads.push((vaddr, offset, filesz)),
+                PT_DYNAMIC => dynamic = Some((offset, filesz)),
+                PT_INTERP => elf.interpreter = reader.string(offset).map(PathBuf::from),
+                _ => {}
+            }
+        }
+
+        let Some((offset, size)) = dynamic else {
+            return Some(elf);
+        };
+        let entry_size = if reader.is_64 { 16 } else { 8 };
+        let mut strtab = None;
+        let mut entries = Vec::new();
+        for i in 0..size / entry_size {
+            let entry = offset.checked_add(i * entry_size)?;
+            let tag = reader.word(entry)?;
+            let value = reader.word(entry.checked_add(entry_size / 2)?)?;
+            match tag {
+                DT_NULL => break,
+                DT_STRTAB => strtab = Some(value),
+                DT_NEEDED | DT_RPATH | DT_RUNPATH => entries.push((tag, value)),
+                _ => {}
+            }
+        }
+        let strtab = strtab?;
+        let (vaddr, offset, _) = loads
+            .iter()
+            .find(|(vaddr, _, filesz)| strtab >= *vaddr && strtab - vaddr < *filesz)?;
+        let strtab = (strtab - vaddr).checked_add(*offset)?;
+        for (tag, value) in entries {
+            let string = reader.string(strtab.checked_add(value)?)?;
+            match tag {
+                DT_NEEDED => elf.needed.push(string),
+                DT_RPATH => elf.rpath.push(string),
+                _ => elf.runpath.push(string),
+            }
+        }
+        Some(elf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime_dir::RuntimeDir;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    const LOADER: &str = "/lib64/ld-linux-x86-64.so.2";
+    const BASE: u64 = 0x40_0000;
+
+    /// A 64-bit little-endian ELF binary with an interpreter and needed libraries, loaded at
+    /// [BASE] unless `vaddr` is given.
+    fn elf_fixture(needed: &[&str], vaddr: Option<u64>) -> Vec<u8> {
+        let phoff = 64;
+        let interp = phoff + 3 * 56;
+        let strtab = interp + LOADER.len() + 1;
+        let mut strings = vec![0];
+        let mut entries = vec![(DT_STRTAB, BASE + strtab as u64)];
+        for name in needed {
+            entries.push((DT_NEEDED, strings.len() as u64));
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+        entries.push((DT_NULL, 0));
+        let dynamic = (strtab + strings.len()).next_multiple_of(8);
+        let size = dynamic + entries.len() * 16;
+
+        let mut elf = vec![0; size];
+        elf[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+        elf[0x20..0x28].copy_from_slice(&(phoff as u64).to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&3u16.to_le_bytes());
+        let headers = [
+            (PT_INTERP, interp, 0, LOADER.len() + 1),
+            (PT_LOAD, 0, vaddr.unwrap_or(BASE), size),
+            (PT_DYNAMIC, dynamic, 0, size - dynamic),
+        ];
+        for (i, (kind, offset, vaddr, filesz)) in headers.into_iter().enumerate() {
+            let header = phoff + i * 56;
+            elf[header..header + 4].copy_from_slice(&kind.to_le_bytes());
+            elf[header + 8..header + 16].copy_from_slice(&(offset as u64).to_le_bytes());
+            elf[header + 16..header + 24].copy_from_slice(&vaddr.to_le_bytes());
+            elf[header + 32..header + 40].copy_from_slice(&(filesz as u64).to_le_bytes());
+        }
+        elf[interp..interp + LOADER.len()].copy_from_slice(LOADER.as_bytes());
+        elf[strtab..strtab + strings.len()].copy_from_slice(&strings);
+        for (i, (tag, value)) in entries.into_iter().enumerate() {
+            let entry = dynamic + i * 16;
+            elf[entry..entry + 8].copy_from_slice(&tag.to_le_bytes());
+            elf[entry + 8..entry + 16].copy_from_slice(&value.to_le_bytes());
+        }
+        elf
+    }
+
+    #[test]
+    fn test_parse_elf() -> Result<(), anyhow::Error> {
+        let dir = RuntimeDir::new("elf-test")?;
+        let binary = dir.path().join("binary");
+        fs::write(&binary, elf_fixture(&["libfoo.so.1", "libc.so.6"], None))?;
+        let elf = Elf::parse(&File::open(&binary)?)?.unwrap();
+        assert_eq!(elf.interpreter, Some(PathBuf::from(LOADER)));
+        assert_eq!(elf.needed, ["libfoo.so.1", "libc.so.6"]);
+
+        let script = dir.path().join("script");
+        fs::write(&script, "#!/bin/sh\n")?;
+        assert!(Elf::parse(&File::open(&script)?)?.is_none());
+
+        // Offsets out of range are errors, not overflows
+        fs::write(&binary, elf_fixture(&["libc.so.6"], Some(u64::MAX - 8)))?;
+        assert!(Elf::parse(&File::open(&binary)?).is_err());
+        let mut elf = elf_fixture(&[], None);
+        elf[0x20..0x28].copy_from_slice(&(u64::MAX - 4).to_le_bytes());
+        fs::write(&binary, elf)?;
+        assert!(Elf::parse(&File::open(&binary)?).is_err());
+        let error = system_closure(&[&binary], false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Missing dependencies for --minimal: {} (malformed ELF file)",
+                binary.display()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_shebang_interpreter() {
+        assert_eq!(
+            shebang_interpreter(b"#!/opt/python/bin/python3 -u\nprint()"),
+            Some(PathBuf::from("/opt/python/bin/python3"))
+        );
+        let sh = shebang_interpreter(b"#! /usr/bin/env -S sh -e\n").unwrap();
+        assert!(sh.is_absolute() && sh.ends_with("sh"));
+        assert_eq!(shebang_interpreter(b"#!\n"), None);
+    }
+
+    #[test]
+    fn test_expand_origin() {
+        let origin = Path::new("/opt/foo/bin");
+        assert_eq!(
+            expand_origin("$ORIGIN/../lib", origin),
+            Some(PathBuf::from("/opt/foo/bin/../lib"))
+        );
+        assert_eq!(
+            expand_origin("${ORIGIN}", origin),
+            Some(PathBuf::from("/opt/foo/bin"))
+        );
+        assert_eq!(expand_origin("/opt/$LIB", origin), None);
+    }
+
+    #[test]
+    fn test_program_dependencies() -> Result<(), anyhow::Error> {
+        let dir = RuntimeDir::new("dependencies-test")?;
+        let prefix = dir.path().join("python");
+        fs::create_dir_all(prefix.join("bin"))?;
+        fs::create_dir_all(prefix.join("lib"))?;
+        // An ELF interpreter with a symlink, like python3 -> python3.12
+        fs::write(
+            prefix.join("bin/python3.12"),
+            elf_fixture(&["libc.so.6"], None),
+        )?;
+        symlink("python3.12", prefix.join("bin/python3"))?;
+        let tools = dir.path().join("tools");
+        fs::create_dir(&tools)?;
+        let script = tools.join("tool");
+        let interpreter = prefix.join("bin/python3");
+        fs::write(&script, format!("#!{} -u\n", interpreter.display()))?;
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+
+        let dependencies = program_dependencies(&script);
+        assert_eq!(
+            dependencies,
+            [
+                Dependency {
+                    dir: tools.clone(),
+                    reason: format!("program {}", script.display())
+                },
+                Dependency {
+                    dir: prefix.clone(),
+                    reason: format!("prefix of the interpreter of {}", script.display())
+                },
+            ]
+        );
+
+        // Only system directories
+        let system_script = tools.join("system");
+        fs::write(&system_script, "#!/bin/sh\n")?;
+        assert!(program_dependencies(&system_script)
+            .iter()
+            .all(|dependency| dependency.dir == tools));
+        Ok(())
+    }
+
+    #[test]
+    fn test_system_closure() -> Result<(), anyhow::Error> {
+        let ls = Path::new("/usr/bin/ls");
+        if !ls.exists() {
+            eprintln!("{} not found, skipping", ls.display());
+            return Ok(());
+        }
+        let files = system_closure(&[ls], false)?;
+        assert!(files.iter().any(|file| file == ls));
+        assert!(files
+            .iter()
+            .any(|file| file.to_str().unwrap().contains("libc.so")));
+        let loader = Elf::parse(&File::open(ls)?)?.unwrap().interpreter.unwrap();
+        assert!(files.contains(&loader));
+        assert!(files.contains(&fs::canonicalize(&loader)?));
+        assert!(files.contains(&PathBuf::from("/etc/ld.so.cache")));
+        assert!(!files.contains(&PathBuf::from("/etc/hosts")));
+        assert!(system_closure(&[ls], true)?.contains(&PathBuf::from("/etc/hosts")));
+
+        let dir = RuntimeDir::new("closure-test")?;
+        let script = dir.path().join("script");
+        fs::write(&script, "#!/nonexistent/sh\n")?;
+        let error = system_closure(&[&script], false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Missing dependencies for --minimal: /nonexistent/sh (interpreter of {})",
+                script.display()
+            )
+        );
+        Ok(())
+    }
+}
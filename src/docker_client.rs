@@ -12,8 +12,9 @@ use serde_json::json;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::io::ErrorKind::UnexpectedEof;
-use std::io::{self, Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::{env, thread};
 
 use StreamType::{Stderr, Stdin, Stdout};
@@ -21,9 +22,12 @@ use StreamType::{Stderr, Stdin, Stdout};
 use crate::docker_client::DockerError::{
     ErrorResponse, HttpError, InvalidJson, InvalidResponse, InvalidStream, NetworkError,
 };
+use crate::limits::ResourceLimits;
+use crate::network::{HostAlias, PortMapping};
 
 const DEFAULT_DOCKER_SOCK: &str = "/var/run/docker.sock";
 const APPLICATION_JSON: &str = "application/json";
+const APPLICATION_TAR: &str = "application/x-tar";
 const BUFFER_SIZE: usize = 1024;
 
 #[derive(thiserror::Error, Debug)]
@@ -85,6 +89,49 @@ impl Tty {
     }
 }
 
+/// State of a container, from `/containers/{id}/json`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainerState {
+    pub status: String,
+    pub exit_code: i64,
+    pub oom_killed: bool,
+    /// Error from the runtime, empty if none
+    pub error: String,
+    pub started_at: String,
+    pub finished_at: String,
+}
+
+impl ContainerState {
+    fn from_json(state: &Value) -> Self {
+        let string = |key: &str| state[key].as_str().unwrap_or_default().to_string();
+        Self {
+            status: string("Status"),
+            exit_code: state["ExitCode"].as_i64().unwrap_or_default(),
+            oom_killed: state["OOMKilled"].as_bool().unwrap_or_default(),
+            error: string("Error"),
+            started_at: string("StartedAt"),
+            finished_at: string("FinishedAt"),
+        }
+    }
+}
+
+/// Details of a container, from `/containers/{id}/json`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainerInspect {
+    /// ID of the local image the container was created from
+    pub image: String,
+    pub state: ContainerState,
+}
+
+impl ContainerInspect {
+    pub fn from_json(body: &Value) -> Self {
+        Self {
+            image: body["Image"].as_str().unwrap_or_default().to_string(),
+            state: ContainerState::from_json(&body["State"]),
+        }
+    }
+}
+
 pub struct DockerClient {
     socket_path: String,
 }
@@ -101,9 +148,13 @@ impl DockerClient {
         .map(|socket_path| Self { socket_path })
     }
 
+    /// Whether the socket of the daemon exists.
+    pub fn is_available(&self) -> bool {
+        Path::new(&self.socket_path).exists()
+    }
+
     /// Build the body payload to create a Docker container.
     pub fn create_container_body(
-        &self,
         image: &str,
         cmd: &Option<&[String]>,
         entrypoint: &Option<&[String]>,
@@ -114,6 +165,10 @@ impl DockerClient {
         tmpfs: &[Tmpfs],
         readonly_rootfs: bool,
         working_dir: &str,
+        ports: &[PortMapping],
+        extra_hosts: &[HostAlias],
+        limits: &ResourceLimits,
+        seccomp: Option<&str>,
         tty: &Option<Tty>,
     ) -> Value {
         let environment = environment
@@ -146,6 +201,31 @@ impl DockerClient {
             .into_iter()
             .map(|tmp| (tmp.container_dest.to_string(), tmp.options.join(",")))
             .collect::<HashMap<String, String>>();
+        let exposed_ports = ports
+            .iter()
+            .map(|port| {
+                (
+                    format!("{}/{}", port.container_port, port.protocol),
+                    json!({}),
+                )
+            })
+            .collect::<serde_json::Map<String, Value>>();
+        let mut port_bindings = serde_json::Map::new();
+        for port in ports {
+            let bindings = port_bindings
+                .entry(format!("{}/{}", port.container_port, port.protocol))
+                .or_insert(json!([]));
+            bindings.as_array_mut().expect("array").push(json!({
+                "HostIp": port.host_ip().to_string(),
+                "HostPort": port.host_port.to_string(),
+            }));
+        }
+        let extra_hosts = extra_hosts
+            .iter()
+            .map(|host| host.to_string())
+            .collect::<Vec<String>>();
+        let mut security_opt = vec!["no-new-privileges:true"];
+        security_opt.extend(seccomp);
 
         json!({
             "Image": image,
@@ -159,14 +239,20 @@ impl DockerClient {
             "Env": environment,
             "Tty": tty.is_some(),
             "WorkingDir": working_dir,
+            "ExposedPorts": exposed_ports,
             "HostConfig": {
                 "NetworkMode": network,
+                "PortBindings": port_bindings,
+                "ExtraHosts": extra_hosts,
                 "Binds": binds,
                 "ReadonlyRootfs": readonly_rootfs,
                 "Tmpfs": tmpfs,
                 "ConsoleSize": tty.as_ref().map(|t| [t.height, t.width]),
+                "Memory": limits.memory,
+                "NanoCpus": limits.cpus.map(|cpus| (cpus * 1e9).round() as u64),
+                "PidsLimit": limits.pids_limit,
                 "CapDrop": [ "ALL" ],
-                "SecurityOpt": [ "no-new-privileges:true" ],
+                "SecurityOpt": security_opt,
             },
         })
     }
@@ -213,6 +299,17 @@ impl DockerClient {
         }
     }
 
+    /// Returns details of a Docker container, including its state.
+    pub fn inspect_container(&self, id: &str) -> Result<ContainerInspect, DockerError> {
+        let (status, maybe_body) =
+            self.empty_request(Method::GET, &format!("/containers/{id}/json"))?;
+        match maybe_body {
+            Some(body) if status.is_success() => Ok(ContainerInspect::from_json(&body)),
+            Some(body) => Err(make_error_response(status, body, "Container inspect failed")),
+            _ => Err(InvalidResponse(status.as_u16(), "".to_string())),
+        }
+    }
+
     /// Starts a Docker container.
     pub fn start_container(&self, id: &str) -> Result<(), DockerError> {
         let (status, maybe_body) =
@@ -227,6 +324,20 @@ impl DockerClient {
         }
     }
 
+    /// Kills a Docker container.
+    pub fn kill_container(&self, id: &str) -> Result<(), DockerError> {
+        let (status, maybe_body) =
+            self.empty_request(Method::POST, &format!("/containers/{id}/kill"))?;
+        if status.is_success() {
+            Ok(())
+        } else {
+            match maybe_body {
+                Some(body) => Err(make_error_response(status, body, "Container kill failed")),
+                _ => Err(InvalidResponse(status.as_u16(), "".to_string())),
+            }
+        }
+    }
+
     /// Removes a Docker container.
     pub fn remove_container(&self, id: &str) -> Result<(), DockerError> {
         let (status, maybe_body) =
@@ -241,6 +352,48 @@ impl DockerClient {
         }
     }
 
+    /// Creates a Docker volume with the local driver, mounting the given file system.
+    pub fn create_volume(
+        &self,
+        name: &str,
+        fs_type: &str,
+        device: &str,
+        options: &str,
+    ) -> Result<(), DockerError> {
+        let body = json!({
+            "Name": name,
+            "Driver": "local",
+            "DriverOpts": {
+                "type": fs_type,
+                "device": device,
+                "o": options,
+            },
+        });
+        let (status, maybe_body) = self.body_request(Method::POST, "/volumes/create", body)?;
+        if status.is_success() {
+            Ok(())
+        } else {
+            match maybe_body {
+                Some(body) => Err(make_error_response(status, body, "Volume creation failed")),
+                _ => Err(InvalidResponse(status.as_u16(), "".to_string())),
+            }
+        }
+    }
+
+    /// Removes a Docker volume.
+    pub fn remove_volume(&self, name: &str) -> Result<(), DockerError> {
+        let (status, maybe_body) =
+            self.empty_request(Method::DELETE, &format!("/volumes/{name}"))?;
+        if status.is_success() {
+            Ok(())
+        } else {
+            match maybe_body {
+                Some(body) => Err(make_error_response(status, body, "Volume remove failed")),
+                _ => Err(InvalidResponse(status.as_u16(), "".to_string())),
+            }
+        }
+    }
+
     /// Attach to a Docker container and stream the output.
     pub fn attach_container(&self, id: &str, is_tty: bool) -> Result<(), DockerError> {
         let req = Request::builder()
@@ -271,6 +424,66 @@ impl DockerClient {
         Ok(())
     }
 
+    /// Extracts a tar archive into a directory of a Docker container, with the files owned by the
+    /// user of the container. Missing parent directories of the files are created.
+    pub fn put_archive(&self, id: &str, path: &str, archive: Vec<u8>) -> Result<(), DockerError> {
+        let req = Request::builder()
+            .method(Method::PUT)
+            .uri(format!(
+                "/containers/{id}/archive?path={}&copyUIDGID=1",
+                query_escape(path)
+            ))
+            .header(header::HOST, "localhost")
+            .header(header::CONTENT_TYPE, APPLICATION_TAR)
+            .header(header::CONTENT_LENGTH, archive.len().to_string())
+            .body(Some(archive))
+            .expect("failed to build request");
+        let (status, body) = self.raw_request(req)?;
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(raw_error_response(status, &body, "Archive upload failed"))
+        }
+    }
+
+    /// Returns a tar archive of a file or directory in a Docker container, read into memory.
+    pub fn get_archive(&self, id: &str, path: &str) -> Result<Vec<u8>, DockerError> {
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(format!(
+                "/containers/{id}/archive?path={}",
+                query_escape(path)
+            ))
+            .header(header::HOST, "localhost")
+            .header(header::ACCEPT, APPLICATION_TAR)
+            .body(None)
+            .expect("failed to build request");
+        let (status, body) = self.raw_request(req)?;
+        if status.is_success() {
+            Ok(body)
+        } else {
+            Err(raw_error_response(status, &body, "Archive download failed"))
+        }
+    }
+
+    /// Whether a path exists in a Docker container.
+    pub fn path_exists(&self, id: &str, path: &str) -> Result<bool, DockerError> {
+        let req = Request::builder()
+            .method(Method::HEAD)
+            .uri(format!(
+                "/containers/{id}/archive?path={}",
+                query_escape(path)
+            ))
+            .header(header::HOST, "localhost")
+            .body(None)
+            .expect("failed to build request");
+        match self.raw_request(req)? {
+            (status, _) if status.is_success() => Ok(true),
+            (StatusCode::NOT_FOUND, _) => Ok(false),
+            (status, body) => Err(raw_error_response(status, &body, "Archive stat failed")),
+        }
+    }
+
     /// Make a request to the Docker daemon without a body.
     fn empty_request(
         &self,
@@ -390,6 +603,75 @@ impl DockerClient {
             }
         }
     }
+
+    /// Make a request to the Docker daemon and read the whole response body, which may be large
+    /// and binary. The body is buffered in memory, it is not streamed.
+    fn raw_request(
+        &self,
+        req: Request<Option<Vec<u8>>>,
+    ) -> Result<(StatusCode, Vec<u8>), DockerError> {
+        let is_head = req.method() == Method::HEAD;
+        let mut stream = UnixStream::connect(self.socket_path.clone())
+            .map_err(|err| DockerError::ConnectError(self.socket_path.clone(), err))?;
+        send_request(req, &mut stream)?;
+
+        let mut data = Vec::new();
+        let mut buffer = [0; BUFFER_SIZE];
+        let (status_code, header_size, is_chunked, content_length) = loop {
+            let bytes_read = stream.read(&mut buffer)?;
+            if bytes_read == 0 {
+                return Err(NetworkError(UnexpectedEof.into()));
+            }
+            data.extend_from_slice(&buffer[..bytes_read]);
+            let mut headers = [httparse::EMPTY_HEADER; 16];
+            let mut response = Response::new(&mut headers);
+            if let Complete(header_size) = response.parse(&data)? {
+                let status_code =
+                    StatusCode::from_u16(response.code.ok_or(HttpError(httparse::Error::Status))?)
+                        .map_err(|_| HttpError(httparse::Error::Status))?;
+                let is_chunked = get_header_value(&mut response, header::TRANSFER_ENCODING)
+                    .is_some_and(|value| value.eq_ignore_ascii_case(b"chunked"));
+                let content_length = get_header_value(&mut response, header::CONTENT_LENGTH)
+                    .map(|value| {
+                        usize::from_radix_10_checked(value)
+                            .0
+                            .ok_or(HttpError(httparse::Error::HeaderValue))
+                    })
+                    .transpose()?;
+                break (status_code, header_size, is_chunked, content_length);
+            }
+        };
+        if is_head || status_code == StatusCode::NO_CONTENT {
+            return Ok((status_code, Vec::new()));
+        }
+
+        let mut reader = BufReader::new(io::Cursor::new(data.split_off(header_size)).chain(stream));
+        let mut body = Vec::new();
+        if is_chunked {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                reader.read_line(&mut line)?;
+                let size = line.trim_end().split(';').next().unwrap_or_default();
+                let size = usize::from_str_radix(size, 16)
+                    .map_err(|_| HttpError(httparse::Error::Token))?;
+                if size == 0 {
+                    break;
+                }
+                let start = body.len();
+                body.resize(start + size, 0);
+                reader.read_exact(&mut body[start..])?;
+                // The line break after the chunk
+                reader.read_line(&mut line)?;
+            }
+        } else if let Some(content_length) = content_length {
+            body.resize(content_length, 0);
+            reader.read_exact(&mut body)?;
+        } else {
+            reader.read_to_end(&mut body)?;
+        }
+        Ok((status_code, body))
+    }
 }
 
 fn read_response(
@@ -590,6 +872,31 @@ fn get_header_value<'headers, 'buf>(
         .map(|h| h.value)
 }
 
+/// Escapes a value in the query of a URL.
+fn query_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// The error of a response which is JSON with a message, or something else.
+fn raw_error_response(
+    status: StatusCode,
+    body: &[u8],
+    fallback_error_message: &str,
+) -> DockerError {
+    match serde_json::from_slice(body) {
+        Ok(body) => make_error_response(status, body, fallback_error_message),
+        Err(_) => ErrorResponse(status.as_u16(), fallback_error_message.to_string()),
+    }
+}
+
 fn make_error_response(
     status: StatusCode,
     body: Value,
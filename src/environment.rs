@@ -0,0 +1,283 @@
+//! # Environment
+//!
+//! `environment` selects the environment variables passed to the sandbox, the same for all
+//! backends: the locale, user, time zone and terminal of the host, variables from env files, and
+//! variables given by name, with a value, or by a pattern like `LC_*`. Variables which may contain
+//! secrets are only passed by patterns if they aren't on the deny list. The values from env files
+//! are kept apart, to keep them out of the command lines of the container tools.
+
+use anyhow::{anyhow, Context};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::{env, fs};
+
+use crate::journal::glob_matches;
+
+/// Variables of the host passed by default, by name or pattern.
+const PASSED_ENV: [&str; 6] = ["LANG", "LC_*", "USER", "TZ", "TERM", "COLORTERM"];
+
+/// Variables which may contain secrets, not passed by patterns.
+const DENIED_ENV: [&str; 7] = [
+    "*_TOKEN",
+    "*_TOKEN_*",
+    "*SECRET*",
+    "*PASSWORD*",
+    "*_API_KEY",
+    "AWS_*",
+    "AZURE_*",
+];
+
+/// The environment variables for the sandbox.
+#[derive(Debug, Default, PartialEq)]
+pub struct SandboxEnv {
+    /// `NAME=VALUE` for values, `NAME` for variables of the host and from env files
+    pub vars: Vec<String>,
+    /// The values of the variables from env files, which may be secrets
+    pub file_values: BTreeMap<String, String>,
+}
+
+impl SandboxEnv {
+    /// The variables as `NAME=VALUE`, with the values from env files, and `NAME` for variables of
+    /// the host.
+    pub fn resolved(&self) -> Vec<String> {
+        self.vars
+            .iter()
+            .map(|var| match self.file_values.get(var) {
+                Some(value) => format!("{var}={value}"),
+                None => var.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Expands the environment for the sandbox: the defaults, the env files and then the variables
+/// given as `NAME`, `NAME=VALUE` or pattern, later ones replacing earlier ones. Variables matching
+/// the deny list, the built-in one and `deny`, are left out unless given by name.
+pub fn sandbox_env(
+    extra_env: &[String],
+    env_files: &[PathBuf],
+    deny: &[String],
+) -> Result<SandboxEnv, anyhow::Error> {
+    let host = env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .collect::<BTreeMap<_, _>>();
+    let mut file_env = Vec::new();
+    for path in env_files {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read env file {path:?}"))?;
+        let vars =
+            parse_env_file(&content).with_context(|| format!("Invalid env file {path:?}"))?;
+        file_env.extend(vars);
+    }
+    expand(&host, &file_env, extra_env, deny)
+}
+
+fn expand(
+    host: &BTreeMap<String, String>,
+    file_env: &[(String, String)],
+    extra_env: &[String],
+    deny: &[String],
+) -> Result<SandboxEnv, anyhow::Error> {
+    let is_denied = |name: &str| {
+        DENIED_ENV
+            .iter()
+            .copied()
+            .chain(deny.iter().map(String::as_str))
+            .any(|pattern| glob_matches(pattern.as_bytes(), name.as_bytes()))
+    };
+    let mut env = SandboxEnv::default();
+    let mut set = |var: String, file_value: Option<&String>| {
+        let name = var.split('=').next().unwrap_or_default().to_string();
+        env.vars
+            .retain(|other| other.split('=').next() != Some(&name));
+        env.vars.push(var);
+        match file_value {
+            Some(value) => env.file_values.insert(name, value.clone()),
+            None => env.file_values.remove(&name),
+        };
+    };
+    let mut denied = Vec::new();
+
+    for pattern in PASSED_ENV {
+        for name in host.keys() {
+            if glob_matches(pattern.as_bytes(), name.as_bytes()) && !is_denied(name) {
+                set(name.clone(), None);
+            }
+        }
+    }
+    for (name, value) in file_env {
+        set(name.clone(), Some(value));
+    }
+    for var in extra_env {
+        if var.contains('=') {
+            set(var.clone(), None);
+        } else if var.contains(['*', '?']) {
+            for name in host.keys() {
+                if glob_matches(var.as_bytes(), name.as_bytes()) {
+                    if is_denied(name) {
+                        denied.push(name.clone());
+                    } else {
+                        set(name.clone(), None);
+                    }
+                }
+            }
+        } else if host.contains_key(var) {
+            set(var.clone(), None);
+        } else {
+            return Err(anyhow!("Environment variable {var} is not set"));
+        }
+    }
+
+    denied.retain(|name| {
+        !env.vars
+            .iter()
+            .any(|var| var.split('=').next() == Some(name))
+    });
+    denied.sort();
+    denied.dedup();
+    if !denied.is_empty() {
+        eprintln!(
+            "contained: not passing {}, which may contain secrets, name them with -e to pass them",
+            denied.join(", ")
+        );
+    }
+    Ok(env)
+}
+
+/// Parses an env file in the dotenv format: `NAME=VALUE` lines, optionally with `export`, values
+/// in single quotes taken literally, in double quotes with `\n`, `\"` and `\\` escapes, and
+/// comments starting with `#`.
+fn parse_env_file(content: &str) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let mut vars = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: &str| anyhow!("Line {}: {message}", number + 1);
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected NAME=VALUE"))?;
+        let name = name.trim();
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(error(&format!("invalid name '{name}'")));
+        }
+        let value = value.trim();
+        let value = if let Some(value) = value.strip_prefix('\'') {
+            let (value, _) = value
+                .split_once('\'')
+                .ok_or_else(|| error("unterminated quote"))?;
+            value.to_string()
+        } else if let Some(value) = value.strip_prefix('"') {
+            let mut unescaped = String::new();
+            let mut chars = value.chars();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => unescaped.push('\n'),
+                        Some(c) => unescaped.push(c),
+                        None => return Err(error("unterminated quote")),
+                    },
+                    Some(c) => unescaped.push(c),
+                    None => return Err(error("unterminated quote")),
+                }
+            }
+            unescaped
+        } else {
+            // Comments after unquoted values
+            let value = value.split(" #").next().unwrap_or_default();
+            value.trim_end().to_string()
+        };
+        vars.push((name.to_string(), value));
+    }
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let host = [
+            ("LANG", "C.UTF-8"),
+            ("LC_TIME", "de_DE.UTF-8"),
+            ("TERM", "xterm"),
+            ("CARGO_HOME", "/c"),
+            ("CARGO_REGISTRY_TOKEN", "secret"),
+            ("GITHUB_TOKEN", "secret"),
+            ("HOME", "/home/u"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect::<BTreeMap<_, _>>();
+        let strings = |vars: &[&str]| vars.iter().map(|var| var.to_string()).collect::<Vec<_>>();
+        let file_env = [("TERM", "dumb"), ("A", "1"), ("B", "2")]
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+
+        assert_eq!(
+            expand(&host, &[], &[], &[]).unwrap().vars,
+            ["LANG", "LC_TIME", "TERM"]
+        );
+        let env = expand(
+            &host,
+            &file_env,
+            &strings(&["CARGO_*", "GITHUB_TOKEN", "A=2"]),
+            &strings(&["LC_*"]),
+        )
+        .unwrap();
+        // The values from the files are kept apart
+        assert_eq!(
+            env.vars,
+            ["LANG", "TERM", "B", "CARGO_HOME", "GITHUB_TOKEN", "A=2"]
+        );
+        assert_eq!(
+            env.file_values,
+            BTreeMap::from([
+                ("B".to_string(), "2".to_string()),
+                ("TERM".to_string(), "dumb".to_string())
+            ])
+        );
+        assert_eq!(
+            env.resolved(),
+            [
+                "LANG",
+                "TERM=dumb",
+                "B=2",
+                "CARGO_HOME",
+                "GITHUB_TOKEN",
+                "A=2"
+            ]
+        );
+        assert!(expand(&host, &[], &strings(&["MISSING"]), &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let content = "# comment\n\
+                       A=1\n\
+                       export B = two words # comment\n\
+                       C='single # not a comment'\n\
+                       D=\"line\\none \\\"quoted\\\"\"\n\
+                       \n\
+                       E=\n";
+        assert_eq!(
+            parse_env_file(content).unwrap(),
+            [
+                ("A", "1"),
+                ("B", "two words"),
+                ("C", "single # not a comment"),
+                ("D", "line\none \"quoted\""),
+                ("E", ""),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+        assert!(parse_env_file("A").is_err());
+        assert!(parse_env_file("1A=x").is_err());
+        assert!(parse_env_file("A=\"open").is_err());
+    }
+}
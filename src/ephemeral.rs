@@ -0,0 +1,343 @@
+//! # Ephemeral root
+//!
+//! `ephemeral` contains throwaway writable overlays over the read-only system directories of the
+//! sandbox, and empty writable directories for `/var/cache` and the home directory, so that
+//! programs can write anywhere without changing the host.
+//!
+//! The changes are kept on a tmpfs limited to the size of the ephemeral root, mounted in the
+//! namespaces in which the sandbox is set up. The Docker daemon mounts the overlays on the host,
+//! where no tmpfs can be mounted for it, so there the space used is watched instead, and the
+//! sandbox is killed when it exceeds the limit.
+
+use anyhow::{anyhow, Context};
+use std::ffi::OsString;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::{fs, io, thread};
+
+use crate::helper::{helper_command, wrap_command};
+use crate::namespace;
+use crate::overlay::{OverlayMount, Overlays};
+use crate::runtime_dir::RuntimeDir;
+
+/// Directories outside of the system directories which are written to, mounted empty and
+/// writable.
+pub const SCRATCH_DIRS: [&str; 1] = ["/var/cache"];
+
+/// How often the space used by the changes is checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Throwaway overlays over the system directories and scratch directories, limited in size.
+pub struct EphemeralRoot {
+    overlays: Overlays,
+    size: u64,
+    dir: RuntimeDir,
+}
+
+/// Watches the space used by the changes while the sandbox runs.
+pub struct SizeWatch {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<bool>,
+    size: u64,
+}
+
+/// The tmpfs of the changes, mounted for Podman, unmounted on drop.
+pub struct PodmanTmpfs<'a> {
+    root: &'a EphemeralRoot,
+}
+
+impl EphemeralRoot {
+    /// Creates overlays for the paths which exist on the host, limited to `size` bytes.
+    pub fn new(paths: &[&str], size: u64) -> Result<Self, anyhow::Error> {
+        let paths = paths
+            .iter()
+            .map(PathBuf::from)
+            .filter(|path| path.exists())
+            .collect::<Vec<_>>();
+        let dir = RuntimeDir::new("ephemeral").context("Unable to create ephemeral root")?;
+        let overlays = Overlays::throwaway(&dir.path().join("overlays"), &paths)
+            .context("Unable to create ephemeral root overlays")?;
+        let root = Self {
+            overlays,
+            size,
+            dir,
+        };
+        for (scratch, _) in root.scratch_dirs() {
+            fs::create_dir_all(scratch)?;
+        }
+        fs::create_dir(root.home())?;
+        Ok(root)
+    }
+
+    pub fn overlays(&self) -> &Overlays {
+        &self.overlays
+    }
+
+    /// The overlay mounted at the path, if any.
+    pub fn mount(&self, path: &str) -> Option<&OverlayMount> {
+        self.overlays
+            .mounts()
+            .iter()
+            .find(|mount| mount.lower == Path::new(path))
+    }
+
+    /// The directories on the host for [SCRATCH_DIRS], with where they are mounted.
+    pub fn scratch_dirs(&self) -> Vec<(PathBuf, &'static str)> {
+        SCRATCH_DIRS
+            .iter()
+            .map(|path| {
+                let relative = path.trim_start_matches('/');
+                (self.dir.path().join("scratch").join(relative), *path)
+            })
+            .collect()
+    }
+
+    /// The home directory, if none is requested.
+    pub fn home(&self) -> PathBuf {
+        self.dir.path().join("home")
+    }
+
+    /// Command running `inner` in new user and mount namespaces with the changes on the tmpfs,
+    /// for a sandbox which then mounts the overlays in them.
+    pub fn command(&self, inner: &Command) -> io::Result<Command> {
+        let mut cmd = helper_command("ephemeral")?;
+        cmd.arg(self.size.to_string())
+            .arg(self.dir.path())
+            .arg("--");
+        Ok(wrap_command(cmd, inner))
+    }
+
+    /// Mounts the tmpfs in the namespaces of rootless Podman, which mounts the overlays in them,
+    /// or on the host when running as root.
+    pub fn mount_for_podman(&self) -> Result<PodmanTmpfs<'_>, anyhow::Error> {
+        let mut mount = helper_command("ephemeral")?;
+        mount.arg(self.size.to_string()).arg(self.dir.path());
+        let status = podman_unshare(mount)
+            .status()
+            .context("Unable to mount ephemeral root for podman")?;
+        if !status.success() {
+            return Err(anyhow!("Unable to mount ephemeral root for podman"));
+        }
+        Ok(PodmanTmpfs { root: self })
+    }
+
+    /// Starts watching the space used by the changes, `kill` is called once if it exceeds the
+    /// limit.
+    pub fn watch(&self, kill: impl FnOnce() + Send + 'static) -> io::Result<SizeWatch> {
+        let dir = self.dir.path().to_path_buf();
+        let size = self.size;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("ephemeral-size".to_string())
+                .spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        if disk_usage(&dir) > size {
+                            kill();
+                            return true;
+                        }
+                        thread::sleep(POLL_INTERVAL);
+                    }
+                    false
+                })?
+        };
+        Ok(SizeWatch { stop, thread, size })
+    }
+}
+
+impl Drop for PodmanTmpfs<'_> {
+    fn drop(&mut self) {
+        let mut umount = Command::new("umount");
+        umount.arg(self.root.dir.path());
+        let _ = podman_unshare(umount).stderr(Stdio::null()).status();
+    }
+}
+
+impl SizeWatch {
+    /// Stops watching after the sandbox exited, reports whether it was killed.
+    pub fn finish(self) -> bool {
+        self.stop.store(true, Ordering::Relaxed);
+        let exceeded = self.thread.join().unwrap_or(false);
+        if exceeded {
+            eprintln!(
+                "contained: killed after changes to the ephemeral root exceeded {} bytes",
+                self.size
+            );
+        }
+        exceeded
+    }
+}
+
+/// The command, in the namespaces of rootless Podman unless running as root.
+fn podman_unshare(cmd: Command) -> Command {
+    // SAFETY: plain system call without pointers
+    if unsafe { libc::geteuid() } == 0 {
+        return cmd;
+    }
+    let mut podman = Command::new("podman");
+    podman.arg("unshare");
+    wrap_command(podman, &cmd)
+}
+
+/// Helper mode: mounts a tmpfs limited to SIZE bytes over DIR, keeping its directories. With a
+/// program after `--`, the tmpfs is mounted in new user and mount namespaces, in which the
+/// program runs.
+pub fn run_helper(args: Vec<OsString>) -> Result<u8, anyhow::Error> {
+    let mut args = args.into_iter();
+    let size = args
+        .next()
+        .and_then(|size| size.to_str()?.parse::<u64>().ok())
+        .ok_or(anyhow!("Invalid ephemeral root size"))?;
+    let dir = PathBuf::from(args.next().ok_or(anyhow!("Missing ephemeral root"))?);
+    let program = match args.next() {
+        None => None,
+        Some(arg) if arg == "--" => Some(args.next().ok_or(anyhow!("Missing program"))?),
+        Some(arg) => return Err(anyhow!("Unexpected argument {arg:?}")),
+    };
+
+    if program.is_some() {
+        // SAFETY: plain system calls without pointers
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        namespace::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS)
+            .context("Unable to create namespaces")?;
+        fs::write("/proc/self/setgroups", "deny")
+            .and_then(|_| fs::write("/proc/self/uid_map", format!("{uid} {uid} 1\n")))
+            .and_then(|_| fs::write("/proc/self/gid_map", format!("{gid} {gid} 1\n")))
+            .context("Unable to map user in namespace")?;
+    }
+    mount_tmpfs(&dir, size)?;
+
+    match program {
+        Some(program) => {
+            let error = Command::new(&program).args(args).exec();
+            Err(anyhow::Error::new(error).context(format!("Unable to run {:?}", program)))
+        }
+        None => Ok(0),
+    }
+}
+
+/// Mounts a tmpfs over the directory, with the same directories in it.
+fn mount_tmpfs(dir: &Path, size: u64) -> Result<(), anyhow::Error> {
+    let mut dirs = Vec::new();
+    subdirectories(dir, Path::new(""), &mut dirs)?;
+    namespace::mount(
+        Some(Path::new("tmpfs")),
+        dir,
+        Some("tmpfs"),
+        libc::MS_NOSUID | libc::MS_NODEV,
+        Some(&format!("size={size},mode=0700")),
+    )?;
+    for relative in dirs {
+        let path = dir.join(relative);
+        fs::create_dir(&path).with_context(|| format!("Unable to create {path:?}"))?;
+    }
+    Ok(())
+}
+
+/// The directories below `dir`, relative to it, parents first.
+fn subdirectories(dir: &Path, relative: &Path, dirs: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir.join(relative))? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let relative = relative.join(entry.file_name());
+            dirs.push(relative.clone());
+            subdirectories(dir, &relative, dirs)?;
+        }
+    }
+    Ok(())
+}
+
+/// Space allocated by the files below the directory, ignoring files removed in the meantime.
+pub fn disk_usage(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let metadata = path.symlink_metadata().ok()?;
+            let nested = if metadata.is_dir() {
+                disk_usage(&path)
+            } else {
+                0
+            };
+            Some(metadata.blocks() * 512 + nested)
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_layout() -> Result<(), anyhow::Error> {
+        let root = EphemeralRoot::new(&["/usr", "/etc", "/nonexistent"], 64 * 1024)?;
+        assert_eq!(root.overlays().mounts().len(), 2);
+        assert!(root.mount("/etc").is_some());
+        assert!(root.mount("/nonexistent").is_none());
+        let scratch = root.scratch_dirs();
+        assert_eq!(scratch.len(), 1);
+        assert_eq!(scratch[0].1, "/var/cache");
+        assert!(scratch[0].0.is_dir());
+        assert!(root.home().is_dir());
+
+        let mut dirs = Vec::new();
+        subdirectories(root.dir.path(), Path::new(""), &mut dirs)?;
+        for path in [
+            "overlays/0/upper",
+            "overlays/1/work",
+            "scratch/var/cache",
+            "home",
+        ] {
+            assert!(dirs.contains(&PathBuf::from(path)), "{path} missing");
+        }
+        assert!(
+            dirs.iter().position(|dir| dir == Path::new("overlays"))
+                < dirs.iter().position(|dir| dir == Path::new("overlays/0"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch() -> Result<(), anyhow::Error> {
+        let root = EphemeralRoot::new(&["/etc"], 64 * 1024)?;
+        let upper = root.mount("/etc").expect("overlay").upper.clone();
+
+        let mut child = Command::new("sleep").arg("10").spawn()?;
+        let pid = child.id() as libc::pid_t;
+        let watch = root.watch(move || {
+            // SAFETY: plain system call without pointers
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+        })?;
+        fs::write(upper.join("small"), [0; 1024])?;
+        thread::sleep(POLL_INTERVAL * 2);
+        assert!(child.try_wait()?.is_none());
+
+        // The scratch directories count as well
+        let start = Instant::now();
+        fs::write(root.scratch_dirs()[0].0.join("large"), [1; 128 * 1024])?;
+        let status = child.wait()?;
+        assert!(!status.success());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(watch.finish());
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_finish() -> Result<(), anyhow::Error> {
+        let root = EphemeralRoot::new(&["/etc"], 1024 * 1024)?;
+        let watch = root.watch(|| panic!("not exceeded"))?;
+        fs::write(root.mount("/etc").expect("overlay").upper.join("file"), "x")?;
+        assert!(!watch.finish());
+        Ok(())
+    }
+}
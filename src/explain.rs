@@ -0,0 +1,509 @@
+//! # Explain
+//!
+//! `explain` describes a sandbox for `--explain` and `--dry-run`, from the same `podman` or
+//! `bwrap` command line, or Docker container body, which is used to run it: the paths it sees and
+//! their access, the environment, the network, the capabilities and the user.
+
+use serde_json::Value;
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::process::Command;
+use users::{get_effective_gid, get_effective_uid};
+
+use crate::environment::SandboxEnv;
+use crate::proxy::PROXY_DIR;
+
+/// A path in the sandbox.
+#[derive(Debug, PartialEq)]
+struct Mount {
+    target: String,
+    /// `ro`, `rw`, `cow` for copy-on-write overlays, or `link` for symlinks
+    access: &'static str,
+    /// Host path, volume, or kind of file system
+    source: String,
+}
+
+/// What a sandbox sees.
+#[derive(Debug, Default, PartialEq)]
+pub struct Explanation {
+    mounts: Vec<Mount>,
+    env: Vec<String>,
+    network: String,
+    capabilities: String,
+    user: String,
+}
+
+impl Explanation {
+    /// Explains a `podman run` command.
+    pub fn podman(command: &Command) -> Self {
+        let args = args(command);
+        let mut explanation = Self::default();
+        let mut cap_drop = Vec::new();
+        let mut no_new_privileges = false;
+        let mut read_only = false;
+        let mut args = args.iter().skip_while(|arg| arg.as_str() == "run");
+        while let Some(arg) = args.next() {
+            let (option, inline) = match arg.split_once('=') {
+                Some((option, value)) if arg.starts_with("--") => (option, Some(value)),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || inline.map(str::to_string).or_else(|| args.next().cloned());
+            match option {
+                "--mount" => {
+                    let value = value().unwrap_or_default();
+                    let fields = value.split(',').collect::<Vec<_>>();
+                    let field = |name: &str| {
+                        fields
+                            .iter()
+                            .find_map(|field| field.strip_prefix(name)?.strip_prefix('='))
+                            .unwrap_or_default()
+                            .to_string()
+                    };
+                    let access = if fields.contains(&"readonly") {
+                        "ro"
+                    } else {
+                        "rw"
+                    };
+                    explanation.mount(&field("target"), access, &field("source"));
+                }
+                "--volume" => {
+                    // Overlays, `LOWER:TARGET:O,upperdir=...`
+                    let value = value().unwrap_or_default();
+                    let mut parts = value.splitn(3, ':');
+                    let source = parts.next().unwrap_or_default();
+                    let target = parts.next().unwrap_or_default();
+                    explanation.mount(target, "cow", source);
+                }
+                "--tmpfs" => {
+                    let value = value().unwrap_or_default();
+                    let target = value.split(':').next().unwrap_or_default();
+                    explanation.mount(target, "rw", "tmpfs");
+                }
+                "-e" => {
+                    let value = value().unwrap_or_default();
+                    let passed = command
+                        .get_envs()
+                        .any(|(name, value_of)| value_of.is_some() && name == value.as_str());
+                    if value.contains('=') {
+                        explanation.env.push(value);
+                    } else if passed {
+                        // Set in the environment of podman, to keep it off its command line
+                        explanation.env.push(format!("{value}, from an env file"));
+                    } else if env::var_os(&value).is_some() {
+                        // Only the name, the value may be a secret
+                        explanation.env.push(format!("{value}, from the host"));
+                    }
+                }
+                "--network" => explanation.network = value().unwrap_or_default(),
+                "--cap-drop" => cap_drop.push(value().unwrap_or_default()),
+                "--security-opt" => {
+                    no_new_privileges |= value().unwrap_or_default() == "no-new-privileges=true"
+                }
+                "--userns" => {
+                    if value().as_deref() == Some("keep-id") {
+                        explanation.user = format!(
+                            "{}:{}, the same as on the host",
+                            get_effective_uid(),
+                            get_effective_gid()
+                        );
+                    }
+                }
+                "--read-only" => read_only = true,
+                "--entrypoint" | "--name" | "--workdir" | "--publish" | "--add-host" => {
+                    value();
+                }
+                _ if option.starts_with('-') => {}
+                image => {
+                    let access = if read_only { "ro" } else { "rw" };
+                    explanation.mount("/", access, &format!("image {image}"));
+                    break;
+                }
+            }
+        }
+        explanation.capabilities = capabilities(&cap_drop, no_new_privileges);
+        explanation.finish()
+    }
+
+    /// Explains a `bwrap` command.
+    pub fn bwrap(command: &Command) -> Self {
+        let args = args(command);
+        let mut explanation = Self {
+            // Dropped by bubblewrap in the user namespace, which also sets no_new_privs
+            capabilities: capabilities(&["ALL".to_string()], true),
+            ..Default::default()
+        };
+        let mut unshare_net = false;
+        let mut ids = None;
+        let mut overlay_source = String::new();
+        let mut args = args.iter();
+        let value = |args: &mut std::slice::Iter<String>| args.next().cloned().unwrap_or_default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--ro-bind" | "--bind" => {
+                    let source = value(&mut args);
+                    let target = value(&mut args);
+                    let access = if arg == "--bind" { "rw" } else { "ro" };
+                    explanation.mount(&target, access, &source);
+                }
+                "--symlink" => {
+                    let source = value(&mut args);
+                    explanation.mount(&value(&mut args), "link", &source);
+                }
+                "--tmpfs" => explanation.mount(&value(&mut args), "rw", "tmpfs"),
+                "--proc" => explanation.mount(&value(&mut args), "ro", "proc"),
+                "--dev" => explanation.mount(&value(&mut args), "rw", "devices"),
+                "--overlay-src" => overlay_source = value(&mut args),
+                "--overlay" => {
+                    let _upper = value(&mut args);
+                    let _work = value(&mut args);
+                    explanation.mount(&value(&mut args), "cow", &overlay_source);
+                }
+                "--setenv" => {
+                    let key = value(&mut args);
+                    explanation.env.push(format!("{key}={}", value(&mut args)));
+                }
+                "--uid" => {
+                    let uid = value(&mut args);
+                    ids = Some((uid, String::new()));
+                }
+                "--gid" => {
+                    if let Some((_, gid)) = &mut ids {
+                        *gid = value(&mut args);
+                    }
+                }
+                "--unshare-net" => unshare_net = true,
+                "--perms" | "--chdir" | "--seccomp" => {
+                    value(&mut args);
+                }
+                _ if arg.starts_with("--") => {}
+                _ => break,
+            }
+        }
+        explanation.network = if unshare_net {
+            "none".to_string()
+        } else if ids.is_some() {
+            "pasta".to_string()
+        } else {
+            "host".to_string()
+        };
+        let (uid, gid) = ids.unwrap_or_else(|| {
+            (
+                get_effective_uid().to_string(),
+                get_effective_gid().to_string(),
+            )
+        });
+        explanation.user = format!(
+            "{uid}:{gid}, mapped to {}:{} on the host",
+            get_effective_uid(),
+            get_effective_gid()
+        );
+        explanation.finish()
+    }
+
+    /// Explains the body of a Docker `/containers/create` request.
+    pub fn docker(body: &Value) -> Self {
+        let mut explanation = Self::default();
+        let host_config = &body["HostConfig"];
+        let read_only = host_config["ReadonlyRootfs"].as_bool().unwrap_or_default();
+        let image = body["Image"].as_str().unwrap_or_default();
+        explanation.mount(
+            "/",
+            if read_only { "ro" } else { "rw" },
+            &format!("image {image}"),
+        );
+        for bind in strings(&host_config["Binds"]) {
+            let mut parts = bind.splitn(3, ':');
+            let source = parts.next().unwrap_or_default();
+            let target = parts.next().unwrap_or_default();
+            let options = parts.next().unwrap_or_default();
+            let access = if options.split(',').any(|option| option == "ro") {
+                "ro"
+            } else {
+                "rw"
+            };
+            explanation.mount(target, access, source);
+        }
+        if let Some(tmpfs) = host_config["Tmpfs"].as_object() {
+            for target in tmpfs.keys() {
+                explanation.mount(target, "rw", "tmpfs");
+            }
+        }
+        explanation.env = strings(&body["Env"]);
+        explanation.network = host_config["NetworkMode"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let no_new_privileges = strings(&host_config["SecurityOpt"])
+            .iter()
+            .any(|option| option == "no-new-privileges:true");
+        explanation.capabilities =
+            capabilities(&strings(&host_config["CapDrop"]), no_new_privileges);
+        explanation.user = format!(
+            "{}, the same as on the host unless the daemon remaps users",
+            body["User"].as_str().unwrap_or_default()
+        );
+        explanation.finish()
+    }
+
+    /// Leaves out the values of the variables from env files, which may be secrets.
+    pub fn hide_file_values(mut self, env: &SandboxEnv) -> Self {
+        for var in &mut self.env {
+            let name = var.split('=').next().unwrap_or_default();
+            if var.contains('=') && env.file_values.contains_key(name) {
+                *var = format!("{name}, from an env file");
+            }
+        }
+        self
+    }
+
+    fn mount(&mut self, target: &str, access: &'static str, source: &str) {
+        self.mounts.push(Mount {
+            target: target.to_string(),
+            access,
+            source: source.to_string(),
+        });
+    }
+
+    fn finish(mut self) -> Self {
+        // Later mounts of the same path replace earlier ones
+        let mut mounts = Vec::<Mount>::new();
+        for mount in self.mounts.drain(..).rev() {
+            if !mounts.iter().any(|other| other.target == mount.target) {
+                mounts.push(mount);
+            }
+        }
+        mounts.sort_by(|a, b| a.target.cmp(&b.target));
+        self.mounts = mounts;
+        if self.mounts.iter().any(|mount| mount.target == PROXY_DIR) {
+            self.network += ", HTTP(S) proxy for the allowed hosts";
+        }
+        self
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self
+            .mounts
+            .iter()
+            .map(|mount| mount.target.len())
+            .max()
+            .unwrap_or_default();
+        writeln!(f, "contained: the sandbox sees")?;
+        for mount in &self.mounts {
+            writeln!(
+                f,
+                "  {:width$}  {:4}  {}",
+                mount.target, mount.access, mount.source
+            )?;
+        }
+        writeln!(f, "contained: environment")?;
+        for var in &self.env {
+            writeln!(f, "  {var}")?;
+        }
+        writeln!(f, "contained: network       {}", self.network)?;
+        writeln!(f, "contained: capabilities  {}", self.capabilities)?;
+        writeln!(f, "contained: user          {}", self.user)
+    }
+}
+
+/// The command line, quoted for the shell.
+pub fn shell_words(command: &Command) -> String {
+    let mut words = vec![quote(command.get_program())];
+    words.extend(command.get_args().map(quote));
+    words.join(" ")
+}
+
+fn quote(word: &OsStr) -> String {
+    let word = word.to_string_lossy();
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-+=:,./@%".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.into_owned()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+fn args(command: &Command) -> Vec<String> {
+    command
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|value| value.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn capabilities(dropped: &[String], no_new_privileges: bool) -> String {
+    let mut capabilities = if dropped.iter().any(|cap| cap == "ALL") {
+        "none, all dropped".to_string()
+    } else if dropped.is_empty() {
+        "the defaults of the runtime".to_string()
+    } else {
+        format!("the defaults of the runtime without {}", dropped.join(", "))
+    };
+    if no_new_privileges {
+        capabilities += ", no new privileges";
+    }
+    capabilities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_quote() {
+        let mut command = Command::new("bwrap");
+        command.args(["--setenv", "PS1", "$ it's", "--bind", "/a b", "/c"]);
+        assert_eq!(
+            shell_words(&command),
+            r"bwrap --setenv PS1 '$ it'\''s' --bind '/a b' /c"
+        );
+    }
+
+    #[test]
+    fn test_explain_bwrap() {
+        let mut command = Command::new("bwrap");
+        command.args([
+            "--ro-bind",
+            "/usr",
+            "/usr",
+            "--symlink",
+            "/usr/lib",
+            "/lib",
+            "--tmpfs",
+            "/tmp",
+            "--ro-bind",
+            "/home/u/p",
+            "/home/u/p",
+            "--bind",
+            "/home/u/p",
+            "/home/u/p",
+            "--overlay-src",
+            "/srv",
+            "--overlay",
+            "/up",
+            "/work",
+            "/srv",
+            "--clearenv",
+            "--setenv",
+            "LANG",
+            "C",
+            "--unshare-user",
+            "--unshare-net",
+            "ls",
+            "--bind",
+        ]);
+        let explanation = Explanation::bwrap(&command);
+        let mounts = explanation
+            .mounts
+            .iter()
+            .map(|mount| (mount.target.as_str(), mount.access))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            mounts,
+            [
+                ("/home/u/p", "rw"),
+                ("/lib", "link"),
+                ("/srv", "cow"),
+                ("/tmp", "rw"),
+                ("/usr", "ro")
+            ]
+        );
+        assert_eq!(explanation.env, ["LANG=C"]);
+        assert_eq!(explanation.network, "none");
+        assert_eq!(
+            explanation.capabilities,
+            "none, all dropped, no new privileges"
+        );
+    }
+
+    #[test]
+    fn test_explain_podman_and_docker() {
+        let mut command = Command::new("podman");
+        command.args([
+            "run",
+            "--userns=keep-id",
+            "--cap-drop",
+            "ALL",
+            "--security-opt",
+            "no-new-privileges=true",
+            "--network=none",
+            "--mount",
+            "type=bind,source=/usr,target=/usr,readonly",
+            "--mount",
+            "type=bind,source=/cache,target=/home/contained/.cargo",
+            "--tmpfs=/tmp:rw,exec,mode=1777",
+            "-e",
+            "A=1",
+            "--read-only",
+            "--entrypoint",
+            "/usr/bin/ls",
+            "empty",
+            "-l",
+        ]);
+        let podman = Explanation::podman(&command);
+        assert_eq!(podman.env, ["A=1"]);
+        let mut from_host = Command::new("podman");
+        from_host.args(["run", "-e", "PATH", "-e", "CONTAINED_TEST_UNSET", "empty"]);
+        assert_eq!(Explanation::podman(&from_host).env, ["PATH, from the host"]);
+        let mut from_file = Command::new("podman");
+        from_file
+            .args(["run", "-e", "TOKEN", "empty"])
+            .env("TOKEN", "secret");
+        assert_eq!(
+            Explanation::podman(&from_file).env,
+            ["TOKEN, from an env file"]
+        );
+        assert_eq!(podman.network, "none");
+        assert_eq!(podman.capabilities, "none, all dropped, no new privileges");
+
+        let body = json!({
+            "Image": "empty",
+            "User": "1000:1000",
+            "Env": ["A=1"],
+            "HostConfig": {
+                "NetworkMode": "none",
+                "Binds": ["/usr:/usr:ro", "contained-cache-cargo:/home/contained/.cargo:rw"],
+                "ReadonlyRootfs": true,
+                "Tmpfs": {"/tmp": "rw,exec,mode=1777"},
+                "CapDrop": ["ALL"],
+                "SecurityOpt": ["no-new-privileges:true"],
+            },
+        });
+        let docker = Explanation::docker(&body);
+        let env = SandboxEnv {
+            vars: vec!["A".to_string()],
+            file_values: [("A".to_string(), "1".to_string())].into(),
+        };
+        assert_eq!(
+            Explanation::docker(&body).hide_file_values(&env).env,
+            ["A, from an env file"]
+        );
+        let mounts = |explanation: &Explanation| {
+            explanation
+                .mounts
+                .iter()
+                .map(|mount| (mount.target.clone(), mount.access))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(mounts(&podman), mounts(&docker));
+        assert_eq!(podman.env, docker.env);
+        assert_eq!(podman.capabilities, docker.capabilities);
+        assert!(docker
+            .to_string()
+            .contains("  /usr                    ro    /usr\n"));
+    }
+}
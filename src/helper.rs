@@ -0,0 +1,171 @@
+//! # Sandbox helper
+//!
+//! `helper` contains code which runs inside the sandbox, as a hidden mode of the tool binaries.
+
+use anyhow::{anyhow, Context};
+use std::ffi::OsString;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::{env, io};
+
+use crate::namespace::SandboxSpec;
+use crate::seccomp::{self, SeccompProfile};
+use crate::{ephemeral, namespace, proxy, verify};
+
+/// Where the tool binary is mounted inside the sandbox.
+pub const HELPER_PATH: &str = "/run/contained-helper";
+
+const HELPER_ARG: &str = "__contained-helper";
+
+/// The path of the running tool binary, to be mounted at [HELPER_PATH].
+pub fn helper_binary() -> io::Result<PathBuf> {
+    env::current_exe()
+}
+
+/// Command line inside the sandbox for running a program with the egress proxy forwarder.
+pub fn proxy_forward_command(program: &Path) -> Vec<OsString> {
+    vec![
+        HELPER_PATH.into(),
+        HELPER_ARG.into(),
+        "proxy-forward".into(),
+        proxy::sandbox_socket().into(),
+        proxy::PROXY_PORT.to_string().into(),
+        "--".into(),
+        program.into(),
+    ]
+}
+
+/// Arguments of the tool binary running the isolation probe of `contained verify`.
+pub fn probe_arguments(arguments: Vec<String>) -> Vec<String> {
+    let mut args = vec![HELPER_ARG.to_string(), "probe".to_string()];
+    args.extend(arguments);
+    args
+}
+
+/// Command running the tool binary as helper in the given mode, outside of the sandbox.
+pub fn helper_command(mode: &str) -> io::Result<Command> {
+    let mut cmd = Command::new(helper_binary()?);
+    cmd.arg(HELPER_ARG).arg(mode);
+    Ok(cmd)
+}
+
+/// Command running the sandbox described by a bubblewrap command with the built-in
+/// namespace sandbox instead.
+pub fn namespace_command(bwrap: &Command) -> Result<Command, anyhow::Error> {
+    let args = bwrap
+        .get_args()
+        .map(|arg| arg.to_os_string())
+        .collect::<Vec<_>>();
+    SandboxSpec::parse(&args)?;
+    let mut cmd = helper_command("namespace")?;
+    cmd.args(args);
+    inherit_environment(&mut cmd, bwrap);
+    Ok(cmd)
+}
+
+/// Command running `inner` with `wrapper`, with the environment and working directory of `inner`.
+/// Its stdio and `pre_exec` cannot be carried over, they have to be set up on the final command.
+pub fn wrap_command(mut wrapper: Command, inner: &Command) -> Command {
+    wrapper.arg(inner.get_program()).args(inner.get_args());
+    inherit_environment(&mut wrapper, inner);
+    wrapper
+}
+
+fn inherit_environment(cmd: &mut Command, inner: &Command) {
+    for (name, value) in inner.get_envs() {
+        match value {
+            Some(value) => cmd.env(name, value),
+            None => cmd.env_remove(name),
+        };
+    }
+    if let Some(dir) = inner.get_current_dir() {
+        cmd.current_dir(dir);
+    }
+}
+
+/// Command passing the compiled seccomp filter of the profile on [seccomp::SECCOMP_FD]
+/// to a command, for `bwrap --seccomp`. Returns `None` if the profile is unconfined.
+pub fn seccomp_command(
+    profile: &SeccompProfile,
+    command: &Command,
+) -> Result<Option<Command>, anyhow::Error> {
+    let profile_arg = match profile {
+        SeccompProfile::Default => OsString::from("default"),
+        SeccompProfile::Unconfined => return Ok(None),
+        SeccompProfile::File(path) => path.as_os_str().to_os_string(),
+    };
+    // Report errors in the profile before starting anything
+    profile
+        .compile()?
+        .map(|filter| filter.compile())
+        .transpose()?;
+
+    let mut cmd = helper_command("seccomp")?;
+    cmd.arg(profile_arg).arg("--");
+    Ok(Some(wrap_command(cmd, command)))
+}
+
+/// Run the helper if the process was started as one, returns the exit code in that case.
+pub fn run_if_requested() -> Result<Option<u8>, anyhow::Error> {
+    let mut args = env::args_os().skip(1);
+    if args.next().is_none_or(|arg| arg != HELPER_ARG) {
+        return Ok(None);
+    }
+    let mode = args.next().ok_or(anyhow!("Missing helper mode"))?;
+    match mode.to_str() {
+        Some("proxy-forward") => {
+            let socket = PathBuf::from(args.next().ok_or(anyhow!("Missing proxy socket"))?);
+            let port = args
+                .next()
+                .and_then(|p| p.to_str().and_then(|p| p.parse::<u16>().ok()))
+                .ok_or(anyhow!("Invalid proxy port"))?;
+            if args.next().is_none_or(|arg| arg != "--") {
+                return Err(anyhow!("Missing program"));
+            }
+            proxy::forward(port, &socket).context("Unable to start proxy forwarder")?;
+            run_program(args).map(Some)
+        }
+        Some("ephemeral") => ephemeral::run_helper(args.collect()).map(Some),
+        Some("namespace") => namespace::run(args.collect()).map(Some),
+        Some("namespace-init") => namespace::init(args.collect()).map(Some),
+        Some("probe") => verify::probe(args.collect()).map(Some),
+        Some("seccomp") => {
+            let profile = args
+                .next()
+                .and_then(|p| p.into_string().ok())
+                .ok_or(anyhow!("Missing seccomp profile"))?
+                .parse::<SeccompProfile>()?;
+            if args.next().is_none_or(|arg| arg != "--") {
+                return Err(anyhow!("Missing program"));
+            }
+            let filter = profile
+                .compile()?
+                .ok_or(anyhow!("No seccomp filter for profile {profile:?}"))?;
+            let file = filter.to_file()?;
+            seccomp::pass_to_exec(&file).context("Unable to pass seccomp filter")?;
+            let program = args.next().ok_or(anyhow!("Missing program"))?;
+            let error = Command::new(&program).args(args).exec();
+            Err(anyhow::Error::new(error).context(format!("Unable to run {:?}", program)))
+        }
+        _ => Err(anyhow!("Unknown helper mode {:?}", mode)),
+    }
+}
+
+fn run_program(mut args: impl Iterator<Item = OsString>) -> Result<u8, anyhow::Error> {
+    let program = args.next().ok_or(anyhow!("Missing program"))?;
+    let status = Command::new(&program)
+        .args(args)
+        .status()
+        .context(format!("Unable to run {:?}", program))?;
+    Ok(exit_code(status))
+}
+
+/// Exit code of a process, with the shell convention of 128 + signal number for a killed process.
+pub fn exit_code(status: ExitStatus) -> u8 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code as u8,
+        (None, Some(signal)) => 128u8.wrapping_add(signal as u8),
+        (None, None) => 1,
+    }
+}
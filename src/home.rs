@@ -0,0 +1,295 @@
+//! # Home directories
+//!
+//! `home` contains private home directories for the sandbox, either thrown away after the run or
+//! kept per application in `~/.local/share/contained/homes`.
+
+use anyhow::{anyhow, Context};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fs, io};
+
+use crate::docker_client::{DockerClient, DockerError};
+use crate::runtime_dir::RuntimeDir;
+
+/// Where the home directory is mounted in the sandbox.
+pub const SANDBOX_HOME: &str = "/home/contained";
+
+/// XDG base directories, relative to the home directory.
+const XDG_DIRS: [(&str, &str); 4] = [
+    ("XDG_CONFIG_HOME", ".config"),
+    ("XDG_CACHE_HOME", ".cache"),
+    ("XDG_DATA_HOME", ".local/share"),
+    ("XDG_STATE_HOME", ".local/state"),
+];
+
+/// The kind of home directory of the sandbox.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HomeMode {
+    /// An empty home directory, removed after the run
+    Ephemeral,
+    /// A home directory kept between runs, named after the program unless a name is given
+    Persistent(Option<String>),
+}
+
+impl FromStr for HomeMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "ephemeral" => Ok(HomeMode::Ephemeral),
+            None if s == "persistent" => Ok(HomeMode::Persistent(None)),
+            Some(("persistent", name)) => {
+                check_name(name)?;
+                Ok(HomeMode::Persistent(Some(name.to_string())))
+            }
+            _ => Err(anyhow!(
+                "Invalid home '{s}', expected ephemeral or persistent[:NAME]"
+            )),
+        }
+    }
+}
+
+/// A home directory on the host, mounted at [`SANDBOX_HOME`].
+pub struct Home {
+    path: PathBuf,
+    name: Option<String>,
+    _dir: Option<RuntimeDir>,
+}
+
+impl Home {
+    pub fn new(mode: &HomeMode, program: &Path) -> Result<Self, anyhow::Error> {
+        match mode {
+            HomeMode::Ephemeral => {
+                let dir = RuntimeDir::new("home").context("Unable to create home directory")?;
+                Ok(Self {
+                    path: dir.path().to_path_buf(),
+                    name: None,
+                    _dir: Some(dir),
+                })
+            }
+            HomeMode::Persistent(name) => Self::persistent(name.as_deref(), program, &homes_dir()?),
+        }
+    }
+
+    /// An ephemeral home directory in a directory removed by its owner.
+    pub fn at(path: PathBuf) -> Self {
+        Self {
+            path,
+            name: None,
+            _dir: None,
+        }
+    }
+
+    fn persistent(name: Option<&str>, program: &Path, dir: &Path) -> Result<Self, anyhow::Error> {
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => {
+                let name = program
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or(anyhow!("Invalid program name {program:?}"))?
+                    .to_string();
+                check_name(&name)?;
+                name
+            }
+        };
+        let path = dir.join(&name);
+        fs::create_dir_all(&path)
+            .with_context(|| format!("Unable to create home directory {path:?}"))?;
+        Ok(Self {
+            path,
+            name: Some(name),
+            _dir: None,
+        })
+    }
+
+    /// The directory on the host.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Name of the Docker volume for a persistent home directory.
+    pub fn volume(&self) -> Option<String> {
+        self.name.as_deref().map(volume_name)
+    }
+
+    /// `HOME` and the XDG base directories in the sandbox.
+    pub fn env() -> Vec<(&'static str, String)> {
+        let mut env = vec![("HOME", SANDBOX_HOME.to_string())];
+        for (key, dir) in XDG_DIRS {
+            env.push((key, format!("{SANDBOX_HOME}/{dir}")));
+        }
+        env
+    }
+}
+
+fn check_name(name: &str) -> Result<(), anyhow::Error> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        return Err(anyhow!(
+            "Invalid home name '{name}', expected letters, digits, '.', '_' or '-'"
+        ));
+    }
+    Ok(())
+}
+
+/// Directory of the data kept by the tools, like home directories and caches.
+pub fn data_dir() -> Result<PathBuf, anyhow::Error> {
+    let data_home = match env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            PathBuf::from(env::var_os("HOME").ok_or(anyhow!("HOME not set"))?).join(".local/share")
+        }
+    };
+    Ok(data_home.join("contained"))
+}
+
+/// Directory of the configuration of the tools, like presets and profiles.
+pub fn config_dir() -> Result<PathBuf, anyhow::Error> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME").ok_or(anyhow!("HOME not set"))?).join(".config"),
+    };
+    Ok(config_home.join("contained"))
+}
+
+/// Directory of the persistent home directories.
+fn homes_dir() -> Result<PathBuf, anyhow::Error> {
+    Ok(data_dir()?.join("homes"))
+}
+
+/// Names of the directories in the directory, which might not exist yet.
+pub fn list_dirs(dir: &Path) -> Result<Vec<String>, anyhow::Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Unable to list {dir:?}")),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Path of the persistent home directory with the name, which might not exist yet.
+pub fn home_path(name: &str) -> Result<PathBuf, anyhow::Error> {
+    check_name(name)?;
+    Ok(homes_dir()?.join(name))
+}
+
+/// Names of the persistent home directories.
+pub fn list_homes() -> Result<Vec<String>, anyhow::Error> {
+    list_dirs(&homes_dir()?)
+}
+
+/// Removes the persistent home directory with the name, and its Docker volume.
+pub fn remove_home(name: &str) -> Result<(), anyhow::Error> {
+    remove_home_in(&homes_dir()?, name, remove_volume)
+}
+
+fn remove_home_in(
+    dir: &Path,
+    name: &str,
+    remove_volume: impl FnOnce(&str) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    check_name(name)?;
+    let path = dir.join(name);
+    if !path.is_dir() {
+        return Err(anyhow!("Home directory '{name}' not found"));
+    }
+    // First, the daemon refuses to remove a volume in use
+    remove_volume(&volume_name(name))?;
+    fs::remove_dir_all(&path).with_context(|| format!("Unable to remove {path:?}"))
+}
+
+fn volume_name(name: &str) -> String {
+    format!("contained-home-{name}")
+}
+
+/// Removes the Docker volume, if there is a daemon and it has the volume.
+fn remove_volume(volume: &str) -> Result<(), anyhow::Error> {
+    let Ok(client) = DockerClient::new() else {
+        return Ok(());
+    };
+    if !client.is_available() {
+        return Ok(());
+    }
+    match client.remove_volume(volume) {
+        Ok(()) | Err(DockerError::ErrorResponse(404, _)) => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Unable to remove Docker volume {volume}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_home() {
+        assert_eq!(
+            "ephemeral".parse::<HomeMode>().unwrap(),
+            HomeMode::Ephemeral
+        );
+        assert_eq!(
+            "persistent".parse::<HomeMode>().unwrap(),
+            HomeMode::Persistent(None)
+        );
+        assert_eq!(
+            "persistent:npm-2".parse::<HomeMode>().unwrap(),
+            HomeMode::Persistent(Some("npm-2".to_string()))
+        );
+        assert!("persistent:".parse::<HomeMode>().is_err());
+        assert!("persistent:../x".parse::<HomeMode>().is_err());
+        assert!("persistent:.hidden".parse::<HomeMode>().is_err());
+        assert!("ephemeral:x".parse::<HomeMode>().is_err());
+        assert!("temporary".parse::<HomeMode>().is_err());
+    }
+
+    #[test]
+    fn test_persistent_homes() -> Result<(), anyhow::Error> {
+        let data = RuntimeDir::new("home-test")?;
+        let dir = data.path();
+        let npm = Path::new("/usr/bin/npm");
+
+        assert!(list_dirs(dir)?.is_empty());
+        let home = Home::persistent(None, npm, dir)?;
+        assert_eq!(home.path(), dir.join("npm"));
+        assert_eq!(home.volume().as_deref(), Some("contained-home-npm"));
+        Home::persistent(Some("other"), npm, dir)?;
+        assert_eq!(list_dirs(dir)?, ["npm", "other"]);
+        assert!(Home::persistent(None, Path::new("/bin/.x"), dir).is_err());
+
+        let mut removed = Vec::new();
+        remove_home_in(dir, "npm", |volume| {
+            removed.push(volume.to_string());
+            Ok(())
+        })?;
+        assert_eq!(list_dirs(dir)?, ["other"]);
+        assert_eq!(removed, ["contained-home-npm"]);
+        // Kept if the volume is in use
+        let in_use = || Err(anyhow!("volume is in use"));
+        assert!(remove_home_in(dir, "other", |_| in_use()).is_err());
+        assert_eq!(list_dirs(dir)?, ["other"]);
+        assert!(remove_home_in(dir, "npm", |_| Ok(())).is_err());
+        assert!(remove_home_in(dir, "..", |_| Ok(())).is_err());
+
+        let ephemeral = Home::new(&HomeMode::Ephemeral, npm)?;
+        let path = ephemeral.path().to_path_buf();
+        assert!(path.is_dir());
+        assert!(ephemeral.volume().is_none());
+        drop(ephemeral);
+        assert!(!path.exists());
+        Ok(())
+    }
+}
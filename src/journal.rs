@@ -0,0 +1,479 @@
+//! # Change journal
+//!
+//! `journal` contains snapshots of the metadata of writable mounts, to report which paths the
+//! sandbox created, modified, deleted or changed permissions of, and to fail on unexpected writes.
+
+use anyhow::{anyhow, Context};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Metadata of a path in a snapshot.
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    size: u64,
+    mtime_ns: i128,
+    inode: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    /// SHA-256 of the content of files, or of the target of symlinks
+    hash: Option<[u8; 32]>,
+}
+
+impl Entry {
+    fn new(path: &Path, metadata: &fs::Metadata, hash: bool) -> io::Result<Self> {
+        let hash = if !hash || metadata.is_dir() {
+            None
+        } else if metadata.is_symlink() {
+            Some(Sha256::digest(fs::read_link(path)?.as_os_str().as_encoded_bytes()).into())
+        } else if metadata.is_file() {
+            Some(hash_file(path)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            size: metadata.size(),
+            mtime_ns: metadata.mtime() as i128 * 1_000_000_000 + metadata.mtime_nsec() as i128,
+            inode: metadata.ino(),
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            hash,
+        })
+    }
+
+    fn content_changed(&self, other: &Entry) -> bool {
+        let file_type = |mode: u32| mode & libc::S_IFMT;
+        if file_type(self.mode) != file_type(other.mode) {
+            return true;
+        }
+        if file_type(self.mode) == libc::S_IFDIR {
+            // Directories change with their entries, which are reported themselves
+            return false;
+        }
+        match (self.hash, other.hash) {
+            (Some(hash), Some(other_hash)) => hash != other_hash,
+            _ => {
+                self.size != other.size
+                    || self.mtime_ns != other.mtime_ns
+                    || self.inode != other.inode
+            }
+        }
+    }
+
+    fn permissions_changed(&self, other: &Entry) -> bool {
+        self.mode != other.mode || self.uid != other.uid || self.gid != other.gid
+    }
+}
+
+/// The metadata of all paths below some directories.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the paths below the roots, which may also be files.
+    pub fn take(roots: &[PathBuf], hash: bool) -> io::Result<Self> {
+        let mut snapshot = Self::default();
+        for root in roots {
+            let metadata = match fs::metadata(root) {
+                Ok(metadata) => metadata,
+                // A file mount removed in the meantime
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if metadata.is_dir() {
+                snapshot.add_dir(root, hash)?;
+            } else {
+                snapshot
+                    .entries
+                    .insert(root.clone(), Entry::new(root, &metadata, hash)?);
+            }
+        }
+        Ok(snapshot)
+    }
+
+    fn add_dir(&mut self, dir: &Path, hash: bool) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let metadata = match path.symlink_metadata() {
+                Ok(metadata) => metadata,
+                // Removed in the meantime
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            self.entries
+                .insert(path.clone(), Entry::new(&path, &metadata, hash)?);
+            if metadata.is_dir() {
+                self.add_dir(&path, hash)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Paths changed between two snapshots.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Journal {
+    pub created: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+    pub permissions_changed: Vec<PathBuf>,
+    hashes: BTreeMap<PathBuf, [u8; 32]>,
+    sizes: BTreeMap<PathBuf, u64>,
+}
+
+impl Journal {
+    pub fn compare(before: &Snapshot, after: &Snapshot) -> Self {
+        let mut journal = Self::default();
+        for (path, entry) in &after.entries {
+            match before.entries.get(path) {
+                None => journal.created.push(path.clone()),
+                Some(old) => {
+                    if old.content_changed(entry) {
+                        journal.modified.push(path.clone());
+                    }
+                    if old.permissions_changed(entry) {
+                        journal.permissions_changed.push(path.clone());
+                    }
+                }
+            }
+            journal.sizes.insert(path.clone(), entry.size);
+            if let Some(hash) = entry.hash {
+                journal.hashes.insert(path.clone(), hash);
+            }
+        }
+        journal.deleted = before
+            .entries
+            .keys()
+            .filter(|path| !after.entries.contains_key(*path))
+            .cloned()
+            .collect();
+        journal
+    }
+
+    /// All changed paths, sorted and without duplicates.
+    pub fn changed_paths(&self) -> Vec<&Path> {
+        let mut paths = self
+            .created
+            .iter()
+            .chain(&self.modified)
+            .chain(&self.deleted)
+            .chain(&self.permissions_changed)
+            .map(PathBuf::as_path)
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Changed paths matching any of the patterns, relative to the given roots.
+    pub fn violations(&self, roots: &[PathBuf], patterns: &[String]) -> Vec<PathBuf> {
+        self.changed_paths()
+            .into_iter()
+            .filter(|path| {
+                roots
+                    .iter()
+                    .filter_map(|root| match path.strip_prefix(root) {
+                        // A file mount matches by its name
+                        Ok(relative) if relative.as_os_str().is_empty() => {
+                            root.file_name().map(Path::new)
+                        }
+                        relative => relative.ok(),
+                    })
+                    .any(|relative| {
+                        patterns
+                            .iter()
+                            .any(|pattern| path_matches(pattern, relative))
+                    })
+            })
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    pub fn to_json(&self, violations: &[PathBuf]) -> Value {
+        let entries = |paths: &[PathBuf], existing: bool| {
+            paths
+                .iter()
+                .map(|path| {
+                    let mut entry = json!({ "path": path.to_string_lossy() });
+                    if existing {
+                        entry["size"] = json!(self.sizes.get(path));
+                        if let Some(hash) = self.hashes.get(path) {
+                            entry["sha256"] = json!(hex(hash));
+                        }
+                    }
+                    entry
+                })
+                .collect::<Vec<_>>()
+        };
+        json!({
+            "created": entries(&self.created, true),
+            "modified": entries(&self.modified, true),
+            "deleted": entries(&self.deleted, false),
+            "permissions_changed": entries(&self.permissions_changed, true),
+            "violations": violations.iter().map(|path| path.to_string_lossy()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// A journal of the writable mounts during a run.
+pub struct JournalRun {
+    roots: Vec<PathBuf>,
+    before: Snapshot,
+    hash: bool,
+    output: Option<PathBuf>,
+    fail_on_write: Vec<String>,
+}
+
+impl JournalRun {
+    /// Takes the snapshot before the run, `None` if neither a journal nor checks are requested.
+    pub fn start(
+        roots: Vec<PathBuf>,
+        output: Option<&Path>,
+        hash: bool,
+        fail_on_write: &[String],
+    ) -> Result<Option<Self>, anyhow::Error> {
+        if output.is_none() && fail_on_write.is_empty() {
+            return Ok(None);
+        }
+        let before = Snapshot::take(&roots, hash).context("Unable to snapshot writable mounts")?;
+        Ok(Some(Self {
+            roots,
+            before,
+            hash,
+            output: output.map(Path::to_path_buf),
+            fail_on_write: fail_on_write.to_vec(),
+        }))
+    }
+
+    /// Writes the journal and checks for unexpected writes after the run. Returns the exit
+    /// code, which is non-zero if there were unexpected writes.
+    pub fn finish(self, exit_code: u8) -> Result<u8, anyhow::Error> {
+        let after =
+            Snapshot::take(&self.roots, self.hash).context("Unable to snapshot writable mounts")?;
+        let journal = Journal::compare(&self.before, &after);
+        let violations = journal.violations(&self.roots, &self.fail_on_write);
+
+        if let Some(output) = &self.output {
+            let json = serde_json::to_string_pretty(&journal.to_json(&violations))?;
+            fs::write(output, json + "\n")
+                .with_context(|| format!("Unable to write journal {output:?}"))?;
+        }
+        if violations.is_empty() {
+            return Ok(exit_code);
+        }
+        for path in &violations {
+            eprintln!("contained: unexpected write to {}", path.display());
+        }
+        Ok(if exit_code == 0 { 1 } else { exit_code })
+    }
+}
+
+/// Checks a pattern for `--fail-on-write`. Like in `.gitignore`, a pattern without `/` matches
+/// a name at any level, a pattern with `/` matches from the root of the mount, and a trailing
+/// `/` matches directories only. Matching a directory includes everything inside.
+/// `*` and `?` don't match `/`, `**` matches anything.
+pub fn path_matches(pattern: &str, path: &Path) -> bool {
+    let (pattern, dir_only) = match pattern.strip_suffix('/') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+    let anchored = pattern.contains('/');
+    let path = path.to_string_lossy();
+
+    // The path itself, and all its parent directories
+    let mut candidates = path
+        .match_indices('/')
+        .map(|(i, _)| &path[..i])
+        .collect::<Vec<_>>();
+    if !dir_only {
+        candidates.push(&path);
+    }
+    candidates.into_iter().any(|candidate| {
+        let candidate = if anchored {
+            candidate
+        } else {
+            candidate.rsplit('/').next().unwrap_or(candidate)
+        };
+        glob_matches(pattern.as_bytes(), candidate.as_bytes())
+    })
+}
+
+/// Matches a glob pattern with `*`, `**` and `?`.
+pub(crate) fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            let rest = rest.strip_prefix(b"/").unwrap_or(rest);
+            (0..=text.len()).any(|i| glob_matches(rest, &text[i..]))
+        }
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|i| *i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_matches(rest, &text[i..])),
+        [b'?', rest @ ..] => {
+            matches!(text, [c, ..] if *c != b'/') && glob_matches(rest, &text[1..])
+        }
+        [c, rest @ ..] => matches!(text, [t, ..] if t == c) && glob_matches(rest, &text[1..]),
+    }
+}
+
+/// Parses a pattern for `--fail-on-write`.
+pub fn parse_write_pattern(s: &str) -> Result<String, anyhow::Error> {
+    if s.is_empty() || s == "/" {
+        return Err(anyhow!("Empty pattern"));
+    }
+    Ok(s.to_string())
+}
+
+fn hash_file(path: &Path) -> io::Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut sha = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            return Ok(sha.finalize().into());
+        }
+        sha.update(&buffer[..bytes_read]);
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime_dir::RuntimeDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn relative<'a>(root: &Path, paths: &'a [PathBuf]) -> Vec<&'a str> {
+        paths
+            .iter()
+            .map(|path| path.strip_prefix(root).unwrap().to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_path_matches() {
+        let matches = |pattern: &str, path: &str| path_matches(pattern, Path::new(path));
+
+        assert!(matches("package-lock.json", "package-lock.json"));
+        assert!(matches("package-lock.json", "web/package-lock.json"));
+        assert!(!matches("package-lock.json", "package.json"));
+        assert!(matches(".github/", ".github/workflows/ci.yml"));
+        assert!(!matches(".github/", ".github"));
+        assert!(matches(".github", ".github"));
+        assert!(matches("*.lock", "Cargo.lock"));
+        assert!(matches("*.lock", "sub/Cargo.lock"));
+        assert!(matches("/src/*.rs", "src/main.rs"));
+        assert!(!matches("/src/*.rs", "src/bin/main.rs"));
+        assert!(!matches("src/*.rs", "lib/src/main.rs"));
+        assert!(matches("src/**/*.rs", "src/bin/main.rs"));
+        assert!(matches("src/**/*.rs", "src/main.rs"));
+        assert!(matches("**/*.rs", "src/bin/main.rs"));
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?.txt", "ab.txt"));
+    }
+
+    #[test]
+    fn test_compare() -> Result<(), anyhow::Error> {
+        let dir = RuntimeDir::new("journal-test")?;
+        let root = dir.path();
+        fs::create_dir(root.join("dir"))?;
+        fs::write(root.join("modified"), "old\n")?;
+        fs::write(root.join("same-size"), "old\n")?;
+        fs::write(root.join("unchanged"), "same\n")?;
+        fs::write(root.join("dir/deleted"), "gone\n")?;
+        fs::write(root.join("chmod"), "mode\n")?;
+        let roots = [root.to_path_buf()];
+        let before = Snapshot::take(&roots, false)?;
+        let before_hashed = Snapshot::take(&roots, true)?;
+
+        fs::write(root.join("modified"), "new content\n")?;
+        let modified = fs::metadata(root.join("same-size"))?.modified()?;
+        fs::write(root.join("same-size"), "new\n")?;
+        File::options()
+            .write(true)
+            .open(root.join("same-size"))?
+            .set_modified(modified)?;
+        fs::remove_file(root.join("dir/deleted"))?;
+        fs::set_permissions(root.join("chmod"), fs::Permissions::from_mode(0o600))?;
+        fs::create_dir(root.join("created"))?;
+        fs::write(root.join("created/file"), "new\n")?;
+
+        let journal = Journal::compare(&before, &Snapshot::take(&roots, false)?);
+        assert_eq!(
+            relative(root, &journal.created),
+            ["created", "created/file"]
+        );
+        assert_eq!(relative(root, &journal.modified), ["modified"]);
+        assert_eq!(relative(root, &journal.deleted), ["dir/deleted"]);
+        assert_eq!(relative(root, &journal.permissions_changed), ["chmod"]);
+
+        // Only the content shows the change without a different size or modification time
+        let journal = Journal::compare(&before_hashed, &Snapshot::take(&roots, true)?);
+        assert_eq!(relative(root, &journal.modified), ["modified", "same-size"]);
+        let json = journal.to_json(&[]);
+        assert_eq!(
+            json["created"][1]["sha256"],
+            "7aa7a5359173d05b63cfd682e3c38487f3cb4f7f1d60659fe59fab1505977d4c"
+        );
+        assert_eq!(json["created"][1]["size"], 4);
+        assert_eq!(
+            json["deleted"][0]["path"],
+            root.join("dir/deleted").to_str().unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fail_on_write() -> Result<(), anyhow::Error> {
+        let dir = RuntimeDir::new("journal-test")?;
+        let root = dir.path().to_path_buf();
+        fs::create_dir(root.join(".github"))?;
+        let output = root.join(".github/journal.json");
+        let patterns = ["package-lock.json".to_string(), ".github/".to_string()];
+
+        let run = JournalRun::start(vec![root.clone()], None, false, &patterns)?.unwrap();
+        fs::write(root.join("index.js"), "")?;
+        assert_eq!(run.finish(0)?, 0);
+
+        let run = JournalRun::start(vec![root.clone()], Some(&output), false, &patterns)?;
+        fs::write(root.join("package-lock.json"), "{}")?;
+        assert_eq!(run.unwrap().finish(0)?, 1);
+        let journal: Value = serde_json::from_slice(&fs::read(&output)?)?;
+        assert_eq!(
+            journal["violations"],
+            json!([root.join("package-lock.json").to_str().unwrap()])
+        );
+
+        let run = JournalRun::start(vec![root.clone()], None, false, &patterns)?.unwrap();
+        fs::write(root.join(".github/workflow.yml"), "")?;
+        assert_eq!(run.finish(3)?, 3);
+
+        // A writable mount may be a file
+        let file = root.join("index.js");
+        let run = JournalRun::start(vec![file.clone()], None, true, &patterns)?.unwrap();
+        fs::write(&file, "changed")?;
+        assert_eq!(run.finish(0)?, 0);
+        let run = JournalRun::start(vec![file.clone()], None, true, &["index.js".to_string()])?;
+        fs::write(&file, "changed again")?;
+        assert_eq!(run.unwrap().finish(0)?, 1);
+
+        assert!(JournalRun::start(vec![root], None, false, &[])?.is_none());
+        Ok(())
+    }
+}
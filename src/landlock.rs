@@ -0,0 +1,244 @@
+//! # Landlock
+//!
+//! `landlock` contains a minimal interface to the [Landlock](https://docs.kernel.org/userspace-api/landlock.html)
+//! LSM, to restrict file system and network access of the current process without external binaries.
+
+use anyhow::{anyhow, Context};
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+const CREATE_RULESET_VERSION: libc::c_uint = 1;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+const ACCESS_NET_BIND_TCP: u64 = 1 << 0;
+const ACCESS_NET_CONNECT_TCP: u64 = 1 << 1;
+
+/// The first ABI version able to restrict TCP bind and connect.
+pub const NETWORK_ABI: i32 = 4;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+    handled_access_net: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// The Landlock ABI version supported by the kernel, 0 if Landlock is not available.
+pub fn abi_version() -> i32 {
+    // SAFETY: querying the version does not dereference the attribute pointer
+    let version = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    version.max(0) as i32
+}
+
+/// All file system access rights known to the given ABI version.
+fn fs_access(abi: i32) -> u64 {
+    let mut access = (1 << 13) - 1;
+    if abi >= 2 {
+        access |= 1 << 13; // REFER
+    }
+    if abi >= 3 {
+        access |= ACCESS_FS_TRUNCATE;
+    }
+    if abi >= 5 {
+        access |= ACCESS_FS_IOCTL_DEV;
+    }
+    access
+}
+
+/// A Landlock ruleset, denying all file system access (and TCP if requested)
+/// except what is allowed by rules.
+pub struct Ruleset {
+    fd: OwnedFd,
+    handled_access_fs: u64,
+}
+
+impl Ruleset {
+    /// Creates a ruleset, refusing if the kernel can't enforce what is requested.
+    pub fn new(restrict_network: bool) -> Result<Self, anyhow::Error> {
+        let abi = abi_version();
+        if abi < 1 {
+            return Err(anyhow!("Landlock is not supported or not enabled by the kernel"));
+        }
+        if restrict_network && abi < NETWORK_ABI {
+            return Err(anyhow!(
+                "Landlock ABI {abi} can't restrict network access, ABI {NETWORK_ABI} (Linux 6.7) is required"
+            ));
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: fs_access(abi),
+            handled_access_net: if restrict_network {
+                ACCESS_NET_BIND_TCP | ACCESS_NET_CONNECT_TCP
+            } else {
+                0
+            },
+        };
+        let size = if abi >= NETWORK_ABI {
+            size_of::<RulesetAttr>()
+        } else {
+            size_of::<u64>()
+        };
+        // SAFETY: the attribute is valid for the given size
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                size,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("Unable to create Landlock ruleset");
+        }
+        Ok(Self {
+            // SAFETY: the file descriptor was just created and is owned by nothing else
+            fd: unsafe { OwnedFd::from_raw_fd(fd as i32) },
+            handled_access_fs: attr.handled_access_fs,
+        })
+    }
+
+    /// Allows access to a file or to everything beneath a directory.
+    pub fn allow(&mut self, path: &Path, access: Access) -> Result<(), anyhow::Error> {
+        let file = open_path(path).with_context(|| format!("Unable to open {path:?}"))?;
+        let read = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+        let mut allowed_access = match access {
+            Access::ReadOnly => read,
+            Access::ReadWrite => self.handled_access_fs,
+        };
+        if !file.metadata()?.is_dir() {
+            // Only some access rights apply to files
+            allowed_access &= ACCESS_FS_EXECUTE
+                | ACCESS_FS_WRITE_FILE
+                | ACCESS_FS_READ_FILE
+                | ACCESS_FS_TRUNCATE
+                | ACCESS_FS_IOCTL_DEV;
+        }
+
+        let attr = PathBeneathAttr {
+            allowed_access: allowed_access & self.handled_access_fs,
+            parent_fd: file.as_raw_fd(),
+        };
+        // SAFETY: the attribute matches the rule type
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                self.fd.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Unable to add Landlock rule for {path:?}"));
+        }
+        Ok(())
+    }
+
+    /// Restricts the calling thread, and everything it executes, to the ruleset.
+    /// Only calls async-signal-safe functions, so it can be used in `pre_exec`.
+    pub fn restrict_self(&self) -> io::Result<()> {
+        // SAFETY: plain system calls without pointers
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::syscall(libc::SYS_landlock_restrict_self, self.fd.as_raw_fd(), 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open_path(path: &Path) -> io::Result<File> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: the path is a valid C string
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor was just opened and is owned by nothing else
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    use crate::runtime_dir::RuntimeDir;
+
+    #[test]
+    fn test_ruleset() -> Result<(), anyhow::Error> {
+        if abi_version() < NETWORK_ABI {
+            eprintln!("Landlock ABI {NETWORK_ABI} not available, skipping");
+            return Ok(());
+        }
+        let readonly = RuntimeDir::new("landlock-ro")?;
+        let writable = RuntimeDir::new("landlock-rw")?;
+        fs::write(readonly.path().join("file"), "content")?;
+
+        let mut ruleset = Ruleset::new(true)?;
+        ruleset.allow(Path::new("/usr"), Access::ReadOnly)?;
+        ruleset.allow(Path::new("/etc"), Access::ReadOnly)?;
+        for path in ["/bin", "/lib", "/lib64"] {
+            if Path::new(path).exists() {
+                ruleset.allow(Path::new(path), Access::ReadOnly)?;
+            }
+        }
+        ruleset.allow(Path::new("/dev/null"), Access::ReadWrite)?;
+        ruleset.allow(readonly.path(), Access::ReadOnly)?;
+        ruleset.allow(writable.path(), Access::ReadWrite)?;
+
+        let script = format!(
+            "cat {ro}/file && echo ok > {rw}/file && ! echo no > {ro}/other 2>/dev/null",
+            ro = readonly.path().display(),
+            rw = writable.path().display()
+        );
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(script);
+        // SAFETY: restrict_self only calls async-signal-safe functions
+        unsafe {
+            cmd.pre_exec(move || ruleset.restrict_self());
+        }
+        let output = cmd.output()?;
+
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "content");
+        assert_eq!(fs::read_to_string(writable.path().join("file"))?, "ok\n");
+        assert!(!readonly.path().join("other").exists());
+        Ok(())
+    }
+}
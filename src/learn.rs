@@ -0,0 +1,500 @@
+//! # Learning mode
+//!
+//! `learn` runs a program in a permissive Landlock sandbox, which can read everything but only
+//! write to the current directory and `/tmp`, and traces it with `ptrace`. The files it opens, the
+//! programs it executes and the sockets it connects to are turned into a [`Profile`] for
+//! `--profile`.
+
+use anyhow::{anyhow, Context};
+use std::collections::{BTreeMap, HashSet};
+use std::env::current_dir;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::{env, fs, io, mem};
+
+use crate::dependencies::program_dependencies;
+use crate::landlock::{Access, Ruleset};
+use crate::profile::{profiles_dir, Profile};
+use crate::{resolve_program, LANDLOCK_DEVICES};
+
+/// Longest path read from the traced program.
+const PATH_MAX: usize = libc::PATH_MAX as usize;
+
+/// Runs the program traced and writes the proposed profile to the output, or to the profiles
+/// directory under the name of the program.
+pub fn learn(
+    program: &Path,
+    arguments: &[String],
+    output: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    let program = resolve_program(program)?;
+    let home = PathBuf::from(env::var_os("HOME").ok_or(anyhow!("HOME not set"))?);
+    let current_dir = current_dir()?;
+    if home.starts_with(&current_dir) {
+        return Err(anyhow!(
+            "Cannot run from home directory or its parent directories"
+        ));
+    }
+
+    let mut ruleset = Ruleset::new(false)?;
+    ruleset.allow(Path::new("/"), Access::ReadOnly)?;
+    ruleset.allow(&current_dir, Access::ReadWrite)?;
+    ruleset.allow(Path::new("/tmp"), Access::ReadWrite)?;
+    for path in LANDLOCK_DEVICES {
+        if Path::new(path).exists() {
+            ruleset.allow(Path::new(path), Access::ReadWrite)?;
+        }
+    }
+
+    eprintln!(
+        "contained: learning {program:?}, writes outside the current directory and /tmp are \
+         recorded but denied"
+    );
+    let mut command = Command::new(&program);
+    command.args(arguments);
+    let trace = trace(command, ruleset)?;
+    if trace.exit_code != 0 {
+        eprintln!(
+            "contained: the program exited with status {}, the profile might be incomplete",
+            trace.exit_code
+        );
+    }
+
+    // Mounted for the program anyway
+    let mut mounted = vec![program
+        .parent()
+        .ok_or(anyhow!("Invalid path"))?
+        .to_path_buf()];
+    mounted.extend(
+        program_dependencies(&program)
+            .into_iter()
+            .map(|dependency| dependency.dir),
+    );
+    let profile = Profile::learned(
+        &program,
+        &trace.paths,
+        trace.network,
+        &home,
+        &current_dir,
+        &mounted,
+    );
+
+    let name = program
+        .file_name()
+        .ok_or(anyhow!("Invalid path"))?
+        .to_string_lossy()
+        .into_owned();
+    let (path, argument) = match output {
+        Some(output) => (output.to_path_buf(), output.display().to_string()),
+        None => (profiles_dir()?.join(format!("{name}.json")), name),
+    };
+    profile.write(&path)?;
+    eprintln!(
+        "contained: wrote the profile to {path:?}, review it and run with --profile {argument}"
+    );
+    Ok(())
+}
+
+/// What a traced program did.
+#[derive(Debug, Default)]
+pub struct Trace {
+    /// Existing paths accessed, directories are writable if entries were created or removed
+    pub paths: BTreeMap<PathBuf, Access>,
+    /// Whether the program connected to an IP address
+    pub network: bool,
+    /// Exit code of the program, 128 + signal number if it was killed
+    pub exit_code: u8,
+}
+
+/// How a system call uses a path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Use {
+    Read,
+    Write,
+    /// Writes the file, creating it if missing
+    Create,
+    /// Creates, removes or renames the entry in its directory
+    Entry,
+}
+
+/// Runs the command restricted by the ruleset, tracing it and all its descendants until they
+/// exit.
+pub fn trace(mut command: Command, ruleset: Ruleset) -> Result<Trace, anyhow::Error> {
+    // SAFETY: only async-signal-safe system calls are made
+    unsafe {
+        command.pre_exec(move || {
+            if libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            ruleset.restrict_self()
+        });
+    }
+    // Stops with SIGTRAP once the program is executed
+    let child = command.spawn().context("Unable to run the program")?;
+    Tracer::default().run(child.id() as libc::pid_t)
+}
+
+#[derive(Default)]
+struct Tracer {
+    trace: Trace,
+    /// Processes which reported their first stop
+    attached: HashSet<libc::pid_t>,
+}
+
+impl Tracer {
+    fn run(mut self, main: libc::pid_t) -> Result<Trace, anyhow::Error> {
+        let mut exit_code = None;
+        loop {
+            let mut status = 0;
+            // SAFETY: the status is a valid pointer
+            let pid = unsafe { libc::waitpid(-1, &mut status, libc::__WALL) };
+            if pid < 0 {
+                let error = io::Error::last_os_error();
+                match error.raw_os_error() {
+                    Some(libc::ECHILD) => break,
+                    Some(libc::EINTR) => continue,
+                    _ => return Err(error).context("Unable to wait for the program"),
+                }
+            }
+            if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+                if pid == main {
+                    exit_code = Some(if libc::WIFEXITED(status) {
+                        libc::WEXITSTATUS(status) as u8
+                    } else {
+                        128u8.wrapping_add(libc::WTERMSIG(status) as u8)
+                    });
+                }
+                continue;
+            }
+            if !libc::WIFSTOPPED(status) {
+                continue;
+            }
+
+            let signal = libc::WSTOPSIG(status);
+            let inject = if signal == libc::SIGTRAP | 0x80 {
+                self.syscall_stop(pid);
+                0
+            } else if signal == libc::SIGTRAP && status >> 16 != 0 {
+                // Fork, clone or exec event
+                0
+            } else if self.attached.insert(pid) {
+                // The exec of the program or the start of a new process, which are traced too
+                if pid == main {
+                    set_options(pid)?;
+                }
+                0
+            } else if is_group_stop(pid) {
+                0
+            } else {
+                signal
+            };
+            // SAFETY: plain system call, the process might have been killed in the meantime
+            unsafe { libc::ptrace(libc::PTRACE_SYSCALL, pid, 0, inject) };
+        }
+        self.trace.exit_code = exit_code.ok_or(anyhow!("The program was not run"))?;
+        Ok(self.trace)
+    }
+
+    fn syscall_stop(&mut self, pid: libc::pid_t) {
+        // SAFETY: the structure is plain data
+        let mut info: libc::ptrace_syscall_info = unsafe { mem::zeroed() };
+        // SAFETY: the kernel writes at most the given size
+        let size = unsafe {
+            libc::ptrace(
+                libc::PTRACE_GET_SYSCALL_INFO,
+                pid,
+                mem::size_of_val(&info),
+                &mut info as *mut libc::ptrace_syscall_info,
+            )
+        };
+        if size < 0 || info.op != libc::PTRACE_SYSCALL_INFO_ENTRY {
+            return;
+        }
+        // SAFETY: the entry is set for a syscall entry stop
+        let (nr, args) = unsafe { (info.u.entry.nr as libc::c_long, info.u.entry.args) };
+        self.syscall(pid, nr, args);
+    }
+
+    /// Records the paths used by the system call at its entry.
+    fn syscall(&mut self, pid: libc::pid_t, nr: libc::c_long, args: [u64; 6]) {
+        const AT_FDCWD: u64 = libc::AT_FDCWD as u64;
+        match nr {
+            libc::SYS_openat => self.open(pid, args[0], args[1], args[2]),
+            libc::SYS_openat2 => {
+                // The flags are the first field of `struct open_how`
+                let mut flags = [0; 8];
+                if read_memory(pid, args[2], &mut flags) == flags.len() {
+                    self.open(pid, args[0], args[1], u64::from_ne_bytes(flags));
+                }
+            }
+            libc::SYS_execve => self.path(pid, AT_FDCWD, args[0], Use::Read),
+            libc::SYS_execveat => self.path(pid, args[0], args[1], Use::Read),
+            libc::SYS_mkdirat | libc::SYS_unlinkat => self.path(pid, args[0], args[1], Use::Entry),
+            libc::SYS_symlinkat => self.path(pid, args[1], args[2], Use::Entry),
+            libc::SYS_linkat => self.path(pid, args[2], args[3], Use::Entry),
+            libc::SYS_truncate => self.path(pid, AT_FDCWD, args[0], Use::Write),
+            libc::SYS_renameat | libc::SYS_renameat2 => {
+                self.path(pid, args[0], args[1], Use::Entry);
+                self.path(pid, args[2], args[3], Use::Entry);
+            }
+            libc::SYS_connect => self.connect(pid, args[1], args[2] as usize),
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_open => self.open(pid, AT_FDCWD, args[0], args[1]),
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_creat => self.path(pid, AT_FDCWD, args[0], Use::Create),
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_mkdir | libc::SYS_rmdir | libc::SYS_unlink => {
+                self.path(pid, AT_FDCWD, args[0], Use::Entry)
+            }
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_symlink | libc::SYS_link => self.path(pid, AT_FDCWD, args[1], Use::Entry),
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_rename => {
+                self.path(pid, AT_FDCWD, args[0], Use::Entry);
+                self.path(pid, AT_FDCWD, args[1], Use::Entry);
+            }
+            _ => {}
+        }
+    }
+
+    fn open(&mut self, pid: libc::pid_t, dirfd: u64, path: u64, flags: u64) {
+        let flags = flags as libc::c_int;
+        let path_use = if flags & libc::O_TMPFILE == libc::O_TMPFILE {
+            // Creates an unnamed file in the directory
+            Use::Write
+        } else if flags & libc::O_CREAT != 0 {
+            Use::Create
+        } else if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            Use::Write
+        } else {
+            Use::Read
+        };
+        self.path(pid, dirfd, path, path_use);
+    }
+
+    fn path(&mut self, pid: libc::pid_t, dirfd: u64, path: u64, path_use: Use) {
+        let Some(path) = read_path(pid, path) else {
+            return;
+        };
+        let Some(path) = resolve(pid, dirfd as libc::c_int, path) else {
+            return;
+        };
+        match path_use {
+            Use::Read if path.exists() => self.record(&path, Access::ReadOnly),
+            Use::Write | Use::Create if path.exists() => self.record(&path, Access::ReadWrite),
+            Use::Create | Use::Entry => {
+                if let Some(dir) = path.parent().filter(|dir| dir.exists()) {
+                    self.record(dir, Access::ReadWrite);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn connect(&mut self, pid: libc::pid_t, address: u64, len: usize) {
+        let mut buf = [0; mem::size_of::<libc::sockaddr_un>()];
+        let len = len.min(buf.len());
+        let len = read_memory(pid, address, &mut buf[..len]);
+        if len < 2 {
+            return;
+        }
+        match u16::from_ne_bytes([buf[0], buf[1]]) as libc::c_int {
+            libc::AF_INET | libc::AF_INET6 => self.trace.network = true,
+            libc::AF_UNIX => {
+                // Abstract sockets start with a NUL byte and have no path
+                let path = &buf[2..len];
+                let end = path.iter().position(|&b| b == 0).unwrap_or(path.len());
+                if end > 0 {
+                    let path = OsString::from_vec(path[..end].to_vec());
+                    if let Some(path) = resolve(pid, libc::AT_FDCWD, path) {
+                        if path.exists() {
+                            self.record(&path, Access::ReadOnly);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Records the path, and where it points to if symlinks are involved.
+    fn record(&mut self, path: &Path, access: Access) {
+        let canonical = fs::canonicalize(path).ok();
+        for path in [Some(normalize(path)), canonical].into_iter().flatten() {
+            let entry = self.trace.paths.entry(path).or_insert(access);
+            if access == Access::ReadWrite {
+                *entry = Access::ReadWrite;
+            }
+        }
+    }
+}
+
+fn set_options(pid: libc::pid_t) -> Result<(), anyhow::Error> {
+    let options = libc::PTRACE_O_TRACESYSGOOD
+        | libc::PTRACE_O_TRACEFORK
+        | libc::PTRACE_O_TRACEVFORK
+        | libc::PTRACE_O_TRACECLONE
+        | libc::PTRACE_O_TRACEEXEC
+        | libc::PTRACE_O_EXITKILL;
+    // SAFETY: plain system call
+    if unsafe { libc::ptrace(libc::PTRACE_SETOPTIONS, pid, 0, options) } != 0 {
+        return Err(io::Error::last_os_error()).context("Unable to trace the program");
+    }
+    Ok(())
+}
+
+/// Whether the stop is a group-stop, which can't be told from a signal-delivery-stop by the
+/// signal alone. The signal must not be injected on restart.
+fn is_group_stop(pid: libc::pid_t) -> bool {
+    // SAFETY: the structure is plain data
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+    // SAFETY: the kernel writes a `siginfo_t`
+    unsafe {
+        libc::ptrace(
+            libc::PTRACE_GETSIGINFO,
+            pid,
+            0,
+            &mut info as *mut libc::siginfo_t,
+        ) < 0
+    }
+}
+
+/// Reads memory of the traced process into the buffer, returns the number of bytes read.
+fn read_memory(pid: libc::pid_t, address: u64, buf: &mut [u8]) -> usize {
+    let local = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let remote = libc::iovec {
+        iov_base: address as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // SAFETY: the local buffer is valid for its length, remote memory is checked by the kernel
+    let read = unsafe { libc::process_vm_readv(pid, &local, 1, &remote, 1, 0) };
+    read.max(0) as usize
+}
+
+/// Reads a NUL terminated path from the memory of the traced process.
+fn read_path(pid: libc::pid_t, mut address: u64) -> Option<OsString> {
+    let mut path = Vec::new();
+    while path.len() < PATH_MAX {
+        // Chunks don't cross page boundaries, the next page might not be mapped
+        let mut chunk = [0; 256];
+        let len = chunk.len() - (address % chunk.len() as u64) as usize;
+        let read = read_memory(pid, address, &mut chunk[..len]);
+        if read == 0 {
+            return None;
+        }
+        if let Some(end) = chunk[..read].iter().position(|&b| b == 0) {
+            path.extend_from_slice(&chunk[..end]);
+            return (!path.is_empty()).then(|| OsString::from_vec(path));
+        }
+        path.extend_from_slice(&chunk[..read]);
+        address += read as u64;
+    }
+    None
+}
+
+/// Resolves a path relative to a directory file descriptor of the traced process.
+fn resolve(pid: libc::pid_t, dirfd: libc::c_int, path: OsString) -> Option<PathBuf> {
+    let path = PathBuf::from(path);
+    if path.is_absolute() {
+        return Some(path);
+    }
+    let dir = if dirfd == libc::AT_FDCWD {
+        fs::read_link(format!("/proc/{pid}/cwd"))
+    } else {
+        fs::read_link(format!("/proc/{pid}/fd/{dirfd}"))
+    };
+    Some(dir.ok()?.join(path))
+}
+
+/// Removes `.` and `..` components, without resolving symlinks.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::landlock;
+    use crate::runtime_dir::RuntimeDir;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("/a/./b/../c")), Path::new("/a/c"));
+        assert_eq!(normalize(Path::new("/../a")), Path::new("/a"));
+    }
+
+    #[test]
+    fn test_trace() -> Result<(), anyhow::Error> {
+        if landlock::abi_version() < 1 {
+            eprintln!("Landlock not available, skipping");
+            return Ok(());
+        }
+        let dir = RuntimeDir::new("learn")?;
+        fs::write(dir.path().join("input"), "data")?;
+        fs::create_dir(dir.path().join("output"))?;
+        fs::create_dir(dir.path().join("links"))?;
+
+        let mut ruleset = Ruleset::new(false)?;
+        ruleset.allow(Path::new("/"), Access::ReadOnly)?;
+        ruleset.allow(dir.path(), Access::ReadWrite)?;
+        let mut command = Command::new("/bin/sh");
+        command
+            .args([
+                "-c",
+                "cat input > output/copy; ln -s ../input links/symlink; ln input links/hard; exit 3",
+            ])
+            .current_dir(dir.path());
+        let trace = trace(command, ruleset)?;
+
+        assert_eq!(trace.exit_code, 3);
+        assert!(!trace.network);
+        assert_eq!(
+            trace.paths.get(&dir.path().join("input")),
+            Some(&Access::ReadOnly)
+        );
+        for path in ["output", "links"] {
+            assert_eq!(
+                trace.paths.get(&dir.path().join(path)),
+                Some(&Access::ReadWrite),
+                "{path}"
+            );
+        }
+        let cat = trace
+            .paths
+            .keys()
+            .any(|path| path.file_name().is_some_and(|name| name == "cat"));
+        assert!(cat, "cat not executed: {:?}", trace.paths);
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate() -> Result<(), anyhow::Error> {
+        let dir = RuntimeDir::new("learn-truncate")?;
+        let file = dir.path().join("file");
+        fs::write(&file, "data")?;
+        let path = std::ffi::CString::new(file.to_str().unwrap())?;
+
+        // The path is read from this process, like from a traced one
+        let mut tracer = Tracer::default();
+        let args = [path.as_ptr() as u64, 0, 0, 0, 0, 0];
+        tracer.syscall(std::process::id() as libc::pid_t, libc::SYS_truncate, args);
+        assert_eq!(tracer.trace.paths.get(&file), Some(&Access::ReadWrite));
+        Ok(())
+    }
+}
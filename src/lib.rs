@@ -10,8 +10,10 @@ use std::ffi::OsString;
 use std::io::IsTerminal;
 use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{self, Command, Stdio};
 use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
 use std::{env, fs, io, slice, thread};
 
 use serde_json::Value;
@@ -19,34 +21,506 @@ use termion::raw::IntoRawMode;
 use termion::terminal_size;
 use users::{get_effective_gid, get_effective_uid};
 
+use crate::arguments::{argument_mounts, Visible};
+use crate::cache::Cache;
+use crate::copy::{copy_in, copy_out};
+use crate::dependencies::{program_dependencies, system_closure};
 use crate::docker_client::{Bind, DockerClient, Tmpfs, Tty};
-
+use crate::environment::sandbox_env;
+use crate::ephemeral::EphemeralRoot;
+use crate::explain::{shell_words, Explanation};
+use crate::helper::{
+    exit_code, helper_binary, namespace_command, proxy_forward_command, seccomp_command,
+    HELPER_PATH,
+};
+use crate::home::{Home, SANDBOX_HOME};
+use crate::journal::JournalRun;
+use crate::landlock::{Access, Ruleset};
+use crate::limits::{
+    report_timeout, wait_with_timeout, Deadline, ProcessLimits, TIMEOUT_EXIT_CODE,
+};
+use crate::network::{resolv_conf_target, PastaNetwork, RESOLV_CONF};
+use crate::overlay::{escape_option, OverlayMount, Overlays};
+use crate::proxy::{proxy_env, RunningProxy, PROXY_DIR};
+use crate::pty::PtyRelay;
+use crate::report::RunReport;
+use crate::seccomp::SECCOMP_FD;
+use crate::secret::{Secrets, SECRETS_DIR};
+
+pub use crate::cache::{list_caches, prune_caches, CacheSpec};
+pub use crate::copy::{parse_copy_in, parse_copy_out, CopySpec};
+pub use crate::home::{home_path, list_homes, remove_home, HomeMode};
+pub use crate::journal::parse_write_pattern;
+pub use crate::learn::learn;
+pub use crate::limits::{parse_cpus, parse_duration, parse_memory, ResourceLimits};
+pub use crate::network::{HostAlias, PortMapping, Protocol};
+pub use crate::overlay::OverlayAction;
+pub use crate::preset::Preset;
+pub use crate::profile::Profile;
+pub use crate::seccomp::SeccompProfile;
+pub use crate::secret::SecretSpec;
+pub use crate::verify::{verify, Backend};
+
+mod arguments;
+mod cache;
+mod copy;
+mod dependencies;
 mod docker_client;
-
-const ENV: [&str; 11] = [
-    "LANG",
-    "LC_ADDRESS",
-    "LC_NAME",
-    "LC_MONETARY",
-    "LC_PAPER",
-    "LC_IDENTIFICATION",
-    "LC_TELEPHONE",
-    "LC_MEASUREMENT",
-    "LC_TIME",
-    "LC_NUMERIC",
-    "USER",
-];
+mod environment;
+mod ephemeral;
+mod explain;
+mod helper;
+mod home;
+mod journal;
+mod landlock;
+mod learn;
+mod limits;
+mod namespace;
+mod network;
+mod overlay;
+mod preset;
+mod profile;
+mod proxy;
+mod pty;
+mod report;
+mod runtime_dir;
+mod seccomp;
+mod secret;
+mod tar;
+mod verify;
 
 const SYSTEM_MOUNTS: [&str; 8] = [
     "/bin", "/etc", "/lib", "/lib32", "/lib64", "/libx32", "/sbin", "/usr",
 ];
 
+/// System directories mounted by bubblewrap, the others are symlinks into `/usr`.
+const BWRAP_SYSTEM_MOUNTS: [&str; 2] = ["/usr", "/etc"];
+
+/// Set up by Podman and Docker, not mounted from the host with `--minimal`.
+const RUNTIME_MOUNTS: [&str; 3] = ["/etc/hosts", "/etc/hostname", "/etc/resolv.conf"];
+
 const USER_MOUNTS: [&str; 2] = ["/etc/passwd", "/etc/group"];
 
 const X11_SOCKET: &str = "/tmp/.X11-unix";
 
+/// Shell of `--shell` if `$SHELL` isn't visible in the sandbox.
+const DEFAULT_SHELL: &str = "/bin/sh";
+
 const SYSTEMD_RESOLVE: &str = "/run/systemd/resolve";
 
+const LANDLOCK_DEVICES: [&str; 6] = [
+    "/dev/null",
+    "/dev/zero",
+    "/dev/full",
+    "/dev/random",
+    "/dev/urandom",
+    "/dev/tty",
+];
+
+/// Additional options for the sandbox, not supported by all tools.
+#[derive(Default, Clone)]
+pub struct SandboxOptions {
+    /// Hosts reachable via an HTTP(S) proxy, while the network is otherwise disabled
+    pub allow_hosts: Vec<String>,
+    /// Use user-mode networking with pasta, instead of the host network
+    pub pasta_network: bool,
+    /// Use the built-in namespace sandbox instead of bubblewrap
+    pub native: bool,
+    /// Ports to publish from the host
+    pub publish: Vec<PortMapping>,
+    /// Additional entries for `/etc/hosts`
+    pub add_hosts: Vec<HostAlias>,
+    /// Limits for memory, CPU, processes and run time
+    pub limits: ResourceLimits,
+    /// Write a JSON summary of the run to this file
+    pub report: Option<PathBuf>,
+    /// Seccomp profile restricting the syscalls available in the sandbox
+    pub seccomp: SeccompProfile,
+    /// Mount the current directory with a copy-on-write overlay
+    pub overlay_current_dir: bool,
+    /// Additional directories mounted with a copy-on-write overlay
+    pub overlay_mounts: Vec<PathBuf>,
+    /// What to do with the changes in the overlays after the run
+    pub overlay_action: OverlayAction,
+    /// Write a journal of the changes in writable mounts to this file
+    pub journal: Option<PathBuf>,
+    /// Compare the content of files in writable mounts, not only their metadata
+    pub journal_hash: bool,
+    /// Fail if paths in writable mounts matching these patterns were changed
+    pub fail_on_write: Vec<String>,
+    /// Make the root file system writable with throwaway overlays, limited to this many bytes
+    pub ephemeral_root: Option<u64>,
+    /// Private home directory of the sandbox
+    pub home: Option<HomeMode>,
+    /// Shared caches mounted writable
+    pub caches: Vec<CacheSpec>,
+    /// Toolchain presets, adding mounts, environment variables and caches
+    pub presets: Vec<Preset>,
+    /// Print the directories mounted for the program and its dependencies, and what the sandbox
+    /// sees
+    pub explain: bool,
+    /// Print the command line or the container JSON instead of running the sandbox
+    pub dry_run: bool,
+    /// Mount only the files of the system directories which the program needs
+    pub minimal: bool,
+    /// Mounts proposed by `contained learn`
+    pub profile: Option<Profile>,
+    /// Mount the paths in the arguments of the program
+    pub auto_mount_args: bool,
+    /// Start an interactive shell in the sandbox of the program instead of the program
+    pub shell: bool,
+    /// Files with environment variables in the dotenv format
+    pub env_files: Vec<PathBuf>,
+    /// Patterns of environment variables which may contain secrets, added to the built-in ones
+    pub env_deny: Vec<String>,
+    /// Secrets mounted as files in `/run/secrets`
+    pub secrets: Vec<SecretSpec>,
+    /// Files and directories copied into the container before it starts, with the daemon
+    pub copy_in: Vec<CopySpec>,
+    /// Files and directories copied out of the container after it exits, with the daemon
+    pub copy_out: Vec<CopySpec>,
+}
+
+/// What a program restricted with Landlock may access besides the system directories.
+#[derive(Default, Clone)]
+pub struct LandlockOptions {
+    /// Allow TCP bind and connect
+    pub network: bool,
+    /// Allow access to the current directory
+    pub current_dir: bool,
+    /// Allow writing to the current directory
+    pub current_dir_writable: bool,
+    /// Additional directories allowed read-only
+    pub mount_readonly: Vec<PathBuf>,
+    /// Additional directories allowed writable
+    pub mount_writable: Vec<PathBuf>,
+    /// Environment variables, `NAME`, `NAME=VALUE` or a pattern like `LC_*`
+    pub extra_env: Vec<String>,
+    /// Working directory
+    pub workdir: Option<PathBuf>,
+    /// Seccomp profile restricting the syscalls available to the program
+    pub seccomp: SeccompProfile,
+}
+
+/// Mounts, environment variables and caches of the sandbox, extended by the presets.
+struct PresetArgs {
+    mount_readonly: Vec<PathBuf>,
+    mount_writable: Vec<PathBuf>,
+    extra_env: Vec<String>,
+    caches: Vec<CacheSpec>,
+    /// Build output directories of the presets, also in `mount_writable`
+    build_dirs: Vec<PathBuf>,
+}
+
+/// What the sandbox needs on the host besides its command line, set up before it starts.
+/// Nothing is set up for a dry run.
+#[derive(Default)]
+struct SandboxResources {
+    proxy: Option<RunningProxy>,
+    overlays: Overlays,
+    ephemeral: Option<EphemeralRoot>,
+    home: Option<Home>,
+    caches: Vec<Cache>,
+    secrets: Option<Secrets>,
+}
+
+impl SandboxResources {
+    fn create(
+        options: &SandboxOptions,
+        program: &Path,
+        caches: &[CacheSpec],
+        build_dirs: &[PathBuf],
+        system_mounts: &[&str],
+    ) -> Result<Self, anyhow::Error> {
+        if options.dry_run {
+            return Ok(Self::default());
+        }
+        create_build_dirs(build_dirs)?;
+        let ephemeral = create_ephemeral_root(options, system_mounts)?;
+        Ok(Self {
+            proxy: start_egress_proxy(&options.allow_hosts)?,
+            overlays: create_overlays(options)?,
+            home: create_home(options, program, ephemeral.as_ref())?,
+            ephemeral,
+            caches: Cache::open_all(caches)?,
+            secrets: Secrets::create(&options.secrets)?,
+        })
+    }
+}
+
+/// Creates the missing build output directories of the presets.
+fn create_build_dirs(build_dirs: &[PathBuf]) -> Result<(), anyhow::Error> {
+    for dir in build_dirs {
+        fs::create_dir_all(dir).with_context(|| format!("Unable to create directory {dir:?}"))?;
+    }
+    Ok(())
+}
+
+/// Reports what the command line of a dry run leaves out, since it is only set up when the
+/// sandbox runs. `direct` is set for sandboxes started directly, without a container engine.
+fn report_dry_run(
+    options: &SandboxOptions,
+    caches: &[CacheSpec],
+    build_dirs: &[PathBuf],
+    direct: bool,
+) {
+    let omitted = [
+        (
+            build_dirs.iter().any(|dir| !dir.exists()),
+            "the missing build directories",
+        ),
+        (!options.allow_hosts.is_empty(), "the egress proxy"),
+        (direct && options.pasta_network, "the pasta network"),
+        (
+            direct && options.limits != ResourceLimits::default(),
+            "the resource limits",
+        ),
+        (
+            options.overlay_current_dir || !options.overlay_mounts.is_empty(),
+            "the overlays",
+        ),
+        (options.ephemeral_root.is_some(), "the ephemeral root"),
+        (home_mode(options).is_some(), "the home directory"),
+        (!caches.is_empty(), "the caches"),
+        (!options.secrets.is_empty(), "the secrets"),
+    ]
+    .into_iter()
+    .filter_map(|(omitted, name)| omitted.then_some(name))
+    .collect::<Vec<_>>();
+    if let Some((last, rest)) = omitted.split_last() {
+        let omitted = match rest {
+            [] => last.to_string(),
+            rest => format!("{} and {last}", rest.join(", ")),
+        };
+        eprintln!("contained: dry run without {omitted}, which are set up when the sandbox runs");
+    }
+}
+
+/// Creates the upper layers for the overlay mounts of the options.
+fn create_overlays(options: &SandboxOptions) -> Result<Overlays, anyhow::Error> {
+    let mut paths = options.overlay_mounts.clone();
+    if options.overlay_current_dir {
+        paths.insert(0, current_dir()?);
+    }
+    Overlays::new(&paths)
+}
+
+/// Creates the throwaway overlays over the system directories, if requested by the options.
+fn create_ephemeral_root(
+    options: &SandboxOptions,
+    paths: &[&str],
+) -> Result<Option<EphemeralRoot>, anyhow::Error> {
+    options
+        .ephemeral_root
+        .map(|size| EphemeralRoot::new(paths, size))
+        .transpose()
+}
+
+/// The home directory requested by the options, toolchains of presets, caches in the home
+/// directory and the ephemeral root need an ephemeral one at least.
+fn home_mode(options: &SandboxOptions) -> Option<&HomeMode> {
+    static EPHEMERAL: HomeMode = HomeMode::Ephemeral;
+    let ephemeral = !options.presets.is_empty()
+        || options.caches.iter().any(CacheSpec::in_sandbox_home)
+        || options.ephemeral_root.is_some();
+    options.home.as_ref().or(ephemeral.then_some(&EPHEMERAL))
+}
+
+/// Creates the home directory of the sandbox, if requested by the options. An ephemeral one is
+/// part of the ephemeral root, if any.
+fn create_home(
+    options: &SandboxOptions,
+    program: &Path,
+    ephemeral: Option<&EphemeralRoot>,
+) -> Result<Option<Home>, anyhow::Error> {
+    match (home_mode(options), ephemeral) {
+        (Some(HomeMode::Ephemeral), Some(ephemeral)) => Ok(Some(Home::at(ephemeral.home()))),
+        (mode, _) => mode.map(|mode| Home::new(mode, program)).transpose(),
+    }
+}
+
+/// Adds the mounts, environment variables and caches of the presets of the options, and the mounts
+/// of the profile. Build output directories are only mounted writable in a read-only current
+/// directory.
+fn apply_presets(
+    options: &SandboxOptions,
+    mount_current_dir: bool,
+    mount_current_dir_writable: bool,
+    mount_readonly: &[PathBuf],
+    mount_writable: &[PathBuf],
+    extra_env: &[String],
+) -> Result<PresetArgs, anyhow::Error> {
+    if options.presets.is_empty() && options.profile.is_none() {
+        return Ok(PresetArgs {
+            mount_readonly: mount_readonly.to_vec(),
+            mount_writable: mount_writable.to_vec(),
+            extra_env: extra_env.to_vec(),
+            caches: options.caches.clone(),
+            build_dirs: Vec::new(),
+        });
+    }
+    let home = PathBuf::from(env::var_os("HOME").ok_or(anyhow!("HOME not set"))?);
+    let current_dir =
+        (mount_current_dir && !mount_current_dir_writable && !options.overlay_current_dir)
+            .then(current_dir)
+            .transpose()?;
+    preset_args(
+        options,
+        &home,
+        current_dir.as_deref(),
+        mount_readonly,
+        mount_writable,
+        extra_env,
+    )
+}
+
+fn preset_args(
+    options: &SandboxOptions,
+    home: &Path,
+    current_dir: Option<&Path>,
+    mount_readonly: &[PathBuf],
+    mount_writable: &[PathBuf],
+    extra_env: &[String],
+) -> Result<PresetArgs, anyhow::Error> {
+    let mut args = PresetArgs {
+        mount_readonly: mount_readonly.to_vec(),
+        mount_writable: mount_writable.to_vec(),
+        extra_env: Vec::new(),
+        caches: options.caches.clone(),
+        build_dirs: Vec::new(),
+    };
+    for preset in &options.presets {
+        args.mount_readonly.extend(preset.readonly_mounts(home));
+        if let Some(current_dir) = current_dir {
+            let build_dirs = preset.writable_mounts(current_dir);
+            // Created when the sandbox starts, a dry run leaves out the missing ones
+            args.mount_writable.extend(
+                build_dirs
+                    .iter()
+                    .filter(|dir| !options.dry_run || dir.exists())
+                    .cloned(),
+            );
+            args.build_dirs.extend(build_dirs);
+        }
+        args.extra_env.extend(preset.env(home));
+        for cache in preset.caches() {
+            // Given on the command line or by another preset
+            if !args.caches.iter().any(|c| c.name() == cache.name()) {
+                args.caches.push(cache.clone());
+            }
+        }
+    }
+    if let Some(profile) = &options.profile {
+        args.mount_readonly.extend(profile.readonly_mounts(home));
+        args.mount_writable.extend(profile.writable_mounts(home));
+    }
+    // Given on the command line, overriding the presets
+    args.extra_env.extend_from_slice(extra_env);
+    Ok(args)
+}
+
+/// Mounts the paths in the arguments with `--auto-mount-args`, returns the arguments with these
+/// paths made absolute.
+fn mount_arguments(
+    options: &SandboxOptions,
+    arguments: &[String],
+    mount_current_dir: bool,
+    mount_current_dir_writable: bool,
+    mount_readonly: &mut Vec<PathBuf>,
+    mount_writable: &mut Vec<PathBuf>,
+) -> Result<Vec<String>, anyhow::Error> {
+    if !options.auto_mount_args {
+        return Ok(arguments.to_vec());
+    }
+    let home = PathBuf::from(env::var_os("HOME").ok_or(anyhow!("HOME not set"))?);
+    let current_dir = current_dir()?;
+    let current_dir_access = mount_current_dir.then_some(
+        if mount_current_dir_writable || options.overlay_current_dir {
+            Access::ReadWrite
+        } else {
+            Access::ReadOnly
+        },
+    );
+    let readonly = mount_readonly.iter().map(|path| (path, Access::ReadOnly));
+    let writable = mount_writable
+        .iter()
+        .chain(&options.overlay_mounts)
+        .map(|path| (path, Access::ReadWrite));
+    let mounts = readonly
+        .chain(writable)
+        .filter_map(|(path, access)| Some((fs::canonicalize(path).ok()?, access)))
+        .collect::<Vec<_>>();
+    let visible = Visible {
+        home: &home,
+        current_dir: &current_dir,
+        current_dir_access,
+        system: !options.minimal,
+        mounts: &mounts,
+    };
+    let mounts = argument_mounts(arguments, &visible)?;
+    mount_readonly.extend(mounts.readonly);
+    mount_writable.extend(mounts.writable);
+    Ok(mounts.arguments)
+}
+
+/// Snapshots the writable mounts if a journal or write checks are requested.
+fn start_journal(
+    options: &SandboxOptions,
+    mount_current_dir_writable: bool,
+    mount_writable: &[PathBuf],
+) -> Result<Option<JournalRun>, anyhow::Error> {
+    if options.journal.is_none() && options.fail_on_write.is_empty() {
+        return Ok(None);
+    }
+    let mut roots = mount_writable
+        .iter()
+        .map(|path| {
+            path.canonicalize()
+                .with_context(|| format!("Unable to resolve {path:?}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if mount_current_dir_writable {
+        roots.insert(0, current_dir()?);
+    }
+    JournalRun::start(
+        roots,
+        options.journal.as_deref(),
+        options.journal_hash,
+        &options.fail_on_write,
+    )
+}
+
+/// Writes the journal after the run, returns the exit code adjusted for unexpected writes.
+fn finish_journal(journal: Option<JournalRun>, exit_code: u8) -> Result<u8, anyhow::Error> {
+    match journal {
+        Some(journal) => journal.finish(exit_code),
+        None => Ok(exit_code),
+    }
+}
+
+/// Run the sandbox helper if the process was started as one inside a sandbox,
+/// returns the exit code in that case.
+pub fn sandbox_helper() -> Result<Option<u8>, anyhow::Error> {
+    helper::run_if_requested()
+}
+
+fn check_publish(network: &str, options: &SandboxOptions) -> Result<(), anyhow::Error> {
+    if !options.publish.is_empty() && network == "none" {
+        Err(anyhow!("Publishing ports requires network"))
+    } else {
+        Ok(())
+    }
+}
+
+fn start_egress_proxy(allow_hosts: &[String]) -> Result<Option<RunningProxy>, anyhow::Error> {
+    if allow_hosts.is_empty() {
+        Ok(None)
+    } else {
+        let proxy = RunningProxy::start(allow_hosts).context("Unable to start egress proxy")?;
+        Ok(Some(proxy))
+    }
+}
+
 pub fn contained_via_daemon(
     image: &str,
     program: &Path,
@@ -59,9 +533,11 @@ pub fn contained_via_daemon(
     extra_env: &[String],
     workdir: Option<PathBuf>,
     x11: bool,
+    options: &SandboxOptions,
 ) -> Result<(String, u8), anyhow::Error> {
-    let client = DockerClient::new()?;
-
+    if options.explain {
+        explain_program(program)?;
+    }
     let user = format!("{}:{}", get_effective_uid(), get_effective_gid());
 
     let is_tty =
@@ -73,8 +549,42 @@ pub fn contained_via_daemon(
         None
     };
 
+    let PresetArgs {
+        mut mount_readonly,
+        mut mount_writable,
+        extra_env,
+        caches: cache_specs,
+        build_dirs,
+    } = apply_presets(
+        options,
+        mount_current_dir,
+        mount_current_dir_writable,
+        mount_readonly,
+        mount_writable,
+        extra_env,
+    )?;
+    let arguments = &mount_arguments(
+        options,
+        arguments,
+        mount_current_dir,
+        mount_current_dir_writable,
+        &mut mount_readonly,
+        &mut mount_writable,
+    )?;
+    let shell = options
+        .shell
+        .then(|| sandbox_shell(&mount_readonly, &mount_writable));
+    let env = sandbox_env(&extra_env, &options.env_files, &options.env_deny)?;
+    let SandboxResources {
+        proxy,
+        overlays,
+        ephemeral,
+        home,
+        caches,
+        secrets,
+    } = SandboxResources::create(options, program, &cache_specs, &build_dirs, &SYSTEM_MOUNTS)?;
+
     let body = contained_body(
-        &client,
         image,
         program,
         arguments,
@@ -82,22 +592,113 @@ pub fn contained_via_daemon(
         &user,
         mount_current_dir,
         mount_current_dir_writable,
-        mount_readonly,
-        mount_writable,
-        extra_env,
+        &mount_readonly,
+        &mount_writable,
+        &env.resolved(),
         workdir,
         x11,
+        options,
+        &overlays,
+        ephemeral.as_ref(),
+        home.as_ref(),
+        &caches,
+        secrets.as_ref(),
+        proxy.as_ref(),
         &tty,
+        shell.as_deref(),
     )?;
+    if options.explain {
+        eprint!("{}", Explanation::docker(&body).hide_file_values(&env));
+    }
+    if options.dry_run {
+        report_dry_run(options, &cache_specs, &build_dirs, false);
+        println!("{}", serde_json::to_string_pretty(&body)?);
+        return Ok((String::new(), 0));
+    }
+    let client = DockerClient::new()?;
+
+    let volumes = OverlayVolumes::create(&client, &overlays)?;
+    let ephemeral_volumes = ephemeral
+        .as_ref()
+        .map(|ephemeral| OverlayVolumes::create(&client, ephemeral.overlays()))
+        .transpose()?;
+    if let Some(volume) = home.as_ref().and_then(Home::volume) {
+        let path = home.as_ref().map(Home::path).expect("home");
+        // A named volume of the directory, kept with it
+        client
+            .create_volume(&volume, "none", &path_to_string(path)?, "bind")
+            .context("Unable to create home volume")?;
+    }
+    for cache in &caches {
+        client
+            .create_volume(
+                &cache.volume(),
+                "none",
+                &path_to_string(cache.path())?,
+                "bind",
+            )
+            .context("Unable to create cache volume")?;
+    }
+    let journal = start_journal(options, mount_current_dir_writable, &mount_writable)?;
+
     let id = client
         .create_container(body)
         .context("Unable to create container")?;
+    let size_watch = match &ephemeral {
+        Some(ephemeral) => {
+            let id = id.clone();
+            Some(ephemeral.watch(move || {
+                if let Ok(client) = DockerClient::new() {
+                    let _ = client.kill_container(&id);
+                }
+            })?)
+        }
+        None => None,
+    };
+
+    let (id, exit_code) = run_container_with_tty(&client, tty, &id, options)?;
+    if let Some(size_watch) = size_watch {
+        size_watch.finish();
+    }
+    drop(ephemeral_volumes);
+    drop(volumes);
+    overlays.review(&options.overlay_action)?;
+    Ok((id, finish_journal(journal, exit_code)?))
+}
+
+/// Docker volumes mounting the overlays, removed on drop.
+struct OverlayVolumes<'a> {
+    client: &'a DockerClient,
+    overlays: &'a Overlays,
+}
 
-    run_container_with_tty(&client, tty, &id)
+impl<'a> OverlayVolumes<'a> {
+    fn create(client: &'a DockerClient, overlays: &'a Overlays) -> Result<Self, anyhow::Error> {
+        let volumes = Self { client, overlays };
+        for mount in overlays.mounts() {
+            let options = format!(
+                "lowerdir={},upperdir={},workdir={}",
+                escape_option(&mount.lower),
+                escape_option(&mount.upper),
+                escape_option(&mount.work)
+            );
+            client
+                .create_volume(&mount.volume, "overlay", "overlay", &options)
+                .context("Unable to create overlay volume")?;
+        }
+        Ok(volumes)
+    }
+}
+
+impl Drop for OverlayVolumes<'_> {
+    fn drop(&mut self) {
+        for mount in self.overlays.mounts() {
+            let _ = self.client.remove_volume(&mount.volume);
+        }
+    }
 }
 
 fn contained_body(
-    docker_client: &DockerClient,
     image: &str,
     program: &Path,
     arguments: &[String],
@@ -110,11 +711,26 @@ fn contained_body(
     extra_env: &[String],
     workdir: Option<PathBuf>,
     x11: bool,
+    options: &SandboxOptions,
+    overlays: &Overlays,
+    ephemeral: Option<&EphemeralRoot>,
+    home: Option<&Home>,
+    caches: &[Cache],
+    secrets: Option<&Secrets>,
+    proxy: Option<&RunningProxy>,
     tty: &Option<Tty>,
+    shell: Option<&Path>,
 ) -> Result<Value, anyhow::Error> {
+    check_publish(network, options)?;
     let program = resolve_program(program)?;
-    let program_dir = program.parent().ok_or(anyhow!("Invalid path"))?;
     let current_dir = current_dir()?;
+    let proxy_mounts = match proxy {
+        Some(proxy) => Some((
+            path_to_string(proxy.dir())?,
+            path_to_string(&helper_binary()?)?,
+        )),
+        None => None,
+    };
     let current_dir_bind_option = [if mount_current_dir_writable {
         "rw"
     } else {
@@ -127,30 +743,54 @@ fn contained_body(
         let current_dir_str = current_dir
             .to_str()
             .ok_or(anyhow!("Current dir is not valid Unicode"))?;
-        binds.push(Bind::new(
-            current_dir_str,
-            current_dir_str,
-            &current_dir_bind_option,
-        ));
+        if !overlays.contains(&current_dir) {
+            binds.push(Bind::new(
+                current_dir_str,
+                current_dir_str,
+                &current_dir_bind_option,
+            ));
+        }
         working_dir = workdir.as_deref().unwrap_or(&*current_dir);
     } else {
         working_dir = workdir.as_deref().unwrap_or("/".as_ref());
     }
 
-    if !program_dir.starts_with("/usr")
-        && !program_dir.starts_with("/bin")
-        && !program_dir.starts_with("/sbin")
-        && !(mount_current_dir && current_dir == program_dir)
-    {
-        let program_dir_str = program_dir
-            .to_str()
-            .ok_or(anyhow!("Program name is not valid Unicode"))?;
-        binds.push(Bind::new(program_dir_str, program_dir_str, &["ro"]));
+    let program_mounts = program_mounts(&program, mount_current_dir, &current_dir)
+        .iter()
+        .map(|dir| path_to_string(dir))
+        .collect::<Result<Vec<_>, _>>()?;
+    for dir in &program_mounts {
+        binds.push(Bind::new(dir, dir, &["ro"]));
     }
 
-    for path in SYSTEM_MOUNTS {
-        if Path::new(path).exists() {
-            binds.push(Bind::new(path, path, &["ro"]));
+    let minimal_mounts =
+        minimal_mounts(options.minimal, &program, network != "none", proxy, shell)?
+            .map(|files| {
+                files
+                    .iter()
+                    .filter(|file| {
+                        !RUNTIME_MOUNTS
+                            .iter()
+                            .any(|path| file.as_path() == Path::new(path))
+                    })
+                    .map(|file| path_to_string(file))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?;
+    match &minimal_mounts {
+        Some(files) => {
+            for file in files {
+                binds.push(Bind::new(file, file, &["ro"]));
+            }
+        }
+        None => {
+            for path in SYSTEM_MOUNTS {
+                if let Some(mount) = ephemeral.and_then(|ephemeral| ephemeral.mount(path)) {
+                    binds.push(Bind::new(&mount.volume, path, &[]));
+                } else if Path::new(path).exists() {
+                    binds.push(Bind::new(path, path, &["ro"]));
+                }
+            }
         }
     }
     for path in mount_readonly {
@@ -161,6 +801,42 @@ fn contained_body(
         let path_str = path.to_str().ok_or(anyhow!("Path is not valid Unicode"))?;
         binds.push(Bind::new(path_str, path_str, &["rw"]));
     }
+    for mount in overlays.mounts() {
+        let path_str = mount.lower.to_str().expect("checked overlay path");
+        binds.push(Bind::new(&mount.volume, path_str, &[]));
+    }
+    let scratch_dirs = ephemeral
+        .map(EphemeralRoot::scratch_dirs)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(dir, target)| Ok((path_to_string(&dir)?, target)))
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    for (dir, target) in &scratch_dirs {
+        binds.push(Bind::new(dir, target, &["rw"]));
+    }
+    let home_source = match home {
+        Some(home) => Some(match home.volume() {
+            Some(volume) => volume,
+            None => path_to_string(home.path())?,
+        }),
+        None => None,
+    };
+    if let Some(home_source) = &home_source {
+        binds.push(Bind::new(home_source, SANDBOX_HOME, &["rw"]));
+    }
+    let cache_binds = caches
+        .iter()
+        .map(|cache| Ok((cache.volume(), path_to_string(cache.target())?)))
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    for (volume, target) in &cache_binds {
+        binds.push(Bind::new(volume, target, &["rw"]));
+    }
+    let secrets_source = secrets
+        .map(|secrets| path_to_string(secrets.path()))
+        .transpose()?;
+    if let Some(secrets_source) = &secrets_source {
+        binds.push(Bind::new(secrets_source, SECRETS_DIR, &["ro"]));
+    }
 
     let mut tmpfs = Vec::new();
     tmpfs.push(Tmpfs::new("/tmp", &["rw", "exec", "mode=1777"]));
@@ -171,8 +847,10 @@ fn contained_body(
         .ok_or(anyhow!("Working directory name is not valid Unicode"))?;
 
     let mut env = Vec::new();
-    for e in ENV {
-        env.push(e.to_string());
+    if home.is_some() {
+        for (k, v) in Home::env() {
+            env.push(format!("{k}={v}"));
+        }
     }
     for e in extra_env {
         env.push(e.to_string());
@@ -183,16 +861,34 @@ fn contained_body(
         binds.push(Bind::new(X11_SOCKET, X11_SOCKET, &[]));
     }
 
-    let mut entrypoint = arguments.to_vec();
-    entrypoint.insert(
-        0,
-        program
-            .to_str()
-            .ok_or(anyhow!("Program name is not valid Unicode"))?
-            .to_string(),
-    );
+    let (program, arguments) = run_program(&program, arguments, shell);
+    let mut entrypoint = Vec::new();
+    if let Some((proxy_dir, helper)) = &proxy_mounts {
+        binds.push(Bind::new(proxy_dir, PROXY_DIR, &["ro"]));
+        binds.push(Bind::new(helper, HELPER_PATH, &["ro"]));
+        for (k, v) in proxy_env() {
+            env.push(format!("{k}={v}"));
+        }
+        for arg in proxy_forward_command(program) {
+            entrypoint.push(
+                arg.into_string()
+                    .map_err(|_| anyhow!("Program name is not valid Unicode"))?,
+            );
+        }
+    } else {
+        entrypoint.push(
+            program
+                .to_str()
+                .ok_or(anyhow!("Program name is not valid Unicode"))?
+                .to_string(),
+        );
+    }
+    entrypoint.extend_from_slice(arguments);
 
-    let body = docker_client.create_container_body(
+    let seccomp = options.seccomp.docker_security_opt()?;
+    // The archive API copies only into and out of a writable root
+    let readonly_rootfs = options.copy_in.is_empty() && options.copy_out.is_empty();
+    let body = DockerClient::create_container_body(
         image,
         &None,
         &Some(&entrypoint),
@@ -201,8 +897,12 @@ fn contained_body(
         &env,
         &binds,
         &tmpfs,
-        true,
+        readonly_rootfs,
         absolute_working_dir_str,
+        &options.publish,
+        &options.add_hosts,
+        &options.limits,
+        seccomp.as_deref(),
         &tty,
     );
     Ok(body)
@@ -220,7 +920,46 @@ pub fn contained_via_command(
     extra_env: &[String],
     workdir: Option<PathBuf>,
     x11: bool,
-) -> Result<(), anyhow::Error> {
+    options: &SandboxOptions,
+) -> Result<u8, anyhow::Error> {
+    if options.explain {
+        explain_program(program)?;
+    }
+    let PresetArgs {
+        mut mount_readonly,
+        mut mount_writable,
+        extra_env,
+        caches: cache_specs,
+        build_dirs,
+    } = apply_presets(
+        options,
+        mount_current_dir,
+        mount_current_dir_writable,
+        mount_readonly,
+        mount_writable,
+        extra_env,
+    )?;
+    let arguments = &mount_arguments(
+        options,
+        arguments,
+        mount_current_dir,
+        mount_current_dir_writable,
+        &mut mount_readonly,
+        &mut mount_writable,
+    )?;
+    let shell = options
+        .shell
+        .then(|| sandbox_shell(&mount_readonly, &mount_writable));
+    let env = sandbox_env(&extra_env, &options.env_files, &options.env_deny)?;
+    let SandboxResources {
+        proxy,
+        overlays,
+        ephemeral,
+        home,
+        caches,
+        secrets,
+    } = SandboxResources::create(options, program, &cache_specs, &build_dirs, &SYSTEM_MOUNTS)?;
+
     let mut command = contained_cmd(
         image,
         program,
@@ -228,12 +967,49 @@ pub fn contained_via_command(
         network,
         mount_current_dir,
         mount_current_dir_writable,
-        mount_readonly,
-        mount_writable,
-        extra_env,
+        &mount_readonly,
+        &mount_writable,
+        &env.vars,
         workdir,
         x11,
+        options,
+        &overlays,
+        ephemeral.as_ref(),
+        home.as_ref(),
+        &caches,
+        secrets.as_ref(),
+        proxy.as_ref(),
+        shell.as_deref(),
     )?;
+    // Passed by name with `-e NAME`, podman takes them from its environment
+    command.envs(&env.file_values);
+    if options.explain {
+        eprint!("{}", Explanation::podman(&command));
+    }
+    if options.dry_run {
+        report_dry_run(options, &cache_specs, &build_dirs, false);
+        println!("{}", shell_words(&command));
+        return Ok(0);
+    }
+
+    let journal = start_journal(options, mount_current_dir_writable, &mount_writable)?;
+    if !overlays.mounts().is_empty()
+        || journal.is_some()
+        || ephemeral.is_some()
+        || matches!(home_mode(options), Some(HomeMode::Ephemeral))
+        || !caches.is_empty()
+        || secrets.is_some()
+    {
+        // Keep running to review the changes, to remove the overlays, the home directory or the
+        // secrets, or to hold the locks of the caches
+        let exit_code = run_podman(command, options.limits.timeout, ephemeral.as_ref())?;
+        overlays.review(&options.overlay_action)?;
+        return finish_journal(journal, exit_code);
+    }
+    if proxy.is_some() || options.limits.timeout.is_some() {
+        // Keep running to serve the proxy, or to report the timeout
+        return run_podman(command, options.limits.timeout, None);
+    }
 
     let error = command.exec();
     // If we reach this point, exec failed
@@ -252,6 +1028,14 @@ fn contained_cmd(
     extra_env: &[String],
     workdir: Option<PathBuf>,
     x11: bool,
+    options: &SandboxOptions,
+    overlays: &Overlays,
+    ephemeral: Option<&EphemeralRoot>,
+    home: Option<&Home>,
+    caches: &[Cache],
+    secrets: Option<&Secrets>,
+    proxy: Option<&RunningProxy>,
+    shell: Option<&Path>,
 ) -> Result<Command, anyhow::Error> {
     let mut cmd = podman_cmd(
         network,
@@ -262,45 +1046,111 @@ fn contained_cmd(
         extra_env,
         workdir,
         x11,
+        options,
+        overlays,
     )?;
 
     cmd.arg("--read-only");
 
     let program = resolve_program(program)?;
-    let program_dir = program
-        .parent()
-        .ok_or(anyhow!("Invalid path"))?
-        .to_path_buf();
-
-    if !program_dir.starts_with("/usr")
-        && !program_dir.starts_with("/bin")
-        && !program_dir.starts_with("/sbin")
-        && !(mount_current_dir && current_dir()? == program_dir)
-    {
+    for dir in program_mounts(&program, mount_current_dir, &current_dir()?) {
         let mut program_dir_arg = OsString::from("type=bind,source=");
-        program_dir_arg.push(program_dir.clone());
+        program_dir_arg.push(&dir);
         program_dir_arg.push(",target=");
-        program_dir_arg.push(program_dir.clone());
+        program_dir_arg.push(&dir);
         program_dir_arg.push(",readonly");
         cmd.arg("--mount").arg(program_dir_arg);
     }
 
-    for path in SYSTEM_MOUNTS {
-        if Path::new(path).exists() {
-            cmd.arg("--mount")
-                .arg(format!("type=bind,source={path},target={path},readonly"));
+    match minimal_mounts(options.minimal, &program, network != "none", proxy, shell)? {
+        Some(files) => {
+            for file in files {
+                if RUNTIME_MOUNTS
+                    .iter()
+                    .any(|path| file.as_path() == Path::new(path))
+                {
+                    continue;
+                }
+                let mut file_arg = OsString::from("type=bind,source=");
+                file_arg.push(&file);
+                file_arg.push(",target=");
+                file_arg.push(&file);
+                file_arg.push(",readonly");
+                cmd.arg("--mount").arg(file_arg);
+            }
+        }
+        None => {
+            for path in SYSTEM_MOUNTS {
+                if let Some(mount) = ephemeral.and_then(|ephemeral| ephemeral.mount(path)) {
+                    cmd.arg("--volume").arg(podman_overlay_volume(mount));
+                } else if Path::new(path).exists() {
+                    cmd.arg("--mount")
+                        .arg(format!("type=bind,source={path},target={path},readonly"));
+                }
+            }
         }
     }
 
     cmd.arg("--tmpfs=/tmp:rw,exec,mode=1777");
+    for (dir, target) in ephemeral
+        .map(EphemeralRoot::scratch_dirs)
+        .unwrap_or_default()
+    {
+        let mut scratch_arg = OsString::from("type=bind,source=");
+        scratch_arg.push(dir);
+        scratch_arg.push(format!(",target={target}"));
+        cmd.arg("--mount").arg(scratch_arg);
+    }
 
-    for e in ENV {
-        cmd.arg("-e").arg(e);
+    if let Some(home) = home {
+        let mut home_arg = OsString::from("type=bind,source=");
+        home_arg.push(home.path());
+        home_arg.push(format!(",target={SANDBOX_HOME}"));
+        cmd.arg("--mount").arg(home_arg);
+        for (k, v) in Home::env() {
+            cmd.arg("-e").arg(format!("{k}={v}"));
+        }
+    }
+    for cache in caches {
+        let mut cache_arg = OsString::from("type=bind,source=");
+        cache_arg.push(cache.path());
+        cache_arg.push(",target=");
+        cache_arg.push(cache.target());
+        cmd.arg("--mount").arg(cache_arg);
+    }
+    if let Some(secrets) = secrets {
+        let mut secrets_arg = OsString::from("type=bind,source=");
+        secrets_arg.push(secrets.path());
+        secrets_arg.push(format!(",target={SECRETS_DIR},readonly"));
+        cmd.arg("--mount").arg(secrets_arg);
     }
 
-    cmd.arg("--entrypoint").arg(program);
+    let (program, arguments) = run_program(&program, arguments, shell);
+    if let Some(proxy) = proxy {
+        let mut proxy_dir_arg = OsString::from("type=bind,source=");
+        proxy_dir_arg.push(proxy.dir());
+        proxy_dir_arg.push(format!(",target={PROXY_DIR},readonly"));
+        cmd.arg("--mount").arg(proxy_dir_arg);
 
-    cmd.arg(image);
+        let mut helper_arg = OsString::from("type=bind,source=");
+        helper_arg.push(helper_binary()?);
+        helper_arg.push(format!(",target={HELPER_PATH},readonly"));
+        cmd.arg("--mount").arg(helper_arg);
+
+        for (k, v) in proxy_env() {
+            cmd.arg("-e").arg(format!("{k}={v}"));
+        }
+
+        let mut helper_command = proxy_forward_command(program).into_iter();
+        cmd.arg("--entrypoint")
+            .arg(helper_command.next().expect("helper path"));
+        cmd.arg(image);
+        cmd.args(helper_command);
+    } else {
+        cmd.arg("--entrypoint").arg(program);
+
+        cmd.arg(image);
+    }
 
     for arg in arguments {
         cmd.arg(arg);
@@ -321,6 +1171,7 @@ pub fn run_image_via_daemon(
     extra_env: &[String],
     workdir: Option<PathBuf>,
     x11: bool,
+    options: &SandboxOptions,
 ) -> Result<(String, u8), anyhow::Error> {
     let client = DockerClient::new()?;
 
@@ -335,8 +1186,12 @@ pub fn run_image_via_daemon(
         None
     };
 
+    let env = sandbox_env(extra_env, &options.env_files, &options.env_deny)?;
+    let overlays = create_overlays(options)?;
+    let volumes = OverlayVolumes::create(&client, &overlays)?;
+    let journal = start_journal(options, mount_current_dir_writable, mount_writable)?;
+
     let body = run_image_body(
-        &client,
         image,
         arguments,
         entrypoint,
@@ -346,20 +1201,24 @@ pub fn run_image_via_daemon(
         mount_current_dir_writable,
         mount_readonly,
         mount_writable,
-        extra_env,
+        &env.resolved(),
         workdir,
         x11,
+        options,
+        &overlays,
         &tty,
     )?;
     let id = client
         .create_container(body)
         .context("Unable to create container")?;
 
-    run_container_with_tty(&client, tty, &id)
+    let (id, exit_code) = run_container_with_tty(&client, tty, &id, options)?;
+    drop(volumes);
+    overlays.review(&options.overlay_action)?;
+    Ok((id, finish_journal(journal, exit_code)?))
 }
 
 fn run_image_body(
-    client: &DockerClient,
     image: &str,
     arguments: &[String],
     entrypoint: Option<String>,
@@ -372,8 +1231,11 @@ fn run_image_body(
     extra_env: &[String],
     workdir: Option<PathBuf>,
     x11: bool,
+    options: &SandboxOptions,
+    overlays: &Overlays,
     tty: &Option<Tty>,
 ) -> Result<Value, anyhow::Error> {
+    check_publish(network, options)?;
     let current_dir = current_dir()?;
     let current_dir_bind_option = [if mount_current_dir_writable {
         "rw"
@@ -386,11 +1248,13 @@ fn run_image_body(
         let current_dir_str = current_dir
             .to_str()
             .ok_or(anyhow!("Current dir is not valid Unicode"))?;
-        binds.push(Bind::new(
-            current_dir_str,
-            current_dir_str,
-            &current_dir_bind_option,
-        ));
+        if !overlays.contains(&current_dir) {
+            binds.push(Bind::new(
+                current_dir_str,
+                current_dir_str,
+                &current_dir_bind_option,
+            ));
+        }
         working_dir = workdir.as_deref().unwrap_or(&*current_dir);
     } else {
         working_dir = workdir.as_deref().unwrap_or("/".as_ref());
@@ -408,6 +1272,10 @@ fn run_image_body(
         let path_str = path.to_str().ok_or(anyhow!("Path is not valid Unicode"))?;
         binds.push(Bind::new(path_str, path_str, &["rw"]));
     }
+    for mount in overlays.mounts() {
+        let path_str = mount.lower.to_str().expect("checked overlay path");
+        binds.push(Bind::new(&mount.volume, path_str, &[]));
+    }
 
     let mut env = Vec::new();
     for e in extra_env {
@@ -424,7 +1292,8 @@ fn run_image_body(
         .to_str()
         .ok_or(anyhow!("Working directory name is not valid Unicode"))?;
 
-    Ok(client.create_container_body(
+    let seccomp = options.seccomp.docker_security_opt()?;
+    Ok(DockerClient::create_container_body(
         image,
         &Some(arguments),
         &entrypoint.as_ref().map(|e| slice::from_ref(e)),
@@ -435,6 +1304,10 @@ fn run_image_body(
         &[],
         false,
         absolute_working_dir_str,
+        &options.publish,
+        &options.add_hosts,
+        &options.limits,
+        seccomp.as_deref(),
         &tty,
     ))
 }
@@ -443,22 +1316,33 @@ fn run_container_with_tty(
     client: &DockerClient,
     tty: Option<Tty>,
     id: &str,
+    options: &SandboxOptions,
 ) -> Result<(String, u8), anyhow::Error> {
-    if tty.is_some() {
+    let timeout = options.limits.timeout;
+    if let Err(error) = copy_in(client, id, &options.copy_in, &options.copy_out) {
+        let _ = client.remove_container(id);
+        return Err(error);
+    }
+    let report = if tty.is_some() {
         let stdout = io::stdout().into_raw_mode()?; // set stdout in raw mode so we can do TTY
-        let result = run_container(client, &id, true);
+        let result = run_container(client, &id, true, timeout, &options.copy_out);
         drop(stdout); // restore terminal mode
         result
     } else {
-        run_container(client, &id, false)
-    }
+        run_container(client, &id, false, timeout, &options.copy_out)
+    }?;
+
+    report.print(options.report.as_deref())?;
+    Ok((id.to_string(), report.exit_code))
 }
 
 fn run_container(
     client: &DockerClient,
     id: &str,
     is_tty: bool,
-) -> Result<(String, u8), anyhow::Error> {
+    timeout: Option<Duration>,
+    copy_out_paths: &[CopySpec],
+) -> Result<RunReport, anyhow::Error> {
     client
         .attach_container(&id, is_tty)
         .context("Unable to attach container")?;
@@ -475,17 +1359,39 @@ fn run_container(
     client
         .start_container(&id)
         .context("Unable to start container")?;
-
-    let result = wait_rx
-        .recv()?
-        .context("Unable to wait for container")
-        .map(|status_code| (id.to_string(), status_code))?;
+    let start = Instant::now();
+
+    let status_code = match timeout {
+        Some(timeout) => match wait_rx.recv_timeout(timeout) {
+            Ok(status_code) => Some(status_code),
+            Err(RecvTimeoutError::Timeout) => {
+                client
+                    .kill_container(&id)
+                    .context("Unable to kill container")?;
+                let _ = wait_rx.recv()?;
+                None
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow!("Unable to wait for container"))
+            }
+        },
+        None => Some(wait_rx.recv()?),
+    };
+    let duration = start.elapsed();
+    let (exit_code, timed_out) = match status_code {
+        Some(status_code) => (status_code.context("Unable to wait for container")?, false),
+        None => (TIMEOUT_EXIT_CODE, true),
+    };
+    // Only used for reporting, the container might already be gone
+    let inspect = client.inspect_container(id).ok();
+    let copied = copy_out(client, id, copy_out_paths);
 
     client
         .remove_container(&id)
         .context("Unable to remove container")?;
+    copied?;
 
-    Ok(result)
+    Ok(RunReport::new(exit_code, duration, timed_out, inspect))
 }
 
 pub fn run_image_via_command(
@@ -500,7 +1406,10 @@ pub fn run_image_via_command(
     extra_env: &[String],
     workdir: Option<PathBuf>,
     x11: bool,
-) -> Result<(), anyhow::Error> {
+    options: &SandboxOptions,
+) -> Result<u8, anyhow::Error> {
+    let env = sandbox_env(extra_env, &options.env_files, &options.env_deny)?;
+    let overlays = create_overlays(options)?;
     let mut command = run_image_cmd(
         image,
         arguments,
@@ -510,16 +1419,75 @@ pub fn run_image_via_command(
         mount_current_dir_writable,
         mount_readonly,
         mount_writable,
-        extra_env,
+        &env.vars,
         workdir,
         x11,
+        options,
+        &overlays,
     )?;
+    // Passed by name with `-e NAME`, podman takes them from its environment
+    command.envs(&env.file_values);
+
+    let journal = start_journal(options, mount_current_dir_writable, mount_writable)?;
+    if !overlays.mounts().is_empty() || journal.is_some() {
+        let exit_code = run_podman(command, options.limits.timeout, None)?;
+        overlays.review(&options.overlay_action)?;
+        return finish_journal(journal, exit_code);
+    }
+    if options.limits.timeout.is_some() {
+        return run_podman(command, options.limits.timeout, None);
+    }
 
     let error = command.exec();
     // If we reach this point, exec failed
     Err(anyhow::Error::new(error).context("Failed to exec"))
 }
 
+/// Run podman and wait for it, with the tmpfs of the ephemeral root mounted for it. The
+/// container is killed after the timeout, which is then reported with a distinct exit code.
+fn run_podman(
+    mut command: Command,
+    timeout: Option<Duration>,
+    ephemeral: Option<&EphemeralRoot>,
+) -> Result<u8, anyhow::Error> {
+    let _tmpfs = ephemeral.map(EphemeralRoot::mount_for_podman).transpose()?;
+    let mut child = command.spawn().context("Failed to run podman")?;
+    let deadline = timeout
+        .map(|timeout| Deadline::start(timeout, kill_podman_container))
+        .transpose()?;
+    let status = child.wait().context("Failed to run podman")?;
+    match (timeout, deadline.map(Deadline::finish)) {
+        (Some(timeout), Some(true)) => Ok(report_timeout(timeout)),
+        _ => Ok(exit_code(status)),
+    }
+}
+
+/// Name of the Podman container, to kill it.
+fn podman_container_name() -> String {
+    format!("contained-{}", process::id())
+}
+
+fn kill_podman_container() {
+    let _ = Command::new("podman")
+        .arg("kill")
+        .arg(podman_container_name())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+}
+
+/// The `--volume` argument mounting an overlay with Podman.
+fn podman_overlay_volume(mount: &OverlayMount) -> OsString {
+    let mut volume_arg = OsString::from(&mount.lower);
+    volume_arg.push(":");
+    volume_arg.push(&mount.lower);
+    volume_arg.push(":O,upperdir=");
+    volume_arg.push(&mount.upper);
+    volume_arg.push(",workdir=");
+    volume_arg.push(&mount.work);
+    volume_arg
+}
+
 fn run_image_cmd(
     image: &str,
     arguments: &[String],
@@ -532,6 +1500,8 @@ fn run_image_cmd(
     extra_env: &[String],
     workdir: Option<PathBuf>,
     x11: bool,
+    options: &SandboxOptions,
+    overlays: &Overlays,
 ) -> Result<Command, anyhow::Error> {
     let mut cmd = podman_cmd(
         network,
@@ -542,6 +1512,8 @@ fn run_image_cmd(
         extra_env,
         workdir,
         x11,
+        options,
+        overlays,
     )?;
 
     if let Some(entrypoint) = entrypoint {
@@ -566,6 +1538,8 @@ fn podman_cmd(
     extra_env: &[String],
     workdir: Option<PathBuf>,
     x11: bool,
+    options: &SandboxOptions,
+    overlays: &Overlays,
 ) -> Result<Command, anyhow::Error> {
     let mut cmd = Command::new("podman");
     cmd.arg("run")
@@ -576,6 +1550,9 @@ fn podman_cmd(
         .arg("no-new-privileges=true")
         .arg("--rm")
         .arg("--interactive");
+    if let Some(seccomp) = options.seccomp.podman_security_opt() {
+        cmd.arg("--security-opt").arg(seccomp);
+    }
 
     let is_tty =
         io::stdin().is_terminal() && io::stdout().is_terminal() && io::stderr().is_terminal();
@@ -586,6 +1563,29 @@ fn podman_cmd(
 
     cmd.arg(format!("--network={network}"));
 
+    check_publish(network, options)?;
+    for port in &options.publish {
+        cmd.arg("--publish").arg(port.to_string());
+    }
+    for host in &options.add_hosts {
+        cmd.arg("--add-host").arg(host.to_string());
+    }
+
+    let limits = &options.limits;
+    if let Some(memory) = limits.memory {
+        cmd.arg(format!("--memory={memory}"));
+    }
+    if let Some(cpus) = limits.cpus {
+        cmd.arg(format!("--cpus={cpus}"));
+    }
+    if let Some(pids_limit) = limits.pids_limit {
+        cmd.arg(format!("--pids-limit={pids_limit}"));
+    }
+    if limits.timeout.is_some() {
+        // Named to kill it after the timeout
+        cmd.arg("--name").arg(podman_container_name());
+    }
+
     if mount_current_dir {
         let current_dir = current_dir()?;
 
@@ -596,14 +1596,16 @@ fn podman_cmd(
             ));
         }
 
-        let mut current_dir_arg = OsString::from("type=bind,source=");
-        current_dir_arg.push(current_dir.clone());
-        current_dir_arg.push(",target=");
-        current_dir_arg.push(current_dir.clone());
-        if !mount_current_dir_writable {
-            current_dir_arg.push(",readonly");
+        if !overlays.contains(&current_dir) {
+            let mut current_dir_arg = OsString::from("type=bind,source=");
+            current_dir_arg.push(current_dir.clone());
+            current_dir_arg.push(",target=");
+            current_dir_arg.push(current_dir.clone());
+            if !mount_current_dir_writable {
+                current_dir_arg.push(",readonly");
+            }
+            cmd.arg("--mount").arg(current_dir_arg);
         }
-        cmd.arg("--mount").arg(current_dir_arg);
 
         cmd.arg("--workdir");
         if let Some(workdir) = workdir {
@@ -634,6 +1636,9 @@ fn podman_cmd(
         mount_arg.push(path);
         cmd.arg("--mount").arg(mount_arg);
     }
+    for mount in overlays.mounts() {
+        cmd.arg("--volume").arg(podman_overlay_volume(mount));
+    }
 
     if x11 {
         cmd.arg("-e").arg("DISPLAY");
@@ -658,23 +1663,165 @@ pub fn wrapped(
     mount_writable: &[PathBuf],
     extra_env: &[String],
     workdir: Option<PathBuf>,
-) -> Result<(), anyhow::Error> {
+    options: &SandboxOptions,
+) -> Result<u8, anyhow::Error> {
+    if options.explain {
+        explain_program(program)?;
+    }
+    if !options.publish.is_empty() && !options.pasta_network {
+        return Err(anyhow!("Publishing ports requires pasta network"));
+    }
+
+    let PresetArgs {
+        mut mount_readonly,
+        mut mount_writable,
+        extra_env,
+        caches: cache_specs,
+        build_dirs,
+    } = apply_presets(
+        options,
+        mount_current_dir,
+        mount_current_dir_writable,
+        mount_readonly,
+        mount_writable,
+        extra_env,
+    )?;
+    let arguments = &mount_arguments(
+        options,
+        arguments,
+        mount_current_dir,
+        mount_current_dir_writable,
+        &mut mount_readonly,
+        &mut mount_writable,
+    )?;
+    let shell = options
+        .shell
+        .then(|| sandbox_shell(&mount_readonly, &mount_writable));
+    let env = sandbox_env(&extra_env, &options.env_files, &options.env_deny)?;
+    let SandboxResources {
+        proxy,
+        overlays,
+        ephemeral,
+        home,
+        caches,
+        secrets,
+    } = SandboxResources::create(
+        options,
+        program,
+        &cache_specs,
+        &build_dirs,
+        &BWRAP_SYSTEM_MOUNTS,
+    )?;
+    let pasta = if options.pasta_network && !options.dry_run {
+        Some(PastaNetwork::new(&options.publish).context("Unable to set up pasta network")?)
+    } else {
+        None
+    };
+    let timeout = options.limits.timeout;
+    // An interactive sandbox gets its own terminal, otherwise it is detached from the terminal
+    let pty = PtyRelay::new_if_interactive()?;
+
     let mut command = bwrap_cmd(
         program,
         arguments,
         network,
         mount_current_dir,
         mount_current_dir_writable,
-        mount_readonly,
-        mount_writable,
-        extra_env,
+        &mount_readonly,
+        &mount_writable,
+        &env.resolved(),
         workdir,
+        proxy.as_ref(),
+        pasta.as_ref(),
+        &overlays,
+        ephemeral.as_ref(),
+        home.as_ref(),
+        &caches,
+        secrets.as_ref(),
+        options.minimal,
+        pty.is_none(),
+        options.seccomp != SeccompProfile::Unconfined,
+        shell.as_deref(),
     )?;
+    if options.explain {
+        eprint!("{}", Explanation::bwrap(&command).hide_file_values(&env));
+    }
+
+    if options.native {
+        command = namespace_command(&command)?;
+    }
+    if let Some(seccomp) = seccomp_command(&options.seccomp, &command)? {
+        command = seccomp;
+    }
+    if let Some(ephemeral) = &ephemeral {
+        command = ephemeral.command(&command)?;
+    }
+    if options.dry_run {
+        report_dry_run(options, &cache_specs, &build_dirs, true);
+        println!("{}", shell_words(&command));
+        return Ok(0);
+    }
+
+    let limits = ProcessLimits::new(&options.limits).context("Unable to set resource limits")?;
+    if let Some(pasta) = &pasta {
+        command = pasta.command(&command);
+    }
+    command = limits.apply(command);
+    if let Some(pty) = &pty {
+        pty.attach(&mut command)?;
+    }
+    let journal = start_journal(options, mount_current_dir_writable, &mount_writable)?;
+
+    if proxy.is_some()
+        || pasta.is_some()
+        || pty.is_some()
+        || !overlays.mounts().is_empty()
+        || journal.is_some()
+        || ephemeral.is_some()
+        || matches!(home_mode(options), Some(HomeMode::Ephemeral))
+        || !caches.is_empty()
+        || secrets.is_some()
+        || options.limits != ResourceLimits::default()
+    {
+        // Keep running to serve the proxy or the terminal, clean up the network setup, the
+        // cgroup, the ephemeral root, the home directory and the secrets, hold the locks of the
+        // caches, kill the sandbox after the timeout, or to review the changes in overlays and
+        // writable mounts
+        let child = command.spawn().context("Failed to run bwrap")?;
+        // Close our copies of the terminal of the sandbox
+        drop(command);
+        let relay = pty.map(PtyRelay::start).transpose()?;
+        let status = wait_with_timeout(child, timeout)?;
+        if let Some(relay) = relay {
+            relay.finish();
+        }
+        overlays.review(&options.overlay_action)?;
+        let exit_code = match status {
+            Some(status) => exit_code(status),
+            None => report_timeout(timeout.unwrap_or_default()),
+        };
+        return finish_journal(journal, exit_code);
+    }
+
     let error = command.exec();
     // If we reach this point, exec failed
     Err(anyhow::Error::new(error).context("Failed to exec bwrap"))
 }
 
+/// Mounts a system directory read-only, or with its throwaway overlay.
+fn bwrap_system_mount(cmd: &mut Command, path: &str, ephemeral: Option<&EphemeralRoot>) {
+    match ephemeral.and_then(|ephemeral| ephemeral.mount(path)) {
+        Some(mount) => cmd
+            .arg("--overlay-src")
+            .arg(&mount.lower)
+            .arg("--overlay")
+            .arg(&mount.upper)
+            .arg(&mount.work)
+            .arg(path),
+        None => cmd.arg("--ro-bind").arg(path).arg(path),
+    };
+}
+
 fn bwrap_cmd(
     program: &Path,
     arguments: &[String],
@@ -685,27 +1832,62 @@ fn bwrap_cmd(
     mount_writable: &[PathBuf],
     extra_env: &[String],
     workdir: Option<PathBuf>,
+    proxy: Option<&RunningProxy>,
+    pasta: Option<&PastaNetwork>,
+    overlays: &Overlays,
+    ephemeral: Option<&EphemeralRoot>,
+    home: Option<&Home>,
+    caches: &[Cache],
+    secrets: Option<&Secrets>,
+    minimal: bool,
+    new_session: bool,
+    seccomp: bool,
+    shell: Option<&Path>,
 ) -> Result<Command, anyhow::Error> {
+    let program = resolve_program(program)?;
     let mut cmd = Command::new("bwrap");
-    cmd.arg("--ro-bind")
-        .arg("/usr")
-        .arg("/usr")
-        .arg("--symlink")
-        .arg("/usr/lib")
-        .arg("/lib")
-        .arg("--symlink")
-        .arg("/usr/lib64")
-        .arg("/lib64")
-        .arg("--symlink")
-        .arg("/usr/bin")
-        .arg("/bin")
-        .arg("--symlink")
-        .arg("/usr/sbin")
-        .arg("/sbin")
-        .arg("--ro-bind")
-        .arg("/etc")
-        .arg("/etc")
-        .arg("--perms")
+    cmd.arg("--die-with-parent");
+    if new_session {
+        // Without a controlling terminal, the sandbox can't inject input with TIOCSTI
+        cmd.arg("--new-session");
+    }
+    if seccomp {
+        // The filter is passed on this file descriptor by the seccomp helper
+        cmd.arg("--seccomp").arg(SECCOMP_FD.to_string());
+    }
+    let network_access = network || pasta.is_some();
+    match minimal_mounts(minimal, &program, network_access, proxy, shell)? {
+        Some(files) => {
+            // As named and with symlinks resolved, without symlinks to directories
+            for file in files {
+                cmd.arg("--ro-bind").arg(&file).arg(file);
+            }
+            let resolv_conf = resolv_conf_target();
+            if network_access && resolv_conf != Path::new(RESOLV_CONF) {
+                cmd.arg("--symlink").arg(&resolv_conf).arg(RESOLV_CONF);
+            }
+            if network && resolv_conf.exists() {
+                cmd.arg("--ro-bind").arg(&resolv_conf).arg(resolv_conf);
+            }
+        }
+        None => {
+            bwrap_system_mount(&mut cmd, "/usr", ephemeral);
+            cmd.arg("--symlink")
+                .arg("/usr/lib")
+                .arg("/lib")
+                .arg("--symlink")
+                .arg("/usr/lib64")
+                .arg("/lib64")
+                .arg("--symlink")
+                .arg("/usr/bin")
+                .arg("/bin")
+                .arg("--symlink")
+                .arg("/usr/sbin")
+                .arg("/sbin");
+            bwrap_system_mount(&mut cmd, "/etc", ephemeral);
+        }
+    }
+    cmd.arg("--perms")
         .arg("1777")
         .arg("--tmpfs")
         .arg("/tmp")
@@ -714,7 +1896,6 @@ fn bwrap_cmd(
         .arg("--dev")
         .arg("/dev");
 
-    let program = resolve_program(program)?;
     let program_dir = program
         .parent()
         .ok_or(anyhow!("Invalid path"))?
@@ -730,12 +1911,14 @@ fn bwrap_cmd(
             ));
         }
 
-        if mount_current_dir_writable {
-            cmd.arg("--bind");
-        } else {
-            cmd.arg("--ro-bind");
+        if !overlays.contains(&current_dir) {
+            if mount_current_dir_writable {
+                cmd.arg("--bind");
+            } else {
+                cmd.arg("--ro-bind");
+            }
+            cmd.arg(current_dir.clone()).arg(current_dir.clone());
         }
-        cmd.arg(current_dir.clone()).arg(current_dir.clone());
 
         cmd.arg("--chdir");
         if let Some(workdir) = workdir {
@@ -755,14 +1938,8 @@ fn bwrap_cmd(
         }
     }
 
-    if !program_dir.starts_with("/usr")
-        && !program_dir.starts_with("/bin")
-        && !program_dir.starts_with("/sbin")
-        && !(mount_current_dir && current_dir == program_dir)
-    {
-        cmd.arg("--ro-bind")
-            .arg(program_dir.clone())
-            .arg(program_dir);
+    for dir in program_mounts(&program, mount_current_dir, &current_dir) {
+        cmd.arg("--ro-bind").arg(&dir).arg(dir);
     }
 
     for path in mount_readonly {
@@ -773,16 +1950,38 @@ fn bwrap_cmd(
         let path = fs::canonicalize(path).context(format!("Mount point {:?} not found", path))?;
         cmd.arg("--bind").arg(path.clone()).arg(path);
     }
+    for mount in overlays.mounts() {
+        cmd.arg("--overlay-src").arg(&mount.lower);
+        cmd.arg("--overlay")
+            .arg(&mount.upper)
+            .arg(&mount.work)
+            .arg(&mount.lower);
+    }
+    for (dir, target) in ephemeral
+        .map(EphemeralRoot::scratch_dirs)
+        .unwrap_or_default()
+    {
+        cmd.arg("--bind").arg(dir).arg(target);
+    }
+    if let Some(home) = home {
+        cmd.arg("--bind").arg(home.path()).arg(SANDBOX_HOME);
+    }
+    for cache in caches {
+        cmd.arg("--bind").arg(cache.path()).arg(cache.target());
+    }
+    if let Some(secrets) = secrets {
+        cmd.arg("--ro-bind").arg(secrets.path()).arg(SECRETS_DIR);
+    }
 
     cmd.arg("--clearenv");
-    for k in ENV {
-        if let Some(v) = env::var_os(k) {
-            cmd.arg("--setenv").arg(k).arg(v);
-        }
-    }
     cmd.arg("--setenv")
         .arg("PATH")
         .arg("/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin");
+    if home.is_some() {
+        for (k, v) in Home::env() {
+            cmd.arg("--setenv").arg(k).arg(v);
+        }
+    }
     for e in extra_env {
         if let Some((k, v)) = e.split_once("=") {
             cmd.arg("--setenv").arg(k).arg(v);
@@ -805,11 +2004,31 @@ fn bwrap_cmd(
                 .arg(SYSTEMD_RESOLVE)
                 .arg(SYSTEMD_RESOLVE);
         }
+    } else if let Some(pasta) = pasta {
+        // Use the network namespace set up by pasta, where we are mapped to root
+        cmd.arg("--unshare-uts")
+            .arg("--uid")
+            .arg(get_effective_uid().to_string())
+            .arg("--gid")
+            .arg(get_effective_gid().to_string())
+            .arg("--ro-bind")
+            .arg(pasta.resolv_conf())
+            .arg(resolv_conf_target());
     } else {
         cmd.arg("--unshare-net").arg("--unshare-uts");
     }
 
-    cmd.arg(program);
+    let (program, arguments) = run_program(&program, arguments, shell);
+    if let Some(proxy) = proxy {
+        cmd.arg("--ro-bind").arg(proxy.dir()).arg(PROXY_DIR);
+        cmd.arg("--ro-bind").arg(helper_binary()?).arg(HELPER_PATH);
+        for (k, v) in proxy_env() {
+            cmd.arg("--setenv").arg(k).arg(v);
+        }
+        cmd.args(proxy_forward_command(program));
+    } else {
+        cmd.arg(program);
+    }
 
     for arg in arguments {
         cmd.arg(arg);
@@ -818,15 +2037,124 @@ fn bwrap_cmd(
     Ok(cmd)
 }
 
+pub fn landlocked(
+    program: &Path,
+    arguments: &[String],
+    options: &LandlockOptions,
+) -> Result<(), anyhow::Error> {
+    let options = LandlockOptions {
+        extra_env: sandbox_env(&options.extra_env, &[], &[])?.resolved(),
+        ..options.clone()
+    };
+    let mut command = landlock_cmd(program, arguments, &options)?;
+
+    let error = command.exec();
+    // If we reach this point, exec failed
+    Err(anyhow::Error::new(error).context("Failed to exec"))
+}
+
+fn landlock_cmd(
+    program: &Path,
+    arguments: &[String],
+    options: &LandlockOptions,
+) -> Result<Command, anyhow::Error> {
+    let mut ruleset = Ruleset::new(!options.network)?;
+    let filter = match options.seccomp.compile()? {
+        Some(filter) => Some(filter.compile()?),
+        None => None,
+    };
+
+    for path in SYSTEM_MOUNTS {
+        if Path::new(path).exists() {
+            ruleset.allow(Path::new(path), Access::ReadOnly)?;
+        }
+    }
+    for path in LANDLOCK_DEVICES {
+        if Path::new(path).exists() {
+            ruleset.allow(Path::new(path), Access::ReadWrite)?;
+        }
+    }
+    ruleset.allow(Path::new("/proc/self"), Access::ReadOnly)?;
+
+    let program = resolve_program(program)?;
+    let program_dir = program.parent().ok_or(anyhow!("Invalid path"))?;
+    ruleset.allow(program_dir, Access::ReadOnly)?;
+    for dependency in program_dependencies(&program) {
+        ruleset.allow(&dependency.dir, Access::ReadOnly)?;
+    }
+
+    let mut cmd = Command::new(&program);
+
+    if options.current_dir {
+        let current_dir = current_dir()?;
+        let home_dir = PathBuf::from(env::var_os("HOME").ok_or(anyhow!("HOME not set"))?);
+        if (current_dir == home_dir) || (home_dir.starts_with(current_dir.as_path())) {
+            return Err(anyhow!(
+                "Cannot run from home directory or its parent directories"
+            ));
+        }
+
+        let access = if options.current_dir_writable {
+            Access::ReadWrite
+        } else {
+            Access::ReadOnly
+        };
+        ruleset.allow(&current_dir, access)?;
+    }
+    if let Some(workdir) = &options.workdir {
+        cmd.current_dir(workdir);
+    }
+
+    for path in &options.mount_readonly {
+        let path = fs::canonicalize(path).context(format!("Mount point {:?} not found", path))?;
+        ruleset.allow(&path, Access::ReadOnly)?;
+    }
+    for path in &options.mount_writable {
+        let path = fs::canonicalize(path).context(format!("Mount point {:?} not found", path))?;
+        ruleset.allow(&path, Access::ReadWrite)?;
+    }
+
+    cmd.env_clear();
+    cmd.env(
+        "PATH",
+        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+    );
+    for e in &options.extra_env {
+        if let Some((k, v)) = e.split_once("=") {
+            cmd.env(k, v);
+        } else {
+            if let Some(v) = env::var_os(e) {
+                cmd.env(e, v);
+            } else {
+                return Err(anyhow!("env var {} is not set", e));
+            }
+        }
+    }
+
+    cmd.args(arguments);
+
+    // SAFETY: restrict_self and seccomp::install only call async-signal-safe functions
+    unsafe {
+        cmd.pre_exec(move || {
+            ruleset.restrict_self()?;
+            match &filter {
+                Some(program) => seccomp::install(program),
+                None => Ok(()),
+            }
+        });
+    }
+
+    Ok(cmd)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::runtime_dir::RuntimeDir;
     use std::error;
 
     #[test]
     fn test_run_body() -> Result<(), Box<dyn error::Error>> {
-        let client = DockerClient::new()?;
-
         let image = "test_image";
         let program = Path::new("/usr/bin/ls");
         let arguments = ["arg1".to_string(), "arg2".to_string()];
@@ -839,10 +2167,10 @@ mod tests {
         let extra_env = ["MY_ENV=123".to_string()];
         let workdir = None;
         let x11 = false;
+        let options = SandboxOptions::default();
         let tty = None;
 
         let body = contained_body(
-            &client,
             image,
             program,
             &arguments,
@@ -855,7 +2183,15 @@ mod tests {
             &extra_env,
             workdir,
             x11,
+            &options,
+            &Overlays::default(),
+            None,
+            None,
+            &[],
+            None,
+            None,
             &tty,
+            None,
         )?;
 
         println!(
@@ -910,8 +2246,6 @@ mod tests {
 
     #[test]
     fn test_run_image_body() -> Result<(), Box<dyn error::Error>> {
-        let client = DockerClient::new()?;
-
         let image = "test_image";
         let arguments = ["arg1".to_string(), "arg2".to_string()];
         let entrypoint = Some("test_entrypoint".to_string());
@@ -924,10 +2258,21 @@ mod tests {
         let extra_env = ["MY_ENV=123".to_string()];
         let workdir = None;
         let x11 = false;
+        let options = SandboxOptions {
+            publish: vec!["8080:80".parse()?, "0.0.0.0:5353:53/udp".parse()?],
+            add_hosts: vec!["host.docker.internal:host-gateway".parse()?],
+            limits: ResourceLimits {
+                memory: Some(parse_memory("512m")?),
+                cpus: Some(1.5),
+                pids_limit: Some(100),
+                timeout: None,
+            },
+            seccomp: SeccompProfile::Unconfined,
+            ..Default::default()
+        };
         let tty = None;
 
         let body = run_image_body(
-            &client,
             image,
             &arguments,
             entrypoint.clone(),
@@ -940,6 +2285,8 @@ mod tests {
             &extra_env,
             workdir,
             x11,
+            &options,
+            &Overlays::default(),
             &tty,
         )?;
 
@@ -958,6 +2305,13 @@ mod tests {
                 .as_str(),
             entrypoint.as_deref(),
         );
+        assert_eq!(body["HostConfig"]["Memory"].as_u64(), Some(512 * 1024 * 1024));
+        assert_eq!(body["HostConfig"]["NanoCpus"].as_u64(), Some(1_500_000_000));
+        assert_eq!(body["HostConfig"]["PidsLimit"].as_u64(), Some(100));
+        assert_eq!(
+            body["HostConfig"]["SecurityOpt"],
+            serde_json::json!(["no-new-privileges:true", "seccomp=unconfined"])
+        );
 
         // Check for bind mounts
         let binds = body["HostConfig"]["Binds"]
@@ -980,6 +2334,23 @@ mod tests {
             );
         }
 
+        // Check for published ports and host aliases
+        assert_eq!(
+            body["ExposedPorts"],
+            serde_json::json!({ "80/tcp": {}, "53/udp": {} })
+        );
+        assert_eq!(
+            body["HostConfig"]["PortBindings"],
+            serde_json::json!({
+                "80/tcp": [{ "HostIp": "127.0.0.1", "HostPort": "8080" }],
+                "53/udp": [{ "HostIp": "0.0.0.0", "HostPort": "5353" }],
+            })
+        );
+        assert_eq!(
+            body["HostConfig"]["ExtraHosts"],
+            serde_json::json!(["host.docker.internal:host-gateway"])
+        );
+
         // Check for environment variables
         let env_vars = body["Env"]
             .as_array()
@@ -1011,6 +2382,7 @@ mod tests {
         let extra_env = ["MY_ENV=123".to_string()];
         let workdir = None;
         let x11 = false;
+        let options = SandboxOptions::default();
 
         let cmd = contained_cmd(
             image,
@@ -1024,6 +2396,14 @@ mod tests {
             &extra_env,
             workdir,
             x11,
+            &options,
+            &Overlays::default(),
+            None,
+            None,
+            &[],
+            None,
+            None,
+            None,
         )?;
 
         let args: Vec<_> = cmd.get_args().map(|s| s.to_str().unwrap()).collect();
@@ -1068,9 +2448,6 @@ mod tests {
         }
 
         // Check for environment variables
-        for var in ENV {
-            assert!(args.contains(&&*var), "env {var} not found");
-        }
         for var in &extra_env {
             assert!(args.contains(&&**var), "env {var} not found");
         }
@@ -1078,6 +2455,290 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_run_cmd_ephemeral_root() -> Result<(), Box<dyn error::Error>> {
+        let options = SandboxOptions {
+            ephemeral_root: Some(parse_memory("512m")?),
+            ..Default::default()
+        };
+        let ephemeral = create_ephemeral_root(&options, &SYSTEM_MOUNTS)?.expect("ephemeral root");
+        let etc = ephemeral.mount("/etc").expect("overlay");
+
+        let cmd = contained_cmd(
+            "test_image",
+            Path::new("/usr/bin/ls"),
+            &[],
+            "none",
+            false,
+            false,
+            &[],
+            &[],
+            &[],
+            None,
+            false,
+            &options,
+            &Overlays::default(),
+            Some(&ephemeral),
+            None,
+            &[],
+            None,
+            None,
+            None,
+        )?;
+
+        let args: Vec<_> = cmd.get_args().map(|s| s.to_str().unwrap()).collect();
+        assert!(args.contains(&"--read-only"));
+        let volume = format!(
+            "/etc:/etc:O,upperdir={},workdir={}",
+            etc.upper.display(),
+            etc.work.display()
+        );
+        assert!(args.windows(2).any(|w| w == ["--volume", &volume]));
+        assert!(!args.contains(&"type=bind,source=/etc,target=/etc,readonly"));
+        let scratch = format!(
+            "type=bind,source={},target=/var/cache",
+            ephemeral.scratch_dirs()[0].0.display()
+        );
+        assert!(args.windows(2).any(|w| w == ["--mount", &scratch]));
+        Ok(())
+    }
+
+    /// Compares the arguments with `tests/golden/NAME`, or updates it with `UPDATE_GOLDEN=1`.
+    fn assert_golden(name: &str, args: &[String]) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(name);
+        let actual = args.join("\n") + "\n";
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&path, &actual).unwrap();
+        }
+        let expected = fs::read_to_string(&path).unwrap_or_default();
+        assert_eq!(
+            actual, expected,
+            "{path:?} differs, run with UPDATE_GOLDEN=1 to update"
+        );
+    }
+
+    #[test]
+    fn test_preset_golden() -> Result<(), Box<dyn error::Error>> {
+        let home = RuntimeDir::new("preset-home")?;
+        for dir in [
+            ".rustup",
+            ".cargo/bin",
+            ".nvm",
+            ".pyenv",
+            "go/bin",
+            ".sdkman",
+        ] {
+            fs::create_dir_all(home.path().join(dir))?;
+        }
+        let project = RuntimeDir::new("preset-project")?;
+        let data = RuntimeDir::new("preset-data")?;
+        // The sandbox mounts the current directory of the test, standing in for the project
+        let current_dir = current_dir()?;
+
+        for name in ["go", "java", "node", "python", "rust"] {
+            // Without the presets of the user
+            let options = SandboxOptions {
+                presets: vec![Preset::load(name, None)?],
+                ..Default::default()
+            };
+            let args = preset_args(
+                &options,
+                home.path(),
+                Some(project.path()),
+                &[],
+                &[],
+                &["EXTRA=1".to_string()],
+            )?;
+            create_build_dirs(&args.build_dirs)?;
+            let caches = Cache::open_all_in(data.path(), &args.caches)?;
+            let sandbox_home =
+                create_home(&options, Path::new("/usr/bin/ls"), None)?.expect("home");
+            // Paths which differ between runs
+            let replacements = [
+                (home.path(), "$HOME"),
+                (project.path(), "$PROJECT"),
+                (current_dir.as_path(), "$PROJECT"),
+                (data.path(), "$DATA"),
+                (sandbox_home.path(), "$SANDBOX_HOME"),
+            ]
+            .map(|(path, name)| (path.to_str().unwrap().to_string(), name));
+            let normalize = |args: Vec<&str>| {
+                args.into_iter()
+                    .map(|arg| {
+                        replacements
+                            .iter()
+                            .fold(arg.to_string(), |arg, (path, name)| arg.replace(path, name))
+                    })
+                    .collect::<Vec<_>>()
+            };
+
+            let cmd = contained_cmd(
+                "test_image",
+                Path::new("/usr/bin/ls"),
+                &[],
+                "none",
+                true,
+                false,
+                &args.mount_readonly,
+                &args.mount_writable,
+                &args.extra_env,
+                None,
+                false,
+                &options,
+                &Overlays::default(),
+                None,
+                Some(&sandbox_home),
+                &caches,
+                None,
+                None,
+                None,
+            )?;
+            let mut podman_args = cmd
+                .get_args()
+                .map(|s| s.to_str().unwrap())
+                .collect::<Vec<_>>();
+            // Depends on the terminal of the test
+            podman_args.retain(|arg| *arg != "--tty");
+            // Only mounted if they exist on the host
+            let system_mounts =
+                SYSTEM_MOUNTS.map(|path| format!("type=bind,source={path},target={path},readonly"));
+            while let Some(i) = podman_args
+                .windows(2)
+                .position(|w| w[0] == "--mount" && system_mounts.iter().any(|m| m == w[1]))
+            {
+                podman_args.drain(i..i + 2);
+            }
+            assert_golden(&format!("preset-{name}.podman"), &normalize(podman_args));
+
+            let cmd = bwrap_cmd(
+                Path::new("/usr/bin/ls"),
+                &[],
+                false,
+                true,
+                false,
+                &args.mount_readonly,
+                &args.mount_writable,
+                &args.extra_env,
+                None,
+                None,
+                None,
+                &Overlays::default(),
+                None,
+                Some(&sandbox_home),
+                &caches,
+                None,
+                false,
+                true,
+                false,
+                None,
+            )?;
+            let bwrap_args = cmd
+                .get_args()
+                .map(|s| s.to_str().unwrap())
+                .collect::<Vec<_>>();
+            assert_golden(&format!("preset-{name}.bwrap"), &normalize(bwrap_args));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bwrap_cmd_with_proxy() -> Result<(), Box<dyn error::Error>> {
+        let program = Path::new("/usr/bin/ls");
+        let arguments = ["arg1".to_string()];
+        let proxy = RunningProxy::start(&["example.org".to_string()])?;
+
+        let cmd = bwrap_cmd(
+            program,
+            &arguments,
+            false,
+            false,
+            false,
+            &[],
+            &[],
+            &[],
+            None,
+            Some(&proxy),
+            None,
+            &Overlays::default(),
+            None,
+            None,
+            &[],
+            None,
+            false,
+            true,
+            true,
+            None,
+        )?;
+
+        let args: Vec<_> = cmd.get_args().map(|s| s.to_str().unwrap()).collect();
+
+        for arg in args.iter() {
+            println!("{:?}", arg);
+        }
+
+        assert!(args.contains(&"--unshare-net"));
+        assert!(args.windows(2).any(|w| w == ["--seccomp", "3"]));
+        assert!(args.contains(&"--new-session"));
+        assert!(args.contains(&"--die-with-parent"));
+        assert!(args
+            .windows(3)
+            .any(|w| w == ["--ro-bind", proxy.dir().to_str().unwrap(), PROXY_DIR]));
+        assert!(args
+            .windows(3)
+            .any(|w| w == ["--setenv", "HTTPS_PROXY", "http://127.0.0.1:3128"]));
+
+        // The program is run via the helper, which forwards to the proxy
+        assert!(args
+            .windows(2)
+            .any(|w| w == [HELPER_PATH, "__contained-helper"]));
+        assert_eq!(&args[args.len() - 3..], &["--", "/usr/bin/ls", "arg1"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_landlock_cmd() -> Result<(), Box<dyn error::Error>> {
+        if landlock::abi_version() < 1 {
+            eprintln!("Landlock not available, skipping");
+            return Ok(());
+        }
+        let program = Path::new("env");
+        let options = LandlockOptions {
+            network: true,
+            extra_env: vec!["MY_ENV=123".to_string()],
+            ..Default::default()
+        };
+
+        let mut cmd = landlock_cmd(program, &[], &options)?;
+        let output = cmd.output()?;
+        let stdout = String::from_utf8(output.stdout)?;
+
+        assert!(output.status.success());
+        assert!(stdout.lines().any(|l| l == "MY_ENV=123"));
+        assert!(stdout.lines().any(|l| l.starts_with("PATH=")));
+        assert!(!stdout.lines().any(|l| l.starts_with("HOME=")));
+
+        // The home directory is not accessible
+        let options = LandlockOptions {
+            network: true,
+            seccomp: SeccompProfile::Unconfined,
+            ..Default::default()
+        };
+        let mut cmd = landlock_cmd(Path::new("ls"), &[env::var("HOME")?], &options)?;
+        assert!(!cmd.output()?.status.success());
+
+        // Unlike in the other sandboxes, /tmp is not private, so it is not accessible either
+        let tmp = RuntimeDir::create_in(Path::new("/tmp"), "landlock-tmp")?;
+        let file = tmp.path().join("file");
+        let mut cmd = landlock_cmd(Path::new("touch"), &[path_to_string(&file)?], &options)?;
+        assert!(!cmd.output()?.status.success());
+        assert!(!file.exists());
+
+        Ok(())
+    }
+
     #[test]
     fn test_run_image_cmd() -> Result<(), Box<dyn error::Error>> {
         let image = "test_image";
@@ -1091,6 +2752,21 @@ mod tests {
         let extra_env = ["MY_ENV=123".to_string()];
         let workdir = None;
         let x11 = false;
+        let options = SandboxOptions {
+            publish: vec!["8080:80".parse()?],
+            add_hosts: vec!["db:10.0.0.2".parse()?],
+            limits: ResourceLimits {
+                memory: Some(parse_memory("1g")?),
+                cpus: None,
+                pids_limit: Some(64),
+                timeout: Some(parse_duration("5m")?),
+            },
+            seccomp: SeccompProfile::File(PathBuf::from("/etc/profile.json")),
+            overlay_mounts: vec![PathBuf::from("/usr")],
+            ..Default::default()
+        };
+        let overlays = create_overlays(&options)?;
+        let overlay = &overlays.mounts()[0];
 
         let cmd = run_image_cmd(
             image,
@@ -1104,6 +2780,8 @@ mod tests {
             &extra_env,
             workdir,
             x11,
+            &options,
+            &overlays,
         )?;
 
         let args: Vec<_> = cmd.get_args().map(|s| s.to_str().unwrap()).collect();
@@ -1114,8 +2792,27 @@ mod tests {
 
         assert!(args.contains(&"--network=host"));
         assert!(args.contains(&image));
+        let volume = format!(
+            "/usr:/usr:O,upperdir={},workdir={}",
+            overlay.upper.display(),
+            overlay.work.display()
+        );
+        assert!(args.windows(2).any(|w| w == ["--volume", &volume]));
         assert!(args.contains(&"--entrypoint"));
         assert!(args.contains(&entrypoint.unwrap().as_str()));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--publish", "127.0.0.1:8080:80/tcp"]));
+        assert!(args.windows(2).any(|w| w == ["--add-host", "db:10.0.0.2"]));
+        assert!(args.contains(&"--memory=1073741824"));
+        assert!(args.contains(&"--pids-limit=64"));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--name", &podman_container_name()]));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--security-opt", "seccomp=/etc/profile.json"]));
+        assert!(!args.iter().any(|a| a.starts_with("--cpus")));
         assert!(args.contains(&"arg1"));
         assert!(args.contains(&"arg2"));
 
@@ -1146,6 +2843,91 @@ mod tests {
     }
 }
 
+/// Files of the system directories which the program needs, mounted with `--minimal` instead of
+/// the whole directories. The proxy helper and the shell of `--shell` run in the sandbox too.
+fn minimal_mounts(
+    minimal: bool,
+    program: &Path,
+    network: bool,
+    proxy: Option<&RunningProxy>,
+    shell: Option<&Path>,
+) -> Result<Option<Vec<PathBuf>>, anyhow::Error> {
+    if !minimal {
+        return Ok(None);
+    }
+    let helper = proxy.map(|_| helper_binary()).transpose()?;
+    let mut programs = vec![program];
+    programs.extend(helper.as_deref());
+    programs.extend(shell);
+    system_closure(&programs, network).map(Some)
+}
+
+/// The shell run by `--shell` in the sandbox of the program: `$SHELL` if it is visible there, in
+/// the system directories or the mounts, `/bin/sh` otherwise.
+fn sandbox_shell(mount_readonly: &[PathBuf], mount_writable: &[PathBuf]) -> PathBuf {
+    env::var_os("SHELL")
+        .map(PathBuf::from)
+        .filter(|shell| {
+            let mut dirs = SYSTEM_MOUNTS.iter().map(Path::new).chain(
+                mount_readonly
+                    .iter()
+                    .chain(mount_writable)
+                    .map(PathBuf::as_path),
+            );
+            shell.is_absolute() && dirs.any(|dir| shell.starts_with(dir)) && is_executable(shell)
+        })
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_SHELL))
+}
+
+/// The program and arguments run in the sandbox, the shell without arguments with `--shell`.
+fn run_program<'a>(
+    program: &'a Path,
+    arguments: &'a [String],
+    shell: Option<&'a Path>,
+) -> (&'a Path, &'a [String]) {
+    match shell {
+        Some(shell) => (shell, &[]),
+        None => (program, arguments),
+    }
+}
+
+/// Directories outside the system mounts which the program needs, mounted read-only: its own
+/// directory, and those of its interpreters and libraries.
+fn program_mounts(program: &Path, mount_current_dir: bool, current_dir: &Path) -> Vec<PathBuf> {
+    program_dependencies(program)
+        .into_iter()
+        .map(|dependency| dependency.dir)
+        .filter(|dir| !(mount_current_dir && dir == current_dir))
+        .collect()
+}
+
+/// Prints the directories mounted for the program, and why.
+fn explain_program(program: &Path) -> Result<(), anyhow::Error> {
+    let program = resolve_program(program)?;
+    let dependencies = program_dependencies(&program);
+    if dependencies.is_empty() {
+        eprintln!(
+            "contained: {} only needs the system directories",
+            program.display()
+        );
+    } else {
+        eprintln!("contained: mounting read-only for {}:", program.display());
+    }
+    let width = dependencies
+        .iter()
+        .map(|dependency| dependency.dir.as_os_str().len())
+        .max()
+        .unwrap_or_default();
+    for dependency in dependencies {
+        eprintln!(
+            "  {:width$}  {}",
+            dependency.dir.display().to_string(),
+            dependency.reason
+        );
+    }
+    Ok(())
+}
+
 fn resolve_program(program: &Path) -> Result<PathBuf, anyhow::Error> {
     let program = if !program.is_absolute() && !program.to_str().map_or(false, |s| s.contains('/'))
     {
@@ -1173,6 +2955,13 @@ fn find_in_path(program: &Path) -> Option<PathBuf> {
     })
 }
 
+fn path_to_string(path: &Path) -> Result<String, anyhow::Error> {
+    Ok(path
+        .to_str()
+        .ok_or(anyhow!("Path {:?} is not valid Unicode", path))?
+        .to_string())
+}
+
 fn is_executable(path: &Path) -> bool {
     use std::os::unix::fs::PermissionsExt;
     path.metadata()
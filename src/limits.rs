@@ -0,0 +1,533 @@
+//! # Resource limits
+//!
+//! `limits` contains limits for memory, CPU, number of processes and run time of the sandbox,
+//! and how to apply them to processes started directly (via a cgroup v2, a systemd scope or
+//! `setrlimit`).
+
+use anyhow::{anyhow, Context};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::mpsc;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::{env, fs, io, process, thread};
+
+use crate::helper::wrap_command;
+
+/// Exit code when the sandbox is killed due to timeout, like GNU `timeout`.
+pub const TIMEOUT_EXIT_CODE: u8 = 124;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CPU_PERIOD: u64 = 100_000;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceLimits {
+    /// Memory limit in bytes
+    pub memory: Option<u64>,
+    /// Number of CPUs
+    pub cpus: Option<f64>,
+    /// Maximum number of processes
+    pub pids_limit: Option<u32>,
+    /// Maximum run time, the sandbox is killed after that
+    pub timeout: Option<Duration>,
+}
+
+impl ResourceLimits {
+    fn has_resource_limits(&self) -> bool {
+        self.memory.is_some() || self.cpus.is_some() || self.pids_limit.is_some()
+    }
+}
+
+/// Parse a memory size with optional unit `b`, `k`, `m`, `g` or `t` (binary units, like `512m`).
+pub fn parse_memory(s: &str) -> Result<u64, anyhow::Error> {
+    let s = s.trim().to_ascii_lowercase();
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 'b')) => (&s[..i], 1),
+        Some((i, 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'g')) => (&s[..i], 1 << 30),
+        Some((i, 't')) => (&s[..i], 1 << 40),
+        _ => (s.as_str(), 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .filter(|n| *n > 0)
+        .ok_or(anyhow!("Invalid memory size '{s}'"))
+}
+
+/// Parse a number of CPUs, like `1.5`.
+pub fn parse_cpus(s: &str) -> Result<f64, anyhow::Error> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|cpus| cpus.is_finite() && *cpus > 0.0)
+        .ok_or(anyhow!("Invalid number of CPUs '{s}'"))
+}
+
+/// Parse a duration with optional unit `s`, `m`, `h` or `d` (default is seconds, like `90s`).
+pub fn parse_duration(s: &str) -> Result<Duration, anyhow::Error> {
+    let s = s.trim();
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .filter(|n| *n > 0)
+        .map(Duration::from_secs)
+        .ok_or(anyhow!("Invalid duration '{s}'"))
+}
+
+/// Resource limits applied to a process started directly, via a cgroup next to our own if the
+/// controllers are delegated to its parent, otherwise via a transient scope of the systemd user
+/// manager, and as a last resort via `setrlimit`.
+pub struct ProcessLimits {
+    _cgroup: Option<Cgroup>,
+    cgroup_procs: Option<CString>,
+    /// Properties of the transient systemd scope
+    scope: Vec<String>,
+    /// Resource and limit of rlimits inherited by the sandbox
+    rlimits: Vec<(libc::c_int, u64)>,
+}
+
+impl ProcessLimits {
+    pub fn new(limits: &ResourceLimits) -> Result<Self, anyhow::Error> {
+        let mut process_limits = Self {
+            _cgroup: None,
+            cgroup_procs: None,
+            scope: Vec::new(),
+            rlimits: Vec::new(),
+        };
+        if !limits.has_resource_limits() {
+            return Ok(process_limits);
+        }
+
+        if let Some(cgroup) = Cgroup::create(limits)? {
+            process_limits.cgroup_procs = Some(CString::new(
+                cgroup.path.join("cgroup.procs").as_os_str().as_bytes(),
+            )?);
+            process_limits._cgroup = Some(cgroup);
+        } else if has_systemd_user_manager() {
+            process_limits.scope = scope_properties(limits);
+        } else {
+            eprintln!(
+                "contained: no delegated cgroup v2 subtree or systemd user session, limiting \
+                 each process of the sandbox with rlimits instead"
+            );
+            if limits.cpus.is_some() && limits.timeout.is_none() {
+                eprintln!("contained: --cpus is only enforced with rlimits given a --timeout");
+            }
+            process_limits.rlimits = rlimits(limits);
+        }
+        Ok(process_limits)
+    }
+
+    /// Apply the limits to a command, before its stdio and `pre_exec` are set up.
+    pub fn apply(&self, cmd: Command) -> Command {
+        if !self.scope.is_empty() {
+            let mut scope = Command::new("systemd-run");
+            scope
+                .arg("--user")
+                .arg("--scope")
+                .arg("--quiet")
+                .arg("--collect");
+            for property in &self.scope {
+                scope.arg("--property").arg(property);
+            }
+            scope.arg("--");
+            return wrap_command(scope, &cmd);
+        }
+        if !self.rlimits.is_empty() {
+            let rlimits = self.rlimits.clone();
+            let mut cmd = cmd;
+            // SAFETY: only async-signal-safe functions are called between fork and exec
+            unsafe {
+                cmd.pre_exec(move || {
+                    for (resource, limit) in &rlimits {
+                        let rlimit = libc::rlimit {
+                            rlim_cur: *limit,
+                            rlim_max: *limit,
+                        };
+                        if libc::setrlimit(*resource as _, &rlimit) != 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+            return cmd;
+        }
+        let Some(cgroup_procs) = self.cgroup_procs.clone() else {
+            return cmd;
+        };
+        let mut cmd = cmd;
+        // SAFETY: only async-signal-safe functions are called between fork and exec
+        unsafe {
+            cmd.pre_exec(move || {
+                let fd = libc::open(cgroup_procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Writing 0 moves the writing process
+                let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+                libc::close(fd);
+                if written < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        cmd
+    }
+}
+
+/// Properties of a systemd scope with the limits.
+fn scope_properties(limits: &ResourceLimits) -> Vec<String> {
+    let mut properties = Vec::new();
+    if let Some(memory) = limits.memory {
+        properties.push(format!("MemoryMax={memory}"));
+    }
+    if let Some(cpus) = limits.cpus {
+        properties.push(format!("CPUQuota={}%", (cpus * 100.0).round() as u64));
+    }
+    if let Some(pids_limit) = limits.pids_limit {
+        properties.push(format!("TasksMax={pids_limit}"));
+    }
+    properties
+}
+
+/// Rlimits approximating the limits, applying to each process rather than the whole sandbox: the
+/// memory limits the address space, not the memory used, the CPUs limit the CPU time to what they
+/// could use until the timeout, and the number of processes counts all processes of the user.
+fn rlimits(limits: &ResourceLimits) -> Vec<(libc::c_int, u64)> {
+    let mut rlimits = Vec::new();
+    if let Some(memory) = limits.memory {
+        rlimits.push((libc::RLIMIT_AS as libc::c_int, memory));
+    }
+    if let (Some(cpus), Some(timeout)) = (limits.cpus, limits.timeout) {
+        let seconds = (cpus * timeout.as_secs_f64()).ceil() as u64;
+        rlimits.push((libc::RLIMIT_CPU as libc::c_int, seconds));
+    }
+    if let Some(pids_limit) = limits.pids_limit {
+        rlimits.push((libc::RLIMIT_NPROC as libc::c_int, pids_limit.into()));
+    }
+    rlimits
+}
+
+fn has_systemd_user_manager() -> bool {
+    env::var_os("XDG_RUNTIME_DIR")
+        .is_some_and(|dir| Path::new(&dir).join("systemd/private").exists())
+}
+
+/// A cgroup for the sandbox next to our own cgroup, removed on drop.
+struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Create a cgroup next to our own cgroup, if the needed controllers are delegated to the
+    /// parent and enabled for its children. Our own cgroup can't have children with controllers,
+    /// since a cgroup with controllers enabled for its children can't contain processes.
+    fn create(limits: &ResourceLimits) -> Result<Option<Self>, anyhow::Error> {
+        let Some(own) = own_cgroup() else {
+            return Ok(None);
+        };
+        // The root cgroup is the only one which can contain processes and children with controllers
+        let parent = match own.parent() {
+            Some(parent) if own != Path::new(CGROUP_ROOT) => parent,
+            _ => &own,
+        };
+        Self::create_in(parent, limits)
+    }
+
+    fn create_in(parent: &Path, limits: &ResourceLimits) -> Result<Option<Self>, anyhow::Error> {
+        let mut needed = Vec::new();
+        if limits.memory.is_some() {
+            needed.push("memory");
+        }
+        if limits.cpus.is_some() {
+            needed.push("cpu");
+        }
+        if limits.pids_limit.is_some() {
+            needed.push("pids");
+        }
+        let enabled = read_controllers(&parent.join("cgroup.subtree_control"));
+        if !needed.iter().all(|c| enabled.iter().any(|e| e == c)) {
+            return Ok(None);
+        }
+
+        let path = parent.join(format!("contained-{}", process::id()));
+        match fs::create_dir(&path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => return Ok(None),
+            Err(err) => return Err(err).context("Unable to create cgroup"),
+        }
+        let cgroup = Self { path };
+        write_cgroup_limits(&cgroup.path, limits)?;
+        Ok(Some(cgroup))
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+fn read_controllers(path: &Path) -> Vec<String> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(|c| c.to_string())
+        .collect()
+}
+
+fn write_cgroup_limits(cgroup: &Path, limits: &ResourceLimits) -> Result<(), anyhow::Error> {
+    if let Some(memory) = limits.memory {
+        fs::write(cgroup.join("memory.max"), memory.to_string())
+            .context("Unable to set memory limit")?;
+    }
+    if let Some(cpus) = limits.cpus {
+        let quota = (cpus * CPU_PERIOD as f64).round() as u64;
+        fs::write(cgroup.join("cpu.max"), format!("{quota} {CPU_PERIOD}"))
+            .context("Unable to set CPU limit")?;
+    }
+    if let Some(pids_limit) = limits.pids_limit {
+        fs::write(cgroup.join("pids.max"), pids_limit.to_string())
+            .context("Unable to set PIDs limit")?;
+    }
+    Ok(())
+}
+
+fn own_cgroup() -> Option<PathBuf> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    Some(Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+}
+
+/// Wait for a child process, kill it with SIGKILL if it does not exit within the timeout.
+/// Returns `None` if the process was killed due to timeout.
+pub fn wait_with_timeout(
+    child: Child,
+    timeout: Option<Duration>,
+) -> Result<Option<ExitStatus>, anyhow::Error> {
+    let Some(timeout) = timeout else {
+        let mut child = child;
+        return Ok(Some(child.wait()?));
+    };
+
+    let pid = child.id() as libc::pid_t;
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("wait".to_string())
+        .spawn(move || {
+            let mut child = child;
+            let _ = tx.send(child.wait());
+        })?;
+
+    match rx.recv_timeout(timeout) {
+        Ok(status) => Ok(Some(status?)),
+        Err(RecvTimeoutError::Timeout) => {
+            // SAFETY: the process has not been reaped yet, since the wait has not returned
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+            }
+            let _ = rx.recv();
+            Ok(None)
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(anyhow!("Unable to wait for process")),
+    }
+}
+
+/// Calls a function to kill a sandbox once its timeout has passed, unless it is finished before.
+pub struct Deadline {
+    finished: Sender<()>,
+    thread: JoinHandle<bool>,
+}
+
+impl Deadline {
+    pub fn start(timeout: Duration, kill: impl FnOnce() + Send + 'static) -> io::Result<Self> {
+        let (finished, rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("deadline".to_string())
+            .spawn(move || match rx.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) => {
+                    kill();
+                    true
+                }
+                _ => false,
+            })?;
+        Ok(Self { finished, thread })
+    }
+
+    /// Stops waiting after the sandbox exited, reports whether it was killed.
+    pub fn finish(self) -> bool {
+        let _ = self.finished.send(());
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+pub fn report_timeout(timeout: Duration) -> u8 {
+    eprintln!("contained: killed after timeout of {}s", timeout.as_secs());
+    TIMEOUT_EXIT_CODE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime_dir::RuntimeDir;
+    use std::time::Instant;
+
+    #[test]
+    fn test_parse_limits() {
+        assert_eq!(parse_memory("512m").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_memory("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_memory("1000").unwrap(), 1000);
+        assert!(parse_memory("0").is_err());
+        assert!(parse_memory("m").is_err());
+        assert!(parse_memory("1.5g").is_err());
+
+        assert_eq!(parse_cpus("1.5").unwrap(), 1.5);
+        assert!(parse_cpus("0").is_err());
+        assert!(parse_cpus("-1").is_err());
+
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert!(parse_duration("1x").is_err());
+    }
+
+    #[test]
+    fn test_wait_with_timeout() -> Result<(), anyhow::Error> {
+        let child = Command::new("sleep").arg("10").spawn()?;
+        let start = Instant::now();
+        let status = wait_with_timeout(child, Some(Duration::from_millis(100)))?;
+        assert!(status.is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let child = Command::new("true").spawn()?;
+        let status = wait_with_timeout(child, Some(Duration::from_secs(10)))?;
+        assert!(status.is_some_and(|s| s.success()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_deadline() -> Result<(), anyhow::Error> {
+        let (tx, rx) = mpsc::channel();
+        let deadline = Deadline::start(Duration::from_millis(10), move || tx.send(()).unwrap())?;
+        rx.recv_timeout(Duration::from_secs(5))?;
+        assert!(deadline.finish());
+
+        let deadline = Deadline::start(Duration::from_secs(60), || panic!("killed"))?;
+        assert!(!deadline.finish());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope() {
+        let limits = ProcessLimits {
+            _cgroup: None,
+            cgroup_procs: None,
+            scope: scope_properties(&ResourceLimits {
+                memory: Some(512 * 1024 * 1024),
+                cpus: Some(1.5),
+                pids_limit: Some(100),
+                timeout: None,
+            }),
+            rlimits: Vec::new(),
+        };
+        let mut cmd = Command::new("bwrap");
+        cmd.arg("--unshare-all").env("LANG", "C");
+        let cmd = limits.apply(cmd);
+        let args = cmd
+            .get_args()
+            .map(|s| s.to_str().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(cmd.get_program(), "systemd-run");
+        assert!(args.starts_with(&["--user", "--scope"]));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--property", "MemoryMax=536870912"]));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--property", "CPUQuota=150%"]));
+        assert!(args.windows(2).any(|w| w == ["--property", "TasksMax=100"]));
+        assert!(args.ends_with(&["--", "bwrap", "--unshare-all"]));
+        assert_eq!(cmd.get_envs().count(), 1);
+    }
+
+    #[test]
+    fn test_rlimits() -> Result<(), anyhow::Error> {
+        let limits = ProcessLimits {
+            _cgroup: None,
+            cgroup_procs: None,
+            scope: Vec::new(),
+            rlimits: rlimits(&ResourceLimits {
+                memory: Some(512 * 1024 * 1024),
+                cpus: Some(1.5),
+                pids_limit: Some(100),
+                timeout: Some(Duration::from_secs(9)),
+            }),
+        };
+        let mut cmd = Command::new("cat");
+        cmd.arg("/proc/self/limits");
+        let output = limits.apply(cmd).output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        let limit = |name: &str| {
+            let line = stdout.lines().find(|line| line.starts_with(name));
+            line.and_then(|line| line[name.len()..].split_whitespace().next())
+        };
+        assert_eq!(limit("Max address space"), Some("536870912"));
+        assert_eq!(limit("Max cpu time"), Some("14"));
+        assert_eq!(limit("Max processes"), Some("100"));
+
+        // The CPUs are not limited without a timeout
+        let rlimits = rlimits(&ResourceLimits {
+            cpus: Some(1.5),
+            ..Default::default()
+        });
+        assert!(rlimits.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cgroup() -> Result<(), anyhow::Error> {
+        let dir = RuntimeDir::new("cgroup")?;
+        let limits = ResourceLimits {
+            memory: Some(512 * 1024 * 1024),
+            pids_limit: Some(100),
+            ..Default::default()
+        };
+        fs::write(dir.path().join("cgroup.subtree_control"), "cpu pids\n")?;
+        assert!(Cgroup::create_in(dir.path(), &limits)?.is_none());
+
+        fs::write(
+            dir.path().join("cgroup.subtree_control"),
+            "cpu memory pids\n",
+        )?;
+        let cgroup = Cgroup::create_in(dir.path(), &limits)?.expect("cgroup");
+        assert_eq!(
+            cgroup.path,
+            dir.path().join(format!("contained-{}", process::id()))
+        );
+        assert_eq!(
+            fs::read_to_string(cgroup.path.join("memory.max"))?,
+            "536870912"
+        );
+        assert_eq!(fs::read_to_string(cgroup.path.join("pids.max"))?, "100");
+        assert!(!cgroup.path.join("cpu.max").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,883 @@
+//! # Namespace sandbox
+//!
+//! `namespace` contains a built-in replacement for bubblewrap, which sets up the sandbox
+//! described by a bubblewrap command line with user, mount, PID, IPC, UTS and network namespaces.
+//!
+//! It runs in two stages of the sandbox helper: the first creates the namespaces, the second runs
+//! as PID 1 of the new PID namespace, builds the root file system and runs the program.
+
+use anyhow::{anyhow, Context};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::{env, fs, io};
+
+use crate::helper::{exit_code, helper_command};
+use crate::overlay::escape_option;
+use crate::seccomp;
+
+/// Where the new root is built, before it is moved to `/`.
+const BASE_DIR: &str = "/tmp";
+const NEW_ROOT: &str = "/newroot";
+const OLD_ROOT: &str = "/oldroot";
+
+const DEV_DEVICES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+const DEV_SYMLINKS: [(&str, &str); 6] = [
+    ("/proc/self/fd", "fd"),
+    ("/proc/self/fd/0", "stdin"),
+    ("/proc/self/fd/1", "stdout"),
+    ("/proc/self/fd/2", "stderr"),
+    ("/proc/kcore", "core"),
+    ("pts/ptmx", "ptmx"),
+];
+/// Parts of `/proc` which are made read-only, like bubblewrap does.
+const PROC_READONLY: [&str; 4] = ["sys", "sysrq-trigger", "irq", "bus"];
+
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+const MOUNT_ATTR_NOSUID: u64 = 0x2;
+const MOUNT_ATTR_NODEV: u64 = 0x4;
+
+/// `_LINUX_CAPABILITY_VERSION_3`, with 64 bits of capabilities in two data structures.
+const CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Operation {
+    Bind {
+        source: PathBuf,
+        dest: PathBuf,
+        readonly: bool,
+    },
+    Symlink {
+        target: PathBuf,
+        dest: PathBuf,
+    },
+    Tmpfs {
+        dest: PathBuf,
+        mode: u32,
+    },
+    Overlay {
+        /// Lower layers, the last one is the top
+        sources: Vec<PathBuf>,
+        upper: PathBuf,
+        work: PathBuf,
+        dest: PathBuf,
+    },
+    Proc {
+        dest: PathBuf,
+    },
+    Dev {
+        dest: PathBuf,
+    },
+}
+
+/// The sandbox described by a bubblewrap command line, supporting the options used by `wrapped`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SandboxSpec {
+    operations: Vec<Operation>,
+    unshare: libc::c_int,
+    try_unshare_cgroup: bool,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    chdir: Option<PathBuf>,
+    clear_env: bool,
+    env: Vec<(OsString, OsString)>,
+    die_with_parent: bool,
+    new_session: bool,
+    seccomp_fd: Option<i32>,
+    command: Vec<OsString>,
+}
+
+impl SandboxSpec {
+    pub fn parse(args: &[OsString]) -> Result<Self, anyhow::Error> {
+        let mut spec = Self::default();
+        let mut perms = None;
+        let mut overlay_sources = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or(anyhow!("Missing value for {name}"))
+                    .map(PathBuf::from)
+            };
+            match arg.to_str() {
+                Some(option @ ("--ro-bind" | "--bind")) => spec.operations.push(Operation::Bind {
+                    source: value(option)?,
+                    dest: value(option)?,
+                    readonly: option == "--ro-bind",
+                }),
+                Some("--symlink") => spec.operations.push(Operation::Symlink {
+                    target: value("--symlink")?,
+                    dest: value("--symlink")?,
+                }),
+                Some("--perms") => {
+                    let mode = value("--perms")?;
+                    perms = Some(
+                        mode.to_str()
+                            .and_then(|m| u32::from_str_radix(m, 8).ok())
+                            .ok_or(anyhow!("Invalid permissions {mode:?}"))?,
+                    );
+                    continue;
+                }
+                Some("--tmpfs") => spec.operations.push(Operation::Tmpfs {
+                    dest: value("--tmpfs")?,
+                    mode: perms.take().unwrap_or(0o755),
+                }),
+                Some("--overlay-src") => {
+                    overlay_sources.push(value("--overlay-src")?);
+                    continue;
+                }
+                Some("--overlay") => spec.operations.push(Operation::Overlay {
+                    sources: std::mem::take(&mut overlay_sources),
+                    upper: value("--overlay")?,
+                    work: value("--overlay")?,
+                    dest: value("--overlay")?,
+                }),
+                Some("--proc") => spec.operations.push(Operation::Proc {
+                    dest: value("--proc")?,
+                }),
+                Some("--dev") => spec.operations.push(Operation::Dev {
+                    dest: value("--dev")?,
+                }),
+                Some("--chdir") => spec.chdir = Some(value("--chdir")?),
+                Some("--clearenv") => spec.clear_env = true,
+                Some("--setenv") => {
+                    let key = value("--setenv")?.into_os_string();
+                    let value = value("--setenv")?.into_os_string();
+                    spec.env.push((key, value));
+                }
+                Some("--unshare-user") => spec.unshare |= libc::CLONE_NEWUSER,
+                Some("--unshare-ipc") => spec.unshare |= libc::CLONE_NEWIPC,
+                Some("--unshare-pid") => spec.unshare |= libc::CLONE_NEWPID,
+                Some("--unshare-net") => spec.unshare |= libc::CLONE_NEWNET,
+                Some("--unshare-uts") => spec.unshare |= libc::CLONE_NEWUTS,
+                Some("--unshare-cgroup-try") => spec.try_unshare_cgroup = true,
+                Some(option @ ("--uid" | "--gid")) => {
+                    let id = value(option)?;
+                    let id = id
+                        .to_str()
+                        .and_then(|id| id.parse::<u32>().ok())
+                        .ok_or(anyhow!("Invalid {option} {id:?}"))?;
+                    if option == "--uid" {
+                        spec.uid = Some(id);
+                    } else {
+                        spec.gid = Some(id);
+                    }
+                }
+                Some("--die-with-parent") => spec.die_with_parent = true,
+                Some("--new-session") => spec.new_session = true,
+                Some("--seccomp") => {
+                    let fd = value("--seccomp")?;
+                    spec.seccomp_fd = Some(
+                        fd.to_str()
+                            .and_then(|fd| fd.parse::<i32>().ok())
+                            .ok_or(anyhow!("Invalid file descriptor {fd:?}"))?,
+                    );
+                }
+                Some("--") => {
+                    spec.command = args.cloned().collect();
+                    break;
+                }
+                Some(option) if option.starts_with("--") => {
+                    return Err(anyhow!("Unsupported sandbox option {option}"))
+                }
+                _ => {
+                    spec.command = std::iter::once(arg).chain(args).cloned().collect();
+                    break;
+                }
+            }
+            if perms.is_some() {
+                return Err(anyhow!("--perms is only supported for --tmpfs"));
+            }
+            if !overlay_sources.is_empty() {
+                return Err(anyhow!("--overlay-src is only supported for --overlay"));
+            }
+        }
+
+        if spec.unshare & libc::CLONE_NEWUSER == 0 {
+            return Err(anyhow!("The namespace sandbox requires --unshare-user"));
+        }
+        if spec.command.is_empty() {
+            return Err(anyhow!("Missing program"));
+        }
+        Ok(spec)
+    }
+}
+
+/// First stage: create the namespaces, and run the second stage in them.
+/// Must be called while the process is single-threaded.
+pub fn run(args: Vec<OsString>) -> Result<u8, anyhow::Error> {
+    let spec = SandboxSpec::parse(&args)?;
+
+    // SAFETY: plain system calls without pointers
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let flags = spec.unshare | libc::CLONE_NEWNS;
+    let unshared = spec.try_unshare_cgroup && unshare(flags | libc::CLONE_NEWCGROUP).is_ok();
+    if !unshared {
+        unshare(flags).context("Unable to create namespaces")?;
+    }
+    fs::write("/proc/self/setgroups", "deny")
+        .and_then(|_| fs::write("/proc/self/uid_map", format!("0 {uid} 1\n")))
+        .and_then(|_| fs::write("/proc/self/gid_map", format!("0 {gid} 1\n")))
+        .context("Unable to map user in namespace")?;
+
+    let mut init = helper_command("namespace-init")?;
+    init.arg(uid.to_string()).arg(gid.to_string()).args(args);
+    if spec.die_with_parent {
+        // SAFETY: prctl is async-signal-safe
+        unsafe {
+            init.pre_exec(|| {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    let status = init.status().context("Unable to start sandbox")?;
+    Ok(exit_code(status))
+}
+
+/// Second stage, PID 1 in the new PID namespace: set up the root file system, drop privileges,
+/// run the program and reap all processes until it exits.
+pub fn init(args: Vec<OsString>) -> Result<u8, anyhow::Error> {
+    let parse_id = |id: Option<&OsString>| {
+        id.and_then(|id| id.to_str()?.parse::<u32>().ok())
+            .ok_or(anyhow!("Invalid user"))
+    };
+    let outer_uid = parse_id(args.first())?;
+    let outer_gid = parse_id(args.get(1))?;
+    let spec = SandboxSpec::parse(&args[2..])?;
+    let seccomp = match spec.seccomp_fd {
+        // SAFETY: the file descriptor was passed on to us and is owned by nothing else
+        Some(fd) => Some(
+            seccomp::read_program(unsafe { File::from_raw_fd(fd) })
+                .context("Unable to read seccomp filter")?,
+        ),
+        None => None,
+    };
+
+    let cwd = env::current_dir().ok();
+    if spec.unshare & libc::CLONE_NEWNET != 0 {
+        // Needs the capabilities of the user namespace owning the network namespace
+        loopback_up().context("Unable to bring up the loopback interface")?;
+    }
+    let proc = setup_root(&spec).context("Unable to set up sandbox root")?;
+
+    unshare(libc::CLONE_NEWUSER).context("Unable to create user namespace")?;
+    write_at(&proc, "self/setgroups", "deny")
+        .and_then(|_| {
+            write_at(
+                &proc,
+                "self/uid_map",
+                &format!("{} 0 1\n", spec.uid.unwrap_or(outer_uid)),
+            )
+        })
+        .and_then(|_| {
+            write_at(
+                &proc,
+                "self/gid_map",
+                &format!("{} 0 1\n", spec.gid.unwrap_or(outer_gid)),
+            )
+        })
+        .context("Unable to map user in sandbox")?;
+    drop(proc);
+
+    // SAFETY: plain system call without pointers
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error()).context("Unable to set no_new_privs");
+    }
+
+    let workdir = spec
+        .chdir
+        .clone()
+        .or(cwd)
+        .filter(|dir| env::set_current_dir(dir).is_ok());
+    if workdir.is_none() {
+        env::set_current_dir("/")?;
+    }
+    drop_capabilities().context("Unable to drop capabilities")?;
+
+    let mut cmd = Command::new(&spec.command[0]);
+    cmd.args(&spec.command[1..]);
+    if spec.clear_env {
+        cmd.env_clear();
+    }
+    cmd.envs(spec.env.iter().map(|(k, v)| (k, v)));
+    if spec.new_session {
+        // SAFETY: setsid is async-signal-safe
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    if let Some(program) = seccomp {
+        // SAFETY: installing the filter only calls async-signal-safe functions
+        unsafe {
+            cmd.pre_exec(move || seccomp::install(&program));
+        }
+    }
+    let child = cmd
+        .spawn()
+        .context(format!("Unable to run {:?}", spec.command[0]))?;
+
+    loop {
+        let mut status = 0;
+        // SAFETY: the status pointer is valid
+        let pid = unsafe { libc::waitpid(-1, &mut status, 0) };
+        if pid < 0 {
+            return Err(io::Error::last_os_error()).context("Unable to wait for program");
+        }
+        if pid as u32 == child.id() {
+            return Ok(exit_code(ExitStatus::from_raw(status)));
+        }
+    }
+}
+
+/// Build the new root with the operations of the spec and move it to `/`.
+/// Returns the host `/proc`, still needed to set up the user namespace of the sandbox.
+fn setup_root(spec: &SandboxSpec) -> Result<OwnedFd, anyhow::Error> {
+    // Resolve symlinks in the sources while the host file system is still at `/`
+    let operations = spec
+        .operations
+        .iter()
+        .map(|op| match op {
+            Operation::Bind {
+                source,
+                dest,
+                readonly,
+            } => Ok(Operation::Bind {
+                source: fs::canonicalize(source)
+                    .with_context(|| format!("Bind source {source:?} not found"))?,
+                dest: dest.clone(),
+                readonly: *readonly,
+            }),
+            Operation::Overlay {
+                sources,
+                upper,
+                work,
+                dest,
+            } => {
+                let canonicalize = |path: &PathBuf| {
+                    fs::canonicalize(path)
+                        .with_context(|| format!("Overlay directory {path:?} not found"))
+                };
+                Ok(Operation::Overlay {
+                    sources: sources.iter().map(canonicalize).collect::<Result<_, _>>()?,
+                    upper: canonicalize(upper)?,
+                    work: canonicalize(work)?,
+                    dest: dest.clone(),
+                })
+            }
+            op => Ok(op.clone()),
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    let host_tty = fs::read_link("/proc/self/fd/1")
+        .ok()
+        .filter(|tty| tty.starts_with("/dev/pts") || tty.starts_with("/dev/tty"));
+
+    mount(
+        None,
+        Path::new("/"),
+        None,
+        libc::MS_SLAVE | libc::MS_REC,
+        None,
+    )?;
+    let base = Path::new(BASE_DIR);
+    mount(
+        Some(Path::new("tmpfs")),
+        base,
+        Some("tmpfs"),
+        libc::MS_NODEV | libc::MS_NOSUID,
+        Some("mode=0755"),
+    )?;
+    env::set_current_dir(base)?;
+    let new_root = base.join(&NEW_ROOT[1..]);
+    fs::create_dir(&new_root)?;
+    mount(
+        Some(&new_root),
+        &new_root,
+        None,
+        libc::MS_BIND | libc::MS_REC,
+        None,
+    )?;
+    fs::create_dir(base.join(&OLD_ROOT[1..]))?;
+    pivot_root(Path::new("."), Path::new(&OLD_ROOT[1..]))?;
+    env::set_current_dir("/")?;
+
+    for op in &operations {
+        apply(op).with_context(|| format!("Unable to set up {op:?}"))?;
+    }
+    if let Some(tty) = host_tty {
+        let console = in_new_root(Path::new("/dev/console"));
+        if console.parent().is_some_and(|dev| dev.is_dir()) {
+            File::create(&console)?;
+            bind_mount(&in_old_root(&tty), &console, MOUNT_ATTR_NOSUID)?;
+        }
+    }
+
+    let proc = open(
+        Path::new(OLD_ROOT).join("proc"),
+        libc::O_PATH | libc::O_DIRECTORY,
+    )?;
+    umount(Path::new(OLD_ROOT))?;
+    fs::remove_dir(OLD_ROOT)?;
+    env::set_current_dir(NEW_ROOT)?;
+    pivot_root(Path::new("."), Path::new("."))?;
+    umount(Path::new("."))?;
+    env::set_current_dir("/")?;
+    Ok(proc)
+}
+
+fn apply(op: &Operation) -> Result<(), anyhow::Error> {
+    match op {
+        Operation::Bind {
+            source,
+            dest,
+            readonly,
+        } => {
+            let source = in_old_root(source);
+            let dest = in_new_root(dest);
+            create_mount_point(&dest, source.is_dir())?;
+            let mut attrs = MOUNT_ATTR_NOSUID | MOUNT_ATTR_NODEV;
+            if *readonly {
+                attrs |= MOUNT_ATTR_RDONLY;
+            }
+            bind_mount(&source, &dest, attrs)
+        }
+        Operation::Symlink { target, dest } => {
+            let dest = in_new_root(dest);
+            if dest.symlink_metadata().is_err() {
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                symlink(target, dest)?;
+            }
+            Ok(())
+        }
+        Operation::Tmpfs { dest, mode } => {
+            let dest = in_new_root(dest);
+            fs::create_dir_all(&dest)?;
+            mount(
+                Some(Path::new("tmpfs")),
+                &dest,
+                Some("tmpfs"),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                Some(&format!("mode={mode:o}")),
+            )
+        }
+        Operation::Overlay {
+            sources,
+            upper,
+            work,
+            dest,
+        } => {
+            let lower = sources
+                .iter()
+                .rev()
+                .map(|source| escape_option(&in_old_root(source)))
+                .collect::<Vec<_>>()
+                .join(":");
+            let dest = in_new_root(dest);
+            fs::create_dir_all(&dest)?;
+            mount(
+                Some(Path::new("overlay")),
+                &dest,
+                Some("overlay"),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                Some(&format!(
+                    "lowerdir={lower},upperdir={},workdir={},userxattr",
+                    escape_option(&in_old_root(upper)),
+                    escape_option(&in_old_root(work))
+                )),
+            )
+        }
+        Operation::Proc { dest } => {
+            let dest = in_new_root(dest);
+            fs::create_dir_all(&dest)?;
+            mount(
+                Some(Path::new("proc")),
+                &dest,
+                Some("proc"),
+                libc::MS_NOSUID | libc::MS_NOEXEC | libc::MS_NODEV,
+                None,
+            )?;
+            for path in PROC_READONLY {
+                let path = dest.join(path);
+                if path.exists() {
+                    bind_mount(
+                        &path,
+                        &path,
+                        MOUNT_ATTR_RDONLY | MOUNT_ATTR_NOSUID | MOUNT_ATTR_NODEV,
+                    )?;
+                }
+            }
+            Ok(())
+        }
+        Operation::Dev { dest } => {
+            let dest = in_new_root(dest);
+            fs::create_dir_all(&dest)?;
+            mount(
+                Some(Path::new("tmpfs")),
+                &dest,
+                Some("tmpfs"),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                Some("mode=0755"),
+            )?;
+            for device in DEV_DEVICES {
+                let source = in_old_root(&Path::new("/dev").join(device));
+                if source.exists() {
+                    File::create(dest.join(device))?;
+                    bind_mount(&source, &dest.join(device), MOUNT_ATTR_NOSUID)?;
+                }
+            }
+            for (target, name) in DEV_SYMLINKS {
+                symlink(target, dest.join(name))?;
+            }
+            fs::create_dir(dest.join("shm"))?;
+            fs::create_dir(dest.join("pts"))?;
+            mount(
+                Some(Path::new("devpts")),
+                &dest.join("pts"),
+                Some("devpts"),
+                libc::MS_NOSUID | libc::MS_NOEXEC,
+                Some("newinstance,ptmxmode=0666,mode=620"),
+            )
+        }
+    }
+}
+
+fn in_new_root(path: &Path) -> PathBuf {
+    Path::new(NEW_ROOT).join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn in_old_root(path: &Path) -> PathBuf {
+    Path::new(OLD_ROOT).join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn create_mount_point(path: &Path, is_dir: bool) -> io::Result<()> {
+    if is_dir {
+        fs::create_dir_all(path)
+    } else {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if !path.exists() {
+            File::create(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bring up `lo` in the new network namespace, like bubblewrap does.
+fn loopback_up() -> io::Result<()> {
+    // SAFETY: plain system call without pointers
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor was just opened and is owned by nothing else
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    // SAFETY: the structure is valid when zeroed
+    let mut request = unsafe { std::mem::zeroed::<libc::ifreq>() };
+    for (dest, byte) in request.ifr_name.iter_mut().zip(b"lo") {
+        *dest = *byte as libc::c_char;
+    }
+    // SAFETY: the structure is valid for this request, and has a null-terminated name
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS as _, &mut request) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: SIOCGIFFLAGS filled in the flags
+    unsafe { request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+    // SAFETY: the structure is valid for this request, and has a null-terminated name
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS as _, &request) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Drop all capabilities, from the bounding, ambient and the other sets, like bubblewrap does.
+fn drop_capabilities() -> io::Result<()> {
+    for capability in 0.. {
+        // SAFETY: plain system call without pointers
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, capability, 0, 0, 0) } != 0 {
+            let error = io::Error::last_os_error();
+            // After the last capability of the kernel
+            if error.raw_os_error() == Some(libc::EINVAL) && capability > 0 {
+                break;
+            }
+            return Err(error);
+        }
+    }
+    // SAFETY: plain system call without pointers
+    let cleared = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    };
+    if cleared != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let header = CapHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [CapData::default(); 2];
+    // SAFETY: the header and the two data structures of version 3 are valid for reading
+    if unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub(crate) fn unshare(flags: libc::c_int) -> io::Result<()> {
+    // SAFETY: plain system call without pointers
+    if unsafe { libc::unshare(flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+pub(crate) fn mount(
+    source: Option<&Path>,
+    target: &Path,
+    fstype: Option<&str>,
+    flags: libc::c_ulong,
+    data: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let source = source.map(c_path).transpose()?;
+    let target_c = c_path(target)?;
+    let fstype = fstype.map(CString::new).transpose()?;
+    let data = data.map(CString::new).transpose()?;
+    // SAFETY: all pointers are null or valid C strings
+    let result = unsafe {
+        libc::mount(
+            source.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            target_c.as_ptr(),
+            fstype.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            flags,
+            data.as_ref()
+                .map_or(std::ptr::null(), |s| s.as_ptr().cast()),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Unable to mount {target:?}"));
+    }
+    Ok(())
+}
+
+/// Bind mount recursively, and set the attributes on all mounts.
+fn bind_mount(source: &Path, dest: &Path, attrs: u64) -> Result<(), anyhow::Error> {
+    mount(Some(source), dest, None, libc::MS_BIND | libc::MS_REC, None)?;
+    let dest_c = c_path(dest)?;
+    let attr = MountAttr {
+        attr_set: attrs,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    // SAFETY: the path is a valid C string and the attribute is valid for its size
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            dest_c.as_ptr(),
+            libc::AT_RECURSIVE,
+            &attr as *const MountAttr,
+            size_of::<MountAttr>(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Unable to set mount attributes of {dest:?}"));
+    }
+    Ok(())
+}
+
+fn umount(target: &Path) -> Result<(), anyhow::Error> {
+    let target_c = c_path(target)?;
+    // SAFETY: the path is a valid C string
+    if unsafe { libc::umount2(target_c.as_ptr(), libc::MNT_DETACH) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Unable to unmount {target:?}"));
+    }
+    Ok(())
+}
+
+fn pivot_root(new_root: &Path, put_old: &Path) -> Result<(), anyhow::Error> {
+    let new_root = c_path(new_root)?;
+    let put_old = c_path(put_old)?;
+    // SAFETY: the paths are valid C strings
+    if unsafe { libc::syscall(libc::SYS_pivot_root, new_root.as_ptr(), put_old.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error()).context("Unable to change root");
+    }
+    Ok(())
+}
+
+fn open(path: PathBuf, flags: libc::c_int) -> io::Result<OwnedFd> {
+    let path = c_path(&path)?;
+    // SAFETY: the path is a valid C string
+    let fd = unsafe { libc::open(path.as_ptr(), flags | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor was just opened and is owned by nothing else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn write_at(dir: &OwnedFd, path: &str, content: &str) -> io::Result<()> {
+    let path = CString::new(OsStr::new(path).as_bytes())?;
+    // SAFETY: the path is a valid C string
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            path.as_ptr(),
+            libc::O_WRONLY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor was just opened and is owned by nothing else
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(content.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_spec() -> Result<(), anyhow::Error> {
+        let args = [
+            "--ro-bind",
+            "/usr",
+            "/usr",
+            "--symlink",
+            "/usr/lib",
+            "/lib",
+            "--perms",
+            "1777",
+            "--tmpfs",
+            "/tmp",
+            "--overlay-src",
+            "/src",
+            "--overlay",
+            "/upper",
+            "/work",
+            "/src",
+            "--proc",
+            "/proc",
+            "--dev",
+            "/dev",
+            "--clearenv",
+            "--setenv",
+            "PATH",
+            "/usr/bin",
+            "--unshare-user",
+            "--unshare-pid",
+            "--unshare-net",
+            "--unshare-cgroup-try",
+            "--uid",
+            "1000",
+            "--seccomp",
+            "3",
+            "--new-session",
+            "/usr/bin/ls",
+            "-l",
+            "--all",
+        ]
+        .map(OsString::from);
+
+        let spec = SandboxSpec::parse(&args)?;
+
+        assert_eq!(
+            spec.operations,
+            [
+                Operation::Bind {
+                    source: "/usr".into(),
+                    dest: "/usr".into(),
+                    readonly: true
+                },
+                Operation::Symlink {
+                    target: "/usr/lib".into(),
+                    dest: "/lib".into()
+                },
+                Operation::Tmpfs {
+                    dest: "/tmp".into(),
+                    mode: 0o1777
+                },
+                Operation::Overlay {
+                    sources: vec!["/src".into()],
+                    upper: "/upper".into(),
+                    work: "/work".into(),
+                    dest: "/src".into()
+                },
+                Operation::Proc {
+                    dest: "/proc".into()
+                },
+                Operation::Dev {
+                    dest: "/dev".into()
+                },
+            ]
+        );
+        assert_eq!(
+            spec.unshare,
+            libc::CLONE_NEWUSER | libc::CLONE_NEWPID | libc::CLONE_NEWNET
+        );
+        assert!(spec.try_unshare_cgroup);
+        assert!(spec.clear_env);
+        assert_eq!(spec.env, [("PATH".into(), "/usr/bin".into())]);
+        assert_eq!(spec.uid, Some(1000));
+        assert_eq!(spec.seccomp_fd, Some(3));
+        assert!(spec.new_session);
+        assert_eq!(
+            spec.command,
+            ["/usr/bin/ls", "-l", "--all"].map(OsString::from)
+        );
+
+        assert!(SandboxSpec::parse(&["/usr/bin/ls".into()]).is_err());
+        assert!(SandboxSpec::parse(&["--unshare-user".into(), "--overlay".into()]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+//! # Network
+//!
+//! `network` contains port publishing and user-mode networking with
+//! [pasta](https://passt.top/).
+
+use anyhow::anyhow;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::{fs, io};
+
+use crate::helper::wrap_command;
+use crate::runtime_dir::RuntimeDir;
+
+/// Address inside the sandbox where pasta forwards DNS queries to the host's name server.
+const PASTA_DNS: &str = "169.254.1.1";
+
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// A port published from the host to the sandbox, `[IP:]HOST:CONTAINER[/PROTOCOL]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortMapping {
+    pub host_ip: Option<IpAddr>,
+    pub host_port: u16,
+    pub container_port: u16,
+    pub protocol: Protocol,
+}
+
+impl PortMapping {
+    /// The host address to bind to, defaults to the loopback address.
+    pub fn host_ip(&self) -> IpAddr {
+        self.host_ip.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+}
+
+impl FromStr for PortMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ports, protocol) = match s.rsplit_once('/') {
+            Some((ports, "tcp")) => (ports, Protocol::Tcp),
+            Some((ports, "udp")) => (ports, Protocol::Udp),
+            Some((_, protocol)) => return Err(anyhow!("Unsupported protocol '{protocol}'")),
+            None => (s, Protocol::Tcp),
+        };
+        let (host, container_port) = ports
+            .rsplit_once(':')
+            .ok_or(anyhow!("Expected [IP:]HOST:CONTAINER, got '{s}'"))?;
+        let (host_ip, host_port) = match host.rsplit_once(':') {
+            Some((ip, port)) => {
+                let ip = ip.trim_start_matches('[').trim_end_matches(']');
+                let ip = ip
+                    .parse::<IpAddr>()
+                    .map_err(|_| anyhow!("Invalid IP address '{ip}'"))?;
+                (Some(ip), port)
+            }
+            None => (None, host),
+        };
+        Ok(Self {
+            host_ip,
+            host_port: parse_port(host_port)?,
+            container_port: parse_port(container_port)?,
+            protocol,
+        })
+    }
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host_ip() {
+            IpAddr::V4(ip) => write!(f, "{ip}")?,
+            IpAddr::V6(ip) => write!(f, "[{ip}]")?,
+        }
+        write!(
+            f,
+            ":{}:{}/{}",
+            self.host_port, self.container_port, self.protocol
+        )
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16, anyhow::Error> {
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(anyhow!("Invalid port '{port}'")),
+    }
+}
+
+/// An additional entry for `/etc/hosts`, `NAME:IP`. The IP can be `host-gateway`
+/// to refer to the host, when supported by the container engine.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostAlias {
+    pub name: String,
+    pub address: String,
+}
+
+impl FromStr for HostAlias {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, address) = s
+            .split_once(':')
+            .ok_or(anyhow!("Expected NAME:IP, got '{s}'"))?;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        {
+            return Err(anyhow!("Invalid host name '{name}'"));
+        }
+        if address != "host-gateway" && address.parse::<IpAddr>().is_err() {
+            return Err(anyhow!("Invalid IP address '{address}'"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            address: address.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for HostAlias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, self.address)
+    }
+}
+
+/// User-mode networking with pasta, which runs the sandbox in its own network namespace
+/// with outbound NAT, but without access to services on the host loopback interface.
+pub struct PastaNetwork {
+    dir: RuntimeDir,
+    publish: Vec<PortMapping>,
+}
+
+impl PastaNetwork {
+    pub fn new(publish: &[PortMapping]) -> io::Result<Self> {
+        let dir = RuntimeDir::new("network")?;
+        fs::write(
+            dir.path().join("resolv.conf"),
+            format!("nameserver {PASTA_DNS}\n"),
+        )?;
+        Ok(Self {
+            dir,
+            publish: publish.to_vec(),
+        })
+    }
+
+    /// A `resolv.conf` using the DNS forwarder of pasta, to be mounted at [resolv_conf_target].
+    pub fn resolv_conf(&self) -> PathBuf {
+        self.dir.path().join("resolv.conf")
+    }
+
+    /// Command running `inner` in a new network namespace set up by pasta. pasta closes inherited
+    /// file descriptors other than stdio, so the sandbox has to open any others itself.
+    pub fn command(&self, inner: &Command) -> Command {
+        let mut cmd = Command::new("pasta");
+        cmd.arg("--config-net")
+            .arg("--quiet")
+            .arg("--no-map-gw")
+            .arg("--dns-forward")
+            .arg(PASTA_DNS)
+            .arg("-T")
+            .arg("none")
+            .arg("-U")
+            .arg("none");
+
+        for protocol in [Protocol::Tcp, Protocol::Udp] {
+            let flag = match protocol {
+                Protocol::Tcp => "-t",
+                Protocol::Udp => "-u",
+            };
+            let mappings = self
+                .publish
+                .iter()
+                .filter(|p| p.protocol == protocol)
+                .collect::<Vec<_>>();
+            if mappings.is_empty() {
+                cmd.arg(flag).arg("none");
+            }
+            for mapping in mappings {
+                cmd.arg(flag).arg(format!(
+                    "{}/{}:{}",
+                    mapping.host_ip(),
+                    mapping.host_port,
+                    mapping.container_port
+                ));
+            }
+        }
+
+        cmd.arg("--");
+        wrap_command(cmd, inner)
+    }
+}
+
+/// Where `resolv.conf` needs to be mounted, following the symlink `/etc/resolv.conf` usually is.
+pub fn resolv_conf_target() -> PathBuf {
+    fs::canonicalize(RESOLV_CONF).unwrap_or_else(|_| Path::new(RESOLV_CONF).to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn test_parse_port_mapping() {
+        assert_eq!(
+            "8080:80".parse::<PortMapping>().unwrap(),
+            PortMapping {
+                host_ip: None,
+                host_port: 8080,
+                container_port: 80,
+                protocol: Protocol::Tcp,
+            }
+        );
+        let mapping = "0.0.0.0:5353:53/udp".parse::<PortMapping>().unwrap();
+        assert_eq!(mapping.host_ip(), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(mapping.protocol, Protocol::Udp);
+        let mapping = "[::1]:8080:80".parse::<PortMapping>().unwrap();
+        assert_eq!(mapping.host_ip(), IpAddr::V6(Ipv6Addr::LOCALHOST));
+
+        assert!("80".parse::<PortMapping>().is_err());
+        assert!("8080:80/sctp".parse::<PortMapping>().is_err());
+        assert!("8080:0".parse::<PortMapping>().is_err());
+        assert!("localhost:8080:80".parse::<PortMapping>().is_err());
+
+        assert_eq!(
+            "8080:80".parse::<PortMapping>().unwrap().to_string(),
+            "127.0.0.1:8080:80/tcp"
+        );
+        assert_eq!(
+            "[::1]:5353:53/udp"
+                .parse::<PortMapping>()
+                .unwrap()
+                .to_string(),
+            "[::1]:5353:53/udp"
+        );
+    }
+
+    #[test]
+    fn test_parse_host_alias() {
+        let alias = "host.docker.internal:host-gateway"
+            .parse::<HostAlias>()
+            .unwrap();
+        assert_eq!(alias.name, "host.docker.internal");
+        assert_eq!(alias.to_string(), "host.docker.internal:host-gateway");
+        assert_eq!(
+            "db:10.0.0.2".parse::<HostAlias>().unwrap().address,
+            "10.0.0.2"
+        );
+        assert!("db".parse::<HostAlias>().is_err());
+        assert!("db:example.org".parse::<HostAlias>().is_err());
+        assert!("bad name:10.0.0.2".parse::<HostAlias>().is_err());
+    }
+
+    #[test]
+    fn test_pasta_command() {
+        let publish = ["8080:80".parse().unwrap(), "5353:53/udp".parse().unwrap()];
+        let pasta = PastaNetwork::new(&publish).unwrap();
+        let mut inner = Command::new("bwrap");
+        inner
+            .arg("--unshare-user")
+            .arg("/usr/bin/ls")
+            .env("LANG", "C")
+            .env_remove("TERM")
+            .current_dir("/tmp");
+
+        let cmd = pasta.command(&inner);
+        let args: Vec<_> = cmd.get_args().map(|s| s.to_str().unwrap()).collect();
+
+        assert_eq!(cmd.get_program(), "pasta");
+        assert!(args.windows(2).any(|w| w == ["-t", "127.0.0.1/8080:80"]));
+        assert!(args.windows(2).any(|w| w == ["-u", "127.0.0.1/5353:53"]));
+        assert!(args.windows(2).any(|w| w == ["-T", "none"]));
+        assert!(args.contains(&"--no-map-gw"));
+        assert!(args.ends_with(&["--", "bwrap", "--unshare-user", "/usr/bin/ls"]));
+        assert_eq!(
+            cmd.get_envs().collect::<Vec<_>>(),
+            [
+                (OsStr::new("LANG"), Some(OsStr::new("C"))),
+                (OsStr::new("TERM"), None)
+            ]
+        );
+        assert_eq!(cmd.get_current_dir(), Some(Path::new("/tmp")));
+        assert_eq!(
+            fs::read_to_string(pasta.resolv_conf()).unwrap(),
+            "nameserver 169.254.1.1\n"
+        );
+    }
+}
@@ -0,0 +1,603 @@
+//! # Copy-on-write overlay
+//!
+//! `overlay` contains writable overlays over host directories, whose changes are kept in an upper
+//! layer outside of the sandbox, to be reviewed after the run and applied, discarded or saved as
+//! a patch.
+
+use anyhow::{anyhow, Context};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::{env, fs, io, process};
+
+use crate::runtime_dir::RuntimeDir;
+
+/// Prefix of whiteouts and opaque directory markers, when the upper layer is not on a file
+/// system supporting character devices and extended attributes (like with fuse-overlayfs).
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+const OPAQUE_XATTRS: [&str; 2] = ["trusted.overlay.opaque", "user.overlay.opaque"];
+
+/// What to do with the changes in the overlays after the run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum OverlayAction {
+    /// Show the changes and ask, discard if not interactive
+    #[default]
+    Ask,
+    Apply,
+    Discard,
+    /// Save the changes as a unified diff
+    Patch(PathBuf),
+}
+
+impl FromStr for OverlayAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ask" => Ok(OverlayAction::Ask),
+            "apply" => Ok(OverlayAction::Apply),
+            "discard" => Ok(OverlayAction::Discard),
+            _ => match s.strip_prefix("patch=") {
+                Some(path) if !path.is_empty() => Ok(OverlayAction::Patch(PathBuf::from(path))),
+                _ => Err(anyhow!(
+                    "Invalid overlay action '{s}', expected ask, apply, discard or patch=FILE"
+                )),
+            },
+        }
+    }
+}
+
+/// A writable overlay over a host directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OverlayMount {
+    /// The host directory, also where the overlay is mounted in the sandbox
+    pub lower: PathBuf,
+    /// Where changes are written
+    pub upper: PathBuf,
+    /// Scratch directory of overlayfs, on the same file system as the upper layer
+    pub work: PathBuf,
+    /// Name of the Docker volume for the overlay
+    pub volume: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// A changed path, relative to the host directory of the overlay.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub path: PathBuf,
+}
+
+/// The overlays of a sandbox, with their upper layers in a private directory removed on drop.
+#[derive(Default)]
+pub struct Overlays {
+    mounts: Vec<OverlayMount>,
+    _dir: Option<RuntimeDir>,
+}
+
+impl Overlays {
+    pub fn new(paths: &[PathBuf]) -> Result<Self, anyhow::Error> {
+        let mut lowers = Vec::new();
+        for path in paths {
+            let lower = fs::canonicalize(path)
+                .with_context(|| format!("Overlay directory {path:?} not found"))?;
+            if !lower.is_dir() {
+                return Err(anyhow!("Overlay {lower:?} is not a directory"));
+            }
+            lowers.push(lower);
+        }
+        Self::create("overlay", lowers)
+    }
+
+    /// Overlays over the paths as they are, without resolving symlinks, for changes which are
+    /// thrown away after the run. The upper layers are created in `dir`, which is not removed.
+    pub fn throwaway(dir: &Path, paths: &[PathBuf]) -> Result<Self, anyhow::Error> {
+        Self::create_in("ephemeral", dir, paths.to_vec())
+    }
+
+    fn create(name: &str, lowers: Vec<PathBuf>) -> Result<Self, anyhow::Error> {
+        if lowers.is_empty() {
+            return Ok(Self::default());
+        }
+        let dir = RuntimeDir::new(name).context("Unable to create overlay directory")?;
+        let overlays = Self::create_in(name, dir.path(), lowers)?;
+        Ok(Self {
+            _dir: Some(dir),
+            ..overlays
+        })
+    }
+
+    fn create_in(name: &str, dir: &Path, lowers: Vec<PathBuf>) -> Result<Self, anyhow::Error> {
+        let mut mounts = Vec::new();
+        for (i, lower) in lowers.into_iter().enumerate() {
+            // Separators in the volume options of Podman and Docker
+            if lower
+                .to_str()
+                .is_none_or(|lower| lower.contains([':', ',']))
+            {
+                return Err(anyhow!(
+                    "Overlay {lower:?} is not supported, invalid characters"
+                ));
+            }
+            let upper = dir.join(format!("{i}/upper"));
+            let work = dir.join(format!("{i}/work"));
+            fs::create_dir_all(&upper)?;
+            fs::create_dir_all(&work)?;
+            mounts.push(OverlayMount {
+                lower,
+                upper,
+                work,
+                volume: format!("contained-{name}-{}-{i}", process::id()),
+            });
+        }
+        Ok(Self { mounts, _dir: None })
+    }
+
+    pub fn mounts(&self) -> &[OverlayMount] {
+        &self.mounts
+    }
+
+    /// Whether the directory is mounted with an overlay.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.mounts.iter().any(|mount| mount.lower == path)
+    }
+
+    /// Shows the changes made in the sandbox and applies, discards or saves them.
+    pub fn review(&self, action: &OverlayAction) -> Result<(), anyhow::Error> {
+        if self.mounts.is_empty() {
+            return Ok(());
+        }
+        let changes = self
+            .mounts
+            .iter()
+            .map(|mount| Ok((mount, changes(mount)?)))
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+        if changes.iter().all(|(_, changes)| changes.is_empty()) {
+            eprintln!("contained: no changes in overlay");
+            return Ok(());
+        }
+
+        let mut action = action.clone();
+        if action == OverlayAction::Ask {
+            eprintln!("contained: changes in overlay:");
+            for (mount, changes) in &changes {
+                for change in changes {
+                    eprintln!("{}", summary_line(mount, change));
+                }
+            }
+            let tty = OpenOptions::new().read(true).write(true).open("/dev/tty");
+            match tty {
+                Ok(tty) if io::stdin().is_terminal() => {
+                    for (mount, changes) in &changes {
+                        io::stderr().write_all(&patch(mount, changes)?)?;
+                    }
+                    action = ask(tty)?;
+                }
+                _ => {
+                    eprintln!(
+                        "contained: not interactive, discarding changes (see --overlay-action)"
+                    );
+                    action = OverlayAction::Discard;
+                }
+            }
+        }
+
+        match action {
+            OverlayAction::Apply => {
+                for (mount, changes) in &changes {
+                    apply(mount, changes)?;
+                }
+                eprintln!("contained: changes applied");
+            }
+            OverlayAction::Patch(path) => {
+                let mut file =
+                    File::create(&path).with_context(|| format!("Unable to create {path:?}"))?;
+                for (mount, changes) in &changes {
+                    file.write_all(&patch(mount, changes)?)?;
+                }
+                eprintln!("contained: changes saved to {path:?}");
+            }
+            OverlayAction::Discard | OverlayAction::Ask => {
+                eprintln!("contained: changes discarded");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Escapes a path for the options of an overlayfs mount.
+pub fn escape_option(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        if matches!(c, '\\' | ',' | ':') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn ask(tty: File) -> Result<OverlayAction, anyhow::Error> {
+    let mut output = tty.try_clone()?;
+    let mut input = BufReader::new(tty);
+    loop {
+        write!(
+            output,
+            "Apply changes? [a]pply, [d]iscard, [s]ave as patch: "
+        )?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(OverlayAction::Discard);
+        }
+        match line.trim() {
+            "a" | "apply" => return Ok(OverlayAction::Apply),
+            "d" | "discard" => return Ok(OverlayAction::Discard),
+            "s" | "save" => {
+                write!(output, "Patch file [contained.patch]: ")?;
+                let mut path = String::new();
+                input.read_line(&mut path)?;
+                let path = match path.trim() {
+                    "" => "contained.patch",
+                    path => path,
+                };
+                return Ok(OverlayAction::Patch(PathBuf::from(path)));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn summary_line(mount: &OverlayMount, change: &Change) -> String {
+    let kind = match change.kind {
+        ChangeKind::Added => 'A',
+        ChangeKind::Modified => 'M',
+        ChangeKind::Deleted => 'D',
+    };
+    let path = display_path(&mount.lower.join(&change.path));
+    if mount.upper.join(&change.path).is_dir() || mount.lower.join(&change.path).is_dir() {
+        format!("  {kind} {}/", path.display())
+    } else {
+        format!("  {kind} {}", path.display())
+    }
+}
+
+/// A path relative to the current directory if it is inside, absolute otherwise.
+fn display_path(path: &Path) -> PathBuf {
+    env::current_dir()
+        .ok()
+        .and_then(|dir| path.strip_prefix(dir).ok().map(Path::to_path_buf))
+        .filter(|path| !path.as_os_str().is_empty())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// The changes in the upper layer of an overlay, sorted by path.
+pub fn changes(mount: &OverlayMount) -> Result<Vec<Change>, anyhow::Error> {
+    let mut changes = Vec::new();
+    walk_upper(mount, Path::new(""), &mut changes)
+        .with_context(|| format!("Unable to read changes in overlay {:?}", mount.lower))?;
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(changes)
+}
+
+fn walk_upper(mount: &OverlayMount, dir: &Path, changes: &mut Vec<Change>) -> io::Result<()> {
+    let upper_dir = mount.upper.join(dir);
+    let lower_dir = mount.lower.join(dir);
+    let mut names = fs::read_dir(&upper_dir)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+
+    // An opaque directory hides everything in the lower directory
+    if is_opaque(&upper_dir, &names) && lower_dir.is_dir() {
+        for entry in fs::read_dir(&lower_dir)? {
+            let name = entry?.file_name();
+            if !names.contains(&name) {
+                changes.push(Change {
+                    kind: ChangeKind::Deleted,
+                    path: dir.join(name),
+                });
+            }
+        }
+    }
+
+    for name in names {
+        if name == OPAQUE_MARKER {
+            continue;
+        }
+        let upper = upper_dir.join(&name);
+        let metadata = upper.symlink_metadata()?;
+        let whiteout = name.to_str().and_then(|n| n.strip_prefix(WHITEOUT_PREFIX));
+        if let Some(deleted) = whiteout {
+            changes.push(Change {
+                kind: ChangeKind::Deleted,
+                path: dir.join(deleted),
+            });
+            continue;
+        }
+        let path = dir.join(&name);
+        let lower = mount.lower.join(&path);
+        let lower_metadata = lower.symlink_metadata().ok();
+        if metadata.file_type().is_char_device() && metadata.rdev() == 0 {
+            changes.push(Change {
+                kind: ChangeKind::Deleted,
+                path,
+            });
+        } else if metadata.is_dir() {
+            match lower_metadata {
+                Some(lower_metadata) if lower_metadata.is_dir() => {}
+                Some(_) => changes.push(Change {
+                    kind: ChangeKind::Modified,
+                    path: path.clone(),
+                }),
+                None => changes.push(Change {
+                    kind: ChangeKind::Added,
+                    path: path.clone(),
+                }),
+            }
+            walk_upper(mount, &path, changes)?;
+        } else {
+            let kind = match lower_metadata {
+                None => Some(ChangeKind::Added),
+                // Copied up for a change of metadata, or opened for writing without changes
+                Some(_) if same_file(&lower, &upper)? => None,
+                Some(_) => Some(ChangeKind::Modified),
+            };
+            if let Some(kind) = kind {
+                changes.push(Change { kind, path });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_opaque(dir: &Path, names: &[std::ffi::OsString]) -> bool {
+    if names.iter().any(|name| name == OPAQUE_MARKER) {
+        return true;
+    }
+    let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    OPAQUE_XATTRS.iter().any(|name| {
+        let name = CString::new(*name).expect("attribute name");
+        let mut value = [0u8; 1];
+        // SAFETY: the strings and the buffer are valid
+        let size = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        size == 1 && value[0] == b'y'
+    })
+}
+
+fn same_file(lower: &Path, upper: &Path) -> io::Result<bool> {
+    let (lower_metadata, upper_metadata) = (lower.symlink_metadata()?, upper.symlink_metadata()?);
+    if lower_metadata.file_type() != upper_metadata.file_type()
+        || lower_metadata.mode() != upper_metadata.mode()
+    {
+        return Ok(false);
+    }
+    if upper_metadata.is_symlink() {
+        return Ok(fs::read_link(lower)? == fs::read_link(upper)?);
+    }
+    Ok(lower_metadata.len() == upper_metadata.len() && fs::read(lower)? == fs::read(upper)?)
+}
+
+/// Applies the changes of an overlay to its host directory.
+fn apply(mount: &OverlayMount, changes: &[Change]) -> Result<(), anyhow::Error> {
+    for change in changes {
+        let lower = mount.lower.join(&change.path);
+        let upper = mount.upper.join(&change.path);
+        let context = || format!("Unable to apply change to {lower:?}");
+        let upper_is_dir = upper.symlink_metadata().is_ok_and(|m| m.is_dir());
+        if let Ok(metadata) = lower.symlink_metadata() {
+            if !metadata.is_dir() {
+                fs::remove_file(&lower).with_context(context)?;
+            } else if change.kind == ChangeKind::Deleted || !upper_is_dir {
+                fs::remove_dir_all(&lower).with_context(context)?;
+            }
+        }
+        if change.kind == ChangeKind::Deleted {
+            continue;
+        }
+
+        let metadata = upper.symlink_metadata()?;
+        if metadata.is_dir() {
+            fs::create_dir_all(&lower).with_context(context)?;
+            fs::set_permissions(&lower, metadata.permissions()).with_context(context)?;
+        } else if metadata.is_symlink() {
+            symlink(fs::read_link(&upper)?, &lower).with_context(context)?;
+        } else {
+            fs::copy(&upper, &lower).with_context(context)?;
+        }
+    }
+    Ok(())
+}
+
+/// The changes of an overlay as a unified diff, applicable with `patch -p1` in the current directory.
+fn patch(mount: &OverlayMount, changes: &[Change]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut files = Vec::new();
+    for change in changes {
+        let lower = mount.lower.join(&change.path);
+        let upper = mount.upper.join(&change.path);
+        if change.kind == ChangeKind::Deleted && lower.is_dir() {
+            list_files(&lower, &mut files)?;
+            continue;
+        }
+        if upper.is_dir() {
+            continue;
+        }
+        files.push(lower);
+    }
+
+    let mut patch = Vec::new();
+    for lower in files {
+        let relative = lower.strip_prefix(&mount.lower).expect("in overlay");
+        let upper = mount.upper.join(relative);
+        let shown = display_path(&lower);
+        let deleted = changes
+            .iter()
+            .any(|change| change.kind == ChangeKind::Deleted && relative.starts_with(&change.path));
+        let output = Command::new("diff")
+            .arg("-u")
+            .arg("--label")
+            .arg(Path::new("a").join(&shown))
+            .arg("--label")
+            .arg(Path::new("b").join(&shown))
+            .arg(if lower.is_file() {
+                &lower
+            } else {
+                Path::new("/dev/null")
+            })
+            .arg(if deleted {
+                Path::new("/dev/null")
+            } else {
+                &upper
+            })
+            .output()
+            .context("Unable to run diff")?;
+        if output.status.code() == Some(2) {
+            return Err(anyhow!(
+                "diff failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        patch.extend(output.stdout);
+    }
+    Ok(patch)
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.symlink_metadata()?.is_dir() {
+            list_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlay(dir: &RuntimeDir) -> Result<OverlayMount, anyhow::Error> {
+        let lower = dir.path().join("lower");
+        let upper = dir.path().join("upper");
+        for path in ["lower/dir", "lower/opaque", "upper/dir", "upper/opaque"] {
+            fs::create_dir_all(dir.path().join(path))?;
+        }
+        fs::write(lower.join("modified"), "old\n")?;
+        fs::write(lower.join("unchanged"), "same\n")?;
+        fs::write(lower.join("deleted"), "gone\n")?;
+        fs::write(lower.join("dir/file"), "file\n")?;
+        fs::write(lower.join("opaque/hidden"), "hidden\n")?;
+
+        fs::write(upper.join("modified"), "new\n")?;
+        fs::write(upper.join("unchanged"), "same\n")?;
+        fs::write(upper.join("added"), "added\n")?;
+        fs::write(upper.join(".wh.deleted"), "")?;
+        fs::write(upper.join("dir/.wh.file"), "")?;
+        fs::write(upper.join("opaque/.wh..wh..opq"), "")?;
+        fs::write(upper.join("opaque/replacement"), "replacement\n")?;
+        Ok(OverlayMount {
+            lower,
+            upper,
+            work: dir.path().join("work"),
+            volume: "volume".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_changes() -> Result<(), anyhow::Error> {
+        let dir = RuntimeDir::new("overlay-test")?;
+        let mount = overlay(&dir)?;
+
+        let changes = changes(&mount)?
+            .into_iter()
+            .map(|change| (change.kind, change.path.to_string_lossy().to_string()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            changes,
+            [
+                (ChangeKind::Added, "added".to_string()),
+                (ChangeKind::Deleted, "deleted".to_string()),
+                (ChangeKind::Deleted, "dir/file".to_string()),
+                (ChangeKind::Modified, "modified".to_string()),
+                (ChangeKind::Deleted, "opaque/hidden".to_string()),
+                (ChangeKind::Added, "opaque/replacement".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_and_patch() -> Result<(), anyhow::Error> {
+        let dir = RuntimeDir::new("overlay-test")?;
+        let mount = overlay(&dir)?;
+        let changes = changes(&mount)?;
+
+        let patch = String::from_utf8(patch(&mount, &changes)?)?;
+        for line in [
+            "+added",
+            "-gone",
+            "-file",
+            "-old",
+            "+new",
+            "-hidden",
+            "+replacement",
+        ] {
+            assert!(patch.lines().any(|l| l == line), "{line} not in {patch}");
+        }
+        assert!(!patch.contains("same"));
+
+        apply(&mount, &changes)?;
+        let read = |path: &str| fs::read_to_string(mount.lower.join(path)).ok();
+        assert_eq!(read("added").as_deref(), Some("added\n"));
+        assert_eq!(read("modified").as_deref(), Some("new\n"));
+        assert_eq!(read("unchanged").as_deref(), Some("same\n"));
+        assert_eq!(read("deleted"), None);
+        assert_eq!(read("dir/file"), None);
+        assert_eq!(read("opaque/hidden"), None);
+        assert_eq!(read("opaque/replacement").as_deref(), Some("replacement\n"));
+        // Only the whiteouts of deleted paths remain
+        assert!(super::changes(&mount)?
+            .iter()
+            .all(|change| change.kind == ChangeKind::Deleted));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_action() -> Result<(), anyhow::Error> {
+        assert_eq!("ask".parse::<OverlayAction>()?, OverlayAction::Ask);
+        assert_eq!("apply".parse::<OverlayAction>()?, OverlayAction::Apply);
+        assert_eq!(
+            "patch=out.patch".parse::<OverlayAction>()?,
+            OverlayAction::Patch(PathBuf::from("out.patch"))
+        );
+        assert!("patch=".parse::<OverlayAction>().is_err());
+        assert!("keep".parse::<OverlayAction>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+//! # Presets
+//!
+//! `preset` contains curated settings for language toolchains: the read-only toolchain
+//! directories of the host, the writable build output directories of the project, environment
+//! variables and caches. The built-in presets can be changed, and new ones added, in
+//! `~/.config/contained/presets.json`.
+
+use anyhow::{anyhow, Context};
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fs, io};
+
+use crate::cache::CacheSpec;
+use crate::home::{config_dir, SANDBOX_HOME};
+
+/// `PATH` of the system directories, extended by the presets.
+const SYSTEM_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Names of the built-in presets.
+const BUILTIN_PRESETS: [&str; 5] = ["go", "java", "node", "python", "rust"];
+
+/// Settings for a toolchain, selected with `--preset NAME`.
+///
+/// Paths starting with `~/` are relative to the home directory on the host.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Preset {
+    /// Toolchain directories, mounted read-only if they exist
+    readonly: Vec<String>,
+    /// Build output directories relative to the current directory, mounted writable
+    writable: Vec<String>,
+    /// Environment variables, `~/` is expanded at the start of each `:` separated part
+    env: Vec<(String, String)>,
+    /// Shared caches, like `--cache`
+    caches: Vec<CacheSpec>,
+}
+
+impl FromStr for Preset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config = read_config()?;
+        Self::load(s, config.as_ref())
+    }
+}
+
+impl Preset {
+    /// The preset with the name, built-in or from the config.
+    pub(crate) fn load(
+        name: &str,
+        config: Option<&Map<String, Value>>,
+    ) -> Result<Self, anyhow::Error> {
+        let overrides = config.and_then(|config| config.get(name));
+        match (Self::builtin(name), overrides) {
+            (preset, Some(overrides)) => preset
+                .unwrap_or_default()
+                .with_config(overrides)
+                .with_context(|| format!("Invalid preset '{name}' in {:?}", config_path())),
+            (Some(preset), None) => Ok(preset),
+            (None, None) => {
+                let mut names = BUILTIN_PRESETS.map(String::from).to_vec();
+                names.extend(config.into_iter().flat_map(|config| config.keys().cloned()));
+                names.sort();
+                names.dedup();
+                Err(anyhow!(
+                    "Unknown preset '{name}', expected one of {}",
+                    names.join(", ")
+                ))
+            }
+        }
+    }
+
+    fn builtin(name: &str) -> Option<Self> {
+        let preset = match name {
+            "go" => Self::new(
+                &["~/go/bin", "~/sdk"],
+                &[],
+                &[
+                    ("GOPATH", format!("{SANDBOX_HOME}/go")),
+                    ("PATH", format!("/usr/local/go/bin:~/go/bin:{SYSTEM_PATH}")),
+                ],
+                &["go"],
+            ),
+            "java" => Self::new(
+                &["~/.sdkman"],
+                &["target", "build", ".gradle"],
+                &[
+                    ("SDKMAN_DIR", "~/.sdkman".to_string()),
+                    ("GRADLE_USER_HOME", format!("{SANDBOX_HOME}/.gradle")),
+                    (
+                        "PATH",
+                        format!(
+                            "~/.sdkman/candidates/java/current/bin:\
+                             ~/.sdkman/candidates/maven/current/bin:\
+                             ~/.sdkman/candidates/gradle/current/bin:{SYSTEM_PATH}"
+                        ),
+                    ),
+                ],
+                &["gradle", "maven"],
+            ),
+            "node" => Self::new(
+                &["~/.nvm"],
+                &["node_modules"],
+                &[
+                    ("NVM_DIR", "~/.nvm".to_string()),
+                    (
+                        "NODE_PATH",
+                        "/usr/local/lib/node_modules:/usr/lib/node_modules".to_string(),
+                    ),
+                ],
+                &["npm"],
+            ),
+            "python" => Self::new(
+                &["~/.pyenv"],
+                &[".venv"],
+                &[
+                    ("PYENV_ROOT", "~/.pyenv".to_string()),
+                    ("PATH", format!("~/.pyenv/shims:~/.pyenv/bin:{SYSTEM_PATH}")),
+                ],
+                &["pip"],
+            ),
+            "rust" => Self::new(
+                &["~/.rustup", "~/.cargo/bin"],
+                &["target"],
+                &[
+                    ("RUSTUP_HOME", "~/.rustup".to_string()),
+                    ("CARGO_HOME", format!("{SANDBOX_HOME}/.cargo")),
+                    ("PATH", format!("~/.cargo/bin:{SYSTEM_PATH}")),
+                ],
+                &["cargo"],
+            ),
+            _ => return None,
+        };
+        Some(preset)
+    }
+
+    fn new(readonly: &[&str], writable: &[&str], env: &[(&str, String)], caches: &[&str]) -> Self {
+        Self {
+            readonly: readonly.iter().map(|path| path.to_string()).collect(),
+            writable: writable.iter().map(|path| path.to_string()).collect(),
+            env: env
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+            caches: caches
+                .iter()
+                .map(|name| name.parse().expect("known cache"))
+                .collect(),
+        }
+    }
+
+    /// Replaces the settings given in the config.
+    fn with_config(mut self, config: &Value) -> Result<Self, anyhow::Error> {
+        let config = config
+            .as_object()
+            .ok_or(anyhow!("Expected an object with the settings"))?;
+        for (key, value) in config {
+            match key.as_str() {
+                "readonly" => self.readonly = strings(key, value)?,
+                "writable" => {
+                    self.writable = strings(key, value)?;
+                    if let Some(path) = self.writable.iter().find(|path| !is_relative(path)) {
+                        return Err(anyhow!(
+                            "Writable path '{path}' is not relative to the current directory"
+                        ));
+                    }
+                }
+                "env" => {
+                    self.env = value
+                        .as_object()
+                        .ok_or(anyhow!("Expected an object for 'env'"))?
+                        .iter()
+                        .map(|(k, v)| {
+                            let v = v.as_str().ok_or(anyhow!("Expected a string for '{k}'"))?;
+                            Ok((k.clone(), v.to_string()))
+                        })
+                        .collect::<Result<_, anyhow::Error>>()?;
+                }
+                "caches" => {
+                    self.caches = strings(key, value)?
+                        .iter()
+                        .map(|cache| cache.parse())
+                        .collect::<Result<_, _>>()?;
+                }
+                _ => return Err(anyhow!("Unknown setting '{key}'")),
+            }
+        }
+        Ok(self)
+    }
+
+    /// Toolchain directories which exist on the host.
+    pub fn readonly_mounts(&self, home: &Path) -> Vec<PathBuf> {
+        self.readonly
+            .iter()
+            .map(|path| expand_home(path, home))
+            .filter(|path| path.exists())
+            .collect()
+    }
+
+    /// Build output directories, which may not exist yet.
+    pub fn writable_mounts(&self, current_dir: &Path) -> Vec<PathBuf> {
+        self.writable
+            .iter()
+            .map(|path| current_dir.join(path))
+            .collect()
+    }
+
+    /// Environment variables as `KEY=VALUE`.
+    pub fn env(&self, home: &Path) -> Vec<String> {
+        self.env
+            .iter()
+            .map(|(k, v)| {
+                let v = v
+                    .split(':')
+                    .map(|part| expand_home(part, home).to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+                    .join(":");
+                format!("{k}={v}")
+            })
+            .collect()
+    }
+
+    pub fn caches(&self) -> &[CacheSpec] {
+        &self.caches
+    }
+}
+
+pub(crate) fn strings(key: &str, value: &Value) -> Result<Vec<String>, anyhow::Error> {
+    value
+        .as_array()
+        .and_then(|values| {
+            values
+                .iter()
+                .map(|value| value.as_str().map(String::from))
+                .collect()
+        })
+        .ok_or(anyhow!("Expected an array of strings for '{key}'"))
+}
+
+fn is_relative(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)))
+}
+
+pub(crate) fn expand_home(path: &str, home: &Path) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(path) => home.join(path),
+        None => PathBuf::from(path),
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    Some(config_dir().ok()?.join("presets.json"))
+}
+
+/// Presets from the config, which is optional.
+fn read_config() -> Result<Option<Map<String, Value>>, anyhow::Error> {
+    let Some(path) = config_path() else {
+        return Ok(None);
+    };
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Unable to read {path:?}")),
+    };
+    match serde_json::from_str(&content).with_context(|| format!("Invalid JSON in {path:?}"))? {
+        Value::Object(config) => Ok(Some(config)),
+        _ => Err(anyhow!("Expected an object with presets in {path:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_builtin_presets() {
+        for name in BUILTIN_PRESETS {
+            let preset = Preset::load(name, None).unwrap();
+            assert!(!preset.caches().is_empty(), "no cache for {name}");
+        }
+        assert!(Preset::load("cobol", None).is_err());
+
+        let rust = Preset::load("rust", None).unwrap();
+        let home = Path::new("/home/user");
+        assert_eq!(
+            rust.env(home),
+            [
+                "RUSTUP_HOME=/home/user/.rustup",
+                "CARGO_HOME=/home/contained/.cargo",
+                "PATH=/home/user/.cargo/bin:/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:\
+                 /sbin:/bin"
+            ]
+        );
+        assert!(rust.readonly_mounts(home).is_empty());
+    }
+
+    #[test]
+    fn test_config_presets() {
+        let config = json!({
+            "rust": {"writable": ["target", "out/bin"], "caches": []},
+            "deno": {"readonly": ["~/.deno"], "env": {"DENO_DIR": "/tmp/deno"}, "caches": ["deno:/tmp/deno"]},
+            "bad": {"writable": ["../x"]},
+            "typo": {"readonyl": []},
+        });
+        let config = config.as_object();
+
+        let rust = Preset::load("rust", config).unwrap();
+        assert_eq!(rust.writable, ["target", "out/bin"]);
+        assert_eq!(rust.readonly, ["~/.rustup", "~/.cargo/bin"]);
+        assert!(rust.caches().is_empty());
+
+        let deno = Preset::load("deno", config).unwrap();
+        assert_eq!(deno.env(Path::new("/home/user")), ["DENO_DIR=/tmp/deno"]);
+        assert_eq!(
+            deno.caches(),
+            ["deno:/tmp/deno".parse::<CacheSpec>().unwrap()]
+        );
+        assert!(deno.writable.is_empty());
+
+        assert!(Preset::load("bad", config).is_err());
+        assert!(Preset::load("typo", config).is_err());
+        let error = Preset::load("cobol", config).unwrap_err();
+        assert!(format!("{error:#}").contains("bad, deno, go, java, node, python, rust, typo"));
+    }
+}
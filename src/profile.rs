@@ -0,0 +1,339 @@
+//! # Profiles
+//!
+//! `profile` contains the mounts and network access a program needs, proposed by
+//! `contained learn` and kept in `~/.config/contained/profiles` for review.
+
+use anyhow::{anyhow, Context};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::arguments::is_sensitive;
+use crate::home::config_dir;
+use crate::landlock::Access;
+use crate::preset::{expand_home, strings};
+use crate::SYSTEM_MOUNTS;
+
+/// Directories provided by the sandbox itself, never proposed as mounts.
+const SANDBOX_DIRS: [&str; 4] = ["/dev", "/proc", "/sys", "/tmp"];
+
+/// Number of entries of a directory from which the directory is proposed instead.
+const COLLAPSE_ENTRIES: usize = 4;
+
+/// Mounts and network access of a program, selected with `--profile NAME|PATH`.
+///
+/// Paths starting with `~/` are relative to the home directory on the host.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    /// The program the profile was learned from
+    program: Option<String>,
+    /// Whether the program connects to the network
+    network: bool,
+    /// Access to the current directory, if it is used
+    current_dir: Option<Access>,
+    /// Files and directories mounted read-only if they exist
+    readonly: Vec<String>,
+    /// Files and directories mounted writable if they exist
+    writable: Vec<String>,
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    /// Reads the profile with the name from the profiles directory, or from a path.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let path = if s.contains('/') || s.ends_with(".json") {
+            PathBuf::from(s)
+        } else {
+            profiles_dir()?.join(format!("{s}.json"))
+        };
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read profile {path:?}"))?;
+        let config =
+            serde_json::from_str(&content).with_context(|| format!("Invalid JSON in {path:?}"))?;
+        Self::from_config(&config).with_context(|| format!("Invalid profile {path:?}"))
+    }
+}
+
+impl Profile {
+    fn from_config(config: &Value) -> Result<Self, anyhow::Error> {
+        let config = config
+            .as_object()
+            .ok_or(anyhow!("Expected an object with the settings"))?;
+        let mut profile = Self::default();
+        for (key, value) in config {
+            match key.as_str() {
+                "program" => {
+                    let program = value
+                        .as_str()
+                        .ok_or(anyhow!("Expected a string for '{key}'"))?;
+                    profile.program = Some(program.to_string());
+                }
+                "network" => {
+                    profile.network = value
+                        .as_bool()
+                        .ok_or(anyhow!("Expected true or false for '{key}'"))?;
+                }
+                "current_dir" => {
+                    profile.current_dir = match value.as_str() {
+                        _ if value.is_null() => None,
+                        Some("readonly") => Some(Access::ReadOnly),
+                        Some("writable") => Some(Access::ReadWrite),
+                        _ => {
+                            return Err(anyhow!(
+                                "Expected null, \"readonly\" or \"writable\" for '{key}'"
+                            ))
+                        }
+                    };
+                }
+                "readonly" => profile.readonly = strings(key, value)?,
+                "writable" => profile.writable = strings(key, value)?,
+                _ => return Err(anyhow!("Unknown setting '{key}'")),
+            }
+        }
+        Ok(profile)
+    }
+
+    fn to_config(&self) -> Value {
+        json!({
+            "program": self.program,
+            "network": self.network,
+            "current_dir": match self.current_dir {
+                None => Value::Null,
+                Some(Access::ReadOnly) => "readonly".into(),
+                Some(Access::ReadWrite) => "writable".into(),
+            },
+            "readonly": self.readonly,
+            "writable": self.writable,
+        })
+    }
+
+    /// Proposes the mounts for the paths accessed by the program, leaving out what the sandbox
+    /// provides anyway: the system directories, the mounted directories of the program, and the
+    /// current directory, which is mounted as a whole. Directories are proposed instead of many
+    /// of their entries, unless they are or contain sensitive files like credentials.
+    pub fn learned(
+        program: &Path,
+        paths: &BTreeMap<PathBuf, Access>,
+        network: bool,
+        home: &Path,
+        current_dir: &Path,
+        mounted: &[PathBuf],
+    ) -> Self {
+        let mut profile = Self {
+            program: Some(program.to_string_lossy().into_owned()),
+            network,
+            ..Default::default()
+        };
+        let provided = |path: &Path| {
+            home.starts_with(path)
+                || current_dir.starts_with(path)
+                || SYSTEM_MOUNTS
+                    .iter()
+                    .chain(&SANDBOX_DIRS)
+                    .any(|dir| path.starts_with(dir))
+                || mounted.iter().any(|dir| path.starts_with(dir))
+        };
+
+        let mut mounts = BTreeMap::new();
+        for (path, &access) in paths {
+            if path.starts_with(current_dir) {
+                profile.current_dir = Some(widest(profile.current_dir, access));
+            } else if !provided(path) {
+                mounts.insert(path.clone(), access);
+            }
+        }
+
+        let mut entries: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        for path in mounts.keys() {
+            if let Some(dir) = path
+                .parent()
+                .filter(|dir| !provided(dir) && !is_sensitive(dir, home))
+            {
+                entries
+                    .entry(dir.to_path_buf())
+                    .or_default()
+                    .push(path.clone());
+            }
+        }
+        for (dir, entries) in entries {
+            if entries.len() >= COLLAPSE_ENTRIES {
+                let access = entries
+                    .iter()
+                    .fold(mounts.get(&dir).copied(), |access, path| {
+                        mounts
+                            .remove(path)
+                            .map(|entry| widest(access, entry))
+                            .or(access)
+                    });
+                mounts.insert(dir, access.unwrap_or(Access::ReadOnly));
+            }
+        }
+
+        for (path, &access) in &mounts {
+            // Covered by the mount of a parent directory
+            let covered = mounts.iter().any(|(dir, &dir_access)| {
+                dir != path
+                    && path.starts_with(dir)
+                    && (dir_access == Access::ReadWrite || access == Access::ReadOnly)
+            });
+            if !covered {
+                let path = match path.strip_prefix(home) {
+                    Ok(path) => format!("~/{}", path.display()),
+                    Err(_) => path.display().to_string(),
+                };
+                match access {
+                    Access::ReadOnly => profile.readonly.push(path),
+                    Access::ReadWrite => profile.writable.push(path),
+                }
+            }
+        }
+        profile
+    }
+
+    /// Writes the profile as JSON, creating its directory.
+    pub fn write(&self, path: &Path) -> Result<(), anyhow::Error> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).with_context(|| format!("Unable to create {dir:?}"))?;
+        }
+        let content = serde_json::to_string_pretty(&self.to_config())? + "\n";
+        fs::write(path, content).with_context(|| format!("Unable to write {path:?}"))
+    }
+
+    pub fn network(&self) -> bool {
+        self.network
+    }
+
+    pub fn mount_current_dir(&self) -> bool {
+        self.current_dir.is_some()
+    }
+
+    pub fn current_dir_writable(&self) -> bool {
+        self.current_dir == Some(Access::ReadWrite)
+    }
+
+    /// Files and directories to mount read-only which exist on the host.
+    pub fn readonly_mounts(&self, home: &Path) -> Vec<PathBuf> {
+        existing(&self.readonly, home)
+    }
+
+    /// Files and directories to mount writable which exist on the host.
+    pub fn writable_mounts(&self, home: &Path) -> Vec<PathBuf> {
+        existing(&self.writable, home)
+    }
+}
+
+/// Directory of the profiles written by `contained learn`.
+pub fn profiles_dir() -> Result<PathBuf, anyhow::Error> {
+    Ok(config_dir()?.join("profiles"))
+}
+
+fn widest(access: Option<Access>, other: Access) -> Access {
+    match access {
+        Some(Access::ReadWrite) => Access::ReadWrite,
+        _ => other,
+    }
+}
+
+fn existing(paths: &[String], home: &Path) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .map(|path| expand_home(path, home))
+        .filter(|path| path.exists())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_learned_profile() {
+        let home = Path::new("/home/user");
+        let paths = [
+            ("/usr/lib/libc.so.6", Access::ReadOnly),
+            ("/etc/passwd", Access::ReadOnly),
+            ("/proc/self/maps", Access::ReadOnly),
+            ("/home/user", Access::ReadOnly),
+            ("/home/user/.gitconfig", Access::ReadOnly),
+            ("/home/user/.cache/tool", Access::ReadWrite),
+            ("/home/user/.cache/tool/index", Access::ReadOnly),
+            ("/home/user/project", Access::ReadOnly),
+            ("/home/user/project/src/main.rs", Access::ReadOnly),
+            ("/home/user/project/target", Access::ReadWrite),
+            ("/home/user/.local/tool/bin/tool", Access::ReadOnly),
+            ("/opt/data/a", Access::ReadOnly),
+            ("/opt/data/b", Access::ReadOnly),
+            ("/opt/data/c", Access::ReadWrite),
+            ("/opt/data/d", Access::ReadOnly),
+            ("/srv/x", Access::ReadOnly),
+            ("/srv/y", Access::ReadOnly),
+            // Not ~/.config, which contains the credentials of gh and gcloud
+            ("/home/user/.config/a", Access::ReadOnly),
+            ("/home/user/.config/b", Access::ReadOnly),
+            ("/home/user/.config/c", Access::ReadOnly),
+            ("/home/user/.config/d", Access::ReadOnly),
+            ("/home/user/.ssh/config", Access::ReadOnly),
+            ("/home/user/.ssh/known_hosts", Access::ReadWrite),
+            ("/home/user/.ssh/id_rsa.pub", Access::ReadOnly),
+            ("/home/user/.ssh/id_ed25519.pub", Access::ReadOnly),
+        ]
+        .into_iter()
+        .map(|(path, access)| (PathBuf::from(path), access))
+        .collect();
+        let mounted = [PathBuf::from("/home/user/.local/tool/bin")];
+
+        let profile = Profile::learned(
+            Path::new("/home/user/.local/tool/bin/tool"),
+            &paths,
+            true,
+            home,
+            Path::new("/home/user/project"),
+            &mounted,
+        );
+        assert!(profile.network());
+        assert!(profile.current_dir_writable());
+        assert_eq!(
+            profile.readonly,
+            [
+                "~/.config/a",
+                "~/.config/b",
+                "~/.config/c",
+                "~/.config/d",
+                "~/.gitconfig",
+                "~/.ssh/config",
+                "~/.ssh/id_ed25519.pub",
+                "~/.ssh/id_rsa.pub",
+                "/srv/x",
+                "/srv/y"
+            ]
+            .map(String::from)
+        );
+        assert_eq!(
+            profile.writable,
+            ["~/.cache/tool", "~/.ssh/known_hosts", "/opt/data"].map(String::from)
+        );
+
+        let config = profile.to_config();
+        assert_eq!(config["current_dir"], "writable");
+        assert_eq!(Profile::from_config(&config).unwrap(), profile);
+    }
+
+    #[test]
+    fn test_profile_config() {
+        let profile = Profile::from_config(&json!({
+            "network": false,
+            "current_dir": null,
+            "readonly": ["~/.gitconfig"],
+        }))
+        .unwrap();
+        assert!(!profile.mount_current_dir());
+        assert_eq!(profile.readonly, ["~/.gitconfig"]);
+
+        assert!(Profile::from_config(&json!({"current_dir": "rw"})).is_err());
+        assert!(Profile::from_config(&json!({"readonyl": []})).is_err());
+        assert!(Profile::from_config(&json!({"network": "yes"})).is_err());
+    }
+}
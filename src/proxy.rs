@@ -0,0 +1,442 @@
+//! # Egress proxy
+//!
+//! `proxy` contains an HTTP(S) proxy which only allows connections to an allowlist of hosts,
+//! and a forwarder which makes it reachable over TCP from inside a network-less sandbox.
+
+use http::{StatusCode, Uri};
+use httparse::Status::Complete;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+use crate::runtime_dir::RuntimeDir;
+
+/// Port the forwarder listens on inside the sandbox.
+pub const PROXY_PORT: u16 = 3128;
+
+/// Where the directory with the proxy socket is mounted inside the sandbox.
+pub const PROXY_DIR: &str = "/run/contained-proxy";
+
+const PROXY_SOCKET: &str = "proxy.sock";
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Resolves a host name and port to socket addresses.
+pub type Resolver = fn(&str, u16) -> io::Result<Vec<SocketAddr>>;
+
+fn system_resolver(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    Ok((host, port).to_socket_addrs()?.collect())
+}
+
+/// Host name patterns which are allowed, such as `example.org` or `*.crates.io`.
+pub struct HostAllowlist {
+    patterns: Vec<String>,
+}
+
+impl HostAllowlist {
+    pub fn new(patterns: &[String]) -> Self {
+        Self {
+            patterns: patterns
+                .iter()
+                .map(|p| p.trim().trim_end_matches('.').to_ascii_lowercase())
+                .filter(|p| !p.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Checks if a host is allowed. `*.example.org` matches subdomains of `example.org`,
+    /// but not `example.org` itself.
+    pub fn is_allowed(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.patterns
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|prefix| prefix.len() > 1 && prefix.ends_with('.')),
+                None => *pattern == host,
+            })
+    }
+}
+
+/// An egress proxy listening on a Unix socket in a private directory.
+pub struct RunningProxy {
+    dir: RuntimeDir,
+}
+
+impl RunningProxy {
+    /// Starts an egress proxy in a background thread.
+    pub fn start(allowed_hosts: &[String]) -> io::Result<Self> {
+        let dir = RuntimeDir::new("proxy")?;
+        let listener = UnixListener::bind(dir.path().join(PROXY_SOCKET))?;
+        serve(listener, HostAllowlist::new(allowed_hosts), system_resolver)?;
+        Ok(Self { dir })
+    }
+
+    /// The directory containing the proxy socket, to be mounted at [PROXY_DIR].
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+/// Environment variables making programs in the sandbox use the proxy.
+pub fn proxy_env() -> Vec<(&'static str, String)> {
+    let url = format!("http://127.0.0.1:{PROXY_PORT}");
+    ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"]
+        .into_iter()
+        .map(|k| (k, url.clone()))
+        .collect()
+}
+
+/// Path of the proxy socket inside the sandbox.
+pub fn sandbox_socket() -> String {
+    format!("{PROXY_DIR}/{PROXY_SOCKET}")
+}
+
+/// Serve proxy requests from a Unix socket in a background thread.
+pub fn serve(
+    listener: UnixListener,
+    allowlist: HostAllowlist,
+    resolver: Resolver,
+) -> io::Result<()> {
+    let allowlist = Arc::new(allowlist);
+    thread::Builder::new()
+        .name("proxy".to_string())
+        .spawn(move || {
+            for client in listener.incoming().flatten() {
+                let allowlist = allowlist.clone();
+                let _ = thread::Builder::new()
+                    .name("proxy-client".to_string())
+                    .spawn(move || {
+                        let _ = handle_client(client, &allowlist, resolver);
+                    });
+            }
+        })?;
+    Ok(())
+}
+
+struct Target {
+    host: String,
+    port: u16,
+    tunnel: bool,
+}
+
+fn handle_client(
+    mut client: UnixStream,
+    allowlist: &HostAllowlist,
+    resolver: Resolver,
+) -> io::Result<()> {
+    let (head, rest) = read_head(&mut client)?;
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    let target = match request.parse(&head) {
+        Ok(Complete(_)) => parse_target(&request),
+        _ => None,
+    };
+    let Some((target, path)) = target else {
+        return respond(&mut client, StatusCode::BAD_REQUEST);
+    };
+
+    if !allowlist.is_allowed(&target.host) {
+        eprintln!(
+            "contained: denied {} to {}:{}",
+            request.method.unwrap_or(""),
+            target.host,
+            target.port
+        );
+        return respond(&mut client, StatusCode::FORBIDDEN);
+    }
+
+    let mut upstream = match connect(resolver, &target.host, target.port) {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            eprintln!(
+                "contained: unable to connect to {}:{}: {}",
+                target.host, target.port, err
+            );
+            return respond(&mut client, StatusCode::BAD_GATEWAY);
+        }
+    };
+
+    if target.tunnel {
+        client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+    } else {
+        upstream.write_all(&origin_request(&request, &path))?;
+    }
+    upstream.write_all(&rest)?;
+
+    relay(client, upstream)
+}
+
+/// Read the request head, returns the head and any bytes read after it.
+fn read_head(client: &mut UnixStream) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buffer.split_off(end + 4);
+            return Ok((buffer, rest));
+        }
+        if buffer.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+        let bytes_read = client.read(&mut chunk)?;
+        if bytes_read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buffer.extend_from_slice(&chunk[..bytes_read]);
+    }
+}
+
+fn parse_target(request: &httparse::Request) -> Option<(Target, String)> {
+    let uri: Uri = request.path?.parse().ok()?;
+    if request.method? == "CONNECT" {
+        let authority = uri.authority()?;
+        Some((
+            Target {
+                host: authority.host().to_string(),
+                port: authority.port_u16()?,
+                tunnel: true,
+            },
+            String::new(),
+        ))
+    } else {
+        if uri.scheme_str()? != "http" {
+            return None;
+        }
+        let path = uri
+            .path_and_query()
+            .map_or("/".to_string(), |p| p.to_string());
+        Some((
+            Target {
+                host: uri.host()?.to_string(),
+                port: uri.port_u16().unwrap_or(80),
+                tunnel: false,
+            },
+            path,
+        ))
+    }
+}
+
+/// Rewrite a proxy request to origin form, one request per connection.
+fn origin_request(request: &httparse::Request, path: &str) -> Vec<u8> {
+    let mut head = format!(
+        "{} {} HTTP/1.{}\r\n",
+        request.method.unwrap_or("GET"),
+        path,
+        request.version.unwrap_or(1)
+    )
+    .into_bytes();
+    for header in request.headers.iter() {
+        let name = header.name.to_ascii_lowercase();
+        if name.starts_with("proxy-") || name == "connection" || name == "keep-alive" {
+            continue;
+        }
+        head.extend_from_slice(header.name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header.value);
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"Connection: close\r\n\r\n");
+    head
+}
+
+fn connect(resolver: Resolver, host: &str, port: u16) -> io::Result<TcpStream> {
+    let addresses = resolver(host, port)?;
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+    for address in addresses {
+        match TcpStream::connect(address) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_error = err,
+        }
+    }
+    Err(last_error)
+}
+
+fn respond(client: &mut UnixStream, status: StatusCode) -> io::Result<()> {
+    client.write_all(
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or("")
+        )
+        .as_bytes(),
+    )
+}
+
+/// A bidirectional stream which can be relayed to another one.
+trait Duplex: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown_write(&self);
+}
+
+impl Duplex for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) {
+        let _ = self.shutdown(Shutdown::Write);
+    }
+}
+
+impl Duplex for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) {
+        let _ = self.shutdown(Shutdown::Write);
+    }
+}
+
+/// Copy data in both directions until both sides are closed.
+fn relay<A: Duplex, B: Duplex>(mut a: A, mut b: B) -> io::Result<()> {
+    let mut a_read = a.try_clone()?;
+    let mut b_write = b.try_clone()?;
+    let upload = thread::spawn(move || {
+        let _ = io::copy(&mut a_read, &mut b_write);
+        b_write.shutdown_write();
+    });
+    let _ = io::copy(&mut b, &mut a);
+    a.shutdown_write();
+    let _ = upload.join();
+    Ok(())
+}
+
+/// Forward TCP connections to `127.0.0.1:port` to a Unix socket, in a background thread.
+pub fn forward(port: u16, socket_path: &Path) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let socket_path = socket_path.to_path_buf();
+    thread::Builder::new()
+        .name("proxy-forward".to_string())
+        .spawn(move || {
+            for client in listener.incoming().flatten() {
+                if let Ok(upstream) = UnixStream::connect(&socket_path) {
+                    thread::spawn(move || {
+                        let _ = relay(client, upstream);
+                    });
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn fake_resolver(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if host.ends_with(".test") {
+            Ok(vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))])
+        } else {
+            Err(io::ErrorKind::NotFound.into())
+        }
+    }
+
+    fn start_proxy(name: &str, allowed: &[&str]) -> RuntimeDir {
+        let dir = RuntimeDir::new(&format!("test-{name}")).unwrap();
+        let socket = dir.path().join(PROXY_SOCKET);
+        let allowed = allowed.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        serve(
+            UnixListener::bind(&socket).unwrap(),
+            HostAllowlist::new(&allowed),
+            fake_resolver,
+        )
+        .unwrap();
+        dir
+    }
+
+    fn start_server() -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buffer = [0; 1024];
+                let bytes_read = stream.read(&mut buffer).unwrap();
+                let request = String::from_utf8_lossy(&buffer[..bytes_read]).to_string();
+                let first_line = request.lines().next().unwrap_or("").to_string();
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                        first_line.len(),
+                        first_line
+                    )
+                    .as_bytes(),
+                );
+            }
+        });
+        port
+    }
+
+    fn exchange(socket: &Path, request: &str) -> String {
+        let mut stream = UnixStream::connect(socket).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let allowlist = HostAllowlist::new(&["example.org".to_string(), "*.crates.io".to_string()]);
+        assert!(allowlist.is_allowed("example.org"));
+        assert!(allowlist.is_allowed("Example.org."));
+        assert!(!allowlist.is_allowed("www.example.org"));
+        assert!(allowlist.is_allowed("static.crates.io"));
+        assert!(!allowlist.is_allowed("crates.io"));
+        assert!(!allowlist.is_allowed("evilcrates.io"));
+    }
+
+    #[test]
+    fn test_proxy_http() {
+        let port = start_server();
+        let dir = start_proxy("http", &["allowed.test"]);
+        let socket = dir.path().join(PROXY_SOCKET);
+
+        let response = exchange(
+            &socket,
+            &format!(
+                "GET http://allowed.test:{port}/index.html HTTP/1.1\r\nHost: allowed.test\r\n\r\n"
+            ),
+        );
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("GET /index.html HTTP/1.1"), "{response}");
+
+        let response = exchange(
+            &socket,
+            &format!("GET http://denied.test:{port}/ HTTP/1.1\r\nHost: denied.test\r\n\r\n"),
+        );
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+    }
+
+    #[test]
+    fn test_proxy_connect() {
+        let port = start_server();
+        let dir = start_proxy("connect", &["*.allowed.test"]);
+        let socket = dir.path().join(PROXY_SOCKET);
+
+        let response = exchange(
+            &socket,
+            &format!("CONNECT www.allowed.test:{port} HTTP/1.1\r\n\r\nPING / HTTP/1.1\r\n\r\n"),
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 200 Connection established\r\n\r\nHTTP/1.1 200 OK"),
+            "{response}"
+        );
+        assert!(response.ends_with("PING / HTTP/1.1"), "{response}");
+
+        let response = exchange(
+            &socket,
+            &format!("CONNECT allowed.test:{port} HTTP/1.1\r\n\r\n"),
+        );
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+    }
+}
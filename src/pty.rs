@@ -0,0 +1,304 @@
+//! # Pseudo terminal relay
+//!
+//! `pty` contains a relay between the terminal of the tool and a private pseudo terminal of the
+//! sandbox, so that the sandbox can't inject input into the host terminal (e.g. with `TIOCSTI`).
+
+use anyhow::Context;
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread::JoinHandle;
+use std::{io, ptr, thread};
+use termion::raw::{IntoRawMode, RawTerminal};
+
+const BUFFER_SIZE: usize = 4096;
+
+/// Write end of the pipe notified of window size changes, -1 if not set up.
+static RESIZE_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// A pseudo terminal for the sandbox, with the size of the host terminal.
+pub struct PtyRelay {
+    master: File,
+    slave: File,
+}
+
+/// A running relay, until the sandbox closed its terminal.
+pub struct RunningRelay {
+    output: JoinHandle<()>,
+    input: JoinHandle<()>,
+    /// Closed to stop relaying input
+    stop_input: File,
+    _raw: RawTerminal<io::Stdout>,
+    _window_size: WindowSizeWatch,
+}
+
+/// The `SIGWINCH` handler copying the window size, the previous handler is restored on drop.
+struct WindowSizeWatch {
+    pipe: File,
+    previous: libc::sighandler_t,
+}
+
+impl PtyRelay {
+    /// Creates a pseudo terminal if stdin and stdout are terminals, `None` otherwise.
+    pub fn new_if_interactive() -> Result<Option<Self>, anyhow::Error> {
+        if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+            return Ok(None);
+        }
+        let (mut master, mut slave) = (0, 0);
+        let size = window_size(io::stdout().as_raw_fd());
+        // SAFETY: the pointers are valid, the name is not requested
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                ptr::null_mut(),
+                ptr::null(),
+                size.as_ref().map_or(ptr::null(), |size| size as *const _),
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error()).context("Unable to create pseudo terminal");
+        }
+        // SAFETY: the file descriptors were just created and are owned by nothing else
+        let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+        for fd in [master.as_raw_fd(), slave.as_raw_fd()] {
+            // SAFETY: plain system call without pointers
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        Ok(Some(Self { master, slave }))
+    }
+
+    /// Runs the command on the pseudo terminal, as controlling terminal of a new session.
+    pub fn attach(&self, cmd: &mut Command) -> io::Result<()> {
+        cmd.stdin(Stdio::from(self.slave.try_clone()?));
+        cmd.stdout(Stdio::from(self.slave.try_clone()?));
+        if io::stderr().is_terminal() {
+            cmd.stderr(Stdio::from(self.slave.try_clone()?));
+        }
+        // SAFETY: setsid and ioctl are async-signal-safe
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Starts relaying, after the sandbox was started. Sets the host terminal to raw mode
+    /// until the relay is finished.
+    pub fn start(self) -> Result<RunningRelay, anyhow::Error> {
+        let Self { master, slave } = self;
+        // The sandbox has its own copies, the relay ends when they are closed
+        drop(slave);
+
+        let raw = io::stdout()
+            .into_raw_mode()
+            .context("Unable to set terminal to raw mode")?;
+        let window_size = watch_window_size(master.try_clone()?)?;
+
+        let mut input = master.try_clone()?;
+        let (stopped, stop_input) = pipe()?;
+        let input = thread::Builder::new()
+            .name("pty-input".to_string())
+            .spawn(move || {
+                // Stdin might never be closed, the relay is stopped instead
+                let _ = copy_input(io::stdin().as_raw_fd(), &stopped, &mut input);
+            })?;
+        let mut output = master;
+        let output = thread::Builder::new()
+            .name("pty-output".to_string())
+            .spawn(move || {
+                // Reading fails with EIO once the sandbox closed the terminal
+                let _ = copy(&mut output, &mut io::stdout());
+            })?;
+        Ok(RunningRelay {
+            output,
+            input,
+            stop_input,
+            _raw: raw,
+            _window_size: window_size,
+        })
+    }
+}
+
+impl RunningRelay {
+    /// Waits for the remaining output of the sandbox, stops reading the host terminal, so that
+    /// nothing typed afterwards is lost, and restores it.
+    pub fn finish(self) {
+        let _ = self.output.join();
+        drop(self.stop_input);
+        let _ = self.input.join();
+    }
+}
+
+impl Drop for WindowSizeWatch {
+    fn drop(&mut self) {
+        // SAFETY: the previous handler was returned by `signal`
+        unsafe { libc::signal(libc::SIGWINCH, self.previous) };
+        // The thread copying the size ends when the pipe is closed, after this
+        RESIZE_PIPE
+            .compare_exchange(
+                self.pipe.as_raw_fd(),
+                -1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .ok();
+    }
+}
+
+fn copy(from: &mut impl Read, to: &mut impl Write) -> io::Result<()> {
+    let mut buffer = [0; BUFFER_SIZE];
+    loop {
+        let bytes_read = from.read(&mut buffer)?;
+        if bytes_read < 1 {
+            return Ok(());
+        }
+        to.write_all(&buffer[..bytes_read])?;
+        to.flush()?;
+    }
+}
+
+/// Copies from the file descriptor until it is closed or the write end of `stopped` is closed.
+/// Reads only what is available, so that nothing is read after it was stopped.
+fn copy_input(from: RawFd, stopped: &File, to: &mut impl Write) -> io::Result<()> {
+    let mut buffer = [0u8; BUFFER_SIZE];
+    loop {
+        let mut fds = [from, stopped.as_raw_fd()].map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        // SAFETY: the array is valid for its length
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+        if fds[1].revents != 0 {
+            return Ok(());
+        }
+        // SAFETY: the buffer is valid for writing its length
+        let bytes_read = unsafe { libc::read(from, buffer.as_mut_ptr().cast(), buffer.len()) };
+        if bytes_read < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        to.write_all(&buffer[..bytes_read as usize])?;
+        to.flush()?;
+    }
+}
+
+/// A pipe, the read end and the write end.
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    // SAFETY: the array has room for both file descriptors
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptors were just created and are owned by nothing else
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+fn window_size(fd: RawFd) -> Option<libc::winsize> {
+    // SAFETY: the structure is valid for writing
+    let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+    // SAFETY: the pointer is valid for this request
+    (unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } == 0).then_some(size)
+}
+
+/// Copies the size of the host terminal to the pseudo terminal whenever it changes, until the
+/// watch is dropped.
+fn watch_window_size(master: File) -> Result<WindowSizeWatch, anyhow::Error> {
+    let (mut notifications, pipe) = pipe().context("Unable to watch terminal size")?;
+    RESIZE_PIPE.store(pipe.as_raw_fd(), Ordering::Relaxed);
+
+    extern "C" fn on_resize(_: libc::c_int) {
+        let fd = RESIZE_PIPE.load(Ordering::Relaxed);
+        if fd >= 0 {
+            // SAFETY: write is async-signal-safe, the buffer is valid
+            unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+        }
+    }
+    // SAFETY: the handler only calls async-signal-safe functions
+    let previous =
+        unsafe { libc::signal(libc::SIGWINCH, on_resize as *const () as libc::sighandler_t) };
+
+    thread::Builder::new()
+        .name("pty-resize".to_string())
+        .spawn(move || {
+            let mut buffer = [0; 1];
+            while notifications.read(&mut buffer).is_ok_and(|n| n > 0) {
+                if let Some(size) = window_size(io::stdout().as_raw_fd()) {
+                    // SAFETY: the pointer is valid for this request
+                    unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &size) };
+                }
+            }
+        })?;
+    Ok(WindowSizeWatch { pipe, previous })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_size_watch() -> Result<(), anyhow::Error> {
+        let watch = watch_window_size(File::open("/dev/null")?)?;
+        let pipe = watch.pipe.as_raw_fd();
+        assert_eq!(RESIZE_PIPE.load(Ordering::Relaxed), pipe);
+        drop(watch);
+
+        assert_eq!(RESIZE_PIPE.load(Ordering::Relaxed), -1);
+        // SAFETY: plain system call without pointers
+        assert_eq!(unsafe { libc::fcntl(pipe, libc::F_GETFD) }, -1);
+        // SAFETY: querying the handler does not dereference the null pointer
+        let mut action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+        unsafe { libc::sigaction(libc::SIGWINCH, ptr::null(), &mut action) };
+        assert_eq!(action.sa_sigaction, libc::SIG_DFL);
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_input() -> Result<(), anyhow::Error> {
+        let (mut input, mut host) = pipe()?;
+        let (stopped, stop) = pipe()?;
+        let fd = input.as_raw_fd();
+        let relay = thread::spawn(move || {
+            let mut copied = Vec::new();
+            copy_input(fd, &stopped, &mut copied).map(|()| copied)
+        });
+        host.write_all(b"relayed")?;
+        let mut unread: libc::c_int = 1;
+        while unread > 0 {
+            thread::sleep(std::time::Duration::from_millis(10));
+            // SAFETY: the pointer is valid for this request
+            unsafe { libc::ioctl(fd, libc::FIONREAD, &mut unread) };
+        }
+
+        // Stopped while the input is still open, what is typed afterwards is left to others
+        drop(stop);
+        let copied = relay.join().expect("relay")?;
+        assert_eq!(copied, b"relayed");
+        host.write_all(b"answer")?;
+        drop(host);
+        let mut remaining = String::new();
+        input.read_to_string(&mut remaining)?;
+        assert_eq!(remaining, "answer");
+        Ok(())
+    }
+}
@@ -0,0 +1,252 @@
+//! # Report
+//!
+//! `report` contains the summary of a sandbox run, explaining abnormal terminations and
+//! optionally written to a file as JSON.
+
+use anyhow::Context;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::docker_client::ContainerInspect;
+
+const SIGNAL_NAMES: [(i32, &str); 15] = [
+    (1, "SIGHUP"),
+    (2, "SIGINT"),
+    (3, "SIGQUIT"),
+    (4, "SIGILL"),
+    (5, "SIGTRAP"),
+    (6, "SIGABRT"),
+    (7, "SIGBUS"),
+    (8, "SIGFPE"),
+    (9, "SIGKILL"),
+    (10, "SIGUSR1"),
+    (11, "SIGSEGV"),
+    (12, "SIGUSR2"),
+    (13, "SIGPIPE"),
+    (14, "SIGALRM"),
+    (15, "SIGTERM"),
+];
+
+/// Summary of a sandbox run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunReport {
+    pub exit_code: u8,
+    pub duration: Duration,
+    pub timed_out: bool,
+    /// The signal which terminated the sandbox, if known. An exit code of `128 + signal` may as
+    /// well be returned by the program itself
+    pub signal: Option<i32>,
+    /// Details of the container after it exited, if available
+    pub inspect: Option<ContainerInspect>,
+}
+
+impl RunReport {
+    /// Report of a container run. The daemon reports a signal only as an exit code of
+    /// `128 + signal` in the state of the container, so that is taken as the signal, unless the
+    /// container was killed for its timeout or for running out of memory.
+    pub fn new(
+        exit_code: u8,
+        duration: Duration,
+        timed_out: bool,
+        inspect: Option<ContainerInspect>,
+    ) -> Self {
+        let signal = match &inspect {
+            _ if timed_out => Some(libc::SIGKILL),
+            Some(inspect) if inspect.state.oom_killed => Some(libc::SIGKILL),
+            Some(inspect) if inspect.state.status == "exited" => {
+                exit_signal(inspect.state.exit_code)
+            }
+            _ => None,
+        };
+        Self {
+            exit_code,
+            duration,
+            timed_out,
+            signal,
+            inspect,
+        }
+    }
+
+    pub fn oom_killed(&self) -> bool {
+        self.inspect.as_ref().is_some_and(|i| i.state.oom_killed)
+    }
+
+    /// The error from the container runtime, if any.
+    pub fn error(&self) -> Option<&str> {
+        self.inspect
+            .as_ref()
+            .map(|i| i.state.error.as_str())
+            .filter(|e| !e.is_empty())
+    }
+
+    /// Human-readable explanations of an abnormal termination.
+    pub fn diagnostics(&self) -> Vec<String> {
+        let mut diagnostics = Vec::new();
+        if self.timed_out {
+            diagnostics.push(format!(
+                "killed after timeout of {}s",
+                self.duration.as_secs()
+            ));
+        } else if self.oom_killed() {
+            diagnostics.push("killed because it ran out of memory".to_string());
+        } else if let Some(signal) = self.signal {
+            diagnostics.push(format!(
+                "terminated by signal {signal} ({})",
+                signal_name(signal)
+            ));
+        }
+        if let Some(error) = self.error() {
+            diagnostics.push(format!("runtime error: {error}"));
+        }
+        diagnostics
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "exit_code": self.exit_code,
+            "duration_secs": self.duration.as_secs_f64(),
+            "timed_out": self.timed_out,
+            "oom_killed": self.oom_killed(),
+            "signal": self.signal.map(signal_name),
+            "error": self.error(),
+            "image_id": self.inspect.as_ref().map(|i| i.image.as_str()),
+        })
+    }
+
+    /// Print the diagnostics to stderr, and write the report as JSON to the file, if any.
+    pub fn print(&self, path: Option<&Path>) -> Result<(), anyhow::Error> {
+        for diagnostic in self.diagnostics() {
+            eprintln!("contained: {diagnostic}");
+        }
+        if let Some(path) = path {
+            fs::write(path, self.to_json().to_string() + "\n")
+                .with_context(|| format!("Unable to write report {path:?}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// The known signal for an exit code of `128 + signal`, if any.
+fn exit_signal(exit_code: i64) -> Option<i32> {
+    SIGNAL_NAMES
+        .iter()
+        .map(|(number, _)| *number)
+        .find(|number| i64::from(*number) + 128 == exit_code)
+}
+
+fn signal_name(signal: i32) -> String {
+    SIGNAL_NAMES
+        .iter()
+        .find(|(number, _)| *number == signal)
+        .map_or_else(|| format!("SIG{signal}"), |(_, name)| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime_dir::RuntimeDir;
+
+    fn inspect(state: Value) -> ContainerInspect {
+        ContainerInspect::from_json(&json!({
+            "Image": "sha256:0123abcd",
+            "State": state,
+        }))
+    }
+
+    #[test]
+    fn test_oom_report() {
+        let report = RunReport::new(
+            137,
+            Duration::from_millis(1500),
+            false,
+            Some(inspect(json!({
+                "Status": "exited",
+                "ExitCode": 137,
+                "OOMKilled": true,
+                "Error": "",
+            }))),
+        );
+
+        assert!(report.oom_killed());
+        assert_eq!(report.signal, Some(9));
+        assert_eq!(
+            report.diagnostics(),
+            ["killed because it ran out of memory"]
+        );
+        assert_eq!(
+            report.to_json(),
+            json!({
+                "exit_code": 137,
+                "duration_secs": 1.5,
+                "timed_out": false,
+                "oom_killed": true,
+                "signal": "SIGKILL",
+                "error": null,
+                "image_id": "sha256:0123abcd",
+            })
+        );
+    }
+
+    #[test]
+    fn test_signal_and_error_report() {
+        let report = RunReport::new(
+            139,
+            Duration::from_secs(1),
+            false,
+            Some(inspect(json!({
+                "Status": "exited",
+                "ExitCode": 139,
+                "OOMKilled": false,
+                "Error": "exec format error",
+            }))),
+        );
+        assert_eq!(report.signal, Some(11));
+        assert_eq!(
+            report.diagnostics(),
+            [
+                "terminated by signal 11 (SIGSEGV)",
+                "runtime error: exec format error"
+            ]
+        );
+
+        let timed_out = RunReport::new(124, Duration::from_secs(5), true, None);
+        assert_eq!(timed_out.signal, Some(9));
+        assert_eq!(timed_out.diagnostics(), ["killed after timeout of 5s"]);
+
+        // Not a signal, or a signal which is not known
+        for exit_code in [1, 128, 200] {
+            let report = RunReport::new(
+                exit_code,
+                Duration::from_secs(1),
+                false,
+                Some(inspect(json!({
+                    "Status": "exited",
+                    "ExitCode": exit_code,
+                    "OOMKilled": false,
+                    "Error": "",
+                }))),
+            );
+            assert_eq!(report.signal, None);
+            assert!(report.diagnostics().is_empty());
+        }
+
+        // Without the state of the container, 130 is not taken as being interrupted
+        let report = RunReport::new(130, Duration::from_secs(1), false, None);
+        assert!(report.diagnostics().is_empty());
+        assert_eq!(report.to_json()["signal"], Value::Null);
+        assert_eq!(report.to_json()["image_id"], Value::Null);
+    }
+
+    #[test]
+    fn test_report_file() -> Result<(), anyhow::Error> {
+        let dir = RuntimeDir::new("report")?;
+        let path = dir.path().join("report.json");
+        let report = RunReport::new(0, Duration::from_secs(2), false, None);
+        report.print(Some(&path))?;
+        let written: Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        assert_eq!(written, report.to_json());
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+//! # Runtime directory
+//!
+//! `runtime_dir` contains a private directory for files shared with the sandbox while it runs.
+
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs, io, process};
+
+/// Shared memory, used when `$XDG_RUNTIME_DIR` isn't a memory-only file system.
+const SHARED_MEMORY: &str = "/dev/shm";
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A private directory in `$XDG_RUNTIME_DIR` (or the temp dir if not set), removed on drop.
+pub struct RuntimeDir {
+    path: PathBuf,
+}
+
+impl RuntimeDir {
+    pub fn new(name: &str) -> io::Result<Self> {
+        let base = env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir);
+        Self::create_in(&base, name)
+    }
+
+    /// Creates the directory on a memory-only file system, `$XDG_RUNTIME_DIR` or `/dev/shm`, for
+    /// files which must never be written to disk.
+    pub fn in_memory(name: &str) -> io::Result<Self> {
+        let base = env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .into_iter()
+            .chain([PathBuf::from(SHARED_MEMORY)])
+            .find(|base| is_tmpfs(base))
+            .ok_or(io::Error::other(
+                "No memory-only file system, neither $XDG_RUNTIME_DIR nor /dev/shm is a tmpfs",
+            ))?;
+        Self::create_in(&base, name)
+    }
+
+    pub(crate) fn create_in(base: &Path, name: &str) -> io::Result<Self> {
+        let path = base.join(format!(
+            "contained-{}-{}-{}",
+            name,
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::DirBuilder::new().mode(0o700).create(&path)?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn is_tmpfs(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: the path is null-terminated, and the structure is valid for writing
+    if unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    // SAFETY: statfs succeeded, so it filled in the structure
+    let stat = unsafe { stat.assume_init() };
+    // The types differ between architectures
+    #[allow(clippy::unnecessary_cast)]
+    let is_tmpfs = stat.f_type as i64 == libc::TMPFS_MAGIC as i64;
+    is_tmpfs
+}
+
+impl Drop for RuntimeDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
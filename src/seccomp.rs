@@ -0,0 +1,852 @@
+//! # Seccomp
+//!
+//! `seccomp` contains a compiler for seccomp BPF filters, from a built-in deny list of dangerous
+//! syscalls or from a seccomp profile in the Docker/OCI JSON format.
+
+use anyhow::{anyhow, Context};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{Seek, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fs, io};
+
+mod syscalls;
+
+/// File descriptor the compiled filter is passed on, to `bwrap --seccomp`.
+pub const SECCOMP_FD: i32 = 3;
+
+/// Syscalls denied by the built-in filter.
+const DENIED_SYSCALLS: [&str; 15] = [
+    "ptrace",
+    "process_vm_readv",
+    "process_vm_writev",
+    "keyctl",
+    "add_key",
+    "request_key",
+    "bpf",
+    "userfaultfd",
+    "perf_event_open",
+    "kexec_load",
+    "kexec_file_load",
+    "init_module",
+    "finit_module",
+    "delete_module",
+    "open_by_handle_at",
+];
+/// `ioctl` requests denied by the built-in filter, which allow injecting input into the terminal.
+const DENIED_IOCTLS: [u64; 2] = [libc::TIOCSTI, libc::TIOCLINUX];
+
+/// Architecture checked by the filter, `None` where filters are not supported.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+/// Architecture names used in profiles.
+#[cfg(target_arch = "x86_64")]
+const ARCH_NAMES: &[&str] = &["amd64", "x86_64", "SCMP_ARCH_X86_64"];
+#[cfg(target_arch = "aarch64")]
+const ARCH_NAMES: &[&str] = &["arm64", "aarch64", "SCMP_ARCH_AARCH64"];
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const ARCH_NAMES: &[&str] = &[];
+/// Syscalls of the x32 ABI have this bit set in their number.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_JGT_K: u16 = 0x25;
+const BPF_JGE_K: u16 = 0x35;
+const BPF_AND_K: u16 = 0x54;
+const BPF_RET_K: u16 = 0x06;
+/// Maximum number of instructions in a filter.
+const BPF_MAXINSNS: usize = 4096;
+
+const OFFSET_NR: u32 = 0;
+const OFFSET_ARCH: u32 = 4;
+const OFFSET_ARGS: u32 = 16;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_KILL_THREAD: u32 = 0x0000_0000;
+const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+/// The seccomp profile of the sandbox.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum SeccompProfile {
+    /// The built-in filter for bubblewrap and Landlock, the default profile of the container engine
+    #[default]
+    Default,
+    /// No seccomp filter
+    Unconfined,
+    /// A profile in the Docker/OCI JSON format
+    File(PathBuf),
+}
+
+impl FromStr for SeccompProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(SeccompProfile::Default),
+            "unconfined" => Ok(SeccompProfile::Unconfined),
+            "" => Err(anyhow!("Empty seccomp profile")),
+            path => Ok(SeccompProfile::File(PathBuf::from(path))),
+        }
+    }
+}
+
+impl SeccompProfile {
+    /// Compiles the filter for this profile, `None` if unconfined.
+    pub fn compile(&self) -> Result<Option<Filter>, anyhow::Error> {
+        match self {
+            SeccompProfile::Default => Ok(Some(Filter::default_deny_list())),
+            SeccompProfile::Unconfined => Ok(None),
+            SeccompProfile::File(path) => Filter::from_profile(path).map(Some),
+        }
+    }
+
+    /// Value for `--security-opt` of Podman, `None` to use its default profile.
+    pub fn podman_security_opt(&self) -> Option<String> {
+        match self {
+            SeccompProfile::Default => None,
+            SeccompProfile::Unconfined => Some("seccomp=unconfined".to_string()),
+            SeccompProfile::File(path) => Some(format!("seccomp={}", path.display())),
+        }
+    }
+
+    /// Value for `HostConfig.SecurityOpt` of the Docker API, which takes the profile content.
+    pub fn docker_security_opt(&self) -> Result<Option<String>, anyhow::Error> {
+        match self {
+            SeccompProfile::Default => Ok(None),
+            SeccompProfile::Unconfined => Ok(Some("seccomp=unconfined".to_string())),
+            SeccompProfile::File(path) => {
+                let profile = read_profile(path)?;
+                Ok(Some(format!("seccomp={profile}")))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Allow,
+    Errno(u16),
+    KillThread,
+    KillProcess,
+    Trap,
+    Trace(u16),
+    Log,
+}
+
+impl Action {
+    fn value(self) -> u32 {
+        match self {
+            Action::Allow => SECCOMP_RET_ALLOW,
+            Action::Errno(errno) => SECCOMP_RET_ERRNO | errno as u32,
+            Action::KillThread => SECCOMP_RET_KILL_THREAD,
+            Action::KillProcess => SECCOMP_RET_KILL_PROCESS,
+            Action::Trap => SECCOMP_RET_TRAP,
+            Action::Trace(data) => SECCOMP_RET_TRACE | data as u32,
+            Action::Log => SECCOMP_RET_LOG,
+        }
+    }
+
+    fn parse(action: &str, errno: u16) -> Result<Self, anyhow::Error> {
+        match action {
+            "SCMP_ACT_ALLOW" => Ok(Action::Allow),
+            "SCMP_ACT_ERRNO" => Ok(Action::Errno(errno)),
+            "SCMP_ACT_KILL" | "SCMP_ACT_KILL_THREAD" => Ok(Action::KillThread),
+            "SCMP_ACT_KILL_PROCESS" => Ok(Action::KillProcess),
+            "SCMP_ACT_TRAP" => Ok(Action::Trap),
+            "SCMP_ACT_TRACE" => Ok(Action::Trace(errno)),
+            "SCMP_ACT_LOG" => Ok(Action::Log),
+            _ => Err(anyhow!("Unsupported seccomp action {action}")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Equal after applying the mask
+    MaskedEq(u64),
+}
+
+/// A condition on a syscall argument.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArgCondition {
+    pub index: u8,
+    pub comparison: Comparison,
+    pub value: u64,
+}
+
+/// The action for a syscall, if all argument conditions match.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub syscall: u32,
+    pub args: Vec<ArgCondition>,
+    pub action: Action,
+}
+
+/// A seccomp filter, the first matching rule applies.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    pub default_action: Action,
+    pub rules: Vec<Rule>,
+}
+
+impl Filter {
+    /// The built-in filter, denying dangerous syscalls.
+    pub fn default_deny_list() -> Self {
+        let deny = Action::Errno(libc::EPERM as u16);
+        let mut rules = DENIED_SYSCALLS
+            .iter()
+            .filter_map(|name| syscall_number(name))
+            .map(|syscall| Rule {
+                syscall,
+                args: Vec::new(),
+                action: deny,
+            })
+            .collect::<Vec<_>>();
+        for request in DENIED_IOCTLS {
+            rules.push(Rule {
+                syscall: libc::SYS_ioctl as u32,
+                args: vec![ArgCondition {
+                    index: 1,
+                    comparison: Comparison::MaskedEq(0xffff_ffff),
+                    value: request,
+                }],
+                action: deny,
+            });
+        }
+        Self {
+            default_action: Action::Allow,
+            rules,
+        }
+    }
+
+    /// Reads a seccomp profile in the Docker/OCI JSON format.
+    pub fn from_profile(path: &Path) -> Result<Self, anyhow::Error> {
+        let profile: Value = serde_json::from_str(&read_profile(path)?)
+            .with_context(|| format!("Invalid seccomp profile {path:?}"))?;
+        Self::from_json(&profile).with_context(|| format!("Invalid seccomp profile {path:?}"))
+    }
+
+    fn from_json(profile: &Value) -> Result<Self, anyhow::Error> {
+        let default_errno = profile["defaultErrnoRet"]
+            .as_u64()
+            .unwrap_or(libc::EPERM as u64) as u16;
+        let default_action = Action::parse(
+            profile["defaultAction"]
+                .as_str()
+                .ok_or(anyhow!("Missing defaultAction"))?,
+            default_errno,
+        )?;
+
+        let mut rules = Vec::new();
+        for entry in profile["syscalls"].as_array().unwrap_or(&Vec::new()) {
+            if !applies(&entry["includes"], &entry["excludes"]) {
+                continue;
+            }
+            let errno = entry["errnoRet"]
+                .as_u64()
+                .map_or(default_errno, |errno| errno as u16);
+            let action = Action::parse(
+                entry["action"].as_str().ok_or(anyhow!("Missing action"))?,
+                errno,
+            )?;
+            let args = entry["args"]
+                .as_array()
+                .unwrap_or(&Vec::new())
+                .iter()
+                .map(parse_arg)
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut names = list(&entry["names"]);
+            names.extend(entry["name"].as_str());
+            // Syscalls unknown on this architecture are ignored, like libseccomp does
+            for syscall in names.into_iter().filter_map(syscall_number) {
+                rules.push(Rule {
+                    syscall,
+                    args: args.clone(),
+                    action,
+                });
+            }
+        }
+        Ok(Self {
+            default_action,
+            rules,
+        })
+    }
+
+    /// Compiles the filter to a BPF program, as `sock_filter` structures. Fails on architectures
+    /// without filter support.
+    pub fn compile(&self) -> Result<Vec<libc::sock_filter>, anyhow::Error> {
+        let arch = AUDIT_ARCH.ok_or(anyhow!(
+            "Seccomp filters are not supported on {}, use `--seccomp-profile unconfined`",
+            std::env::consts::ARCH
+        ))?;
+        let mut program = vec![
+            load(OFFSET_ARCH),
+            jump(BPF_JEQ_K, arch, 1, 0),
+            ret(SECCOMP_RET_KILL_PROCESS),
+            load(OFFSET_NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        {
+            program.push(jump(BPF_JGE_K, X32_SYSCALL_BIT, 0, 1));
+            program.push(ret(Action::Errno(libc::ENOSYS as u16).value()));
+        }
+
+        let mut loaded_nr = true;
+        for rule in &self.rules {
+            let block = rule_block(rule, !loaded_nr)?;
+            program.extend(block);
+            loaded_nr = rule.args.is_empty();
+        }
+        program.push(ret(self.default_action.value()));
+
+        if program.len() > BPF_MAXINSNS {
+            return Err(anyhow!(
+                "Seccomp filter is too large, {} instructions",
+                program.len()
+            ));
+        }
+        Ok(program)
+    }
+
+    /// Compiles the filter into an anonymous file, as read by `bwrap --seccomp`.
+    pub fn to_file(&self) -> Result<File, anyhow::Error> {
+        let program = self.compile()?;
+        let name = c"contained-seccomp";
+        // SAFETY: the name is a valid C string
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("Unable to create seccomp filter");
+        }
+        // SAFETY: the file descriptor was just created and is owned by nothing else
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        for instruction in program {
+            file.write_all(&instruction.code.to_ne_bytes())?;
+            file.write_all(&[instruction.jt, instruction.jf])?;
+            file.write_all(&instruction.k.to_ne_bytes())?;
+        }
+        file.rewind()?;
+        Ok(file)
+    }
+}
+
+/// Reads a compiled filter, in the format of [Filter::to_file].
+pub fn read_program(mut file: File) -> io::Result<Vec<libc::sock_filter>> {
+    let mut bytes = Vec::new();
+    io::Read::read_to_end(&mut file, &mut bytes)?;
+    if !bytes.len().is_multiple_of(8) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid seccomp filter",
+        ));
+    }
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|b| libc::sock_filter {
+            code: u16::from_ne_bytes([b[0], b[1]]),
+            jt: b[2],
+            jf: b[3],
+            k: u32::from_ne_bytes([b[4], b[5], b[6], b[7]]),
+        })
+        .collect())
+}
+
+/// Installs a compiled filter for the calling thread and what it executes.
+/// Only calls async-signal-safe functions, so it can be used in `pre_exec`.
+/// Requires `no_new_privs` to be set.
+pub fn install(program: &[libc::sock_filter]) -> io::Result<()> {
+    let prog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    // SAFETY: the program is valid while the filter is installed, the kernel copies it
+    let result = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Moves a compiled filter to [SECCOMP_FD] without close-on-exec, to pass it to bubblewrap.
+pub fn pass_to_exec(file: &File) -> io::Result<()> {
+    // SAFETY: plain system calls on file descriptors
+    unsafe {
+        if file.as_raw_fd() != SECCOMP_FD && libc::dup2(file.as_raw_fd(), SECCOMP_FD) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::fcntl(SECCOMP_FD, libc::F_SETFD, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn read_profile(path: &Path) -> Result<String, anyhow::Error> {
+    fs::read_to_string(path).with_context(|| format!("Unable to read seccomp profile {path:?}"))
+}
+
+fn syscall_number(name: &str) -> Option<u32> {
+    syscalls::COMMON
+        .iter()
+        .chain(syscalls::ARCH)
+        .find(|(sys, _)| sys.strip_prefix("SYS_") == Some(name))
+        .map(|(_, number)| *number)
+}
+
+/// Whether a profile entry applies, given its `includes` and `excludes` conditions.
+/// The sandbox has no capabilities, so entries requiring capabilities never apply.
+fn applies(includes: &Value, excludes: &Value) -> bool {
+    let is_arch = |arch: &&str| ARCH_NAMES.contains(arch);
+
+    let include_arches = list(&includes["arches"]);
+    if !include_arches.is_empty() && !include_arches.iter().any(is_arch) {
+        return false;
+    }
+    if !list(&includes["caps"]).is_empty() {
+        return false;
+    }
+    if let Some(min_kernel) = includes["minKernel"].as_str() {
+        if kernel_version() < parse_kernel_version(min_kernel) {
+            return false;
+        }
+    }
+    !list(&excludes["arches"]).iter().any(is_arch)
+}
+
+fn list(value: &Value) -> Vec<&str> {
+    value
+        .as_array()
+        .map(|values| values.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default()
+}
+
+fn kernel_version() -> (u32, u32) {
+    // SAFETY: utsname only contains arrays of chars, for which all zeroes are valid
+    let mut uts = unsafe { std::mem::zeroed::<libc::utsname>() };
+    // SAFETY: the structure is valid for writing
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return (0, 0);
+    }
+    // SAFETY: uname returns a null-terminated string
+    let release = unsafe { std::ffi::CStr::from_ptr(uts.release.as_ptr()) };
+    parse_kernel_version(&release.to_string_lossy())
+}
+
+fn parse_kernel_version(version: &str) -> (u32, u32) {
+    let mut parts = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+fn parse_arg(arg: &Value) -> Result<ArgCondition, anyhow::Error> {
+    let index = arg["index"]
+        .as_u64()
+        .filter(|index| *index < 6)
+        .ok_or(anyhow!("Invalid argument index in {arg}"))? as u8;
+    let value = arg["value"].as_u64().unwrap_or(0);
+    let value_two = arg["valueTwo"].as_u64().unwrap_or(0);
+    let (comparison, value) = match arg["op"].as_str() {
+        Some("SCMP_CMP_EQ") => (Comparison::Eq, value),
+        Some("SCMP_CMP_NE") => (Comparison::Ne, value),
+        Some("SCMP_CMP_LT") => (Comparison::Lt, value),
+        Some("SCMP_CMP_LE") => (Comparison::Le, value),
+        Some("SCMP_CMP_GT") => (Comparison::Gt, value),
+        Some("SCMP_CMP_GE") => (Comparison::Ge, value),
+        Some("SCMP_CMP_MASKED_EQ") => (Comparison::MaskedEq(value), value_two),
+        op => return Err(anyhow!("Unsupported comparison {op:?}")),
+    };
+    Ok(ArgCondition {
+        index,
+        comparison,
+        value,
+    })
+}
+
+/// Instructions for a rule, jumping past the end of the block if it does not match.
+fn rule_block(rule: &Rule, load_nr: bool) -> Result<Vec<libc::sock_filter>, anyhow::Error> {
+    // Jump targets relative to the block: FAIL is past the end, resolved at the end
+    const FAIL: u8 = u8::MAX;
+    let mut block = Vec::new();
+    if load_nr {
+        block.push(load(OFFSET_NR));
+    }
+    block.push(jump(BPF_JEQ_K, rule.syscall, 0, FAIL));
+
+    for arg in &rule.args {
+        let offset = OFFSET_ARGS + 8 * arg.index as u32;
+        // Arguments are 64 bits, compared as high and low words (little-endian)
+        let (high, low) = ((arg.value >> 32) as u32, arg.value as u32);
+        match arg.comparison {
+            Comparison::Eq | Comparison::Ne | Comparison::MaskedEq(_) => {
+                let (mask_high, mask_low) = match arg.comparison {
+                    Comparison::MaskedEq(mask) => ((mask >> 32) as u32, mask as u32),
+                    _ => (u32::MAX, u32::MAX),
+                };
+                let masked = matches!(arg.comparison, Comparison::MaskedEq(_));
+                if arg.comparison == Comparison::Ne {
+                    // Matches if either word differs
+                    block.push(load(offset + 4));
+                    block.push(jump(BPF_JEQ_K, high, 0, 2));
+                    block.push(load(offset));
+                    block.push(jump(BPF_JEQ_K, low, FAIL, 0));
+                } else {
+                    block.push(load(offset + 4));
+                    if masked {
+                        block.push(alu_and(mask_high));
+                    }
+                    block.push(jump(BPF_JEQ_K, high & mask_high, 0, FAIL));
+                    block.push(load(offset));
+                    if masked {
+                        block.push(alu_and(mask_low));
+                    }
+                    block.push(jump(BPF_JEQ_K, low & mask_low, 0, FAIL));
+                }
+            }
+            Comparison::Gt | Comparison::Ge | Comparison::Lt | Comparison::Le => {
+                // Greater (or less) in the high word decides, equal high words compare the low word
+                let greater = matches!(arg.comparison, Comparison::Gt | Comparison::Ge);
+                let low_op = match arg.comparison {
+                    Comparison::Gt | Comparison::Le => BPF_JGT_K,
+                    _ => BPF_JGE_K,
+                };
+                block.push(load(offset + 4));
+                if greater {
+                    block.push(jump(BPF_JGT_K, high, 3, 0));
+                } else {
+                    block.push(jump(BPF_JGE_K, high, 0, 3));
+                }
+                block.push(jump(BPF_JEQ_K, high, 0, FAIL));
+                block.push(load(offset));
+                // For less than, the low word comparison is inverted
+                if greater {
+                    block.push(jump(low_op, low, 0, FAIL));
+                } else {
+                    block.push(jump(low_op, low, FAIL, 0));
+                }
+            }
+        }
+    }
+    block.push(ret(rule.action.value()));
+
+    // Resolve the jumps past the end of the block
+    let len = block.len();
+    if len > u8::MAX as usize {
+        return Err(anyhow!("Seccomp rule is too large"));
+    }
+    for (i, instruction) in block.iter_mut().enumerate() {
+        let past_end = (len - i - 1) as u8;
+        if instruction.code & 0x07 == 0x05 {
+            if instruction.jt == FAIL {
+                instruction.jt = past_end;
+            }
+            if instruction.jf == FAIL {
+                instruction.jf = past_end;
+            }
+        }
+    }
+    Ok(block)
+}
+
+fn load(offset: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: BPF_LD_W_ABS,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+fn alu_and(k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: BPF_AND_K,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn ret(k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: BPF_RET_K,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+#[cfg(all(test, any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    /// Runs a filter on a syscall, like the kernel does.
+    fn evaluate(program: &[libc::sock_filter], nr: u32, args: [u64; 6]) -> u32 {
+        let word = |offset: u32| match offset {
+            OFFSET_NR => nr,
+            OFFSET_ARCH => AUDIT_ARCH.expect("supported architecture"),
+            _ => {
+                let arg = args[((offset - OFFSET_ARGS) / 8) as usize];
+                if offset.is_multiple_of(8) {
+                    arg as u32
+                } else {
+                    (arg >> 32) as u32
+                }
+            }
+        };
+        let (mut pc, mut acc) = (0, 0);
+        loop {
+            let i = program[pc];
+            pc += 1;
+            match i.code {
+                BPF_LD_W_ABS => acc = word(i.k),
+                BPF_AND_K => acc &= i.k,
+                BPF_RET_K => return i.k,
+                code => {
+                    let taken = match code {
+                        BPF_JEQ_K => acc == i.k,
+                        BPF_JGT_K => acc > i.k,
+                        BPF_JGE_K => acc >= i.k,
+                        _ => panic!("unexpected instruction {code:#x}"),
+                    };
+                    pc += if taken { i.jt } else { i.jf } as usize;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_compile_comparisons() -> Result<(), anyhow::Error> {
+        let values = [0, 1, 0xffff_ffff, 0x1_0000_0000, 0x1_0000_0001, u64::MAX];
+        for comparison in [
+            Comparison::Eq,
+            Comparison::Ne,
+            Comparison::Lt,
+            Comparison::Le,
+            Comparison::Gt,
+            Comparison::Ge,
+            Comparison::MaskedEq(0xffff_0000_ffff),
+        ] {
+            for value in values {
+                let filter = Filter {
+                    default_action: Action::Allow,
+                    rules: vec![Rule {
+                        syscall: 1,
+                        args: vec![ArgCondition {
+                            index: 2,
+                            comparison,
+                            value: value & 0xffff_0000_ffff,
+                        }],
+                        action: Action::Errno(1),
+                    }],
+                };
+                let program = filter.compile()?;
+                for arg in values {
+                    let value = value & 0xffff_0000_ffff;
+                    let expected = match comparison {
+                        Comparison::Eq => arg == value,
+                        Comparison::Ne => arg != value,
+                        Comparison::Lt => arg < value,
+                        Comparison::Le => arg <= value,
+                        Comparison::Gt => arg > value,
+                        Comparison::Ge => arg >= value,
+                        Comparison::MaskedEq(mask) => arg & mask == value,
+                    };
+                    let action = evaluate(&program, 1, [0, 0, arg, 0, 0, 0]);
+                    assert_eq!(
+                        action == Action::Errno(1).value(),
+                        expected,
+                        "{arg:#x} {comparison:?} {value:#x}"
+                    );
+                    // Other syscalls are not affected
+                    assert_eq!(
+                        evaluate(&program, 2, [0, 0, arg, 0, 0, 0]),
+                        SECCOMP_RET_ALLOW
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_deny_list() -> Result<(), anyhow::Error> {
+        let program = Filter::default_deny_list().compile()?;
+        let ptrace = libc::SYS_ptrace as u32;
+        let ioctl = libc::SYS_ioctl as u32;
+        let eperm = Action::Errno(libc::EPERM as u16).value();
+
+        assert_eq!(evaluate(&program, ptrace, [0; 6]), eperm);
+        assert_eq!(evaluate(&program, libc::SYS_bpf as u32, [0; 6]), eperm);
+        assert_eq!(
+            evaluate(&program, ioctl, [0, libc::TIOCSTI, 0, 0, 0, 0]),
+            eperm
+        );
+        // The upper half of the ioctl request is ignored by the kernel
+        assert_eq!(
+            evaluate(&program, ioctl, [0, 1 << 32 | libc::TIOCSTI, 0, 0, 0, 0]),
+            eperm
+        );
+        assert_eq!(
+            evaluate(&program, ioctl, [0, libc::TIOCGWINSZ, 0, 0, 0, 0]),
+            SECCOMP_RET_ALLOW
+        );
+        assert_eq!(
+            evaluate(&program, libc::SYS_read as u32, [0; 6]),
+            SECCOMP_RET_ALLOW
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_profile() -> Result<(), anyhow::Error> {
+        let profile = json!({
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "defaultErrnoRet": 38,
+            "syscalls": [
+                {
+                    "names": ["read", "write", "no_such_syscall"],
+                    "action": "SCMP_ACT_ALLOW"
+                },
+                {
+                    "names": ["personality"],
+                    "action": "SCMP_ACT_ALLOW",
+                    "args": [
+                        { "index": 0, "value": 8, "valueTwo": 0, "op": "SCMP_CMP_EQ" }
+                    ]
+                },
+                {
+                    "names": ["mount"],
+                    "action": "SCMP_ACT_ALLOW",
+                    "includes": { "caps": ["CAP_SYS_ADMIN"] }
+                },
+                {
+                    "names": ["chmod"],
+                    "action": "SCMP_ACT_ALLOW",
+                    "excludes": { "arches": ARCH_NAMES }
+                },
+                {
+                    "name": "ptrace",
+                    "action": "SCMP_ACT_ERRNO",
+                    "errnoRet": 1
+                }
+            ]
+        });
+
+        let filter = Filter::from_json(&profile)?;
+
+        assert_eq!(filter.default_action, Action::Errno(38));
+        let read = libc::SYS_read as u32;
+        let write = libc::SYS_write as u32;
+        let personality = libc::SYS_personality as u32;
+        let ptrace = libc::SYS_ptrace as u32;
+        assert_eq!(
+            filter
+                .rules
+                .iter()
+                .map(|rule| (rule.syscall, rule.action))
+                .collect::<Vec<_>>(),
+            [
+                (read, Action::Allow),
+                (write, Action::Allow),
+                (personality, Action::Allow),
+                (ptrace, Action::Errno(1)),
+            ]
+        );
+        assert_eq!(
+            filter.rules[2].args,
+            [ArgCondition {
+                index: 0,
+                comparison: Comparison::Eq,
+                value: 8
+            }]
+        );
+
+        let program = filter.compile()?;
+        assert_eq!(
+            evaluate(&program, personality, [8, 0, 0, 0, 0, 0]),
+            SECCOMP_RET_ALLOW
+        );
+        assert_eq!(
+            evaluate(&program, personality, [0; 6]),
+            Action::Errno(38).value()
+        );
+
+        assert!(Filter::from_json(&json!({ "syscalls": [] })).is_err());
+        assert!(Filter::from_json(&json!({
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "syscalls": [{ "names": ["read"], "action": "SCMP_ACT_NOTIFY" }]
+        }))
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_install() -> Result<(), anyhow::Error> {
+        // Deny writes of 6 bytes
+        let filter = Filter {
+            default_action: Action::Allow,
+            rules: vec![Rule {
+                syscall: libc::SYS_write as u32,
+                args: vec![ArgCondition {
+                    index: 2,
+                    comparison: Comparison::Eq,
+                    value: 6,
+                }],
+                action: Action::Errno(libc::EPERM as u16),
+            }],
+        };
+        let program = read_program(filter.to_file()?)?;
+        let key = |i: &libc::sock_filter| (i.code, i.jt, i.jf, i.k);
+        assert!(program
+            .iter()
+            .map(key)
+            .eq(filter.compile()?.iter().map(key)));
+
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("echo out; echo error; echo end");
+        // SAFETY: prctl and install only call async-signal-safe functions
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                install(&program)
+            });
+        }
+        let output = cmd.output()?;
+
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "out\nend\n");
+        Ok(())
+    }
+}
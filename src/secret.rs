@@ -0,0 +1,259 @@
+//! # Secrets
+//!
+//! `secret` materialises the secrets given with `--secret NAME=SOURCE` as files in a private
+//! directory on a memory-only file system, which is mounted read-only at `/run/secrets` in the
+//! sandbox. The values never appear in the command line, the environment or the container config
+//! of the sandbox, and the files are removed after the run, also if the tool is interrupted.
+
+use anyhow::{anyhow, Context};
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::ptr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::{env, fs};
+
+use crate::runtime_dir::RuntimeDir;
+
+/// Where the secrets are mounted in the sandbox.
+pub const SECRETS_DIR: &str = "/run/secrets";
+
+/// Signals which end the tool, after which the secrets are removed by the signal handler.
+const EXIT_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// The files and then the directory of the secrets, for the signal handler.
+static SECRET_PATHS: AtomicPtr<Vec<CString>> = AtomicPtr::new(ptr::null_mut());
+
+/// Where the value of a secret comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SecretSource {
+    /// The content of a file
+    File(PathBuf),
+    /// An environment variable of the host
+    Env(String),
+    /// The output of a shell command, without the final newline
+    Command(String),
+}
+
+/// A secret requested with `--secret NAME=SOURCE`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecretSpec {
+    name: String,
+    source: SecretSource,
+}
+
+impl FromStr for SecretSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, source) = s
+            .split_once('=')
+            .ok_or(anyhow!("Invalid secret '{s}', expected NAME=SOURCE"))?;
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+            return Err(anyhow!("Invalid secret name '{name}'"));
+        }
+        let source = if let Some(path) = source.strip_prefix("file:") {
+            SecretSource::File(PathBuf::from(path))
+        } else if let Some(var) = source.strip_prefix("env:") {
+            SecretSource::Env(var.to_string())
+        } else if let Some(command) = source.strip_prefix("cmd:") {
+            SecretSource::Command(command.to_string())
+        } else {
+            return Err(anyhow!(
+                "Unknown secret source '{source}', use file:PATH, env:VAR or cmd:COMMAND"
+            ));
+        };
+        Ok(Self {
+            name: name.to_string(),
+            source,
+        })
+    }
+}
+
+impl SecretSpec {
+    fn read(&self) -> Result<Vec<u8>, anyhow::Error> {
+        match &self.source {
+            SecretSource::File(path) => {
+                fs::read(path).with_context(|| format!("Unable to read secret file {path:?}"))
+            }
+            SecretSource::Env(var) => env::var_os(var)
+                .map(|value| value.into_encoded_bytes())
+                .ok_or(anyhow!("Environment variable {var} is not set")),
+            SecretSource::Command(command) => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::inherit())
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .context("Unable to run secret command")?;
+                if !output.status.success() {
+                    return Err(anyhow!(
+                        "Secret command '{command}' failed with {}",
+                        output.status
+                    ));
+                }
+                let mut value = output.stdout;
+                if value.ends_with(b"\n") {
+                    value.pop();
+                }
+                Ok(value)
+            }
+        }
+    }
+}
+
+/// The files of the secrets, removed on drop or when the tool is ended by a signal.
+pub struct Secrets {
+    dir: RuntimeDir,
+    /// The previous handlers of [EXIT_SIGNALS], once ours are installed
+    previous_handlers: Vec<(libc::c_int, libc::sighandler_t)>,
+}
+
+impl Secrets {
+    /// Reads the secrets into files readable only by the user, if any are requested.
+    pub fn create(specs: &[SecretSpec]) -> Result<Option<Self>, anyhow::Error> {
+        if specs.is_empty() {
+            return Ok(None);
+        }
+        for (i, spec) in specs.iter().enumerate() {
+            if specs[..i].iter().any(|other| other.name == spec.name) {
+                return Err(anyhow!("Secret {} given twice", spec.name));
+            }
+        }
+        let dir = RuntimeDir::in_memory("secrets").context("Unable to store secrets")?;
+        let mut secrets = Self {
+            dir,
+            previous_handlers: Vec::new(),
+        };
+        let mut paths = Vec::new();
+        for spec in specs {
+            let path = secrets.dir.path().join(&spec.name);
+            paths.push(CString::new(path.as_os_str().as_bytes())?);
+        }
+        paths.push(CString::new(secrets.path().as_os_str().as_bytes())?);
+        secrets.remove_on_signal(paths);
+
+        for spec in specs {
+            let value = spec
+                .read()
+                .with_context(|| format!("Unable to read secret {}", spec.name))?;
+            let path = secrets.dir.path().join(&spec.name);
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o400)
+                .open(&path)
+                .with_context(|| format!("Unable to write secret {}", spec.name))?;
+            file.write_all(&value)?;
+        }
+        Ok(Some(secrets))
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Installs handlers for [EXIT_SIGNALS] removing the paths, then ending the tool as the
+    /// signal would have.
+    fn remove_on_signal(&mut self, paths: Vec<CString>) {
+        extern "C" fn on_exit_signal(signal: libc::c_int) {
+            let paths = SECRET_PATHS.load(Ordering::SeqCst);
+            if !paths.is_null() {
+                // SAFETY: the paths are only freed after the handlers are restored
+                for path in unsafe { &*paths } {
+                    // SAFETY: unlink and rmdir are async-signal-safe, the paths are valid
+                    unsafe {
+                        if libc::unlink(path.as_ptr()) != 0 {
+                            libc::rmdir(path.as_ptr());
+                        }
+                    }
+                }
+            }
+            // SAFETY: signal and raise are async-signal-safe
+            unsafe {
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+            }
+        }
+
+        SECRET_PATHS.store(Box::into_raw(Box::new(paths)), Ordering::SeqCst);
+        for signal in EXIT_SIGNALS {
+            // SAFETY: the handler only calls async-signal-safe functions
+            let previous =
+                unsafe { libc::signal(signal, on_exit_signal as *const () as libc::sighandler_t) };
+            if previous == libc::SIG_IGN {
+                // Ignored signals, like SIGHUP with nohup, stay ignored
+                // SAFETY: restores the disposition
+                unsafe { libc::signal(signal, libc::SIG_IGN) };
+            }
+            self.previous_handlers.push((signal, previous));
+        }
+    }
+}
+
+impl Drop for Secrets {
+    fn drop(&mut self) {
+        for (signal, previous) in self.previous_handlers.drain(..) {
+            // SAFETY: restores the handler which was installed before
+            unsafe { libc::signal(signal, previous) };
+        }
+        let paths = SECRET_PATHS.swap(ptr::null_mut(), Ordering::SeqCst);
+        if !paths.is_null() {
+            // SAFETY: created by Box::into_raw, and no handler can use it anymore
+            drop(unsafe { Box::from_raw(paths) });
+        }
+        // The directory and the files are removed by the RuntimeDir
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_parse_secret() {
+        assert_eq!(
+            "TOKEN=file:/run/token".parse::<SecretSpec>().unwrap(),
+            SecretSpec {
+                name: "TOKEN".to_string(),
+                source: SecretSource::File(PathBuf::from("/run/token")),
+            }
+        );
+        assert_eq!(
+            "npmrc=cmd:pass show npm=token"
+                .parse::<SecretSpec>()
+                .unwrap()
+                .source,
+            SecretSource::Command("pass show npm=token".to_string())
+        );
+        assert!("TOKEN".parse::<SecretSpec>().is_err());
+        assert!("TOKEN=/run/token".parse::<SecretSpec>().is_err());
+        assert!("../TOKEN=env:TOKEN".parse::<SecretSpec>().is_err());
+        assert!("=env:TOKEN".parse::<SecretSpec>().is_err());
+    }
+
+    #[test]
+    fn test_secrets() -> Result<(), anyhow::Error> {
+        let specs = ["A=cmd:echo first", "B=cmd:printf 'two\\n\\n'"]
+            .map(|spec| spec.parse::<SecretSpec>().unwrap());
+        let secrets = Secrets::create(&specs)?.expect("secrets");
+        let dir = secrets.path().to_path_buf();
+        assert_eq!(fs::read(dir.join("A"))?, b"first");
+        assert_eq!(fs::read(dir.join("B"))?, b"two\n");
+        assert_eq!(fs::metadata(dir.join("A"))?.mode() & 0o777, 0o400);
+        drop(secrets);
+        assert!(!dir.exists());
+
+        assert!(Secrets::create(&[])?.is_none());
+        let twice = ["A=cmd:true", "A=cmd:true"].map(|spec| spec.parse::<SecretSpec>().unwrap());
+        assert!(Secrets::create(&twice).is_err());
+        Ok(())
+    }
+}
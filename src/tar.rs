@@ -0,0 +1,562 @@
+//! # Tar
+//!
+//! `tar` writes and reads the tar archives of the Docker archive API: ustar headers, with PAX
+//! headers for long names and large files. Unpacking keeps the permissions of the files, and never
+//! writes outside the target, neither via `..` in names nor via symlinks in the archive or in
+//! existing directories.
+
+use anyhow::{anyhow, Context};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::{symlink, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::{fs, str};
+
+const BLOCK_SIZE: usize = 512;
+
+const REGULAR: u8 = b'0';
+const HARD_LINK: u8 = b'1';
+const SYMLINK: u8 = b'2';
+const DIRECTORY: u8 = b'5';
+const PAX_HEADER: u8 = b'x';
+const PAX_GLOBAL_HEADER: u8 = b'g';
+const GNU_LONG_NAME: u8 = b'L';
+const GNU_LONG_LINK: u8 = b'K';
+
+/// Sizes from 8 GiB need a PAX header, they don't fit in the 11 octal digits of the size field.
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+/// Writes a tar archive in memory, with all files owned by the given user. The whole archive is
+/// kept in memory until it is sent, so its size is bounded by the memory available.
+pub struct TarWriter {
+    data: Vec<u8>,
+    uid: u32,
+    gid: u32,
+}
+
+impl TarWriter {
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self {
+            data: Vec::new(),
+            uid,
+            gid,
+        }
+    }
+
+    /// Adds a file, a symlink or a directory with its content as `name` in the archive, reading
+    /// the files into memory.
+    pub fn append_path(&mut self, source: &Path, name: &str) -> Result<(), anyhow::Error> {
+        let metadata =
+            fs::symlink_metadata(source).with_context(|| format!("Unable to read {source:?}"))?;
+        let mode = metadata.mode() & 0o7777;
+        let mtime = metadata.mtime().max(0) as u64;
+        if metadata.is_symlink() {
+            let target = fs::read_link(source)?;
+            let target = target
+                .to_str()
+                .ok_or(anyhow!("Symlink {source:?} is not valid Unicode"))?;
+            self.append(SYMLINK, name, target, mode, mtime, &[]);
+        } else if metadata.is_dir() {
+            self.append(DIRECTORY, &format!("{name}/"), "", mode, mtime, &[]);
+            let mut entries = fs::read_dir(source)?
+                .map(|entry| Ok(entry?.file_name()))
+                .collect::<Result<Vec<_>, anyhow::Error>>()?;
+            entries.sort();
+            for entry in entries {
+                let entry_name = entry
+                    .to_str()
+                    .ok_or(anyhow!("File name {entry:?} is not valid Unicode"))?;
+                self.append_path(&source.join(&entry), &format!("{name}/{entry_name}"))?;
+            }
+        } else if metadata.is_file() {
+            let content = fs::read(source).with_context(|| format!("Unable to read {source:?}"))?;
+            self.append(REGULAR, name, "", mode, mtime, &content);
+        } else {
+            return Err(anyhow!("{source:?} is not a file, directory or symlink"));
+        }
+        Ok(())
+    }
+
+    /// Adds an empty directory.
+    pub fn append_dir(&mut self, name: &str, mode: u32) {
+        let mtime = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.append(DIRECTORY, &format!("{name}/"), "", mode, mtime, &[]);
+    }
+
+    /// Returns the archive, with the two empty blocks marking its end.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.data.resize(self.data.len() + 2 * BLOCK_SIZE, 0);
+        self.data
+    }
+
+    fn append(
+        &mut self,
+        entry_type: u8,
+        name: &str,
+        link_name: &str,
+        mode: u32,
+        mtime: u64,
+        content: &[u8],
+    ) {
+        let size = content.len() as u64;
+        let mut records = Vec::new();
+        if name.len() > 100 {
+            records.push(pax_record("path", name));
+        }
+        if link_name.len() > 100 {
+            records.push(pax_record("linkpath", link_name));
+        }
+        if size > MAX_OCTAL_SIZE {
+            records.push(pax_record("size", &size.to_string()));
+        }
+        if !records.is_empty() {
+            let records = records.concat();
+            let header = self.header(PAX_HEADER, "pax", "", 0o644, mtime, records.len() as u64);
+            self.data.extend_from_slice(&header);
+            self.append_content(records.as_bytes());
+        }
+        let header = self.header(entry_type, name, link_name, mode, mtime, size);
+        self.data.extend_from_slice(&header);
+        self.append_content(content);
+    }
+
+    fn header(
+        &self,
+        entry_type: u8,
+        name: &str,
+        link_name: &str,
+        mode: u32,
+        mtime: u64,
+        size: u64,
+    ) -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+        // Long names are in the PAX header, truncated here
+        let name = &name.as_bytes()[..name.len().min(100)];
+        header[..name.len()].copy_from_slice(name);
+        octal(&mut header[100..108], mode.into());
+        octal(&mut header[108..116], self.uid.into());
+        octal(&mut header[116..124], self.gid.into());
+        octal(&mut header[124..136], size.min(MAX_OCTAL_SIZE));
+        octal(&mut header[136..148], mtime);
+        header[156] = entry_type;
+        let link_name = &link_name.as_bytes()[..link_name.len().min(100)];
+        header[157..157 + link_name.len()].copy_from_slice(link_name);
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        let checksum = checksum(&header);
+        octal(&mut header[148..155], checksum);
+        header[155] = b' ';
+        header
+    }
+
+    fn append_content(&mut self, content: &[u8]) {
+        self.data.extend_from_slice(content);
+        let padding = (BLOCK_SIZE - content.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        self.data.resize(self.data.len() + padding, 0);
+    }
+}
+
+/// Writes a number as octal digits, zero-padded and terminated by NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{value:0width$o}");
+    field[..width].copy_from_slice(&digits.as_bytes()[digits.len() - width..]);
+    field[width] = 0;
+}
+
+/// The sum of the bytes of the header, with spaces in the checksum field.
+fn checksum(header: &[u8; BLOCK_SIZE]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| {
+            if (148..156).contains(&i) {
+                u64::from(b' ')
+            } else {
+                u64::from(*byte)
+            }
+        })
+        .sum()
+}
+
+/// A PAX record, `LENGTH KEY=VALUE\n` with the length of the whole record.
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + rest.to_string().len();
+    if length.to_string().len() + rest != length {
+        length += 1;
+    }
+    format!("{length} {key}={value}\n")
+}
+
+/// Unpacks an archive with a single top-level entry, like the ones of the Docker archive API, to
+/// `target`: the top-level entry becomes `target`, replacing a file or symlink there, or merged
+/// into a directory.
+pub fn unpack(archive: &[u8], target: &Path) -> Result<(), anyhow::Error> {
+    let mut offset = 0;
+    let mut root = None;
+    let mut pax = HashMap::<String, String>::new();
+    let mut long_name = None;
+    let mut long_link = None;
+    let mut dirs = Vec::new();
+
+    while offset + BLOCK_SIZE <= archive.len() {
+        let header: &[u8; BLOCK_SIZE] = archive[offset..offset + BLOCK_SIZE]
+            .try_into()
+            .expect("block");
+        offset += BLOCK_SIZE;
+        if header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+        if parse_number(&header[148..156])? != checksum(header) {
+            return Err(anyhow!("Invalid tar header checksum"));
+        }
+        let entry_type = header[156];
+        let size = match pax.get("size") {
+            Some(size) if entry_type != PAX_HEADER => size
+                .parse::<u64>()
+                .map_err(|_| anyhow!("Invalid PAX size {size}"))?,
+            _ => parse_number(&header[124..136])?,
+        };
+        let size = usize::try_from(size)?;
+        let content = offset
+            .checked_add(size)
+            .and_then(|end| archive.get(offset..end))
+            .ok_or(anyhow!("Truncated tar archive"))?;
+        offset += size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+        match entry_type {
+            PAX_HEADER => {
+                pax = parse_pax(content)?;
+                continue;
+            }
+            PAX_GLOBAL_HEADER => continue,
+            GNU_LONG_NAME => {
+                long_name = Some(c_string(content).to_vec());
+                continue;
+            }
+            GNU_LONG_LINK => {
+                long_link = Some(c_string(content).to_vec());
+                continue;
+            }
+            _ => {}
+        }
+
+        let mut name = match (pax.remove("path"), long_name.take()) {
+            (Some(path), _) => path.into_bytes(),
+            (None, Some(name)) => name,
+            (None, None) => {
+                let mut name = c_string(&header[..100]).to_vec();
+                let prefix = c_string(&header[345..500]);
+                if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                    name = [prefix, b"/", &name].concat();
+                }
+                name
+            }
+        };
+        let link_name = match (pax.remove("linkpath"), long_link.take()) {
+            (Some(path), _) => path.into_bytes(),
+            (None, Some(name)) => name,
+            (None, None) => c_string(&header[157..257]).to_vec(),
+        };
+        pax.clear();
+        if name.ends_with(b"/") {
+            name.pop();
+        }
+        let path = destination(Path::new(str::from_utf8(&name)?), &mut root, target)?;
+        let mode = parse_number(&header[100..108])? as u32 & 0o777;
+
+        match entry_type {
+            DIRECTORY => {
+                prepare(&path, target, true)?;
+                dirs.push((path, mode));
+            }
+            REGULAR | b'\0' | b'7' => {
+                prepare(&path, target, false)?;
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .custom_flags(libc::O_NOFOLLOW)
+                    .mode(0o600)
+                    .open(&path)
+                    .with_context(|| format!("Unable to write {path:?}"))?;
+                file.write_all(content)?;
+                let mtime = parse_number(&header[136..148])?;
+                file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))?;
+                file.set_permissions(fs::Permissions::from_mode(mode))?;
+            }
+            SYMLINK => {
+                prepare(&path, target, false)?;
+                symlink(Path::new(str::from_utf8(&link_name)?), &path)
+                    .with_context(|| format!("Unable to create symlink {path:?}"))?;
+            }
+            HARD_LINK => {
+                let source =
+                    destination(Path::new(str::from_utf8(&link_name)?), &mut root, target)?;
+                // The source must not be reached via symlinks either
+                check_ancestors(&source, target, false)?;
+                if !fs::symlink_metadata(&source).is_ok_and(|metadata| metadata.is_file()) {
+                    return Err(anyhow!("Hard link to {source:?}, which isn't a file"));
+                }
+                prepare(&path, target, false)?;
+                fs::hard_link(&source, &path)
+                    .with_context(|| format!("Unable to create hard link {path:?}"))?;
+            }
+            // Devices and FIFOs aren't copied
+            _ => {}
+        }
+    }
+
+    // Last, so that read-only directories can be filled
+    for (dir, mode) in dirs.iter().rev() {
+        fs::set_permissions(dir, fs::Permissions::from_mode(*mode))?;
+    }
+    if root.is_none() {
+        return Err(anyhow!("Empty tar archive"));
+    }
+    Ok(())
+}
+
+/// The path of an entry under `target`, with its first component replaced by `target`. All
+/// entries must be under the same top-level entry.
+fn destination(
+    name: &Path,
+    root: &mut Option<PathBuf>,
+    target: &Path,
+) -> Result<PathBuf, anyhow::Error> {
+    let mut components = Vec::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(component) => components.push(component),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(anyhow!("Path {name:?} in tar archive leaves the target"));
+            }
+        }
+    }
+    let (first, rest) = components
+        .split_first()
+        .ok_or(anyhow!("Empty path in tar archive"))?;
+    match root {
+        Some(root) if root.as_os_str() != *first => {
+            return Err(anyhow!("Tar archive has more than one top-level entry"));
+        }
+        Some(_) => {}
+        None => *root = Some(PathBuf::from(first)),
+    }
+    Ok(rest
+        .iter()
+        .fold(target.to_path_buf(), |path, component| path.join(component)))
+}
+
+/// Prepares writing a file or directory at `path`: its parent directories from the target on must
+/// be directories, not symlinks, and are created if missing, and a file or symlink at `path` is
+/// removed.
+fn prepare(path: &Path, target: &Path, is_dir: bool) -> Result<(), anyhow::Error> {
+    check_ancestors(path, target, true)?;
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            if !is_dir {
+                return Err(anyhow!("{path:?} is a directory"));
+            }
+        }
+        Ok(_) => {
+            fs::remove_file(path)?;
+            if is_dir {
+                fs::create_dir(path)?;
+            }
+        }
+        Err(_) if is_dir => fs::create_dir(path)?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+/// Checks that the parent directories of `path` from the target on are directories, not symlinks,
+/// and creates missing ones if `create` is set.
+fn check_ancestors(path: &Path, target: &Path, create: bool) -> Result<(), anyhow::Error> {
+    let ancestors = path
+        .ancestors()
+        .skip(1)
+        .filter(|ancestor| ancestor.starts_with(target))
+        .collect::<Vec<_>>();
+    for ancestor in ancestors.into_iter().rev() {
+        match fs::symlink_metadata(ancestor) {
+            Ok(metadata) if metadata.is_symlink() => {
+                return Err(anyhow!("Tar archive writes through symlink {ancestor:?}"));
+            }
+            Ok(metadata) if !metadata.is_dir() => {
+                return Err(anyhow!("{ancestor:?} is not a directory"));
+            }
+            Ok(_) => {}
+            Err(_) if create => fs::create_dir(ancestor)?,
+            Err(err) => return Err(err).with_context(|| format!("Unable to read {ancestor:?}")),
+        }
+    }
+    Ok(())
+}
+
+fn parse_pax(content: &[u8]) -> Result<HashMap<String, String>, anyhow::Error> {
+    let mut records = HashMap::new();
+    let mut rest = content;
+    while !rest.is_empty() {
+        let space = rest
+            .iter()
+            .position(|byte| *byte == b' ')
+            .ok_or(anyhow!("Invalid PAX header"))?;
+        let length = str::from_utf8(&rest[..space])?
+            .parse::<usize>()
+            .map_err(|_| anyhow!("Invalid PAX header"))?;
+        let record = rest
+            .get(..length)
+            .and_then(|record| record.get(space + 1..length.saturating_sub(1)))
+            .ok_or(anyhow!("Invalid PAX header"))?;
+        if let Some((key, value)) = str::from_utf8(record)?.split_once('=') {
+            records.insert(key.to_string(), value.to_string());
+        }
+        rest = &rest[length..];
+    }
+    Ok(records)
+}
+
+/// Parses a number field, octal or base-256 with the high bit set.
+fn parse_number(field: &[u8]) -> Result<u64, anyhow::Error> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |value, byte| {
+                (value << 8) | u64::from(*byte)
+            }));
+    }
+    let digits = str::from_utf8(c_string(field))?.trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| anyhow!("Invalid number in tar header"))
+}
+
+fn c_string(field: &[u8]) -> &[u8] {
+    let end = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    &field[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime_dir::RuntimeDir;
+
+    #[test]
+    fn test_round_trip() -> Result<(), anyhow::Error> {
+        let dir = RuntimeDir::new("tar-test")?;
+        let source = dir.path().join("source");
+        let long_name = "n".repeat(150);
+        fs::create_dir_all(source.join("sub"))?;
+        fs::write(source.join("sub").join(&long_name), "long")?;
+        fs::write(source.join("script"), "#!/bin/sh\n")?;
+        fs::set_permissions(source.join("script"), fs::Permissions::from_mode(0o750))?;
+        symlink("sub", source.join("link"))?;
+
+        let mut writer = TarWriter::new(1000, 1000);
+        writer.append_path(&source, "source")?;
+        let archive = writer.finish();
+        let target = dir.path().join("target");
+        unpack(&archive, &target)?;
+
+        assert_eq!(
+            fs::read_to_string(target.join("sub").join(&long_name))?,
+            "long"
+        );
+        let metadata = fs::metadata(target.join("script"))?;
+        assert_eq!(metadata.mode() & 0o777, 0o750);
+        assert_eq!(
+            metadata.mtime(),
+            fs::metadata(source.join("script"))?.mtime()
+        );
+        assert_eq!(fs::read_link(target.join("link"))?, Path::new("sub"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_unsafe() -> Result<(), anyhow::Error> {
+        let dir = RuntimeDir::new("tar-test")?;
+        let target = dir.path().join("target");
+        let archive = |entries: &[(u8, &str, &str)]| {
+            let mut writer = TarWriter::new(0, 0);
+            for (entry_type, name, link_name) in entries {
+                writer.append(*entry_type, name, link_name, 0o644, 0, b"x");
+            }
+            writer.finish()
+        };
+
+        assert!(unpack(&archive(&[(REGULAR, "out/../../escaped", "")]), &target).is_err());
+        assert!(unpack(
+            &archive(&[(REGULAR, "out", ""), (REGULAR, "other", "")]),
+            &target
+        )
+        .is_err());
+        // Not written via the symlink to the parent directory
+        let via_symlink = archive(&[
+            (DIRECTORY, "out/", ""),
+            (SYMLINK, "out/link", ".."),
+            (REGULAR, "out/link/escaped", ""),
+        ]);
+        assert!(unpack(&via_symlink, &target).is_err());
+        assert!(!dir.path().join("escaped").exists());
+        // A symlink is replaced by a file, not followed
+        let replaced = archive(&[
+            (DIRECTORY, "out/", ""),
+            (SYMLINK, "out/file", "../escaped"),
+            (REGULAR, "out/file", ""),
+        ]);
+        unpack(&replaced, &target)?;
+        assert!(fs::symlink_metadata(target.join("file"))?.is_file());
+        assert!(!dir.path().join("escaped").exists());
+        // A hard link source is not resolved via a symlink to a host directory
+        let host = dir.path().join("host");
+        fs::create_dir(&host)?;
+        fs::write(host.join("secret"), "secret")?;
+        let host_link = archive(&[
+            (DIRECTORY, "out/", ""),
+            (SYMLINK, "out/host", host.to_str().expect("UTF-8")),
+            (HARD_LINK, "out/secret", "out/host/secret"),
+        ]);
+        assert!(unpack(&host_link, &target).is_err());
+        assert!(!target.join("secret").exists());
+        assert_eq!(fs::metadata(host.join("secret"))?.nlink(), 1);
+        // A PAX record longer than the header
+        let mut writer = TarWriter::new(0, 0);
+        writer.append(PAX_HEADER, "pax", "", 0o644, 0, b"6 a=b");
+        writer.append(REGULAR, "out", "", 0o644, 0, b"x");
+        assert!(unpack(&writer.finish(), &target).is_err());
+        // A base-256 size beyond the end of the address space
+        let mut huge = archive(&[(REGULAR, "out", "")]);
+        huge[124..136].copy_from_slice(&[
+            0x80, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        ]);
+        let header: &[u8; BLOCK_SIZE] = huge[..BLOCK_SIZE].try_into().expect("block");
+        let checksum = checksum(header);
+        octal(&mut huge[148..155], checksum);
+        assert!(unpack(&huge, &target).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pax_record() {
+        assert_eq!(pax_record("path", "abc"), "12 path=abc\n");
+        // The length includes its own digits
+        for length in 85..100 {
+            let record = pax_record("path", &"a".repeat(length));
+            let (prefix, _) = record.split_once(' ').expect("length");
+            assert_eq!(prefix.parse::<usize>(), Ok(record.len()));
+        }
+    }
+}
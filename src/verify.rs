@@ -0,0 +1,579 @@
+//! # Verify
+//!
+//! `verify` checks that the sandboxes still isolate programs, for `contained verify`. A probe,
+//! the tool binary in a hidden helper mode, runs in each backend and tries to escape: read the
+//! home directory and credentials, write outside the writable mounts, connect to the network, see
+//! the host processes, `ptrace` the host process and gain capabilities. Its results are written
+//! to a writable mount, and writes which reached the host are checked afterwards.
+
+use anyhow::{anyhow, Context};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::env::current_dir;
+use std::ffi::OsString;
+use std::fmt;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs, io, process, ptr};
+
+use crate::docker_client::DockerClient;
+use crate::helper::{helper_binary, probe_arguments};
+use crate::runtime_dir::RuntimeDir;
+use crate::{
+    contained_via_command, contained_via_daemon, find_in_path, wrapped, ResourceLimits,
+    SandboxOptions,
+};
+
+/// Address the probe tries to connect to.
+const PROBE_ADDRESS: &str = "1.1.1.1:443";
+
+/// Time limit of a probe connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Time limit of a probe run, also keeping the tools from replacing the process by the sandbox.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Name of the results of the probe in its writable mount.
+const RESULTS_FILE: &str = "results.json";
+
+/// Exit code if a check failed or a backend couldn't be verified.
+const VERIFY_FAILED: u8 = 1;
+
+/// A sandbox verified by `contained verify`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// `contained`, rootless Podman
+    Podman,
+    /// `contained-d`, the Docker daemon
+    Docker,
+    /// `wrapped`, bubblewrap
+    Bwrap,
+    /// `wrapped --native`, the built-in namespace sandbox
+    Native,
+}
+
+impl Backend {
+    const ALL: [Backend; 4] = [
+        Backend::Podman,
+        Backend::Docker,
+        Backend::Bwrap,
+        Backend::Native,
+    ];
+
+    fn is_available(self) -> bool {
+        match self {
+            Backend::Podman => find_in_path(Path::new("podman")).is_some(),
+            Backend::Docker => DockerClient::new().is_ok_and(|client| client.is_available()),
+            Backend::Bwrap => find_in_path(Path::new("bwrap")).is_some(),
+            Backend::Native => true,
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "podman" => Ok(Backend::Podman),
+            "docker" => Ok(Backend::Docker),
+            "bwrap" => Ok(Backend::Bwrap),
+            "native" => Ok(Backend::Native),
+            _ => Err(anyhow!(
+                "Unknown backend '{s}', expected podman, docker, bwrap or native"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Backend::Podman => "podman",
+            Backend::Docker => "docker",
+            Backend::Bwrap => "bwrap",
+            Backend::Native => "native",
+        };
+        f.pad(name)
+    }
+}
+
+/// The outcome of an escape attempt.
+#[derive(Clone, Debug, PartialEq)]
+struct Check {
+    name: String,
+    /// Whether the sandbox prevented it
+    passed: bool,
+    detail: String,
+}
+
+impl Check {
+    fn new(name: &str, passed: bool, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            passed,
+            detail: detail.into(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({"name": self.name, "passed": self.passed, "detail": self.detail})
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(Self {
+            name: value["name"].as_str()?.to_string(),
+            passed: value["passed"].as_bool()?,
+            detail: value["detail"].as_str()?.to_string(),
+        })
+    }
+}
+
+/// Runs the probe in the backends, all available ones if none are given, and prints a matrix of
+/// the checks. Returns a non-zero exit code if a check failed or a backend couldn't run the probe.
+pub fn verify(backends: &[Backend], image: &str) -> Result<u8, anyhow::Error> {
+    let selected = if backends.is_empty() {
+        Backend::ALL
+            .into_iter()
+            .filter(|backend| {
+                let available = backend.is_available();
+                if !available {
+                    eprintln!("contained: skipping {backend}, it isn't available");
+                }
+                available
+            })
+            .collect()
+    } else {
+        backends.to_vec()
+    };
+    if selected.is_empty() {
+        return Err(anyhow!("No sandbox backend is available"));
+    }
+
+    let dir = RuntimeDir::new("verify")?;
+    // A copy of the binary, so that nothing of the home directory is mounted for the program
+    let probe_dir = dir.path().join("probe");
+    fs::create_dir(&probe_dir)?;
+    let program = probe_dir.join("contained-probe");
+    fs::copy(helper_binary()?, &program).context("Unable to copy the probe")?;
+    let readonly = dir.path().join("readonly");
+    fs::create_dir(&readonly)?;
+    let home = PathBuf::from(env::var_os("HOME").ok_or(anyhow!("HOME not set"))?);
+    // Writable on the host, but not in the sandbox
+    let targets = [
+        readonly.clone(),
+        home.clone(),
+        env::temp_dir(),
+        current_dir()?,
+    ]
+    .into_iter()
+    .collect::<BTreeSet<_>>();
+    let process = host_process()?;
+
+    let mut results = Vec::new();
+    for backend in selected {
+        let results_dir = dir.path().join(format!("results-{backend}"));
+        fs::create_dir(&results_dir)?;
+        let mut arguments = vec![
+            results_dir.display().to_string(),
+            home.display().to_string(),
+            process.clone(),
+        ];
+        arguments.extend(targets.iter().map(|target| target.display().to_string()));
+        let checks = run_probe(
+            backend,
+            image,
+            &program,
+            &probe_arguments(arguments),
+            &readonly,
+            &results_dir,
+        )
+        .and_then(|()| read_results(&results_dir))
+        .map(|mut checks| {
+            // After the reads
+            checks.insert(3, check_writes(&targets));
+            checks
+        });
+        results.push((backend, checks));
+    }
+
+    print!("{}", Matrix(&results));
+    let passed = results
+        .iter()
+        .all(|(_, checks)| checks.as_ref().is_ok_and(|c| c.iter().all(|c| c.passed)));
+    Ok(if passed { 0 } else { VERIFY_FAILED })
+}
+
+/// Runs the probe with the defaults of the tool of the backend, the results directory writable.
+fn run_probe(
+    backend: Backend,
+    image: &str,
+    program: &Path,
+    arguments: &[String],
+    readonly: &Path,
+    results_dir: &Path,
+) -> Result<(), anyhow::Error> {
+    let options = SandboxOptions {
+        native: backend == Backend::Native,
+        limits: ResourceLimits {
+            timeout: Some(PROBE_TIMEOUT),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mount_readonly = [readonly.to_path_buf()];
+    let mount_writable = [results_dir.to_path_buf()];
+    let exit_code = match backend {
+        Backend::Podman => contained_via_command(
+            image,
+            program,
+            arguments,
+            "none",
+            false,
+            false,
+            &mount_readonly,
+            &mount_writable,
+            &[],
+            None,
+            false,
+            &options,
+        )?,
+        Backend::Docker => {
+            contained_via_daemon(
+                image,
+                program,
+                arguments,
+                "none",
+                false,
+                false,
+                &mount_readonly,
+                &mount_writable,
+                &[],
+                None,
+                false,
+                &options,
+            )?
+            .1
+        }
+        Backend::Bwrap | Backend::Native => wrapped(
+            program,
+            arguments,
+            false,
+            false,
+            false,
+            &mount_readonly,
+            &mount_writable,
+            &[],
+            None,
+            &options,
+        )?,
+    };
+    if exit_code != 0 {
+        return Err(anyhow!("The probe exited with {exit_code}"));
+    }
+    Ok(())
+}
+
+fn read_results(results_dir: &Path) -> Result<Vec<Check>, anyhow::Error> {
+    let path = results_dir.join(RESULTS_FILE);
+    let content = fs::read_to_string(&path).context("The probe wrote no results")?;
+    let results: Value = serde_json::from_str(&content)?;
+    results
+        .as_array()
+        .and_then(|checks| checks.iter().map(Check::from_json).collect())
+        .ok_or(anyhow!("Invalid results of the probe"))
+}
+
+/// Checks whether the writes of the probe reached the host, and removes them.
+fn check_writes(targets: &BTreeSet<PathBuf>) -> Check {
+    let written = targets
+        .iter()
+        .map(|target| target.join(marker_name(process::id())))
+        .filter(|path| fs::remove_file(path).is_ok())
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>();
+    if written.is_empty() {
+        let dirs = targets.iter().map(|target| target.display().to_string());
+        let dirs = dirs.collect::<Vec<_>>().join(", ");
+        Check::new(
+            "write outside writable mounts",
+            true,
+            format!("not in {dirs}"),
+        )
+    } else {
+        let detail = format!("wrote {}", written.join(", "));
+        Check::new("write outside writable mounts", false, detail)
+    }
+}
+
+/// The pass/fail matrix of the checks, with the details of failures.
+struct Matrix<'a>(&'a [(Backend, Result<Vec<Check>, anyhow::Error>)]);
+
+impl fmt::Display for Matrix<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = Vec::<&str>::new();
+        for (_, checks) in self.0 {
+            for check in checks.iter().flatten() {
+                if !names.contains(&check.name.as_str()) {
+                    names.push(&check.name);
+                }
+            }
+        }
+        let width = names
+            .iter()
+            .map(|name| name.len())
+            .max()
+            .unwrap_or_default();
+        let mut header = format!("{:width$}", "");
+        for (backend, _) in self.0 {
+            header += &format!("  {backend:6}");
+        }
+        writeln!(f, "{}", header.trim_end())?;
+        for name in &names {
+            let mut row = format!("{name:width$}");
+            for (_, checks) in self.0 {
+                let outcome = match checks {
+                    Ok(checks) => match checks.iter().find(|check| check.name == *name) {
+                        Some(check) if check.passed => "pass",
+                        Some(_) => "FAIL",
+                        None => "-",
+                    },
+                    Err(_) => "error",
+                };
+                row += &format!("  {outcome:6}");
+            }
+            writeln!(f, "{}", row.trim_end())?;
+        }
+        for (backend, checks) in self.0 {
+            match checks {
+                Ok(checks) => {
+                    for check in checks.iter().filter(|check| !check.passed) {
+                        writeln!(f, "{backend}: {}: {}", check.name, check.detail)?;
+                    }
+                }
+                Err(error) => writeln!(f, "{backend}: unable to run the probe: {error:#}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the checks in the sandbox and writes the results, for the `probe` helper mode. The
+/// arguments are the results directory, the home directory and the process of `contained verify`
+/// on the host, and directories to write to.
+pub fn probe(args: Vec<OsString>) -> Result<u8, anyhow::Error> {
+    let mut args = args.into_iter().map(PathBuf::from);
+    let results_dir = args.next().ok_or(anyhow!("Missing results directory"))?;
+    let home = args.next().ok_or(anyhow!("Missing home directory"))?;
+    let process = args.next().ok_or(anyhow!("Missing host process"))?;
+    let process = process.to_string_lossy();
+    let (pid, start_time) = process
+        .split_once(':')
+        .ok_or(anyhow!("Invalid host process {process:?}"))?;
+
+    let checks = [
+        check_read("read $HOME", &home),
+        check_read("read ~/.ssh", &home.join(".ssh")),
+        check_read("read /etc/shadow", Path::new("/etc/shadow")),
+        check_network(),
+        check_host_pids(pid, start_time),
+        check_ptrace(pid, start_time),
+        check_capabilities(),
+    ];
+    let marker = marker_name(pid.parse()?);
+    for target in args {
+        // Checked on the host, writes in the sandbox may not reach it
+        let _ = fs::write(target.join(&marker), "");
+    }
+
+    let results = checks.iter().map(Check::to_json).collect::<Vec<_>>();
+    fs::write(
+        results_dir.join(RESULTS_FILE),
+        serde_json::to_string(&results)?,
+    )
+    .context("Unable to write the results")?;
+    Ok(0)
+}
+
+fn check_read(name: &str, path: &Path) -> Check {
+    let result = if path.is_dir() {
+        fs::read_dir(path)
+            .map(|entries| entries.count())
+            .map(|count| match count {
+                0 => None,
+                count => Some(format!("listed {count} entries of {path:?}")),
+            })
+    } else {
+        fs::read(path).map(|content| Some(format!("read {} bytes of {path:?}", content.len())))
+    };
+    match result {
+        Ok(Some(detail)) => Check::new(name, false, detail),
+        Ok(None) => Check::new(name, true, format!("{path:?} is empty")),
+        Err(error) => Check::new(name, true, error.to_string()),
+    }
+}
+
+fn check_network() -> Check {
+    let name = "network connection";
+    let interfaces = fs::read_to_string("/proc/net/dev")
+        .unwrap_or_default()
+        .lines()
+        .skip(2)
+        .filter_map(|line| Some(line.split_once(':')?.0.trim().to_string()))
+        .filter(|interface| interface != "lo")
+        .collect::<Vec<_>>();
+    if !interfaces.is_empty() {
+        return Check::new(name, false, format!("interfaces {}", interfaces.join(", ")));
+    }
+    let address: SocketAddr = PROBE_ADDRESS.parse().expect("valid address");
+    match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+        Ok(_) => Check::new(name, false, format!("connected to {address}")),
+        Err(error) => Check::new(name, true, error.to_string()),
+    }
+}
+
+fn check_host_pids(pid: &str, start_time: &str) -> Check {
+    let name = "see host PIDs";
+    if self::start_time(pid).as_deref() == Some(start_time) {
+        Check::new(name, false, format!("host process {pid} is visible"))
+    } else {
+        Check::new(name, true, format!("host process {pid} isn't visible"))
+    }
+}
+
+fn check_ptrace(pid: &str, start_time: &str) -> Check {
+    let name = "ptrace host process";
+    // Tracing processes in the sandbox is allowed by the default seccomp profiles of Podman and
+    // Docker, only tracing the host process of `contained verify` would be an escape
+    if self::start_time(pid).as_deref() != Some(start_time) {
+        return Check::new(name, true, format!("host process {pid} isn't visible"));
+    }
+    let Ok(pid) = pid.parse::<libc::pid_t>() else {
+        return Check::new(name, false, format!("invalid host process {pid}"));
+    };
+    // Attached from a child process, which detaches by exiting
+    // SAFETY: the probe is single-threaded, so the child can't inherit locks held by other
+    // threads, and it only calls the async-signal-safe ptrace and _exit
+    let child = unsafe { libc::fork() };
+    if child == 0 {
+        // SAFETY: PTRACE_SEIZE without options takes neither address nor data, and doesn't stop
+        // the tracee
+        let result = unsafe {
+            libc::ptrace(
+                libc::PTRACE_SEIZE,
+                pid,
+                ptr::null_mut::<libc::c_void>(),
+                ptr::null_mut::<libc::c_void>(),
+            )
+        };
+        let errno = match result {
+            0 => 0,
+            _ => io::Error::last_os_error().raw_os_error().unwrap_or(libc::EPERM),
+        };
+        // SAFETY: exits the child without running the destructors and exit handlers of the probe
+        unsafe { libc::_exit(errno) };
+    }
+    if child < 0 {
+        let error = io::Error::last_os_error();
+        return Check::new(name, false, format!("unable to fork: {error}"));
+    }
+    let mut status = 0;
+    // SAFETY: child is a child process of this one, and status a valid pointer
+    if unsafe { libc::waitpid(child, &mut status, 0) } < 0 || !libc::WIFEXITED(status) {
+        return Check::new(name, false, "unable to wait for the tracing process");
+    }
+    match libc::WEXITSTATUS(status) {
+        0 => Check::new(name, false, format!("attached to host process {pid}")),
+        errno => Check::new(name, true, io::Error::from_raw_os_error(errno).to_string()),
+    }
+}
+
+fn check_capabilities() -> Check {
+    let name = "gain capabilities";
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let field = |key: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+            .and_then(|value| u64::from_str_radix(value.trim(), 16).ok())
+    };
+    let (Some(effective), Some(permitted), Some(bounding), Some(no_new_privs)) = (
+        field("CapEff"),
+        field("CapPrm"),
+        field("CapBnd"),
+        field("NoNewPrivs"),
+    ) else {
+        return Check::new(name, false, "unable to read /proc/self/status");
+    };
+    let ambient = field("CapAmb").unwrap_or_default();
+    if effective | permitted | ambient != 0 {
+        let detail = format!("capabilities {:#x}", effective | permitted | ambient);
+        Check::new(name, false, detail)
+    } else if bounding != 0 && no_new_privs == 0 {
+        let detail = format!("setuid programs can gain capabilities {bounding:#x}");
+        Check::new(name, false, detail)
+    } else {
+        Check::new(name, true, "no capabilities, and none can be gained")
+    }
+}
+
+/// `PID:START_TIME` of this process, identifying it in the sandbox if it is visible there.
+fn host_process() -> Result<String, anyhow::Error> {
+    let pid = process::id().to_string();
+    let start_time = start_time(&pid).ok_or(anyhow!("Unable to read the process start time"))?;
+    Ok(format!("{pid}:{start_time}"))
+}
+
+/// Start time of a process since boot, from `/proc/PID/stat`.
+fn start_time(pid: &str) -> Option<String> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // After the command name, which may contain spaces, starting with the state as field 3
+    let fields = stat.rsplit_once(')')?.1;
+    fields.split_whitespace().nth(19).map(String::from)
+}
+
+/// Name of the files the probe tries to write outside the writable mounts.
+fn marker_name(pid: u32) -> String {
+    format!(".contained-verify-{pid}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matrix() {
+        let results = [
+            (
+                Backend::Bwrap,
+                Ok(vec![
+                    Check::new("read $HOME", true, "No such file or directory"),
+                    Check::new("ptrace host process", false, "attached to host process 42"),
+                ]),
+            ),
+            (Backend::Podman, Err(anyhow!("The probe exited with 125"))),
+        ];
+        assert_eq!(
+            Matrix(&results).to_string(),
+            "                     bwrap   podman\n\
+             read $HOME           pass    error\n\
+             ptrace host process  FAIL    error\n\
+             bwrap: ptrace host process: attached to host process 42\n\
+             podman: unable to run the probe: The probe exited with 125\n"
+        );
+        assert_eq!("native".parse::<Backend>().unwrap(), Backend::Native);
+        assert!("lxc".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn test_start_time() {
+        let pid = process::id().to_string();
+        assert!(start_time(&pid).is_some_and(|time| time.parse::<u64>().is_ok()));
+        assert_eq!(
+            host_process().unwrap(),
+            format!("{pid}:{}", start_time(&pid).unwrap())
+        );
+    }
+}
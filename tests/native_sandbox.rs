@@ -0,0 +1,483 @@
+use std::error;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Mount point, file system type and whether it is read-only, for each mount in the sandbox.
+fn mounts(args: &[&str]) -> Result<Vec<(String, String, bool)>, Box<dyn error::Error>> {
+    let output = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .args(args)
+        .arg("cat")
+        .arg("/proc/self/mountinfo")
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let mut mounts = String::from_utf8(output.stdout)?
+        .lines()
+        .map(|line| {
+            let (fields, fs) = line.split_once(" - ").expect("separator");
+            let fields = fields.split(' ').collect::<Vec<_>>();
+            let readonly = fields[5].split(',').any(|option| option == "ro");
+            let fs_type = fs.split(' ').next().expect("file system type");
+            (fields[4].to_string(), fs_type.to_string(), readonly)
+        })
+        .collect::<Vec<_>>();
+    mounts.sort();
+    Ok(mounts)
+}
+
+#[test]
+fn test_native_sandbox_layout() -> Result<(), Box<dyn error::Error>> {
+    let native = mounts(&["--native"])?;
+
+    for (mount_point, fs_type, readonly) in [
+        ("/", "tmpfs", false),
+        ("/usr", "", true),
+        ("/etc", "", true),
+        ("/tmp", "tmpfs", false),
+        ("/proc", "proc", false),
+        ("/proc/sys", "proc", true),
+        ("/dev", "tmpfs", false),
+        ("/dev/pts", "devpts", false),
+    ] {
+        assert!(
+            native.iter().any(|(m, f, r)| m == mount_point
+                && (fs_type.is_empty() || f == fs_type)
+                && *r == readonly),
+            "{mount_point} not mounted as expected: {native:?}"
+        );
+    }
+    assert!(native.iter().any(|(m, _, _)| m == "/dev/null"));
+
+    if Command::new("bwrap").arg("--version").output().is_ok() {
+        assert_eq!(native, mounts(&[])?);
+    } else {
+        eprintln!("bwrap not available, not comparing");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_seccomp() -> Result<(), Box<dyn error::Error>> {
+    let seccomp_mode = |args: &[&str]| -> Result<String, Box<dyn error::Error>> {
+        let output = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+            .arg("--native")
+            .args(args)
+            .arg("grep")
+            .arg("^Seccomp:")
+            .arg("/proc/self/status")
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Ok(String::from_utf8(output.stdout)?
+            .split_whitespace()
+            .collect())
+    };
+
+    assert_eq!(seccomp_mode(&[])?, "Seccomp:2");
+    // Without a filter, the sandbox inherits the mode of the test
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let own_mode = status
+        .lines()
+        .find(|line| line.starts_with("Seccomp:"))
+        .map(|line| line.split_whitespace().collect::<String>());
+    assert_eq!(
+        Some(seccomp_mode(&["--seccomp-profile", "unconfined"])?),
+        own_mode
+    );
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_new_session() -> Result<(), Box<dyn error::Error>> {
+    let output = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .arg("--native")
+        .arg("cat")
+        .arg("/proc/self/stat")
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // The program leads its own session, without a controlling terminal
+    let stat = String::from_utf8(output.stdout)?;
+    let (pid, fields) = stat.split_once(" (").expect("pid");
+    let fields = fields.rsplit_once(") ").expect("command").1;
+    let fields = fields.split(' ').collect::<Vec<_>>();
+    assert_eq!(fields[3], pid, "session of {stat}");
+    assert_eq!(fields[4], "0", "controlling terminal of {stat}");
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_ephemeral_root() -> Result<(), Box<dyn error::Error>> {
+    let marker = format!("/etc/contained-ephemeral-{}", std::process::id());
+    let output = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .arg("--native")
+        .arg("--ephemeral-root=1m")
+        .arg("sh")
+        .arg("--")
+        .arg("-c")
+        .arg(format!("echo written > {marker} && cat {marker}"))
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8(output.stdout)?, "written\n");
+    assert!(!std::path::Path::new(&marker).exists());
+
+    // Dotfiles and caches can be written as well
+    let output = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .arg("--native")
+        .arg("--ephemeral-root=1m")
+        .arg("sh")
+        .arg("--")
+        .arg("-c")
+        .arg("touch ~/.tool /var/cache/tool && ls -A ~ /var/cache")
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "/home/contained:\n.tool\n\n/var/cache:\ntool\n"
+    );
+
+    // The changes can't exceed the limit
+    let output = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .arg("--native")
+        .arg("--ephemeral-root=1m")
+        .arg("sh")
+        .arg("--")
+        .arg("-c")
+        .arg("head -c 4000000 /dev/zero > /usr/large")
+        .output()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("No space left on device"));
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_ephemeral_home() -> Result<(), Box<dyn error::Error>> {
+    let output = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .arg("--native")
+        .arg("--home=ephemeral")
+        .arg("sh")
+        .arg("--")
+        .arg("-c")
+        .arg("mkdir -p $XDG_CONFIG_HOME && touch $XDG_CONFIG_HOME/tool && cd && find . | sort")
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        ".\n./.config\n./.config/tool\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_cache_home() -> Result<(), Box<dyn error::Error>> {
+    let data_home = std::env::temp_dir().join(format!("contained-test-{}", std::process::id()));
+    fs::create_dir(&data_home)?;
+    let output = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .args(["--native", "--cache", "cargo"])
+        .args(["sh", "--", "-c", "echo $HOME && test -d $HOME/.cargo/registry"])
+        .env("XDG_DATA_HOME", &data_home)
+        .output()?;
+    fs::remove_dir_all(&data_home)?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // The tools find the cache in the home directory of the sandbox
+    assert_eq!(String::from_utf8(output.stdout)?, "/home/contained\n");
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_minimal() -> Result<(), Box<dyn error::Error>> {
+    let output = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .arg("--native")
+        .arg("--minimal")
+        .arg("ls")
+        .arg("/usr/bin")
+        .arg("/etc/shadow")
+        .output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // Only the program itself is visible
+    assert_eq!(stdout.lines().collect::<Vec<_>>(), ["/usr/bin:", "ls"]);
+    assert!(stderr.contains("/etc/shadow"), "{stderr}");
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_learned_profile() -> Result<(), Box<dyn error::Error>> {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("learned-profile");
+    let data = dir.join("data");
+    let work = dir.join("work");
+    fs::create_dir_all(&data)?;
+    fs::create_dir_all(&work)?;
+    fs::write(data.join("input"), "learned")?;
+    let profile = work.join("cat.json");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_contained"))
+        .args(["learn", "--output"])
+        .arg(&profile)
+        .args(["--", "cat"])
+        .arg(data.join("input"))
+        .current_dir(&work)
+        .output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("Landlock is not supported") {
+        eprintln!("Landlock not available, skipping");
+        return Ok(());
+    }
+    assert!(output.status.success(), "{stderr}");
+    assert_eq!(output.stdout, b"learned");
+
+    let run = |profile: Option<&Path>| -> Result<_, Box<dyn error::Error>> {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_wrapped"));
+        command.arg("--native");
+        if let Some(profile) = profile {
+            command.arg("--profile").arg(profile);
+        }
+        Ok(command
+            .arg("cat")
+            .arg(data.join("input"))
+            .current_dir(&work)
+            .output()?)
+    };
+    assert!(!run(None)?.status.success());
+    let output = run(Some(&profile))?;
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(output.stdout, b"learned");
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_auto_mount_args() -> Result<(), Box<dyn error::Error>> {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("auto-mount-args");
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("notes.txt"), "notes")?;
+    let _ = fs::remove_file(dir.join("copy.txt"));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .args([
+            "--native",
+            "--auto-mount-args",
+            "cp",
+            "notes.txt",
+            "./copy.txt",
+        ])
+        .current_dir(&dir)
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(fs::read_to_string(dir.join("copy.txt"))?, "notes");
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_verify() -> Result<(), Box<dyn error::Error>> {
+    let output = Command::new(env!("CARGO_BIN_EXE_contained"))
+        .args(["verify", "--backend", "native"])
+        .output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+
+    for check in [
+        "read $HOME",
+        "read ~/.ssh",
+        "write outside writable mounts",
+        "network connection",
+        "see host PIDs",
+        "ptrace host process",
+        "gain capabilities",
+    ] {
+        assert!(
+            stdout
+                .lines()
+                .any(|line| line.starts_with(check) && line.ends_with(" pass")),
+            "{check} not passed: {stdout}"
+        );
+    }
+    // Run as root, /etc/shadow is readable in the sandbox, like in the other ones
+    // SAFETY: plain system call without pointers
+    let is_root = unsafe { libc::geteuid() } == 0;
+    let failed = stdout.lines().filter(|line| line.ends_with(" FAIL"));
+    let failed = failed.map(|line| line.split("  ").next().unwrap_or_default());
+    let expected: &[&str] = if is_root { &["read /etc/shadow"] } else { &[] };
+    assert_eq!(failed.collect::<Vec<_>>(), expected, "{stdout}");
+    assert_eq!(output.status.code(), Some(i32::from(is_root)));
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_shell() -> Result<(), Box<dyn error::Error>> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .args([
+            "--native",
+            "--shell",
+            "--env",
+            "LANG=C",
+            "--workdir",
+            "/etc",
+        ])
+        .args(["false", "--", "--ignored"])
+        .env("SHELL", "/nonexistent/shell")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("stdin")
+        .write_all(b"echo \"$0 $LANG $PWD\"\n")?;
+    let output = child.wait_with_output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // The shell runs instead of the program, in its sandbox
+    assert_eq!(String::from_utf8(output.stdout)?, "/bin/sh C /etc\n");
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_secret() -> Result<(), Box<dyn error::Error>> {
+    let output = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .args(["--native", "--secret", "token=env:CONTAINED_TEST_SECRET"])
+        .args(["sh", "--", "-c"])
+        .arg("stat -c %a /run/secrets/token && cat /run/secrets/token && env | grep -c hunter2")
+        .env("CONTAINED_TEST_SECRET", "hunter2")
+        .output()?;
+
+    // The secret is only visible as a file, not in the environment
+    let stdout = String::from_utf8(output.stdout)?;
+    assert_eq!(
+        stdout,
+        "400\nhunter20\n",
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(!output.status.success());
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_secret_on_signal() -> Result<(), Box<dyn error::Error>> {
+    let runtime_dir = Path::new("/dev/shm").join(format!("contained-test-{}", std::process::id()));
+    fs::create_dir(&runtime_dir)?;
+    let mut child = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .args(["--native", "--secret", "token=env:CONTAINED_TEST_SECRET"])
+        .args(["sleep", "10"])
+        .env("CONTAINED_TEST_SECRET", "hunter2")
+        .env("XDG_RUNTIME_DIR", &runtime_dir)
+        .stderr(Stdio::null())
+        .spawn()?;
+    let secrets = || fs::read_dir(&runtime_dir).map(|entries| entries.count());
+    for _ in 0..100 {
+        if secrets()? > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(secrets()?, 1);
+
+    // Ended by a signal, the secrets are removed anyway
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()?;
+    let status = child.wait()?;
+    let remaining = secrets()?;
+    fs::remove_dir_all(&runtime_dir)?;
+    assert!(!status.success());
+    assert_eq!(remaining, 0);
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_published_port() -> Result<(), Box<dyn error::Error>> {
+    if Command::new("pasta").arg("--version").output().is_err() {
+        eprintln!("pasta not available, skipping");
+        return Ok(());
+    }
+    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let child = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .args(["--native", "--network=pasta", "--publish"])
+        .arg(format!("{port}:8000"))
+        .args(["python3", "-c"])
+        .arg(concat!(
+            "import socket\n",
+            "server = socket.create_server(('', 8000))\n",
+            "server.accept()[0].sendall(b'published')\n",
+        ))
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut received = String::new();
+    for _ in 0..100 {
+        // pasta accepts connections before the program listens, and closes them then
+        if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)) {
+            stream.read_to_string(&mut received)?;
+            if !received.is_empty() {
+                break;
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let output = child.wait_with_output()?;
+    assert_eq!(
+        received,
+        "published",
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(output.status.success());
+    Ok(())
+}
+
+#[test]
+fn test_native_sandbox_allow_host() -> Result<(), Box<dyn error::Error>> {
+    let output = Command::new(env!("CARGO_BIN_EXE_wrapped"))
+        .args(["--native", "--allow-host", "example.org"])
+        .args(["bash", "--", "-c"])
+        .arg("exec 3<>/dev/tcp/127.0.0.1/3128 && echo connected")
+        .output()?;
+
+    // The forwarder to the proxy listens on the loopback interface of the sandbox
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "connected\n",
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(output.status.success());
+    Ok(())
+}